@@ -0,0 +1,69 @@
+//! High Precision Event Timer (HPET) description table
+
+use super::tables::{get_table, SDTHeader};
+
+/// Address space ID of a generic address structure that lies in system memory
+const ADDRESS_SPACE_SYSTEM_MEMORY: u8 = 0;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+struct HpetTable {
+    header: SDTHeader,
+    event_timer_block_id: u32,
+    address_space_id: u8,
+    register_bit_width: u8,
+    register_bit_offset: u8,
+    access_size: u8,
+    base_address: u64,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Hpet {
+    header: SDTHeader,
+    event_timer_block_id: u32,
+    base_address: u64,
+    minimum_tick: u16,
+}
+
+impl Hpet {
+    /// Returns `None` if the table is invalid or the timer block is not memory mapped
+    pub fn new(addr: usize) -> Option<Self> {
+        get_table(addr, *b"HPET")?;
+        // SAFETY: get_table validated the header and checksum of the table at `addr`, which the
+        // firmware maps in full
+        let table = unsafe { (addr as *const HpetTable).read_unaligned() };
+        if table.address_space_id != ADDRESS_SPACE_SYSTEM_MEMORY {
+            return None;
+        }
+        Some(Hpet {
+            header: table.header,
+            event_timer_block_id: table.event_timer_block_id,
+            base_address: table.base_address,
+            minimum_tick: table.minimum_tick,
+        })
+    }
+
+    #[allow(unused)]
+    pub fn header(&self) -> SDTHeader {
+        self.header
+    }
+
+    #[allow(unused)]
+    pub fn event_timer_block_id(&self) -> u32 {
+        self.event_timer_block_id
+    }
+
+    /// The physical address of the timer block's registers
+    pub fn base_address(&self) -> u64 {
+        self.base_address
+    }
+
+    /// The minimum number of main counter ticks between periodic interrupts
+    #[allow(unused)]
+    pub fn minimum_tick(&self) -> u16 {
+        self.minimum_tick
+    }
+}
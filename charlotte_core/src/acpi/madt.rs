@@ -36,61 +36,76 @@ impl Madt {
         self.flags
     }
 
-    pub fn iter(&self) -> MadtIter {
+    /// Returns the interrupt controller structures that follow the fixed part of the table
+    pub fn entries(&self) -> MadtIter {
         MadtIter {
             addr: self.addr + mem::size_of::<SDTHeader>() + 8, // Skip over the header, the local APIC address and flags
             offset: 0,
-            len: self.header.length() as usize - mem::size_of::<SDTHeader>() - 8,
+            len: (self.header.length() as usize).saturating_sub(mem::size_of::<SDTHeader>() + 8),
         }
     }
 }
 
 /// MADT Entry Iterator
+/// Iteration stops at an entry that claims to be shorter than its header or to extend past the
+/// end of the table, since the entries after it cannot be located.
 pub struct MadtIter {
     addr: usize,
     offset: usize,
     len: usize,
 }
 
+impl MadtIter {
+    /// Reads the entry at the current offset as a `T` if it is long enough to hold one
+    fn read<T: Copy>(&self, length: usize) -> Option<T> {
+        if length < mem::size_of::<T>() {
+            return None;
+        }
+        // SAFETY: the caller checked that the entry lies within the table and `T` is no longer than
+        // the entry
+        Some(unsafe { ((self.addr + self.offset) as *const T).read_unaligned() })
+    }
+}
+
 impl Iterator for MadtIter {
     type Item = MadtEntry;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.offset < self.len {
-            let header = unsafe { &*((self.addr + self.offset) as *const MadtEntryHeader) };
-
-            let entry = match header.entry_type {
-                0 => MadtEntry::ProcessorLocalApic(unsafe {
-                    *((self.addr + self.offset) as *const ProcessorLocalApic)
-                }),
-                1 => MadtEntry::IOApic(unsafe { *((self.addr + self.offset) as *const IoApic) }),
-                2 => MadtEntry::InterruptSourceOverride(unsafe {
-                    *((self.addr + self.offset) as *const InterruptSourceOverride)
-                }),
-                3 => MadtEntry::NonMaskableInterruptSource(unsafe {
-                    *((self.addr + self.offset) as *const NonMaskableInterruptSource)
-                }),
-                4 => MadtEntry::LocalApicNmi(unsafe {
-                    *((self.addr + self.offset) as *const LocalApicNmi)
-                }),
-                5 => MadtEntry::LocalApicAddressOverride(unsafe {
-                    *((self.addr + self.offset) as *const LocalApicAddressOverride)
-                }),
-                _ => MadtEntry::Unknown(header.entry_type),
-            };
-            self.offset += header.length as usize;
-            Some(entry)
-        } else {
-            None
+        if self.offset + mem::size_of::<MadtEntryHeader>() > self.len {
+            return None;
+        }
+        let header =
+            // SAFETY: the entry header lies within the table as checked above
+            unsafe { ((self.addr + self.offset) as *const MadtEntryHeader).read_unaligned() };
+        let length = header.length as usize;
+        if length < mem::size_of::<MadtEntryHeader>() || self.offset + length > self.len {
+            self.offset = self.len;
+            return None;
         }
+
+        let entry = match header.entry_type {
+            0 => self.read(length).map(MadtEntry::ProcessorLocalApic),
+            1 => self.read(length).map(MadtEntry::IOApic),
+            2 => self.read(length).map(MadtEntry::InterruptSourceOverride),
+            3 => self.read(length).map(MadtEntry::NonMaskableInterruptSource),
+            4 => self.read(length).map(MadtEntry::LocalApicNmi),
+            5 => self.read(length).map(MadtEntry::LocalApicAddressOverride),
+            9 => self.read(length).map(MadtEntry::ProcessorLocalX2Apic),
+            10 => self.read(length).map(MadtEntry::LocalX2ApicNmi),
+            _ => None,
+        };
+        self.offset += length;
+        Some(entry.unwrap_or(MadtEntry::Unknown(header.entry_type)))
     }
 }
 
 /// MADT Entries
+/// Entries of a type that is not decoded, or that are too short for their type, are `Unknown`.
 #[derive(Debug)]
 pub enum MadtEntry {
     #[allow(unused)]
     ProcessorLocalApic(ProcessorLocalApic),
+    #[allow(unused)]
     IOApic(IoApic),
     #[allow(unused)]
     InterruptSourceOverride(InterruptSourceOverride),
@@ -101,6 +116,10 @@ pub enum MadtEntry {
     #[allow(unused)]
     LocalApicAddressOverride(LocalApicAddressOverride),
     #[allow(unused)]
+    ProcessorLocalX2Apic(ProcessorLocalX2Apic),
+    #[allow(unused)]
+    LocalX2ApicNmi(LocalX2ApicNmi),
+    #[allow(unused)]
     Unknown(u8),
 }
 
@@ -127,10 +146,10 @@ pub struct ProcessorLocalApic {
 #[derive(Debug, Copy, Clone)]
 pub struct IoApic {
     header: MadtEntryHeader,
-    io_apic_id: u8,
+    pub io_apic_id: u8,
     reserved: u8,
-    io_apic_addr: u32,
-    global_system_interrupt_base: u32,
+    pub io_apic_addr: u32,
+    pub global_system_interrupt_base: u32,
 }
 
 /// Interrupt Source Override Structure
@@ -138,10 +157,10 @@ pub struct IoApic {
 #[derive(Debug, Copy, Clone)]
 pub struct InterruptSourceOverride {
     header: MadtEntryHeader,
-    bus: u8,
-    source: u8,
-    global_system_interrupt: u32,
-    flags: u16,
+    pub bus: u8,
+    pub source: u8,
+    pub global_system_interrupt: u32,
+    pub flags: u16,
 }
 
 /// Non-maskable Interrupt Source Structure
@@ -149,8 +168,8 @@ pub struct InterruptSourceOverride {
 #[derive(Debug, Copy, Clone)]
 pub struct NonMaskableInterruptSource {
     header: MadtEntryHeader,
-    flags: u16,
-    global_system_interrupt: u32,
+    pub flags: u16,
+    pub global_system_interrupt: u32,
 }
 
 /// Local APIC NMI Structure
@@ -158,9 +177,9 @@ pub struct NonMaskableInterruptSource {
 #[derive(Debug, Copy, Clone)]
 pub struct LocalApicNmi {
     header: MadtEntryHeader,
-    processor_id: u8,
-    flags: u16,
-    local_apic_lint: u8,
+    pub processor_id: u8,
+    pub flags: u16,
+    pub local_apic_lint: u8,
 }
 
 /// Local APIC Address Override Structure
@@ -171,3 +190,122 @@ pub struct LocalApicAddressOverride {
     reserved: u16,
     pub local_apic_address: u64,
 }
+
+/// Processor Local x2APIC Structure, used for LPs whose APIC ID does not fit in 8 bits
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct ProcessorLocalX2Apic {
+    header: MadtEntryHeader,
+    reserved: u16,
+    pub x2apic_id: u32,
+    pub flags: u32,
+    pub acpi_processor_uid: u32,
+}
+
+/// Local x2APIC NMI Structure
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct LocalX2ApicNmi {
+    header: MadtEntryHeader,
+    pub flags: u16,
+    pub acpi_processor_uid: u32,
+    pub local_x2apic_lint: u8,
+    reserved: [u8; 3],
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The size of the header, local APIC address and flags that precede the entries
+    const FIXED_SIZE: usize = mem::size_of::<SDTHeader>() + 8;
+
+    /// Builds a MADT whose interrupt controller structures are `entries` and whose header claims
+    /// the table is `length` bytes long. The table has to outlive the returned `Madt`.
+    fn build_madt(entries: &[u8], length: usize) -> (Vec<u8>, Madt) {
+        let mut table = vec![0u8; FIXED_SIZE];
+        table[..4].copy_from_slice(b"APIC");
+        table[4..8].copy_from_slice(&(length as u32).to_le_bytes());
+        table.extend_from_slice(entries);
+        let addr = table.as_ptr() as usize;
+        // SAFETY: the table starts with a whole SDT header
+        let header = unsafe { (addr as *const SDTHeader).read_unaligned() };
+        let madt = Madt {
+            header,
+            local_apic_addr: 0xFEE0_0000,
+            flags: 0,
+            addr,
+        };
+        (table, madt)
+    }
+
+    const LOCAL_APIC: [u8; 8] = [0, 8, 1, 2, 1, 0, 0, 0];
+    const X2APIC: [u8; 16] = [9, 16, 0, 0, 0x00, 0x01, 0, 0, 1, 0, 0, 0, 7, 0, 0, 0];
+
+    #[test]
+    fn entries_are_decoded_in_order() {
+        let entries = [&LOCAL_APIC[..], &X2APIC[..], &[0x7F, 2]].concat();
+        let (_table, madt) = build_madt(&entries, FIXED_SIZE + entries.len());
+        let mut iter = madt.entries();
+        match iter.next() {
+            Some(MadtEntry::ProcessorLocalApic(lapic)) => {
+                assert_eq!({ lapic.processor_id }, 1);
+                assert_eq!({ lapic.apic_id }, 2);
+                assert_eq!({ lapic.flags }, 1);
+            }
+            entry => panic!("expected a local APIC, got {:?}", entry),
+        }
+        match iter.next() {
+            Some(MadtEntry::ProcessorLocalX2Apic(x2apic)) => {
+                assert_eq!({ x2apic.x2apic_id }, 0x100);
+                assert_eq!({ x2apic.acpi_processor_uid }, 7);
+            }
+            entry => panic!("expected a local x2APIC, got {:?}", entry),
+        }
+        assert!(matches!(iter.next(), Some(MadtEntry::Unknown(0x7F))));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn entries_too_short_for_their_type_are_unknown() {
+        let entries = [&[0, 4, 1, 2][..], &LOCAL_APIC[..]].concat();
+        let (_table, madt) = build_madt(&entries, FIXED_SIZE + entries.len());
+        let mut iter = madt.entries();
+        assert!(matches!(iter.next(), Some(MadtEntry::Unknown(0))));
+        assert!(matches!(
+            iter.next(),
+            Some(MadtEntry::ProcessorLocalApic(_))
+        ));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn iteration_stops_at_malformed_entries() {
+        // an entry shorter than its header would never advance the iterator
+        let entries = [&[0x7F, 0][..], &LOCAL_APIC[..]].concat();
+        let (_table, madt) = build_madt(&entries, FIXED_SIZE + entries.len());
+        assert_eq!(madt.entries().count(), 0);
+
+        // the table length cuts the second entry short
+        let entries = [LOCAL_APIC, LOCAL_APIC].concat();
+        let (_table, madt) = build_madt(&entries, FIXED_SIZE + 12);
+        let mut iter = madt.entries();
+        assert!(matches!(
+            iter.next(),
+            Some(MadtEntry::ProcessorLocalApic(_))
+        ));
+        assert!(iter.next().is_none());
+        assert!(iter.next().is_none());
+
+        // a single trailing byte cannot hold an entry header
+        let entries = [&LOCAL_APIC[..], &[0]].concat();
+        let (_table, madt) = build_madt(&entries, FIXED_SIZE + entries.len());
+        assert_eq!(madt.entries().count(), 1);
+    }
+
+    #[test]
+    fn table_shorter_than_its_fixed_part_has_no_entries() {
+        let (_table, madt) = build_madt(&LOCAL_APIC, mem::size_of::<SDTHeader>());
+        assert_eq!(madt.entries().count(), 0);
+    }
+}
@@ -6,12 +6,14 @@ use crate::bootinfo::RSDP_REQUEST;
 
 use self::bgrt::Bgrt;
 use self::fadt::Fadt;
+use self::hpet::Hpet;
 use self::madt::Madt;
 use self::sdt::Sdt;
 use self::srat::Srat;
 
 pub mod bgrt;
 pub mod fadt;
+pub mod hpet;
 pub mod madt;
 pub mod rsdp;
 pub mod sdt;
@@ -29,6 +31,7 @@ pub struct AcpiInfo {
     bgrt: Bgrt,
     #[allow(unused)]
     srat: Option<Srat>,
+    hpet: Option<Hpet>,
 }
 
 impl AcpiInfo {
@@ -40,6 +43,7 @@ impl AcpiInfo {
         fadt: Fadt,
         bgrt: Bgrt,
         srat: Option<Srat>,
+        hpet: Option<Hpet>,
     ) -> Self {
         Self {
             rsdp,
@@ -48,6 +52,7 @@ impl AcpiInfo {
             fadt,
             bgrt,
             srat,
+            hpet,
         }
     }
 
@@ -66,6 +71,10 @@ impl AcpiInfo {
     pub fn bgrt(&self) -> &Bgrt {
         &self.bgrt
     }
+
+    pub fn hpet(&self) -> Option<&Hpet> {
+        self.hpet.as_ref()
+    }
 }
 
 pub fn parse() -> AcpiInfo {
@@ -83,7 +92,8 @@ pub fn parse() -> AcpiInfo {
         } else {
             None
         };
-        AcpiInfo::new(rsdp, sdt, madt, fadt, bgrt, srat)
+        let hpet = sdt.get_table(*b"HPET").and_then(Hpet::new);
+        AcpiInfo::new(rsdp, sdt, madt, fadt, bgrt, srat, hpet)
     } else {
         panic!("Failed to obtain RSDP response.");
     }
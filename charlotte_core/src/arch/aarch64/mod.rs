@@ -5,6 +5,8 @@ pub mod uart;
 
 use uart::Uart;
 
+use crate::memory::address::VirtualAddress;
+
 pub struct Api;
 
 /// Provide the implementation of the Api trait for the Api struct
@@ -44,6 +46,33 @@ impl crate::arch::Api for Api {
     fn outb(_port: u16, _val: u8) {
         todo!()
     }
+    /// Cleans the data cache and invalidates the instruction cache by line over the range, then
+    /// synchronizes the instruction stream
+    fn sync_instruction_cache(start: VirtualAddress, len: usize) {
+        let ctr: u64;
+        // SAFETY: reading CTR_EL0 has no side effects
+        unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr) };
+        // CTR_EL0 holds the log2 of the smallest line sizes in words
+        let dline = 4usize << ((ctr >> 16) & 0xF);
+        let iline = 4usize << (ctr & 0xF);
+        let end = start.bits() as usize + len;
+        for line in (start.bits() as usize & !(dline - 1)..end).step_by(dline) {
+            // SAFETY: cleaning a data cache line to the point of unification does not change memory
+            unsafe { asm!("dc cvau, {}", in(reg) line) };
+        }
+        // SAFETY: barriers only order memory accesses
+        unsafe { asm!("dsb ish") };
+        for line in (start.bits() as usize & !(iline - 1)..end).step_by(iline) {
+            // SAFETY: invalidating an instruction cache line only makes it be fetched again
+            unsafe { asm!("ic ivau, {}", in(reg) line) };
+        }
+        // SAFETY: barriers only order memory accesses and instruction fetches
+        unsafe { asm!("dsb ish", "isb") };
+    }
+    /// LPs are not assigned logical indices yet
+    fn current_cpu_index() -> Option<usize> {
+        None
+    }
     /// Initialize the bootstrap processor (BSP)
     fn init_bsp() {}
     ///
@@ -6,11 +6,13 @@
 use core::fmt;
 use core::fmt::Write;
 use core::result::Result;
+use core::time::Duration;
 
-use spin::{lazy::Lazy, mutex::TicketMutex};
+use spin::lazy::Lazy;
 
 use crate::framebuffer::console::CONSOLE;
 use crate::memory::address::{PhysicalAddress, UAddr, VirtualAddress};
+use crate::sync::IrqSafeMutex;
 
 #[cfg(target_arch = "aarch64")]
 pub mod aarch64;
@@ -19,9 +21,18 @@ pub mod riscv64;
 #[cfg(target_arch = "x86_64")]
 pub mod x86_64;
 
-pub static LOGGER: Lazy<TicketMutex<Logger>> = Lazy::new(|| {
-    TicketMutex::new(Logger {
+/// The kernel log, locked for a whole line at a time so that lines from different LPs and from
+/// interrupt handlers never interleave
+///
+/// Lines mirrored to the framebuffer take [`CONSOLE`] while this lock is held, so the lock order is
+/// always `LOGGER` then `CONSOLE` and code holding `CONSOLE` must never log
+pub static LOGGER: Lazy<IrqSafeMutex<Logger>> = Lazy::new(|| {
+    IrqSafeMutex::new(Logger {
         logger: <ArchApi as Api>::get_logger(),
+        level: LogLevel::Info,
+        clock: None,
+        mirror_to_console: true,
+        at_line_start: true,
     })
 });
 
@@ -49,8 +60,12 @@ pub trait MemoryMap {
     /// * `vaddr` - The virtual address to unmap.
     /// # Returns
     /// Returns an error of type `Self::Error` if unmapping fails or the physical address that was
-    /// previously mapped to the given virtual address if successful.
-    fn unmap_page(&mut self, vaddr: VirtualAddress) -> Result<PhysicalAddress, Self::Error>;
+    /// previously mapped to the given virtual address along with the flags it was mapped with if
+    /// successful.
+    fn unmap_page(
+        &mut self,
+        vaddr: VirtualAddress,
+    ) -> Result<(PhysicalAddress, Self::Flags), Self::Error>;
 
     /// Maps a large page (2 MiB) at the given virtual address.
     /// # Arguments
@@ -71,8 +86,12 @@ pub trait MemoryMap {
     /// * `vaddr` - The virtual address to unmap.
     /// # Returns
     /// Returns an error of type `Self::Error` if unmapping fails or the physical address that was
-    /// previously mapped to the given virtual address if successful.
-    fn unmap_large_page(&mut self, vaddr: VirtualAddress) -> Result<PhysicalAddress, Self::Error>;
+    /// previously mapped to the given virtual address along with the flags it was mapped with if
+    /// successful.
+    fn unmap_large_page(
+        &mut self,
+        vaddr: VirtualAddress,
+    ) -> Result<(PhysicalAddress, Self::Flags), Self::Error>;
 
     /// Maps a huge page (1 GiB) at the given virtual address.
     /// # Arguments
@@ -93,8 +112,12 @@ pub trait MemoryMap {
     /// * `vaddr` - The virtual address to unmap.
     /// # Returns
     /// Returns an error of type `Self::Error` if unmapping fails or the physical address that was
-    /// previously mapped to the given virtual address if successful.
-    fn unmap_huge_page(&mut self, vaddr: VirtualAddress) -> Result<PhysicalAddress, Self::Error>;
+    /// previously mapped to the given virtual address along with the flags it was mapped with if
+    /// successful.
+    fn unmap_huge_page(
+        &mut self,
+        vaddr: VirtualAddress,
+    ) -> Result<(PhysicalAddress, Self::Flags), Self::Error>;
 }
 pub enum HwTimerMode {
     OneShot,
@@ -113,6 +136,17 @@ pub struct IsaParams {
     pub paging: PagingParams,
 }
 
+/// A free running counter that can be read as nanoseconds, such as the TSC or the HPET
+/// The trait is object safe so that the best source can be chosen at runtime, see [crate::time].
+pub trait ClockSource: Send + Sync {
+    /// A short name for log messages
+    fn name(&self) -> &'static str;
+    /// Returns the current count in nanoseconds, which never decreases between reads
+    fn now_ns(&self) -> u64;
+    /// Returns the number of nanoseconds between two consecutive counts, at least 1
+    fn resolution_ns(&self) -> u64;
+}
+
 pub trait Api {
     type Api: Api;
     type DebugLogger: Write;
@@ -131,6 +165,9 @@ pub trait Api {
     fn validate_vaddr(raw: u64) -> bool;
     #[allow(unused)]
     fn halt() -> !;
+    /// Waits for an interrupt unless the calling LP has work waiting, without missing a wakeup
+    /// that arrives while it is going to sleep. Interrupts are enabled when this returns.
+    fn idle();
     fn panic() -> !;
     fn inb(port: u16) -> u8;
     fn outb(port: u16, val: u8);
@@ -149,10 +186,29 @@ pub trait Api {
     fn disable_interrupts(&mut self);
     #[allow(unused)]
     fn restore_interrupts(&mut self);
+    /// Disables interrupts on the calling LP and returns whether they were enabled before
+    fn irq_save() -> bool;
+    /// Enables interrupts on the calling LP again if they were enabled before the matching
+    /// [irq_save](Api::irq_save)
+    fn irq_restore(enabled: bool);
+    /// Returns the logical index of the calling LP, `None` if it has not been assigned one yet
+    fn current_cpu_index() -> Option<usize>;
     #[allow(unused)]
     fn set_interrupt_handler(&mut self, h: fn(vector: u64), vector: u32);
     #[allow(unused)]
     fn end_of_interrupt();
+    /// Makes code written to the `len` bytes at `start` visible to instruction fetch on the calling
+    /// LP. This has to be called after writing to executable memory and before running it, such as
+    /// after loading a module or patching kernel code.
+    fn sync_instruction_cache(start: VirtualAddress, len: usize);
+    /// Logs the physical address, page size and permissions that the current address space
+    /// translates `vaddr` to
+    fn log_translation(vaddr: VirtualAddress);
+    /// Returns every clock source that was found and calibrated by [isa_init](Api::isa_init)
+    fn clock_sources(&self) -> impl Iterator<Item = &'static dyn ClockSource>;
+    /// Logs a single block summarizing the processor, memory, number of LPs, paging mode and
+    /// enabled processor features once bring up has finished
+    fn log_boot_summary(&self);
 }
 
 pub trait Serial {
@@ -160,15 +216,95 @@ pub trait Serial {
     fn put_char(&mut self, c: char);
 }
 
+/// The verbosity threshold of the kernel log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    /// Parses a log level from its name as given on the kernel command line e.g. `log_level=debug`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
 /// A logger that writes to both the framebuffer console and the serial port.
 pub struct Logger {
     logger: <ArchApi as Api>::DebugLogger,
+    level: LogLevel,
+    /// Monotonic time since boot used to timestamp log lines, `None` until a clock is calibrated
+    clock: Option<fn() -> Duration>,
+    /// Whether log lines are also written to the framebuffer console in addition to the debug logger
+    mirror_to_console: bool,
+    at_line_start: bool,
+}
+
+impl Logger {
+    /// Sets the most verbose level of messages that will be logged
+    pub fn set_level(&mut self, level: LogLevel) {
+        self.level = level;
+    }
+
+    /// Checks whether messages of the given level are currently logged
+    pub fn is_enabled(&self, level: LogLevel) -> bool {
+        level <= self.level
+    }
+
+    /// Prefix every subsequent log line with the time since boot as reported by `clock`
+    pub fn enable_timestamps(&mut self, clock: fn() -> Duration) {
+        self.clock = Some(clock);
+    }
+
+    /// Stop prefixing log lines with a timestamp
+    #[allow(unused)]
+    pub fn disable_timestamps(&mut self) {
+        self.clock = None;
+    }
+
+    /// Selects whether log lines are also written to the framebuffer console
+    pub fn set_console_mirroring(&mut self, enabled: bool) {
+        self.mirror_to_console = enabled;
+    }
+
+    fn write_all(&mut self, args: fmt::Arguments) {
+        self.logger.write_fmt(args).unwrap();
+        if self.mirror_to_console {
+            // lock order: LOGGER is already held by the caller, see `LOGGER`
+            CONSOLE.lock().write_fmt(args).unwrap();
+        }
+    }
+}
+
+/// The prefix of a timestamped log line, the time since boot in seconds with millisecond precision
+struct Timestamp(Duration);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:5}.{:03}] ", self.0.as_secs(), self.0.subsec_millis())
+    }
 }
 
 impl Write for Logger {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        write!(self.logger, "{}", s).unwrap();
-        write!(CONSOLE.lock(), "{}", s).unwrap();
+        for line in s.split_inclusive('\n') {
+            if self.at_line_start {
+                if let Some(clock) = self.clock {
+                    self.write_all(format_args!("{}", Timestamp(clock())));
+                }
+            }
+            self.write_all(format_args!("{}", line));
+            self.at_line_start = line.ends_with('\n');
+        }
         Ok(())
     }
 }
@@ -182,9 +318,20 @@ macro_rules! log {
 
 #[macro_export]
 macro_rules! logln {
+    ($($arg:tt)*) => {{
+        let mut logger = $crate::arch::LOGGER.lock();
+        logger.write_fmt(format_args!($($arg)*)).unwrap();
+        logger.write_str("\n").unwrap();
+    }};
+}
+
+/// Like [logln] but only emits the line when the log level is [LogLevel::Debug]
+#[macro_export]
+macro_rules! debugln {
     ($($arg:tt)*) => {
-        $crate::arch::LOGGER.lock().write_fmt(format_args!($($arg)*)).unwrap();
-        $crate::arch::LOGGER.lock().write_str("\n").unwrap();
+        if $crate::arch::LOGGER.lock().is_enabled($crate::arch::LogLevel::Debug) {
+            $crate::logln!($($arg)*);
+        }
     };
 }
 
@@ -201,3 +348,21 @@ pub const ISA_PARAMS: IsaParams = x86_64::X86_ISA_PARAMS;
 pub static MEMORY_PARAMS: PagingParams = aarch64::ISA_MEMORY_PARAMS;
 #[cfg(target_arch = "riscv64")]
 pub static MEMORY_PARAMS: PagingParams = riscv64::ISA_MEMORY_PARAMS;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock_clock() -> Duration {
+        Duration::from_millis(1234)
+    }
+
+    #[test]
+    fn timestamp_prefix() {
+        assert_eq!(format!("{}", Timestamp(mock_clock())), "[    1.234] ");
+        assert_eq!(
+            format!("{}", Timestamp(Duration::new(123456, 7_999_999))),
+            "[123456.007] "
+        );
+    }
+}
@@ -0,0 +1,32 @@
+//! # Backtrace
+//! Stack walking for diagnostics that have to work without the logger, e.g. from fault handlers.
+
+use core::arch::asm;
+use core::fmt::Write;
+
+use ignore_result::Ignore;
+
+/// The maximum number of stack frames printed by [log_backtrace]
+const MAX_BACKTRACE_DEPTH: usize = 16;
+
+/// Follows the chain of saved frame pointers and logs each return address
+/// This relies on the kernel being built with frame pointers.
+pub fn log_backtrace(logger: &mut impl Write) {
+    let mut rbp: *const u64;
+    // SAFETY: reading RBP has no side effects
+    unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+    for depth in 0..MAX_BACKTRACE_DEPTH {
+        if rbp.is_null() || !rbp.is_aligned() {
+            break;
+        }
+        // SAFETY: `rbp` is a non-null aligned frame pointer, the return address is saved right
+        // above it
+        let return_addr = unsafe { rbp.add(1).read() };
+        if return_addr == 0 {
+            break;
+        }
+        writeln!(logger, "  #{}: {:#X}", depth, return_addr).ignore();
+        // SAFETY: as above, the caller's frame pointer is saved at `rbp`
+        rbp = unsafe { rbp.read() } as *const u64;
+    }
+}
@@ -0,0 +1,203 @@
+//! # Clock Sources
+//! The x86_64 [ClockSource]s, the main counter of the HPET and the invariant TSC.
+//! The TSC's frequency is read from CPUID where it is enumerated and otherwise measured against
+//! the HPET, so the TSC is only offered if it is invariant and one of the two is available.
+
+use core::arch::x86_64::{__cpuid, _mm_pause, _rdtsc};
+use core::fmt::Write;
+
+use spin::Once;
+
+use super::memory::page_map::page_table::page_table_entry::MemType;
+use super::memory::page_map::{asm_get_cr3, PageMap};
+use crate::acpi::hpet::Hpet as HpetTable;
+use crate::arch::{ClockSource, MemoryMap};
+use crate::logln;
+use crate::memory::address::PhysicalAddress;
+use crate::memory::mmio::RegisterBlock;
+use crate::memory::pmm::DIRECT_MAP;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+const FS_PER_NS: u64 = 1_000_000;
+
+const HPET_CAPABILITIES: usize = 0x0;
+const HPET_CONFIGURATION: usize = 0x10;
+const HPET_MAIN_COUNTER: usize = 0xF0;
+/// Set in the capabilities register if the main counter is 64 bits wide
+const HPET_COUNT_SIZE_CAP: u64 = 1 << 13;
+const HPET_ENABLE_CNF: u64 = 1 << 0;
+/// The longest counter period in femtoseconds that the HPET specification allows
+const HPET_MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// CPUID.80000007H:EDX[8], the TSC runs at a constant rate in every P-, C- and T-state
+const INVARIANT_TSC: u32 = 1 << 8;
+/// How long the TSC is measured against the HPET if CPUID does not enumerate its frequency
+const TSC_CALIBRATION_NS: u64 = 10_000_000;
+
+static HPET: Once<HpetCounter> = Once::new();
+static TSC: Once<Tsc> = Once::new();
+
+/// Finds and calibrates the clock sources, the HPET is described by the ACPI `hpet` table if any
+pub fn init(hpet: Option<&HpetTable>) {
+    if let Some(table) = hpet {
+        match HpetCounter::new(table) {
+            Some(counter) => {
+                logln!("HPET counter period: {}fs", counter.period_fs);
+                HPET.call_once(|| counter);
+            }
+            None => {
+                logln!("The HPET is unusable as a clock source");
+            }
+        }
+    }
+    match Tsc::new(HPET.get()) {
+        Some(tsc) => {
+            logln!("TSC frequency: {}Hz", tsc.frequency);
+            TSC.call_once(|| tsc);
+        }
+        None => {
+            logln!("The TSC is unusable as a clock source");
+        }
+    }
+}
+
+/// Returns the clock sources found by [init]
+pub fn sources() -> impl Iterator<Item = &'static dyn ClockSource> {
+    let hpet = HPET.get().map(|hpet| hpet as &dyn ClockSource);
+    let tsc = TSC.get().map(|tsc| tsc as &dyn ClockSource);
+    [hpet, tsc].into_iter().flatten()
+}
+
+/// The HPET's main counter, only timer blocks with a 64 bit counter are used so it never wraps
+#[derive(Debug)]
+pub struct HpetCounter {
+    registers: RegisterBlock,
+    period_fs: u64,
+}
+
+impl HpetCounter {
+    fn new(table: &HpetTable) -> Option<Self> {
+        let base = table.base_address();
+        let page = PhysicalAddress::from(base & !0xFFF);
+        let vaddr = *DIRECT_MAP + page.bits();
+        // the direct map only covers RAM so the register page is usually not mapped yet
+        // SAFETY: reading CR3 has no side effects
+        let mut page_map = match PageMap::from_cr3(unsafe { asm_get_cr3() }) {
+            Ok(page_map) => page_map,
+            Err(e) => {
+                logln!("Failed to access the current page map: {:?}", e);
+                return None;
+            }
+        };
+        if page_map.effective_permissions(vaddr).is_err() {
+            if let Err(e) = page_map.map_page(vaddr, page, MemType::Mmio.flags()) {
+                logln!("Failed to map the HPET registers at {}: {:?}", page, e);
+                return None;
+            }
+        }
+        // Safety: the page holding the timer block's registers was mapped above
+        let registers = unsafe { RegisterBlock::new(vaddr + (base & 0xFFF)) };
+
+        let capabilities = registers.read::<u64>(HPET_CAPABILITIES);
+        let period_fs = capabilities >> 32;
+        if period_fs == 0 || period_fs > HPET_MAX_PERIOD_FS {
+            return None;
+        }
+        if capabilities & HPET_COUNT_SIZE_CAP == 0 {
+            return None;
+        }
+        let configuration = registers.read::<u64>(HPET_CONFIGURATION);
+        registers.write(HPET_CONFIGURATION, configuration | HPET_ENABLE_CNF);
+
+        Some(HpetCounter {
+            registers,
+            period_fs,
+        })
+    }
+
+    fn ticks(&self) -> u64 {
+        self.registers.read::<u64>(HPET_MAIN_COUNTER)
+    }
+}
+
+impl ClockSource for HpetCounter {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn now_ns(&self) -> u64 {
+        (self.ticks() as u128 * self.period_fs as u128 / FS_PER_NS as u128) as u64
+    }
+
+    fn resolution_ns(&self) -> u64 {
+        self.period_fs.div_ceil(FS_PER_NS)
+    }
+}
+
+/// The time stamp counter of the calling LP
+#[derive(Debug)]
+pub struct Tsc {
+    frequency: u64,
+}
+
+impl Tsc {
+    fn new(hpet: Option<&HpetCounter>) -> Option<Self> {
+        if !Self::is_invariant() {
+            return None;
+        }
+        let frequency = Self::enumerated_frequency().or_else(|| hpet.map(Self::calibrate))?;
+        if frequency == 0 {
+            return None;
+        }
+        Some(Tsc { frequency })
+    }
+
+    fn is_invariant() -> bool {
+        let max_extended_leaf = __cpuid(0x8000_0000).eax;
+        max_extended_leaf >= 0x8000_0007 && __cpuid(0x8000_0007).edx & INVARIANT_TSC != 0
+    }
+
+    /// Reads the TSC frequency from CPUID leaf 0x15, which is the crystal clock frequency scaled
+    /// by the TSC to crystal clock ratio. Either of them may not be enumerated.
+    fn enumerated_frequency() -> Option<u64> {
+        if __cpuid(0).eax < 0x15 {
+            return None;
+        }
+        let leaf = __cpuid(0x15);
+        if leaf.eax == 0 || leaf.ebx == 0 || leaf.ecx == 0 {
+            return None;
+        }
+        Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64)
+    }
+
+    /// Measures how many times the TSC ticks while the HPET counts [TSC_CALIBRATION_NS]
+    fn calibrate(hpet: &HpetCounter) -> u64 {
+        let start_ns = hpet.now_ns();
+        // SAFETY: rdtsc has no side effects
+        let start_ticks = unsafe { _rdtsc() };
+        let mut elapsed_ns = 0;
+        while elapsed_ns < TSC_CALIBRATION_NS {
+            _mm_pause();
+            elapsed_ns = hpet.now_ns() - start_ns;
+        }
+        // SAFETY: rdtsc has no side effects
+        let ticks = unsafe { _rdtsc() } - start_ticks;
+        (ticks as u128 * NS_PER_SEC as u128 / elapsed_ns as u128) as u64
+    }
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn now_ns(&self) -> u64 {
+        // SAFETY: rdtsc has no side effects
+        let ticks = unsafe { _rdtsc() };
+        (ticks as u128 * NS_PER_SEC as u128 / self.frequency as u128) as u64
+    }
+
+    fn resolution_ns(&self) -> u64 {
+        NS_PER_SEC.div_ceil(self.frequency)
+    }
+}
@@ -37,4 +37,14 @@ asm_get_vendor_string:
 	mov[rdi+4], edx
 	mov[rdi+8], ecx
 	mov rbx, r10 //restore rbx
-	ret
\ No newline at end of file
+	ret
+
+.global asm_set_cr4
+asm_set_cr4:
+	mov cr4, rdi
+	ret
+
+.global asm_get_cr0
+asm_get_cr0:
+	mov rax, cr0
+	ret
@@ -1,12 +1,24 @@
 use core::arch::asm;
-use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdtsc};
+use core::fmt::Write;
+use core::marker::PhantomData;
+use core::str;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 use spin::lazy::Lazy;
 
 use crate::arch::x86_64::cpu::cpu_intrinsics::{asm_read_msr, asm_write_msr};
+use crate::arch::x86_64::memory::asm_get_cr4;
+use crate::arch::x86_64::memory::page_map::asm_get_cr3;
+use crate::logln;
+use crate::memory::address::VirtualAddress;
 
 mod cpu_intrinsics;
 
+/// The number of LPs that per-LP state is kept for, LPs are identified by the logical index that
+/// [register_cpu] assigns them rather than by their APIC ID, which need not be dense
+pub const MAX_CPUS: usize = 64;
+
 /// The number of significant bits in a physical address on the current CPU.
 pub static PADDR_SIG_BITS: Lazy<u8> = Lazy::new(|| {
     let cpuid = unsafe { __cpuid_count(0x80000008, 0) };
@@ -24,6 +36,17 @@ pub static VADDR_SIG_BITS: Lazy<u8> = Lazy::new(|| {
 });
 
 pub static ARE_HUGE_PAGES_SUPPORTED: Lazy<bool> = Lazy::new(huge_pages_supported);
+pub static ARE_LARGE_PAGES_SUPPORTED: Lazy<bool> = Lazy::new(large_pages_supported);
+/// Whether the `invpcid` instruction is available, indicated by CPUID.(EAX=07H,ECX=0):EBX bit 10
+#[cfg(not(test))]
+pub static IS_INVPCID_SUPPORTED: Lazy<bool> = Lazy::new(|| {
+    let max_leaf = __cpuid(0).eax;
+    max_leaf >= 7 && __cpuid_count(7, 0).ebx & (1 << 10) != 0
+});
+/// Unit tests run as a user space process that may not execute `invpcid`, PCIDs are flushed by
+/// reloading the simulated CR3 instead
+#[cfg(test)]
+pub static IS_INVPCID_SUPPORTED: Lazy<bool> = Lazy::new(|| false);
 pub static CPU_HAS_MSR: Lazy<bool> = Lazy::new(|| {
     let res = unsafe { __cpuid_count(0, 0) };
     res.edx & 1 << 5 != 0
@@ -33,6 +56,412 @@ extern "C" {
     pub fn asm_halt() -> !;
     pub fn asm_get_vendor_string(dest: &mut [u8; 12]);
     pub fn asm_get_privilege_level() -> u8;
+    fn asm_set_cr4(value: u64);
+    fn asm_get_cr0() -> u64;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    UnsupportedOperation,
+    /// CR4.PCIDE cannot be set while CR3[11:0] is nonzero, doing so is a #GP
+    NonzeroPcid,
+}
+
+/// Optional processor features that are switched on through a bit in CR4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(unused)]
+pub enum Cr4Feature {
+    /// 57-bit linear addresses (5-level paging)
+    La57 = 12,
+    /// RDFSBASE, RDGSBASE, WRFSBASE and WRGSBASE instructions
+    FsGsBase = 16,
+    /// Process-context identifiers
+    Pcide = 17,
+    /// Supervisor mode execution prevention
+    Smep = 20,
+    /// Supervisor mode access prevention
+    Smap = 21,
+    /// Protection keys for user-mode pages
+    Pke = 22,
+}
+
+impl Cr4Feature {
+    #[inline]
+    const fn cr4_bit(self) -> u64 {
+        1 << self as u64
+    }
+
+    /// Checks the CPUID bit that enumerates this feature
+    fn is_supported(self) -> bool {
+        if self == Cr4Feature::Pcide {
+            return __cpuid(1).ecx & (1 << 17) != 0;
+        }
+        let max_leaf = __cpuid(0).eax;
+        if max_leaf < 7 {
+            return false;
+        }
+        let leaf7 = __cpuid_count(7, 0);
+        let supported = match self {
+            Cr4Feature::La57 => leaf7.ecx & (1 << 16),
+            Cr4Feature::FsGsBase => leaf7.ebx & 1,
+            Cr4Feature::Smep => leaf7.ebx & (1 << 7),
+            Cr4Feature::Smap => leaf7.ebx & (1 << 20),
+            Cr4Feature::Pke => leaf7.ecx & (1 << 3),
+            Cr4Feature::Pcide => unreachable!(),
+        };
+        supported != 0
+    }
+}
+
+/// The CR4 feature bits that the kernel has enabled so far
+/// APs are expected to enable the same set of features during their own initialization.
+static ENABLED_CR4_FEATURES: AtomicU64 = AtomicU64::new(0);
+
+/// Enables a CR4 controlled feature if and only if CPUID reports that it is supported.
+/// CR4 is only written if the feature bit is not already set on the calling LP.
+/// # Returns
+/// Returns `Error::UnsupportedOperation` without modifying CR4 if the processor does not support
+/// the feature or if the feature cannot be toggled in the current operating mode, and
+/// `Error::NonzeroPcid` when enabling PCIDs while the low 12 bits of CR3 are not zero.
+#[allow(unused)]
+pub fn enable_cr4_feature(feature: Cr4Feature) -> Result<(), Error> {
+    let bit = feature.cr4_bit();
+    if !feature.is_supported() {
+        return Err(Error::UnsupportedOperation);
+    }
+    // SAFETY: reading CR4 has no side effects
+    let cr4 = unsafe { asm_get_cr4() };
+    if cr4 & bit == 0 {
+        // CR4.LA57 can only be changed while paging is disabled, attempting it in long mode is a #GP
+        if feature == Cr4Feature::La57 {
+            return Err(Error::UnsupportedOperation);
+        }
+        // SAFETY: reading CR3 has no side effects
+        if feature == Cr4Feature::Pcide && unsafe { asm_get_cr3() } & 0xfff != 0 {
+            return Err(Error::NonzeroPcid);
+        }
+        // SAFETY: CPUID enumerates the feature and it can be toggled in long mode as checked above
+        unsafe { asm_set_cr4(cr4 | bit) };
+    }
+    ENABLED_CR4_FEATURES.fetch_or(bit, Ordering::AcqRel);
+    Ok(())
+}
+
+/// Checks whether a CR4 controlled feature is currently enabled on the calling LP
+#[allow(unused)]
+pub fn is_cr4_feature_enabled(feature: Cr4Feature) -> bool {
+    // SAFETY: reading CR4 has no side effects
+    unsafe { asm_get_cr4() & feature.cr4_bit() != 0 }
+}
+
+/// Returns the CR4 bits of every feature the kernel has enabled through [enable_cr4_feature]
+#[allow(unused)]
+pub fn enabled_cr4_features() -> u64 {
+    ENABLED_CR4_FEATURES.load(Ordering::Acquire)
+}
+
+/// Allows the kernel to access user pages for as long as it is alive by setting RFLAGS.AC, which
+/// suspends SMAP checks. Code that reads or writes user memory should keep the guard's scope as
+/// small as possible, ideally just the copy loop. If SMAP is not enabled on the calling LP the
+/// guard does nothing. The guard cannot be sent to another LP since AC is per LP state.
+#[derive(Debug)]
+pub struct SmapGuard {
+    active: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl SmapGuard {
+    #[allow(unused)]
+    pub fn new() -> Self {
+        let active = is_cr4_feature_enabled(Cr4Feature::Smap);
+        if active {
+            // SAFETY: SMAP is enabled so stac exists, it only allows supervisor accesses to user
+            // pages until the guard is dropped
+            unsafe { asm!("stac", options(nomem, nostack)) };
+        }
+        SmapGuard {
+            active,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for SmapGuard {
+    fn drop(&mut self) {
+        if self.active {
+            // SAFETY: SMAP is enabled so clac exists, it only restores the protection stac lifted
+            unsafe { asm!("clac", options(nomem, nostack)) };
+        }
+    }
+}
+
+/// The kinds of TLB invalidation counted by [tlb_stats]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlbFlush {
+    /// A single page was invalidated with `invlpg` or an individual-address `invpcid`
+    SinglePage,
+    /// All non-global entries were flushed by reloading CR3 without a PCID
+    Full,
+    /// All entries tagged with one PCID were flushed
+    Pcid,
+}
+
+static TLB_SINGLE_PAGE_FLUSHES: AtomicU64 = AtomicU64::new(0);
+static TLB_FULL_FLUSHES: AtomicU64 = AtomicU64::new(0);
+static TLB_PCID_FLUSHES: AtomicU64 = AtomicU64::new(0);
+
+/// The number of TLB invalidations of each kind performed by all LPs since boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(unused)]
+pub struct TlbStats {
+    pub single_page: u64,
+    pub full: u64,
+    pub pcid: u64,
+}
+
+/// Records that a TLB invalidation of the given kind was performed
+#[inline]
+pub fn count_tlb_flush(kind: TlbFlush) {
+    let counter = match kind {
+        TlbFlush::SinglePage => &TLB_SINGLE_PAGE_FLUSHES,
+        TlbFlush::Full => &TLB_FULL_FLUSHES,
+        TlbFlush::Pcid => &TLB_PCID_FLUSHES,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of TLB invalidations of each kind performed so far
+#[allow(unused)]
+pub fn tlb_stats() -> TlbStats {
+    TlbStats {
+        single_page: TLB_SINGLE_PAGE_FLUSHES.load(Ordering::Relaxed),
+        full: TLB_FULL_FLUSHES.load(Ordering::Relaxed),
+        pcid: TLB_PCID_FLUSHES.load(Ordering::Relaxed),
+    }
+}
+
+/// The IA32_EFER MSR
+const EFER_MSR: u32 = 0xC000_0080;
+/// EFER.NXE, which makes the XD bit of page table entries take effect
+const EFER_NXE: u64 = 1 << 11;
+/// CR0.WP, which makes supervisor writes to read only pages fault
+const CR0_WP: u64 = 1 << 16;
+
+/// The memory protections that are in effect on an LP at the time [security_state] is called
+/// Sensitive paths can assert on the fields they rely on, e.g. that SMAP is on before trusting it
+/// to catch stray accesses to user memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(unused)]
+pub struct SecurityState {
+    /// CR0.WP, the kernel cannot write to read only pages
+    pub write_protect: bool,
+    /// EFER.NXE, pages mapped without execute permission cannot be executed
+    pub no_execute: bool,
+    /// CR4.SMEP, the kernel cannot execute user pages
+    pub smep: bool,
+    /// CR4.SMAP, the kernel cannot access user pages outside of a [SmapGuard]
+    pub smap: bool,
+    /// CR4.FSGSBASE, [read_gs_base] and [write_gs_base] use the instructions instead of the MSR.
+    /// This is not a protection and is not considered by [is_complete](Self::is_complete).
+    pub fsgsbase: bool,
+}
+
+impl SecurityState {
+    /// Checks whether every protection that `features` reports as supported is in effect, write
+    /// protection is always supported
+    #[allow(unused)]
+    pub fn is_complete(&self, features: &CpuFeatures) -> bool {
+        self.write_protect
+            && (self.no_execute || !features.nx)
+            && (self.smep || !features.smep)
+            && (self.smap || !features.smap)
+    }
+}
+
+/// Takes a snapshot of the protections in effect on the calling LP by reading CR0, CR4 and EFER
+#[allow(unused)]
+pub fn security_state() -> SecurityState {
+    SecurityState {
+        // SAFETY: reading CR0 has no side effects
+        write_protect: unsafe { asm_get_cr0() } & CR0_WP != 0,
+        no_execute: read_msr_u64(EFER_MSR) & EFER_NXE != 0,
+        smep: is_cr4_feature_enabled(Cr4Feature::Smep),
+        smap: is_cr4_feature_enabled(Cr4Feature::Smap),
+        fsgsbase: is_cr4_feature_enabled(Cr4Feature::FsGsBase),
+    }
+}
+
+/// The IA32_GS_BASE MSR
+const GS_BASE_MSR: u32 = 0xC000_0101;
+
+/// Sets up the GS base of the calling LP. CR4.FSGSBASE is enabled first where it is supported, so
+/// that from then on [read_gs_base] and [write_gs_base] take the `rdgsbase` and `wrgsbase` fast
+/// path on this LP instead of the much slower MSR accesses.
+pub fn init_gs_base(base: u64) {
+    // without FSGSBASE the MSR path keeps working, so there is nothing to report
+    let _ = enable_cr4_feature(Cr4Feature::FsGsBase);
+    write_gs_base(base);
+}
+
+/// Reads the GS base of the calling LP, with `rdgsbase` if CR4.FSGSBASE is enabled on it and from
+/// the IA32_GS_BASE MSR otherwise
+#[cfg(not(test))]
+pub fn read_gs_base() -> u64 {
+    if is_cr4_feature_enabled(Cr4Feature::FsGsBase) {
+        let base: u64;
+        // SAFETY: CR4.FSGSBASE is enabled so rdgsbase is available, it has no side effects
+        unsafe { asm!("rdgsbase {}", out(reg) base, options(nomem, nostack, preserves_flags)) };
+        base
+    } else {
+        read_msr_u64(GS_BASE_MSR)
+    }
+}
+
+/// Writes the GS base of the calling LP, with `wrgsbase` if CR4.FSGSBASE is enabled on it and to
+/// the IA32_GS_BASE MSR otherwise
+#[cfg(not(test))]
+pub fn write_gs_base(base: u64) {
+    if is_cr4_feature_enabled(Cr4Feature::FsGsBase) {
+        // SAFETY: CR4.FSGSBASE is enabled so wrgsbase is available, the GS base is only used by the
+        // kernel
+        unsafe { asm!("wrgsbase {}", in(reg) base, options(nomem, nostack, preserves_flags)) };
+    } else {
+        write_msr(
+            GS_BASE_MSR,
+            MSRValue {
+                eax: base as u32,
+                edx: (base >> 32) as u32,
+            },
+        );
+    }
+}
+
+// Unit tests run as a user space process that may not change its GS base, so each test thread
+// gets a simulated one instead
+#[cfg(test)]
+std::thread_local! {
+    static TEST_GS_BASE: core::cell::Cell<u64> = const { core::cell::Cell::new(0) };
+}
+
+#[cfg(test)]
+pub fn read_gs_base() -> u64 {
+    TEST_GS_BASE.get()
+}
+
+#[cfg(test)]
+pub fn write_gs_base(base: u64) {
+    TEST_GS_BASE.set(base)
+}
+
+/// Makes the test thread run as the LP with the given logical index, as far as
+/// [current_cpu_index] is concerned
+#[cfg(test)]
+pub fn simulate_cpu(index: usize) {
+    write_gs_base(&CPU_LOCALS[index] as *const CpuLocal as u64);
+}
+
+/// Checks that the `rdgsbase` fast path and the IA32_GS_BASE MSR report the same GS base on the
+/// calling LP. Without CR4.FSGSBASE both reads go through the MSR and trivially agree.
+#[allow(unused)]
+pub fn gs_base_paths_agree() -> bool {
+    read_gs_base() == read_msr_u64(GS_BASE_MSR)
+}
+
+/// Identification and optional features of the current LP as reported by CPUID
+#[derive(Debug, Clone, Copy)]
+pub struct CpuFeatures {
+    pub vendor: [u8; 12],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub nx: bool,
+    pub smep: bool,
+    pub smap: bool,
+    pub pcid: bool,
+    pub huge_pages: bool,
+    pub fsgsbase: bool,
+    pub la57: bool,
+    pub pku: bool,
+}
+
+impl CpuFeatures {
+    pub fn detect() -> Self {
+        let mut vendor = [0u8; 12];
+        // SAFETY: the destination holds the 12 bytes of the vendor string
+        unsafe { asm_get_vendor_string(&mut vendor) };
+
+        let signature = __cpuid(1).eax;
+        let mut family = (signature >> 8) & 0xF;
+        let mut model = (signature >> 4) & 0xF;
+        if family == 0xF {
+            family += (signature >> 20) & 0xFF;
+        }
+        if family == 0x6 || family >= 0xF {
+            model |= ((signature >> 16) & 0xF) << 4;
+        }
+        let nx = __cpuid_count(0x80000001, 0).edx & (1 << 20) != 0;
+
+        CpuFeatures {
+            vendor,
+            family,
+            model,
+            stepping: signature & 0xF,
+            nx,
+            smep: Cr4Feature::Smep.is_supported(),
+            smap: Cr4Feature::Smap.is_supported(),
+            pcid: Cr4Feature::Pcide.is_supported(),
+            huge_pages: *ARE_HUGE_PAGES_SUPPORTED,
+            fsgsbase: Cr4Feature::FsGsBase.is_supported(),
+            la57: Cr4Feature::La57.is_supported(),
+            pku: Cr4Feature::Pke.is_supported(),
+        }
+    }
+
+    /// Returns the name of each optional feature along with whether it is supported and whether it
+    /// is currently enabled on the calling LP
+    pub fn feature_states(&self) -> [(&'static str, bool, bool); 8] {
+        let nx_enabled = self.nx && read_msr_u64(EFER_MSR) & EFER_NXE != 0;
+        [
+            ("NX", self.nx, nx_enabled),
+            ("SMEP", self.smep, is_cr4_feature_enabled(Cr4Feature::Smep)),
+            ("SMAP", self.smap, is_cr4_feature_enabled(Cr4Feature::Smap)),
+            ("PCID", self.pcid, is_cr4_feature_enabled(Cr4Feature::Pcide)),
+            // 1 GiB pages have no enable bit, they are usable whenever they are supported
+            ("1GiB pages", self.huge_pages, self.huge_pages),
+            (
+                "FSGSBASE",
+                self.fsgsbase,
+                is_cr4_feature_enabled(Cr4Feature::FsGsBase),
+            ),
+            ("LA57", self.la57, is_cr4_feature_enabled(Cr4Feature::La57)),
+            ("PKU", self.pku, is_cr4_feature_enabled(Cr4Feature::Pke)),
+        ]
+    }
+
+    /// Returns the vendor ID string, or "unknown" if it is not valid UTF-8
+    pub fn vendor_str(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    /// Logs the processor's identification followed by a line per optional feature stating
+    /// whether it is unsupported, supported but disabled or enabled.
+    pub fn log_summary(&self) {
+        logln!("CPU Vendor ID: {}", self.vendor_str());
+        logln!(
+            "Family: {:#X}, Model: {:#X}, Stepping: {:#X}",
+            self.family,
+            self.model,
+            self.stepping
+        );
+        for (name, supported, enabled) in self.feature_states() {
+            let state = match (supported, enabled) {
+                (false, _) => "unsupported",
+                (true, false) => "disabled",
+                (true, true) => "enabled",
+            };
+            logln!("  {}: {}", name, state);
+        }
+    }
 }
 
 pub struct MSRValue {
@@ -40,6 +469,31 @@ pub struct MSRValue {
     pub edx: u32,
 }
 
+/// Makes code that was written to memory visible to instruction fetch on the calling LP.
+/// x86 keeps the instruction caches coherent with stores by itself, even with stores through a
+/// different linear address, so only instructions that were already fetched or decoded have to be
+/// discarded, which any serializing instruction does. The range is not needed for that and is only
+/// taken so that callers stay portable to ISAs that maintain their instruction caches by address.
+/// Other LPs that may run the code have to serialize themselves before they do.
+pub fn sync_instruction_cache(_start: VirtualAddress, _len: usize) {
+    serialize();
+}
+
+/// Executes a serializing instruction on the calling LP
+fn serialize() {
+    // cpuid is the serializing instruction that every x86_64 processor can execute in ring 0
+    // without side effects
+    let _ = __cpuid(0);
+    #[cfg(test)]
+    TEST_SERIALIZATIONS.set(TEST_SERIALIZATIONS.get() + 1);
+}
+
+// Unit tests cannot observe the pipeline, so each test thread counts its serializing instructions
+#[cfg(test)]
+std::thread_local! {
+    static TEST_SERIALIZATIONS: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+}
+
 pub fn get_privilege_level() -> u8 {
     unsafe { asm_get_privilege_level() }
 }
@@ -81,6 +535,7 @@ pub fn clear_msr_bit(msr: u32, bit: u8) {
 }
 
 /// Test the flags of the processor to determine if the interrupts are enabled
+#[cfg(not(test))]
 pub fn asm_are_interrupts_enabled() -> bool {
     let mut flags: u64;
     unsafe { asm!("pushf\n\tpop {}", out(reg) flags) };
@@ -88,6 +543,7 @@ pub fn asm_are_interrupts_enabled() -> bool {
 }
 
 #[allow(unused)]
+#[cfg(not(test))]
 pub fn irq_disable() {
     unsafe {
         asm!("cli");
@@ -95,12 +551,73 @@ pub fn irq_disable() {
 }
 
 #[allow(unused)]
+#[cfg(not(test))]
 pub fn irq_restore() {
     unsafe {
         asm!("sti");
     };
 }
 
+// Unit tests run as a user space process that may not change the interrupt flag, so each test
+// thread gets a simulated one instead
+#[cfg(test)]
+std::thread_local! {
+    static TEST_IRQ_ENABLED: core::cell::Cell<bool> = const { core::cell::Cell::new(true) };
+}
+
+#[cfg(test)]
+pub fn asm_are_interrupts_enabled() -> bool {
+    TEST_IRQ_ENABLED.get()
+}
+
+#[cfg(test)]
+pub fn irq_disable() {
+    TEST_IRQ_ENABLED.set(false);
+}
+
+#[cfg(test)]
+pub fn irq_restore() {
+    TEST_IRQ_ENABLED.set(true);
+}
+
+/// Set for an LP when it has work waiting so that it does not go to sleep in [idle]
+static NEED_RESCHED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Marks the LP with the given APIC ID as having work waiting
+/// This only prevents the LP from sleeping, waking it if it is already halted takes an interrupt.
+#[allow(unused)]
+pub fn set_need_resched(apic_id: u32) {
+    if let Some(index) = cpu_index(apic_id) {
+        NEED_RESCHED[index].store(true, Ordering::Release);
+    }
+}
+
+/// Clears the calling LP's flag and returns whether it was set
+#[allow(unused)]
+pub fn take_need_resched() -> bool {
+    current_cpu_index().is_some_and(|index| NEED_RESCHED[index].swap(false, Ordering::AcqRel))
+}
+
+fn need_resched() -> bool {
+    current_cpu_index().is_some_and(|index| NEED_RESCHED[index].load(Ordering::Acquire))
+}
+
+/// Halts the calling LP until the next interrupt unless it has work waiting, in which case this
+/// returns immediately. The flag is checked with interrupts disabled and `sti` only enables them
+/// after the instruction that follows it, so an interrupt that arrives after the check wakes the
+/// LP from `hlt` rather than being handled before it and leaving the LP asleep.
+/// Interrupts are enabled when this returns.
+pub fn idle() {
+    irq_disable();
+    if need_resched() {
+        irq_restore();
+        return;
+    }
+    // SAFETY: sti and hlt only let the LP sleep until the next interrupt with interrupts enabled
+    unsafe { asm!("sti", "hlt") };
+}
+
+#[cfg(not(test))]
 pub fn asm_outb(port: u16, val: u8) {
     unsafe {
         asm!(
@@ -113,6 +630,7 @@ pub fn asm_outb(port: u16, val: u8) {
     }
 }
 
+#[cfg(not(test))]
 pub fn asm_inb(port: u16) -> u8 {
     let val: u8;
     unsafe {
@@ -127,6 +645,18 @@ pub fn asm_inb(port: u16) -> u8 {
     val
 }
 
+/// Unit tests run as a user space process that may not access I/O ports, so byte port I/O goes to
+/// the simulated serial port of the test thread instead
+#[cfg(test)]
+pub fn asm_outb(port: u16, val: u8) {
+    crate::arch::x86_64::serial::simulated::outb(port, val)
+}
+
+#[cfg(test)]
+pub fn asm_inb(port: u16) -> u8 {
+    crate::arch::x86_64::serial::simulated::inb(port)
+}
+
 /// outputs `word` to `port`
 pub fn asm_outw(port: u16, word: u16) {
     unsafe {
@@ -191,6 +721,135 @@ pub fn get_tsc_frequency() -> u32 {
     cpuid_res.ebx / cpuid_res.eax
 }
 
+/// Reads the time stamp counter of the calling LP
+pub fn read_tsc() -> u64 {
+    // SAFETY: rdtsc has no side effects
+    unsafe { _rdtsc() }
+}
+
+/// Returns the initial APIC ID of the LP executing this function.
+/// The full 32 bit x2APIC ID is read from CPUID leaf 0xB where it is available, otherwise the 8 bit
+/// xAPIC ID from CPUID leaf 1 is used. Unlike the Local APIC ID register this works before the
+/// Local APIC has been mapped and regardless of whether it is in xAPIC or x2APIC mode.
+pub fn current_apic_id() -> u32 {
+    if __cpuid(0).eax >= 0xB {
+        let topology = __cpuid_count(0xB, 0);
+        // EBX is zero if leaf 0xB is not actually implemented
+        if topology.ebx != 0 {
+            return topology.edx;
+        }
+    }
+    __cpuid(1).ebx >> 24
+}
+
+/// The APIC ID of the LP that was assigned each logical index, `u32::MAX` for unassigned indices
+static CPU_APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(u32::MAX) }; MAX_CPUS];
+
+/// The per-LP block that the GS base of a registered LP points to, it holds what the LP needs too
+/// often to look up by its APIC ID
+#[derive(Debug)]
+pub struct CpuLocal {
+    /// The logical index [register_cpu] assigned to the LP
+    index: usize,
+}
+
+static CPU_LOCALS: [CpuLocal; MAX_CPUS] = {
+    let mut locals = [const { CpuLocal { index: 0 } }; MAX_CPUS];
+    let mut index = 0;
+    while index < MAX_CPUS {
+        locals[index].index = index;
+        index += 1;
+    }
+    locals
+};
+
+/// Assigns the calling LP the lowest free logical index, by which per-LP state is kept, and points
+/// its GS base at its [CpuLocal] block. Every LP has to call this during its bring-up before it
+/// uses any per-LP state, calling it again returns the index it was already assigned.
+/// # Returns
+/// Returns the logical index, or `None` if [MAX_CPUS] other LPs have already been assigned one. In
+/// that case the LP has no per-LP state and this is logged.
+pub fn register_cpu() -> Option<usize> {
+    let apic_id = current_apic_id();
+    for (index, slot) in CPU_APIC_IDS.iter().enumerate() {
+        match slot.compare_exchange(u32::MAX, apic_id, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => {}
+            Err(owner) if owner == apic_id => {}
+            Err(_) => continue,
+        }
+        write_gs_base(&CPU_LOCALS[index] as *const CpuLocal as u64);
+        return Some(index);
+    }
+    logln!(
+        "The LP with APIC ID {} exceeds the limit of {} LPs and has no per-LP state",
+        apic_id,
+        MAX_CPUS
+    );
+    None
+}
+
+/// Returns the logical index that [register_cpu] assigned to the LP with the given APIC ID
+pub fn cpu_index(apic_id: u32) -> Option<usize> {
+    CPU_APIC_IDS
+        .iter()
+        .position(|slot| slot.load(Ordering::Acquire) == apic_id)
+}
+
+/// Returns the logical index of the calling LP, `None` if it has not been assigned one
+/// The index is read from the LP's [CpuLocal] block, which is cheap compared to [cpu_index] since
+/// CPUID is serializing and causes a VM exit under virtualization.
+pub fn current_cpu_index() -> Option<usize> {
+    let base = read_gs_base();
+    if base == 0 {
+        return None;
+    }
+    // SAFETY: the GS base is only set to something other than 0 by register_cpu, which points it at
+    // a block of CPU_LOCALS
+    Some(unsafe { (*(base as *const CpuLocal)).index })
+}
+
+/// Determines the number of page colors of the outermost unified cache, that is the size of one
+/// way of the cache divided by the page size. Intel enumerates cache parameters with CPUID leaf 4
+/// and AMD with leaf 0x8000001D, both using the same register layout.
+/// Returns 1 if the cache parameters cannot be enumerated.
+pub fn cache_colors() -> usize {
+    let leaf = if __cpuid(0).eax >= 4 && __cpuid_count(4, 0).eax & 0x1F != 0 {
+        4
+    } else if __cpuid(0x80000000).eax >= 0x8000001D {
+        0x8000001D
+    } else {
+        return 1;
+    };
+
+    let mut colors = 1;
+    for subleaf in 0.. {
+        let params = __cpuid_count(leaf, subleaf);
+        // a cache type of 0 means there are no more caches
+        match params.eax & 0x1F {
+            0 => break,
+            // only unified caches are considered, the last one enumerated is the outermost
+            3 => {}
+            _ => continue,
+        }
+        let line_size = (params.ebx & 0xFFF) as usize + 1;
+        let partitions = ((params.ebx >> 12) & 0x3FF) as usize + 1;
+        let sets = params.ecx as usize + 1;
+        colors = (line_size * partitions * sets / 4096).max(1);
+    }
+    colors
+}
+
+/// Determines whether the current LP supports large (2 MiB) pages.
+/// This is always the case in long mode but it is checked properly via CPUID.PSE and CR4.PAE
+/// rather than assumed.
+fn large_pages_supported() -> bool {
+    let cpuid_result = __cpuid(1);
+    let has_pse = cpuid_result.edx & (1 << 3) != 0;
+    // SAFETY: reading CR4 has no side effects
+    let pae_enabled = unsafe { asm_get_cr4() } & (1 << 5) != 0;
+    has_pse && pae_enabled
+}
+
 /// Determines whether the current LP supports huge pages.
 /// Returns `true` if huge pages are supported, `false` otherwise.
 fn huge_pages_supported() -> bool {
@@ -198,3 +857,36 @@ fn huge_pages_supported() -> bool {
     let edx = cpuid_result.edx;
     edx & (1 << 26) != 0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::address::PAGE_SIZE;
+
+    #[test]
+    fn registered_lp_reads_its_index_from_its_block() {
+        assert_eq!(current_cpu_index(), None);
+        let index = register_cpu().unwrap();
+        assert_eq!(read_gs_base(), &CPU_LOCALS[index] as *const CpuLocal as u64);
+        assert_eq!(current_cpu_index(), Some(index));
+        assert_eq!(cpu_index(current_apic_id()), Some(index));
+    }
+
+    #[test]
+    fn sync_instruction_cache_serializes_once_for_any_range() {
+        let code = [0xC3u8; 3 * PAGE_SIZE as usize];
+        let start = VirtualAddress::try_from(code.as_ptr() as u64).unwrap();
+        let ranges = [
+            (start, 0),
+            (start + 1usize, 1),
+            (start, PAGE_SIZE as usize),
+            (start + 0x7FFusize, 2 * PAGE_SIZE as usize),
+        ];
+        for (start, len) in ranges {
+            let before = TEST_SERIALIZATIONS.get();
+            sync_instruction_cache(start, len);
+            assert_eq!(TEST_SERIALIZATIONS.get(), before + 1);
+        }
+        assert_eq!(code[0], 0xC3);
+    }
+}
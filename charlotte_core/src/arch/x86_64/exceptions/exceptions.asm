@@ -44,20 +44,54 @@ isr_double_fault:
 
 .global isr_general_protection_fault
 isr_general_protection_fault:
-	call save_regs
-	pop rdi //pop the error code
-	mov rsi, [rip] // Save the RIP to RSI for the handler
+	//Registers are not saved since the handler panics
+	mov rdi, [rsp] //the error code
+	mov rsi, [rsp + 8] //the RIP of the faulting instruction
 	call ih_general_protection_fault
 	hlt
-	call restore_regs
-	iretq
 
 .global isr_page_fault
 isr_page_fault:
-	call save_regs
-	pop rdi //pop the error code
+	//The handler returns when it resolves the fault so the registers are saved inline, a call to
+	//save_regs would return through the pushed registers
+	push rax
+	push rbx
+	push rcx
+	push rdx
+	push rsi
+	push rdi
+	push rbp
+	push r8
+	push r9
+	push r10
+	push r11
+	push r12
+	push r13
+	push r14
+	push r15
+	mov rdi, [rsp + 15 * 8] //the error code, pushed by the CPU below the saved registers
+	//the CPU aligns the stack to 16 bytes before pushing the 6 quadwords of the frame and error
+	//code, the 15 saved registers leave it 8 bytes short of the alignment the ABI requires
+	sub rsp, 8
+	cld
 	call ih_page_fault
-	call restore_regs
+	add rsp, 8
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop r11
+	pop r10
+	pop r9
+	pop r8
+	pop rbp
+	pop rdi
+	pop rsi
+	pop rdx
+	pop rcx
+	pop rbx
+	pop rax
+	add rsp, 8 //remove the error code so that iretq finds the interrupt frame
 	iretq
 
 .global isr_segment_not_present
@@ -1,13 +1,21 @@
 mod exceptions;
 
+use core::arch::asm;
 use core::fmt::Write;
 
 use ignore_result::Ignore;
 
 use super::serial::{ComPort::COM1, SerialPort};
+use crate::arch::x86_64::backtrace::log_backtrace;
 use crate::arch::x86_64::idt::*;
+use crate::arch::x86_64::memory::page_map::page_table::PageTableLevel;
+use crate::arch::x86_64::memory::page_map::{asm_get_cr3, PageMap};
+use crate::arch::x86_64::memory::stack_guard;
+use crate::arch::x86_64::memory::Error;
+use crate::arch::x86_64::watchdog;
 
 use crate::arch::*;
+use crate::memory::address::VirtualAddress;
 
 pub fn load_exceptions(idt: &mut Idt) {
     idt.set_gate(0, isr_divide_by_zero, 1 << 3, true, true);
@@ -82,33 +90,252 @@ extern "C" fn ih_divide_by_zero() {
     .ignore();
 }
 
+/// The descriptor table a selector error code refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// The selector error code pushed by exceptions that are caused by a segment selector or an IDT
+/// vector, such as a general protection fault
+/// ## References:
+/// * Intel SDM Vol3 6.13
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(u64);
+
+#[allow(unused)]
+impl SelectorErrorCode {
+    /// The exception occurred while delivering an event external to the program, such as an
+    /// interrupt or an earlier exception
+    pub fn is_external(&self) -> bool {
+        self.0 & 1 << 0 != 0
+    }
+
+    pub fn table(&self) -> DescriptorTable {
+        if self.0 & 1 << 1 != 0 {
+            DescriptorTable::Idt
+        } else if self.0 & 1 << 2 != 0 {
+            DescriptorTable::Ldt
+        } else {
+            DescriptorTable::Gdt
+        }
+    }
+
+    /// The index of the descriptor in its table, or the vector if the table is the IDT
+    pub fn index(&self) -> u16 {
+        ((self.0 >> 3) & 0x1FFF) as u16
+    }
+}
+
+impl From<u64> for SelectorErrorCode {
+    fn from(error_code: u64) -> Self {
+        SelectorErrorCode(error_code)
+    }
+}
+
+impl core::fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?} index {}", self.table(), self.index())?;
+        if self.table() == DescriptorTable::Gdt {
+            write!(f, " (selector {:#x})", self.index() << 3)?;
+        }
+        if self.is_external() {
+            write!(f, ", external event")?;
+        }
+        Ok(())
+    }
+}
+
 #[no_mangle]
 extern "C" fn ih_general_protection_fault(error_code: u64, rip: u64) {
     let mut logger = SerialPort::try_new(COM1).unwrap();
-    let rip_adjusted = 0xffffffff80000000 - rip;
+    // most general protection faults are not caused by a segment and push a zero error code
     if error_code != 0 {
         writeln!(
             &mut logger,
-            "A general protection fault has occurred in kernel space with error code {:X}! Panicking!
-            this is usually the segment selector that caused the fault. RIP = {:X}",
-            error_code, rip_adjusted
+            "A general protection fault has occurred at RIP = {:#x} referencing {}! Panicking!",
+            rip,
+            SelectorErrorCode::from(error_code)
         )
-            .ignore();
+        .ignore();
     } else {
         writeln!(
             &mut logger,
-            "A general protection fault has occurred in kernelspace! Panicking!
-            RIP = {:X}",
-            rip_adjusted,
+            "A general protection fault has occurred at RIP = {:#x}! Panicking!",
+            rip
+        )
+        .ignore();
+    }
+    writeln!(&mut logger, "Backtrace:").ignore();
+    log_backtrace(&mut logger);
+    ArchApi::panic();
+}
+
+/// The error code pushed by a page fault
+/// ## References:
+/// * Intel SDM Vol3 4.7
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultError(u64);
+
+#[allow(unused)]
+impl PageFaultError {
+    /// The fault was caused by a protection violation rather than a non-present page
+    pub fn is_present(&self) -> bool {
+        self.0 & 1 << 0 != 0
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.0 & 1 << 1 != 0
+    }
+
+    pub fn is_user(&self) -> bool {
+        self.0 & 1 << 2 != 0
+    }
+
+    /// A reserved bit was set in one of the paging structure entries used for the translation
+    pub fn is_reserved_bit(&self) -> bool {
+        self.0 & 1 << 3 != 0
+    }
+
+    pub fn is_instruction_fetch(&self) -> bool {
+        self.0 & 1 << 4 != 0
+    }
+
+    pub fn is_protection_key(&self) -> bool {
+        self.0 & 1 << 5 != 0
+    }
+
+    pub fn is_shadow_stack(&self) -> bool {
+        self.0 & 1 << 6 != 0
+    }
+}
+
+impl From<u64> for PageFaultError {
+    fn from(error_code: u64) -> Self {
+        PageFaultError(error_code)
+    }
+}
+
+fn read_cr2() -> u64 {
+    let cr2: u64;
+    // SAFETY: reading CR2 has no side effects
+    unsafe {
+        asm! {
+            "mov {0}, cr2",
+            out(reg) cr2,
+        }
+    }
+    cr2
+}
+
+/// Logs every entry used to translate `vaddr` along with any reserved bits that are set in it.
+/// A reserved bit fault means that a page table is corrupt, which is always a kernel bug.
+/// # Returns
+/// Returns the first level that has reserved bits set, if any.
+fn dump_reserved_bit_walk(
+    logger: &mut impl Write,
+    vaddr: VirtualAddress,
+) -> Option<PageTableLevel> {
+    // SAFETY: reading CR3 has no side effects
+    let Ok(page_map) = PageMap::from_cr3(unsafe { asm_get_cr3() }) else {
+        writeln!(logger, "Unable to read the page map from CR3").ignore();
+        return None;
+    };
+    let levels = [
+        PageTableLevel::PML4,
+        PageTableLevel::PDPT,
+        PageTableLevel::PD,
+        PageTableLevel::PT,
+    ];
+    let mut offender = None;
+    for (level, entry) in levels.into_iter().zip(page_map.walk_entries(vaddr)) {
+        let Some(entry) = entry else {
+            break;
+        };
+        let reserved = entry.reserved_bits(level);
+        writeln!(
+            logger,
+            "{:?} entry: {:#018x}, reserved bits set: {:#x}",
+            level,
+            entry.bits(),
+            reserved
         )
         .ignore();
+        if reserved != 0 && offender.is_none() {
+            offender = Some(level);
+        }
     }
+    offender
 }
 
 #[no_mangle]
 extern "C" fn ih_page_fault(error_code: u64) {
+    let error = PageFaultError::from(error_code);
+    // a write to a present page may be the first write to a copy-on-write page and an access to
+    // a page that is not present may be the first access to a demand-zero page
+    if !error.is_reserved_bit() && (!error.is_present() || error.is_write()) {
+        if let (Ok(mut page_map), Ok(vaddr)) = (
+            // SAFETY: reading CR3 has no side effects
+            PageMap::from_cr3(unsafe { asm_get_cr3() }),
+            VirtualAddress::try_from(read_cr2()),
+        ) {
+            let resolved = if error.is_present() {
+                page_map.resolve_cow_fault(vaddr)
+            } else {
+                page_map.resolve_demand_zero_fault(vaddr)
+            };
+            // another LP backing the page first resolves the fault just as well
+            if matches!(resolved, Ok(()) | Err(Error::AddressInUse)) {
+                return;
+            }
+        }
+    }
+
     let mut logger = SerialPort::try_new(COM1).unwrap();
 
+    if !error.is_present() {
+        let cr2 = read_cr2();
+        if let Some(owner) = VirtualAddress::try_from(cr2)
+            .ok()
+            .and_then(stack_guard::find_owner)
+        {
+            writeln!(
+                &mut logger,
+                "Kernel stack overflow in {}! The guard page at {:#x} was accessed. Panicking!\nBacktrace:",
+                owner, cr2
+            )
+            .ignore();
+            log_backtrace(&mut logger);
+            ArchApi::panic();
+        }
+    }
+
+    if error.is_reserved_bit() {
+        let cr2 = read_cr2();
+        writeln!(
+            &mut logger,
+            "A page fault caused by a reserved bit in a paging structure entry has occurred at {:#x}! Panicking!",
+            cr2
+        )
+        .ignore();
+        match VirtualAddress::try_from(cr2) {
+            Ok(vaddr) => match dump_reserved_bit_walk(&mut logger, vaddr) {
+                Some(level) => {
+                    writeln!(&mut logger, "The {:?} entry has reserved bits set", level).ignore();
+                }
+                None => {
+                    writeln!(&mut logger, "No entry on the walk has reserved bits set").ignore();
+                }
+            },
+            Err(_) => {
+                writeln!(&mut logger, "CR2 does not hold a canonical address").ignore();
+            }
+        }
+        ArchApi::panic();
+    }
+
     writeln!(
         &mut logger,
         "A page fault has occurred with error code {:32b}",
@@ -138,6 +365,9 @@ extern "C" fn ih_debug() {
 
 #[no_mangle]
 extern "C" fn ih_non_maskable_interrupt() {
+    if watchdog::handle_nmi() {
+        return;
+    }
     let mut logger = SerialPort::try_new(COM1).unwrap();
 
     writeln!(&mut logger, "Non-maskable Interrupt Occurred!").ignore();
@@ -300,3 +530,60 @@ extern "C" fn ih_security_exception(error_code: u64) {
     )
     .ignore();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_fault_error_decodes_each_bit() {
+        let error = PageFaultError::from(0b101_0111);
+        assert!(error.is_present());
+        assert!(error.is_write());
+        assert!(error.is_user());
+        assert!(!error.is_reserved_bit());
+        assert!(error.is_instruction_fetch());
+        assert!(!error.is_protection_key());
+        assert!(error.is_shadow_stack());
+
+        let error = PageFaultError::from(0b10_1000);
+        assert!(!error.is_present());
+        assert!(!error.is_write());
+        assert!(!error.is_user());
+        assert!(error.is_reserved_bit());
+        assert!(!error.is_instruction_fetch());
+        assert!(error.is_protection_key());
+        assert!(!error.is_shadow_stack());
+    }
+
+    #[test]
+    fn selector_error_code_decodes_table_and_index() {
+        // GDT selector 0x28
+        let error = SelectorErrorCode::from(0x28);
+        assert_eq!(error.table(), DescriptorTable::Gdt);
+        assert_eq!(error.index(), 5);
+        assert!(!error.is_external());
+
+        // the IDT bit takes precedence over the table indicator bit
+        let error = SelectorErrorCode::from(13 << 3 | 0b111);
+        assert_eq!(error.table(), DescriptorTable::Idt);
+        assert_eq!(error.index(), 13);
+        assert!(error.is_external());
+
+        let error = SelectorErrorCode::from(0x1FFF << 3 | 0b100);
+        assert_eq!(error.table(), DescriptorTable::Ldt);
+        assert_eq!(error.index(), 0x1FFF);
+    }
+
+    #[test]
+    fn selector_error_code_display() {
+        assert_eq!(
+            format!("{}", SelectorErrorCode::from(0x28)),
+            "Gdt index 5 (selector 0x28)"
+        );
+        assert_eq!(
+            format!("{}", SelectorErrorCode::from(14 << 3 | 0b11)),
+            "Idt index 14, external event"
+        );
+    }
+}
@@ -1,20 +1,43 @@
-use core::arch::x86_64::{__cpuid, __rdtscp, _mm_lfence, _mm_pause, _rdtsc};
-use core::ptr;
+use core::arch::x86_64::{__cpuid, __rdtscp, _mm_lfence, _mm_mfence, _mm_pause, _rdtsc};
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::time::Duration;
 
 use crate::acpi::madt::{Madt, MadtEntry};
-use crate::arch::x86_64::cpu::{irq_disable, irq_restore, read_msr, write_msr};
+use crate::arch::x86_64::cpu::{irq_disable, irq_restore, read_msr, write_msr, MSRValue};
 use crate::arch::x86_64::idt::Idt;
 use crate::arch::x86_64::interrupts::apic_consts::{
-    APIC_DISABLE, APIC_NMI, APIC_SW_ENABLE, DESTINATION_FORMAT, EOI_REGISTER, LAPIC_VERSION,
-    LOGICAL_DESTINATION, LVT_LINT0, LVT_LINT1, LVT_PERFORMANCE_MONITORING_COUNTERS, LVT_TIMER,
+    APIC_DISABLE, APIC_NMI, APIC_SW_ENABLE, DESTINATION_FORMAT, EOI_REGISTER,
+    INTERRUPT_COMMAND_ICR, INTERRUPT_COMMAND_ICR_HIGH, LAPIC_VERSION, LOGICAL_DESTINATION,
+    LVT_LINT0, LVT_LINT1, LVT_PERFORMANCE_MONITORING_COUNTERS, LVT_TIMER,
     SPURIOUS_INTERRUPT_VECTOR, TASK_PRIORITY_TPR, TIMER_CURRENT, TIMER_DIVISOR, TIMER_INIT_COUNT,
 };
 use crate::arch::x86_64::interrupts::isa_handler::load_handlers;
 use crate::arch::HwTimerMode;
+use crate::memory::address::VirtualAddress;
+use crate::memory::mmio::RegisterBlock;
 
 const FEAT_EDX_APIC: u32 = 1 << 9;
+const FEAT_ECX_X2APIC: u32 = 1 << 21;
+/// CPUID.01H:ECX[24], the APIC timer supports TSC-deadline mode
+const FEAT_ECX_TSC_DEADLINE: u32 = 1 << 24;
+/// The MSR that holds the TSC value at which the timer fires in TSC-deadline mode, 0 disarms it
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
 const APIC_MSR: u32 = 0x1B;
+/// IA32_APIC_BASE bit 10, switches the APIC into x2APIC mode when it is globally enabled
+const APIC_MSR_EXTD: u32 = 1 << 10;
+/// IA32_APIC_BASE bit 11, globally enables the APIC
+const APIC_MSR_EN: u32 = 1 << 11;
+/// In x2APIC mode the register at MMIO offset `n` is the MSR `X2APIC_MSR_BASE + (n >> 4)`
+const X2APIC_MSR_BASE: u32 = 0x800;
+/// The 64 bit x2APIC ICR, which replaces the pair of 32 bit xAPIC ICR registers
+const X2APIC_ICR_MSR: u32 = X2APIC_MSR_BASE + (INTERRUPT_COMMAND_ICR >> 4);
+/// ICR delivery status, set while the xAPIC is still sending the last IPI
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+/// Whether the APICs have been switched into x2APIC mode
+/// The mode is system wide, APs have to call [`Apic::enable_x2apic`] before touching their APIC
+/// once the BSP has done so.
+static X2APIC_ENABLED: AtomicBool = AtomicBool::new(false);
 
 #[no_mangle]
 static mut LAPIC_REMAPPED_LOCATION: u64 = 0xFEE00000;
@@ -109,8 +132,7 @@ impl Apic {
     #[allow(unused)]
     pub fn get_apic_addr(madt: &Madt) -> usize {
         let mut addr = madt.local_apic_addr() as usize;
-        let mut itr = madt.iter();
-        for entry in itr {
+        for entry in madt.entries() {
             if let MadtEntry::LocalApicAddressOverride(addr_o) = entry {
                 addr = addr_o.local_apic_address as usize;
             }
@@ -119,14 +141,49 @@ impl Apic {
         addr
     }
 
+    fn registers(&self) -> RegisterBlock {
+        Self::register_block(self.get_addr() as u64)
+    }
+
     pub fn write_apic_reg(&self, offset: u32, value: u32) {
-        let addr = (self.get_addr() + offset as usize) as *mut u32;
-        unsafe { ptr::write_volatile(addr, value) }
+        if Self::is_x2apic_enabled() {
+            Self::write_x2apic_reg(offset, value)
+        } else {
+            self.registers().write(offset as usize, value)
+        }
     }
 
     pub fn read_apic_reg(&self, offset: u32) -> u32 {
-        let addr = (self.get_addr() + offset as usize) as *const u32;
-        unsafe { ptr::read_volatile(addr) }
+        if Self::is_x2apic_enabled() {
+            Self::read_x2apic_reg(offset)
+        } else {
+            self.registers().read(offset as usize)
+        }
+    }
+
+    /// Sends a fixed interrupt with `vector` to the LP whose APIC ID is `destination`
+    /// In xAPIC mode only the low 8 bits of `destination` can be encoded.
+    #[allow(unused)]
+    pub fn send_ipi(&self, destination: u32, vector: u8) {
+        let icr = Self::icr_value(destination, vector);
+        if Self::is_x2apic_enabled() {
+            // a single write to the x2APIC ICR sends the IPI, there is no delivery status to poll
+            write_msr(
+                X2APIC_ICR_MSR,
+                MSRValue {
+                    eax: icr as u32,
+                    edx: (icr >> 32) as u32,
+                },
+            );
+        } else {
+            while self.read_apic_reg(INTERRUPT_COMMAND_ICR) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+            // the xAPIC keeps the destination in bits 24..32 of the high ICR register and sends
+            // the IPI when the low register is written
+            self.write_apic_reg(INTERRUPT_COMMAND_ICR_HIGH, destination << 24);
+            self.write_apic_reg(INTERRUPT_COMMAND_ICR, icr as u32);
+        }
     }
 
     pub fn init(&mut self) {
@@ -150,10 +207,16 @@ impl Apic {
         // reset the apic to make sure it's in a known state
         Self::enable_apic(false);
         Self::enable_apic(true);
+        if Self::is_x2apic_supported() {
+            Self::enable_x2apic();
+        }
 
-        self.write_apic_reg(DESTINATION_FORMAT, 0x0FFFFFFFF);
-        let ldf = self.read_apic_reg(LOGICAL_DESTINATION) & 0x00FFFFFF;
-        self.write_apic_reg(LOGICAL_DESTINATION, ldf);
+        // the logical destination is read only and the DFR does not exist in x2APIC mode
+        if !Self::is_x2apic_enabled() {
+            self.write_apic_reg(DESTINATION_FORMAT, 0x0FFFFFFFF);
+            let ldf = self.read_apic_reg(LOGICAL_DESTINATION) & 0x00FFFFFF;
+            self.write_apic_reg(LOGICAL_DESTINATION, ldf);
+        }
         self.write_apic_reg(SPURIOUS_INTERRUPT_VECTOR, 0x27 + APIC_SW_ENABLE);
         self.write_apic_reg(LVT_TIMER, APIC_DISABLE);
         self.write_apic_reg(LVT_PERFORMANCE_MONITORING_COUNTERS, APIC_NMI);
@@ -201,10 +264,48 @@ impl Apic {
         self.write_apic_reg(LVT_TIMER, value);
     }
 
+    /// Starts the timer as configured by [setup_timer](Self::setup_timer). TSC-deadline mode falls
+    /// back to the periodic mode if the APIC does not support it. In TSC-deadline mode the initial
+    /// count is not used and the timer only fires once a deadline is armed with
+    /// [arm_tsc_deadline](Self::arm_tsc_deadline).
     pub fn start_timer(&self) {
+        let mode = match self.timer_mode {
+            TimerMode::TscDeadline if !Self::is_tsc_deadline_supported() => TimerMode::Periodic,
+            mode => mode,
+        };
+        if let TimerMode::TscDeadline = mode {
+            self.set_lvt_timer_register(mode, true, 32);
+            return;
+        }
         self.set_timer_divisor(self.timer_divisor);
         self.set_timer_counter(self.timer_count);
-        self.set_lvt_timer_register(self.timer_mode, true, 32);
+        self.set_lvt_timer_register(mode, true, 32);
+    }
+
+    /// Puts the timer into TSC-deadline mode and arms it to fire `vector` once the TSC of the
+    /// calling LP reaches `deadline`. A deadline that has already passed fires immediately and a
+    /// deadline of 0 disarms the timer.
+    /// # Returns
+    /// Returns false without touching the timer if TSC-deadline mode is not supported, in which
+    /// case the caller has to fall back to the one-shot or periodic mode.
+    #[allow(unused)]
+    pub fn arm_tsc_deadline(&self, deadline: u64, vector: u8) -> bool {
+        if !Self::is_tsc_deadline_supported() {
+            return false;
+        }
+        self.set_lvt_timer_register(TimerMode::TscDeadline, true, vector);
+        // the write to the LVT has to be ordered before the write to the deadline MSR, which is
+        // not serializing with respect to memory mapped APIC registers
+        // SAFETY: mfence only orders memory accesses
+        unsafe { _mm_mfence() };
+        write_msr(
+            IA32_TSC_DEADLINE,
+            MSRValue {
+                eax: deadline as u32,
+                edx: (deadline >> 32) as u32,
+            },
+        );
+        true
     }
 
     pub fn write_eoi(&self) {
@@ -214,14 +315,91 @@ impl Apic {
     pub fn get_timer_current_count(&self) -> u32 {
         self.read_apic_reg(TIMER_CURRENT)
     }
+
+    /// Computes the initial count and divisor needed for the timer to expire after `duration`
+    /// The smallest divisor for which the count fits in the initial count register is chosen.
+    pub fn timer_params_for(&self, duration: Duration) -> (u32, TimerDivisor) {
+        let ticks = (self.tps as u128 * duration.as_millis()) / 1000;
+        let mut divisor = 1u8;
+        while divisor < 128 && ticks / divisor as u128 > u32::MAX as u128 {
+            divisor <<= 1;
+        }
+        let count = (ticks / divisor as u128).min(u32::MAX as u128) as u32;
+        (count, divisor.into())
+    }
 }
 
 // static methods
 impl Apic {
+    fn register_block(base: u64) -> RegisterBlock {
+        let base = VirtualAddress::try_from(base).expect("APIC base address is not canonical");
+        // Safety: the APIC's registers are always present at its base address
+        unsafe { RegisterBlock::new(base) }
+    }
+
+    fn remapped_registers() -> RegisterBlock {
+        // SAFETY: the remapped location is only written once while the APIC is set up, before it is
+        // used
+        Self::register_block(unsafe { LAPIC_REMAPPED_LOCATION })
+    }
+
+    /// Writes a register of the calling LP's APIC through whichever interface is enabled
+    fn write_local_reg(offset: u32, value: u32) {
+        if Self::is_x2apic_enabled() {
+            Self::write_x2apic_reg(offset, value)
+        } else {
+            Self::remapped_registers().write(offset as usize, value)
+        }
+    }
+
+    fn write_x2apic_reg(offset: u32, value: u32) {
+        write_msr(
+            X2APIC_MSR_BASE + (offset >> 4),
+            MSRValue { eax: value, edx: 0 },
+        )
+    }
+
+    fn read_x2apic_reg(offset: u32) -> u32 {
+        read_msr(X2APIC_MSR_BASE + (offset >> 4)).eax
+    }
+
+    /// Encodes a fixed, physical destination, edge triggered IPI as a 64 bit x2APIC ICR value.
+    /// The low 32 bits are also the xAPIC low ICR value for the same IPI.
+    pub const fn icr_value(destination: u32, vector: u8) -> u64 {
+        ((destination as u64) << 32) | vector as u64
+    }
+
     pub fn signal_eoi() {
-        let base = unsafe { LAPIC_REMAPPED_LOCATION };
-        let addr = (base + EOI_REGISTER as u64) as *mut u32;
-        unsafe { ptr::write_volatile(addr, 0) }
+        Self::write_local_reg(EOI_REGISTER, 0u32)
+    }
+
+    /// Restarts the countdown of the running timer from `count`
+    pub fn reset_timer_count(count: u32) {
+        Self::write_local_reg(TIMER_INIT_COUNT, count)
+    }
+
+    /// Masks the timer interrupt of the calling LP's APIC
+    pub fn mask_timer() {
+        Self::write_local_reg(LVT_TIMER, APIC_DISABLE)
+    }
+
+    /// Starts the timer of the calling LP's APIC in one-shot mode so that it raises an NMI once
+    /// `count` ticks have elapsed. Unlike a fixed interrupt the NMI is taken even while interrupts
+    /// are disabled.
+    pub fn start_oneshot_nmi_timer(count: u32, divisor: TimerDivisor) {
+        Self::write_local_reg(TIMER_DIVISOR, divisor as u32);
+        Self::write_local_reg(LVT_TIMER, TimerMode::Oneshot as u32 | APIC_NMI);
+        Self::write_local_reg(TIMER_INIT_COUNT, count);
+    }
+
+    /// Reads the current count of the calling LP's APIC timer, which stays 0 once a one-shot
+    /// countdown has run out
+    pub fn timer_count() -> u32 {
+        if Self::is_x2apic_enabled() {
+            Self::read_x2apic_reg(TIMER_CURRENT)
+        } else {
+            Self::remapped_registers().read(TIMER_CURRENT as usize)
+        }
     }
 
     fn measure_tsc_duration(duration: Duration) -> u64 {
@@ -255,17 +433,48 @@ impl Apic {
         let mut msr = read_msr(APIC_MSR);
 
         if enable {
-            msr.eax |= 1 << 11;
+            msr.eax |= APIC_MSR_EN;
         } else {
-            msr.eax ^= 1 << 11;
+            // globally disabling the APIC also leaves x2APIC mode
+            msr.eax &= !(APIC_MSR_EN | APIC_MSR_EXTD);
+            X2APIC_ENABLED.store(false, Ordering::Release);
+        }
+        write_msr(APIC_MSR, msr);
+    }
+
+    /// Switches the calling LP's APIC into x2APIC mode, all register accesses go through MSRs
+    /// afterwards. Returns false if the processor does not support x2APIC.
+    pub fn enable_x2apic() -> bool {
+        if !Self::is_x2apic_supported() {
+            return false;
         }
+        let mut msr = read_msr(APIC_MSR);
+        // EN and EXTD have to be set together, xAPIC -> x2APIC is the only legal transition
+        // that does not pass through the disabled state
+        msr.eax |= APIC_MSR_EN | APIC_MSR_EXTD;
         write_msr(APIC_MSR, msr);
+        X2APIC_ENABLED.store(true, Ordering::Release);
+        true
+    }
+
+    pub fn is_x2apic_enabled() -> bool {
+        X2APIC_ENABLED.load(Ordering::Acquire)
+    }
+
+    pub fn is_x2apic_supported() -> bool {
+        let cpuid = __cpuid(1);
+        (cpuid.ecx & FEAT_ECX_X2APIC) == FEAT_ECX_X2APIC
+    }
+
+    pub fn is_tsc_deadline_supported() -> bool {
+        let cpuid = __cpuid(1);
+        (cpuid.ecx & FEAT_ECX_TSC_DEADLINE) == FEAT_ECX_TSC_DEADLINE
     }
 
     pub fn is_apic_enabled() -> bool {
         let msr = read_msr(APIC_MSR);
 
-        (msr.eax & 1 << 11) != 0
+        (msr.eax & APIC_MSR_EN) != 0
     }
 
     pub fn is_present() -> bool {
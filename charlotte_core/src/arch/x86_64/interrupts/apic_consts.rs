@@ -48,6 +48,9 @@ pub const LVT_CORRECTED_MACHINE_CHECK_INTERRUPT_CMCI: u32 = 0x2F0;
 /// Read/Write
 pub const INTERRUPT_COMMAND_ICR: u32 = 0x300;
 
+/// Read/Write, xAPIC only
+pub const INTERRUPT_COMMAND_ICR_HIGH: u32 = 0x310;
+
 /// Read/Write
 pub const LVT_TIMER: u32 = 0x320;
 
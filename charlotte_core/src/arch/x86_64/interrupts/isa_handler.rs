@@ -34,6 +34,8 @@ extern "C" {
 #[repr(u8)]
 pub enum IntIdx {
     Timer = 0x20,
+    /// ISA IRQ 4, the received data available interrupt of COM1
+    Com1 = 0x24,
 }
 
 pub fn load_handlers(idt: &mut Idt) {
@@ -0,0 +1,113 @@
+//! # Fixmap
+//! Per-CPU virtual windows for temporarily mapping a single physical frame, e.g. to access a frame
+//! that is not covered by the direct map. Every LP owns a few slots in [layout::FIXMAP] so mapping
+//! a frame never takes a global lock and unmapping it only invalidates the calling LP's TLB entry.
+//! The tables for the whole window are allocated by [init] so that mapping a frame never allocates.
+//! Since the kernel half is shared by every page map the slots are usable in any address space.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use super::page_map::page_table::page_table_entry::{MemType, PteFlags};
+use super::page_map::{asm_get_cr3, PageMap};
+use super::Error;
+use crate::arch::x86_64::cpu::{current_cpu_index, MAX_CPUS};
+use crate::arch::MemoryMap;
+use crate::memory::address::{PhysicalAddress, VirtualAddress, PAGE_SIZE};
+use crate::memory::layout;
+
+/// The number of frames each LP can have mapped at the same time
+pub const SLOTS_PER_CPU: usize = 4;
+
+const _: () = assert!((MAX_CPUS * SLOTS_PER_CPU) as u64 * PAGE_SIZE <= layout::FIXMAP.size());
+const _: () = assert!(SLOTS_PER_CPU <= u8::BITS as usize);
+
+/// The frame is only borrowed so it is marked shared, which keeps unmapping it from freeing it
+const SLOT_FLAGS: u64 = MemType::KernelReadWrite.flags() | PteFlags::CcShared as u64;
+
+/// A bitmap of the slots in use by each LP
+static USED_SLOTS: [AtomicU8; MAX_CPUS] = [const { AtomicU8::new(0) }; MAX_CPUS];
+
+/// Allocates the page tables that back the fixmap window
+pub fn init() -> Result<(), Error> {
+    // SAFETY: reading CR3 has no side effects
+    let mut page_map = PageMap::from_cr3(unsafe { asm_get_cr3() })?;
+    for base in (layout::FIXMAP.start..layout::FIXMAP.end).step_by(0x20_0000) {
+        let vaddr = VirtualAddress::try_from(base).map_err(|_| Error::InvalidAddress)?;
+        page_map.prepare_tables(vaddr)?;
+    }
+    Ok(())
+}
+
+fn slot_vaddr(cpu: usize, slot: usize) -> VirtualAddress {
+    let offset = (cpu * SLOTS_PER_CPU + slot) as u64 * PAGE_SIZE;
+    // every slot lies within the window as checked above
+    VirtualAddress::try_from(layout::FIXMAP.start + offset).unwrap()
+}
+
+/// Maps the frame at `paddr` into a free slot of the calling LP
+/// The mapping is removed when the returned guard is dropped, which has to happen on the same LP.
+/// # Returns
+/// Returns `Error::VAddrRangeUnavailable` if all of the calling LP's slots are in use and
+/// `Error::UnsupportedOperation` if the calling LP has not been assigned a logical index.
+#[allow(unused)]
+pub fn map_temporary(paddr: PhysicalAddress) -> Result<FixmapGuard, Error> {
+    if !paddr.is_page_aligned() {
+        return Err(Error::InvalidPAddrAlignment);
+    }
+    let cpu = current_cpu_index().ok_or(Error::UnsupportedOperation)?;
+    let used = &USED_SLOTS[cpu];
+    let mut current = used.load(Ordering::Acquire);
+    let slot = loop {
+        let free = (!current).trailing_zeros() as usize;
+        if free >= SLOTS_PER_CPU {
+            return Err(Error::VAddrRangeUnavailable);
+        }
+        match used.compare_exchange_weak(
+            current,
+            current | 1 << free,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => break free,
+            Err(actual) => current = actual,
+        }
+    };
+
+    let vaddr = slot_vaddr(cpu, slot);
+    // SAFETY: reading CR3 has no side effects
+    let mapped = PageMap::from_cr3(unsafe { asm_get_cr3() })
+        .and_then(|mut page_map| page_map.try_map_existing(vaddr, paddr, SLOT_FLAGS));
+    if let Err(e) = mapped {
+        used.fetch_and(!(1 << slot), Ordering::AcqRel);
+        return Err(e);
+    }
+    Ok(FixmapGuard { cpu, slot, vaddr })
+}
+
+/// A frame mapped into a fixmap slot, the slot is unmapped and released when this is dropped
+#[derive(Debug)]
+pub struct FixmapGuard {
+    cpu: usize,
+    slot: usize,
+    vaddr: VirtualAddress,
+}
+
+impl FixmapGuard {
+    /// Returns the address the frame is mapped at
+    #[allow(unused)]
+    pub fn vaddr(&self) -> VirtualAddress {
+        self.vaddr
+    }
+}
+
+impl Drop for FixmapGuard {
+    fn drop(&mut self) {
+        debug_assert_eq!(current_cpu_index(), Some(self.cpu));
+        // unmap_page only invalidates the TLB entry of the calling LP
+        // SAFETY: reading CR3 has no side effects
+        if let Ok(mut page_map) = PageMap::from_cr3(unsafe { asm_get_cr3() }) {
+            let _ = page_map.unmap_page(self.vaddr);
+        }
+        USED_SLOTS[self.cpu].fetch_and(!(1 << self.slot), Ordering::AcqRel);
+    }
+}
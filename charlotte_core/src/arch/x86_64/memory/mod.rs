@@ -1,8 +1,17 @@
+pub mod fixmap;
 pub mod page_map;
+pub mod stack_guard;
 
 use core::arch::x86_64::__cpuid_count;
+use core::ptr::addr_of;
 
-use crate::memory::address::{PhysicalAddress, VirtualAddress};
+use page_map::page_table::page_table_entry::MemType;
+use page_map::{asm_get_cr3, PageMap};
+
+use crate::arch::x86_64::cpu::{count_tlb_flush, TlbFlush};
+use crate::elf::Error as ElfError;
+use crate::memory::address::{PageCount, PhysicalAddress, VirtualAddress, PAGE_SIZE};
+use crate::memory::layout;
 use crate::memory::pmm::Error as PmmError;
 use spin::lazy::Lazy;
 
@@ -39,7 +48,26 @@ pub enum Error {
     OpNotSupportedAtThisLevel,
     AlredyHasPcid,
     InvalidPcid,
+    NotMapped,
+    AddressInUse,
+    /// A walk to the table a mapping belongs in ended above that table's level
+    WalkIncomplete,
+    /// The mapping would exceed the page map's memory limit
+    MemoryLimitExceeded,
+    /// An address is not in canonical form
+    NonCanonicalAddress,
+    /// A range that has to belong to user space reaches into the null guard or the kernel half
+    NotUserAddress,
+    /// A page is mapped but does not grant the access that is needed
+    InsufficientPermissions,
+    /// The page map is loaded on an LP, so its tables cannot be freed
+    MapInUse,
+    /// Every account for the user half of an address space is in use
+    TooManyAddressSpaces,
+    /// A page fault could not take the PMM lock, which the code it interrupted may hold
+    PmmBusy,
     PmmError(PmmError),
+    ElfError(ElfError),
 }
 
 impl From<PmmError> for Error {
@@ -48,8 +76,84 @@ impl From<PmmError> for Error {
     }
 }
 
+impl From<ElfError> for Error {
+    fn from(error: ElfError) -> Self {
+        Error::ElfError(error)
+    }
+}
+
+extern "C" {
+    static __kernel_text_start: u8;
+    static __kernel_text_end: u8;
+    static __kernel_rodata_start: u8;
+    static __kernel_rodata_end: u8;
+    static __kernel_data_start: u8;
+    static __kernel_data_end: u8;
+}
+
+/// Returns the bounds of each section of the kernel image along with the kind of memory it holds
+fn kernel_sections() -> [(u64, u64, MemType); 3] {
+    [
+        (
+            addr_of!(__kernel_text_start) as u64,
+            addr_of!(__kernel_text_end) as u64,
+            MemType::KernelCode,
+        ),
+        (
+            addr_of!(__kernel_rodata_start) as u64,
+            addr_of!(__kernel_rodata_end) as u64,
+            MemType::KernelReadOnly,
+        ),
+        (
+            addr_of!(__kernel_data_start) as u64,
+            addr_of!(__kernel_data_end) as u64,
+            MemType::KernelReadWrite,
+        ),
+    ]
+}
+
+/// Remaps the kernel image in the current page map so that `.text` is read-execute, `.rodata` is
+/// read-only and `.data` and `.bss` are read-write but not executable, regardless of the
+/// permissions the bootloader mapped them with.
+/// The linker script starts each section on a new page so the sections never share a page.
+pub fn protect_kernel_sections() -> Result<(), Error> {
+    // SAFETY: reading CR3 has no side effects
+    let mut page_map = PageMap::from_cr3(unsafe { asm_get_cr3() })?;
+    for (start, end, mem_type) in kernel_sections() {
+        debug_assert!(layout::KERNEL_IMAGE.contains(start) && end <= layout::KERNEL_IMAGE.end);
+        let base = start & !(PAGE_SIZE - 1);
+        let Some(n_pages) = PageCount::from_bytes_round_up((end - base) as usize) else {
+            continue;
+        };
+        let vaddr = VirtualAddress::try_from(base).map_err(|_| Error::InvalidAddress)?;
+        page_map.protect(vaddr, n_pages, mem_type.flags())?;
+    }
+    Ok(())
+}
+
+/// Invalidates the TLB entry for the page containing `vaddr` in the current address space
+fn invalidate_tlb_entry(vaddr: VirtualAddress) {
+    // SAFETY: invlpg only drops a TLB entry, which is refilled from the page tables as needed
+    unsafe { asm_invalidate_tlb_entry(vaddr) };
+    count_tlb_flush(TlbFlush::SinglePage);
+}
+
 extern "C" {
     fn asm_load_page_map(paddr: PhysicalAddress);
+    #[cfg(not(test))]
     fn asm_invalidate_tlb_entry(vaddr: VirtualAddress);
+    #[cfg(not(test))]
     pub fn asm_get_cr4() -> u64;
 }
+
+/// Unit tests run as a user space process that may not read CR4, they see only PAE enabled as it
+/// is in long mode
+#[cfg(test)]
+pub unsafe fn asm_get_cr4() -> u64 {
+    1 << 5
+}
+
+/// Unit tests run as a user space process that may not execute `invlpg`, their page maps are only
+/// ever loaded into a simulated CR3 so they have no TLB entries to invalidate
+#[cfg(test)]
+unsafe fn asm_invalidate_tlb_entry(_vaddr: VirtualAddress) {}
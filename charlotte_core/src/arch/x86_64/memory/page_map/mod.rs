@@ -1,25 +1,44 @@
 pub mod page_table;
 
-use page_table::PageTable;
+use page_table::page_table_entry::{
+    CacheType, MemType, PageTableEntry, PatLayout, PteFlags, SwBit,
+};
+use page_table::{PageTable, PageTableLevel};
 
-use super::Error;
+use super::{invalidate_tlb_entry, Error};
 
 use core::arch::{asm, global_asm};
-use core::fmt::Write;
+use core::fmt::{self, Display, Write};
+use core::hint::spin_loop;
 use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
-use crate::arch::x86_64::cpu::ARE_HUGE_PAGES_SUPPORTED;
+use spin::mutex::MutexGuard;
+
+use crate::arch::x86_64::cpu::{
+    count_tlb_flush, current_cpu_index, is_cr4_feature_enabled, Cr4Feature, TlbFlush,
+    ARE_HUGE_PAGES_SUPPORTED, ARE_LARGE_PAGES_SUPPORTED, IS_INVPCID_SUPPORTED, MAX_CPUS,
+};
 use crate::arch::{Api, ArchApi, MemoryMap};
-use crate::logln;
-use crate::memory::address::VirtualAddress;
-use crate::memory::{address::PhysicalAddress, pmm::PHYSICAL_FRAME_ALLOCATOR};
+use crate::debugln;
+use crate::elf::{Elf64, Error as ElfError, ProgramHeader};
+use crate::memory::address::{PageCount, PhysicalAddress, VirtualAddress, PAGE_SIZE};
+use crate::memory::dma::DmaBuffer;
+use crate::memory::layout;
+use crate::memory::pmm::{PhysicalFrameAllocator, PHYSICAL_FRAME_ALLOCATOR};
 
+/// Walks the tables of a page map down to the level needed for a mapping, allocating missing
+/// tables on the way. If a step of the walk fails, every table the walker installed is removed and
+/// freed again so that a failed walk leaves the page map exactly as it was.
 struct Walker<'a> {
     page_map: &'a PageMap,
     pml4: Option<&'a mut PageTable>,
     pdpt: Option<&'a mut PageTable>,
     pd: Option<&'a mut PageTable>,
     pt: Option<&'a mut PageTable>,
+    /// The parent table and index of each entry this walker installed a new table at, in the order
+    /// they were installed
+    created: [Option<(*mut PageTable, usize)>; 3],
 }
 
 impl<'a> Walker<'a> {
@@ -30,130 +49,2708 @@ impl<'a> Walker<'a> {
             pdpt: None,
             pd: None,
             pt: None,
+            created: [None; 3],
+        }
+    }
+    fn walk_cr3(&mut self) -> Result<(), Error> {
+        unsafe {
+            self.pml4 = Some(&mut *(<*mut PageTable>::from(self.page_map.get_pml4_paddr())));
+        }
+        Ok(())
+    }
+
+    /// Gets or installs the table below `table` that translates `vaddr`, recording it if it was
+    /// installed by this call. A new table is linked with [table_flags] of the leaf `flags`.
+    /// The whole walk is rolled back if this fails.
+    unsafe fn step(
+        &mut self,
+        table: *mut PageTable,
+        vaddr: VirtualAddress,
+        level: page_table::PageTableLevel,
+        flags: u64,
+    ) -> Result<&'a mut PageTable, Error> {
+        let index = level.index_of(vaddr);
+        let was_present = (*table).get(index).is_present();
+        match (*table).get_or_map_table(vaddr, level, table_flags(flags)) {
+            Ok(next) => {
+                if !was_present {
+                    if let Some(slot) = self.created.iter_mut().find(|slot| slot.is_none()) {
+                        *slot = Some((table, index));
+                    }
+                }
+                Ok(&mut *next)
+            }
+            Err(e) => {
+                self.rollback();
+                Err(e)
+            }
+        }
+    }
+
+    /// Removes and frees the tables installed by this walker, deepest first.
+    /// A table that another LP has already started using is left in place.
+    fn rollback(&mut self) {
+        for (table, index) in self.created.iter_mut().rev().filter_map(|slot| slot.take()) {
+            // SAFETY: the walker installed each table it recorded and nothing else has freed them
+            // yet
+            unsafe {
+                let Ok(child) = (*table).get(index).addr() else {
+                    continue;
+                };
+                if (*<*const PageTable>::from(child)).is_empty() {
+                    let _ = (*table).unmap_table(index);
+                }
+            }
+        }
+        self.pdpt = None;
+        self.pd = None;
+        self.pt = None;
+    }
+
+    fn walk_pml4(&mut self, vaddr: VirtualAddress, flags: u64) -> Result<(), Error> {
+        match &mut self.pml4 {
+            Some(pml4) => {
+                unsafe {
+                    let pml4_ptr: *mut PageTable = addr_of_mut!(**pml4);
+                    self.pdpt = Some(self.step(
+                        pml4_ptr,
+                        vaddr,
+                        page_table::PageTableLevel::PML4,
+                        flags,
+                    )?);
+                }
+                Ok(())
+            }
+            None => {
+                self.walk_cr3()?;
+                self.walk_pml4(vaddr, flags)
+            }
+        }
+    }
+
+    fn walk_pdpt(&mut self, vaddr: VirtualAddress, flags: u64) -> Result<(), Error> {
+        match &mut self.pdpt {
+            Some(pdpt) => {
+                unsafe {
+                    let pdpt_ptr: *mut PageTable = addr_of_mut!(**pdpt);
+                    self.pd = Some(self.step(
+                        pdpt_ptr,
+                        vaddr,
+                        page_table::PageTableLevel::PDPT,
+                        flags,
+                    )?);
+                }
+                Ok(())
+            }
+            None => {
+                debugln!("Walking PML4");
+                self.walk_pml4(vaddr, flags)?;
+                self.walk_pdpt(vaddr, flags)
+            }
+        }
+    }
+
+    fn walk_pd(&mut self, vaddr: VirtualAddress, flags: u64) -> Result<(), Error> {
+        match &mut self.pd {
+            Some(pd) => {
+                unsafe {
+                    let pd_ptr: *mut PageTable = addr_of_mut!(**pd);
+                    debugln!("Obtained PD pointer: {:p}", pd_ptr);
+                    self.pt =
+                        Some(self.step(pd_ptr, vaddr, page_table::PageTableLevel::PD, flags)?);
+                    debugln!("Obtained or Mapped PT.");
+                }
+                Ok(())
+            }
+            None => {
+                debugln!("Walking PDPT");
+                self.walk_pdpt(vaddr, flags)?;
+                self.walk_pd(vaddr, flags)
+            }
+        }
+    }
+}
+
+/// The default number of pages above which flushing a range flushes the whole address space from
+/// the TLB rather than invalidating each page individually
+pub const DEFAULT_TLB_FLUSH_THRESHOLD: usize = 32;
+
+/// The number of pages above which flushing a range flushes the whole address space, see
+/// [set_tlb_flush_threshold]
+static TLB_FLUSH_THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_TLB_FLUSH_THRESHOLD);
+
+/// Sets the number of pages above which [PageMap::flush_range] and [PageMap::unmap_range] flush the
+/// whole address space instead of invalidating each page. Where individual invalidations become
+/// slower than refilling the TLB depends on the processor, so this is meant to be tuned by
+/// benchmarking or from the `tlb_flush_threshold` command line option.
+pub fn set_tlb_flush_threshold(n_pages: usize) {
+    TLB_FLUSH_THRESHOLD.store(n_pages, Ordering::Relaxed);
+}
+
+/// Returns the number of pages above which flushing a range flushes the whole address space
+pub fn tlb_flush_threshold() -> usize {
+    TLB_FLUSH_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// When set in a value written to CR3 with PCIDs enabled, the TLB entries of the new PCID are kept
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+/// The number of PCIDs per LP whose last PML4 is remembered, PCIDs share slots by their low bits
+const PCID_OWNER_SLOTS: usize = 16;
+
+/// The CR3 value, without the no-flush bit, that each recently used PCID was last loaded with on
+/// each LP. A slot holding a different PCID says nothing about the PCIDs that share it.
+static PCID_OWNERS: [[AtomicU64; PCID_OWNER_SLOTS]; MAX_CPUS] =
+    [const { [const { AtomicU64::new(0) }; PCID_OWNER_SLOTS] }; MAX_CPUS];
+
+/// Records that the calling LP loaded `cr3`, after which the TLB entries tagged with its PCID
+/// belong to its PML4
+/// # Returns
+/// Returns true if the PCID was already known to have last been loaded with the same PML4 on this
+/// LP, false if it was loaded with a different one or is not known.
+fn record_pcid_owner(cr3: u64) -> bool {
+    let cr3 = cr3 & !CR3_NO_FLUSH;
+    let pcid = (cr3 & 0xFFF) as usize;
+    if pcid == 0 {
+        return false;
+    }
+    match current_cpu_index() {
+        Some(index) => {
+            PCID_OWNERS[index][pcid % PCID_OWNER_SLOTS].swap(cr3, Ordering::AcqRel) == cr3
+        }
+        None => false,
+    }
+}
+
+/// The physical address of the PML4 each LP has loaded or is about to load, 0 if not known.
+/// Together with [PRUNING_PML4] this keeps [PageMap::prune_empty_tables] from freeing tables that
+/// another LP may be walking: an LP publishes the PML4 here before writing it to CR3 and then waits
+/// for any prune of it to finish, while a prune claims the PML4 before checking whether any LP has
+/// published it. With both orderings sequentially consistent, one side always sees the other.
+static LOADED_PML4S: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// The physical address of the PML4 whose tables are being pruned, 0 if none are
+static PRUNING_PML4: AtomicU64 = AtomicU64::new(0);
+
+/// The most address spaces whose user half can be accounted at the same time
+const MAX_ACCOUNTS: usize = 256;
+
+/// The accounting of the user half of an address space. It is kept apart from [PageMap] and looked
+/// up by the address of the PML4 so that every page map for the same PML4, like the one a page
+/// fault handler obtains with [PageMap::from_cr3], charges the address space that owns the PML4.
+struct Account {
+    /// The physical address of the PML4 the account belongs to, 0 if the slot is free
+    pml4: AtomicU64,
+    /// The number of standard pages mapped in the user half
+    resident_pages: AtomicUsize,
+    /// The number of standard pages the user half is promised, the resident pages along with the
+    /// demand-zero reservations that have not been backed yet
+    committed_pages: AtomicUsize,
+    /// The number of standard pages the user half may have committed, `usize::MAX` if unlimited
+    memory_limit: AtomicUsize,
+}
+
+impl Account {
+    const fn new() -> Self {
+        Account {
+            pml4: AtomicU64::new(0),
+            resident_pages: AtomicUsize::new(0),
+            committed_pages: AtomicUsize::new(0),
+            memory_limit: AtomicUsize::new(usize::MAX),
+        }
+    }
+
+    /// Resets the counters to those of an empty address space
+    fn reset(&self) {
+        self.resident_pages.store(0, Ordering::Release);
+        self.committed_pages.store(0, Ordering::Release);
+        self.memory_limit.store(usize::MAX, Ordering::Release);
+    }
+
+    /// Commits `n_pages` more pages
+    /// # Returns
+    /// Returns `Error::MemoryLimitExceeded` without committing them if they would exceed the limit.
+    fn commit(&self, n_pages: usize) -> Result<(), Error> {
+        let limit = self.memory_limit.load(Ordering::Acquire);
+        let mut committed = self.committed_pages.load(Ordering::Acquire);
+        loop {
+            let charged = committed
+                .checked_add(n_pages)
+                .filter(|&charged| charged <= limit)
+                .ok_or(Error::MemoryLimitExceeded)?;
+            match self.committed_pages.compare_exchange_weak(
+                committed,
+                charged,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(current) => committed = current,
+            }
+        }
+    }
+
+    /// Commits `n_pages` fewer pages
+    fn uncommit(&self, n_pages: usize) {
+        saturating_release(&self.committed_pages, n_pages);
+    }
+
+    /// Counts `n_pages` committed pages as resident
+    fn back(&self, n_pages: usize) {
+        self.resident_pages.fetch_add(n_pages, Ordering::AcqRel);
+    }
+
+    /// Drops `n_pages` pages that are resident from both counts
+    fn unmap(&self, n_pages: usize) {
+        saturating_release(&self.resident_pages, n_pages);
+        self.uncommit(n_pages);
+    }
+}
+
+/// Subtracts `n` from `counter` without going below 0
+fn saturating_release(counter: &AtomicUsize, n: usize) {
+    let mut current = counter.load(Ordering::Acquire);
+    while let Err(actual) = counter.compare_exchange_weak(
+        current,
+        current.saturating_sub(n),
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    ) {
+        current = actual;
+    }
+}
+
+static ACCOUNTS: [Account; MAX_ACCOUNTS] = [const { Account::new() }; MAX_ACCOUNTS];
+
+/// Returns the account of the address space whose PML4 is at `pml4`, if it has one
+fn find_account(pml4: PhysicalAddress) -> Option<&'static Account> {
+    ACCOUNTS
+        .iter()
+        .find(|account| account.pml4.load(Ordering::Acquire) == pml4.bits())
+}
+
+/// Gives the address space whose PML4 was just allocated at `pml4` an empty account. An account
+/// left behind by an address space whose PML4 was freed without closing it is reused.
+/// # Returns
+/// Returns `Error::TooManyAddressSpaces` if every account is in use.
+fn open_account(pml4: PhysicalAddress) -> Result<(), Error> {
+    let account = match find_account(pml4) {
+        Some(account) => account,
+        None => ACCOUNTS
+            .iter()
+            .find(|account| {
+                account
+                    .pml4
+                    .compare_exchange(0, pml4.bits(), Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+            })
+            .ok_or(Error::TooManyAddressSpaces)?,
+    };
+    account.reset();
+    Ok(())
+}
+
+/// Frees the account of the address space whose PML4 is at `pml4`
+fn close_account(pml4: PhysicalAddress) {
+    if let Some(account) = find_account(pml4) {
+        account.pml4.store(0, Ordering::Release);
+    }
+}
+
+/// How many iterations of the spin loop hint the resolution of a page fault waits for the PMM lock.
+/// The code the fault interrupted may hold the lock on the same LP, in which case it is never
+/// released while the handler waits for it.
+const FAULT_PMM_SPINS: usize = 1 << 16;
+
+/// Takes the PMM lock for resolving a page fault, giving up after [FAULT_PMM_SPINS] attempts
+/// # Returns
+/// Returns `Error::PmmBusy` if the lock could not be taken in time.
+fn lock_pmm_for_fault() -> Result<MutexGuard<'static, PhysicalFrameAllocator>, Error> {
+    for _ in 0..FAULT_PMM_SPINS {
+        if let Some(pmm) = PHYSICAL_FRAME_ALLOCATOR.try_lock() {
+            return Ok(pmm);
+        }
+        spin_loop();
+    }
+    Err(Error::PmmBusy)
+}
+
+/// Publishes that the calling LP is about to write `cr3` to CR3 and waits until the tables of its
+/// PML4 are not being pruned. Every write of CR3 has to be preceded by a call to this.
+fn announce_load(cr3: u64) {
+    let pml4 = cr3 & !CR3_NO_FLUSH & !0xFFF;
+    if let Some(index) = current_cpu_index() {
+        LOADED_PML4S[index].store(pml4, Ordering::SeqCst);
+    }
+    while PRUNING_PML4.load(Ordering::SeqCst) == pml4 {
+        spin_loop();
+    }
+}
+
+/// Returns the table that holds the entry mapping a page of `size` at `vaddr` in the page map
+/// rooted at `pml4`, creating any missing tables on the way with `table_flags`
+/// # Safety
+/// `pml4` must point to a valid PML4 that nothing else is modifying.
+unsafe fn leaf_table(
+    pml4: *mut PageTable,
+    vaddr: VirtualAddress,
+    size: page_table::PageSize,
+    table_flags: u64,
+) -> Result<*mut PageTable, Error> {
+    let levels: &[page_table::PageTableLevel] = match size {
+        page_table::PageSize::Standard => &[
+            page_table::PageTableLevel::PML4,
+            page_table::PageTableLevel::PDPT,
+            page_table::PageTableLevel::PD,
+        ],
+        page_table::PageSize::Large => &[
+            page_table::PageTableLevel::PML4,
+            page_table::PageTableLevel::PDPT,
+        ],
+        page_table::PageSize::Huge => &[page_table::PageTableLevel::PML4],
+    };
+    let mut table = pml4;
+    for level in levels {
+        // SAFETY: `table` is `pml4` or a table installed below it, both reachable through the
+        // direct map
+        table = unsafe { (*table).get_or_map_table(vaddr, *level, table_flags)? };
+    }
+    Ok(table)
+}
+
+/// Returns the index of the entry that maps a page of `size` at `vaddr` in its table
+fn leaf_index(vaddr: VirtualAddress, size: page_table::PageSize) -> usize {
+    match size {
+        page_table::PageSize::Standard => vaddr.pt_index(),
+        page_table::PageSize::Large => vaddr.pd_index(),
+        page_table::PageSize::Huge => vaddr.pdpt_index(),
+    }
+}
+
+/// Maps `mapping`'s frame with `flags` at its virtual address and size into the page map rooted
+/// at `pml4`, creating any missing tables with `table_flags`. No TLB entries are invalidated.
+/// # Safety
+/// `pml4` must point to a valid PML4 that nothing else is modifying.
+unsafe fn map_leaf(
+    pml4: *mut PageTable,
+    mapping: &Mapping,
+    flags: u64,
+    table_flags: u64,
+) -> Result<(), Error> {
+    // SAFETY: the caller guarantees that `pml4` is valid and not modified concurrently
+    let table = unsafe { leaf_table(pml4, mapping.vaddr, mapping.size, table_flags)? };
+    let index = leaf_index(mapping.vaddr, mapping.size);
+    // SAFETY: `leaf_table` returned a valid table of the required level
+    unsafe { (*table).map_page(mapping.size, index, mapping.paddr, flags) }
+}
+
+/// The flags of the tables leading to user pages, the leaf entries restrict the access further
+const USER_TABLE_FLAGS: u64 =
+    PteFlags::Present as u64 | PteFlags::Write as u64 | PteFlags::User as u64;
+
+/// Returns the flags for a table leading to a leaf mapped with `leaf_flags`. Tables never restrict
+/// access themselves, so that the leaves below them can be mapped with any permissions, and only
+/// pass on whether the leaves may belong to user space.
+const fn table_flags(leaf_flags: u64) -> u64 {
+    PteFlags::Present as u64 | PteFlags::Write as u64 | (leaf_flags & PteFlags::User as u64)
+}
+
+/// Looks up the address of a symbol that an image being loaded does not define by its name, `None`
+/// if the image is not relocated
+type SymbolResolver<'r> = Option<&'r mut dyn FnMut(&str) -> Option<u64>>;
+
+/// The size of a large page in bytes
+const LARGE_PAGE_SIZE: u64 = 0x20_0000;
+
+/// The size of the region at the bottom of the address space that may never be mapped so that
+/// null pointer dereferences, including those with a small offset, always fault.
+pub const NULL_GUARD_SIZE: u64 = layout::USER.start;
+
+/// Ensures that a mapping starting at `vaddr` does not overlap the null guard region.
+/// Mappings are always at least page aligned so checking the base address is sufficient.
+fn check_null_guard(vaddr: VirtualAddress) -> Result<(), Error> {
+    if vaddr.is_null() || vaddr.bits() < NULL_GUARD_SIZE {
+        Err(Error::InvalidAddress)
+    } else {
+        Ok(())
+    }
+}
+
+/// Restores the saved CR3 value when dropped so that the original address space is reloaded even
+/// if the code that temporarily switched away from it returns early.
+struct Cr3Guard {
+    saved_cr3: u64,
+}
+
+impl Drop for Cr3Guard {
+    fn drop(&mut self) {
+        announce_load(self.saved_cr3);
+        // SAFETY: the saved CR3 maps the kernel as it did when it was saved
+        unsafe { write_cr3(self.saved_cr3) }
+        record_pcid_owner(self.saved_cr3);
+        count_tlb_flush(TlbFlush::Full);
+    }
+}
+
+/// How [PageMap::map_region_fixed] treats pages in the requested range that are already mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(unused)]
+pub enum FixedMapping {
+    /// Existing mappings in the range are unmapped and replaced
+    Replace,
+    /// The request fails if any page in the range is already mapped
+    NoReplace,
+}
+
+/// How the frames backing a region mapped by [PageMap::map_region_fixed] are used, which selects
+/// how they are allocated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[allow(unused)]
+pub enum RegionHint {
+    /// Each page gets a frame of the cache color matching its virtual page number, so that
+    /// consecutive pages never compete for the same cache sets
+    ReadMostly,
+    /// Frames should be local to the NUMA node of the LPs writing to them. The PMM does not track
+    /// nodes yet so these come from the default allocation.
+    #[default]
+    WriteHeavy,
+    /// The region is backed by one physically contiguous block that is mapped uncacheable, so
+    /// that devices without cache snooping see every write
+    DmaCoherent,
+}
+
+/// A single present leaf mapping in a page map
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    pub vaddr: VirtualAddress,
+    pub paddr: PhysicalAddress,
+    pub size: page_table::PageSize,
+    pub flags: u64,
+}
+
+/// Iterates over every present leaf mapping in a page map in ascending virtual address order
+/// The page tables are only ever read.
+pub struct MappingIter<'a> {
+    tables: [*const PageTable; 4],
+    indices: [usize; 4],
+    level: usize,
+    user_only: bool,
+    _page_map: core::marker::PhantomData<&'a PageMap>,
+}
+
+impl MappingIter<'_> {
+    /// Reconstructs the canonical virtual address selected by the current table indices
+    fn current_vaddr(&self) -> VirtualAddress {
+        let mut raw = 0u64;
+        for (level, shift) in [39, 30, 21, 12]
+            .into_iter()
+            .enumerate()
+            .take(self.level + 1)
+        {
+            raw |= (self.indices[level] as u64) << shift;
+        }
+        // sign extend addresses in the higher half
+        if self.indices[0] >= layout::HIGHER_HALF_PML4_INDEX {
+            raw |= 0xFFFF_0000_0000_0000;
+        }
+        VirtualAddress::try_from(raw).unwrap()
+    }
+}
+
+impl Iterator for MappingIter<'_> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.indices[self.level] >= page_table::N_PT_ENTRIES {
+                if self.level == 0 {
+                    return None;
+                }
+                self.level -= 1;
+                self.indices[self.level] += 1;
+                continue;
+            }
+            if self.level == 0
+                && self.user_only
+                && self.indices[0] >= layout::HIGHER_HALF_PML4_INDEX
+            {
+                return None;
+            }
+
+            // SAFETY: the walker only descends into present tables of the page map it walks
+            let entry = unsafe { (*self.tables[self.level]).get(self.indices[self.level]) };
+            if !entry.is_present() {
+                self.indices[self.level] += 1;
+                continue;
+            }
+            let size = match self.level {
+                1 if entry.is_size_bit_set() => Some(page_table::PageSize::Huge),
+                2 if entry.is_size_bit_set() => Some(page_table::PageSize::Large),
+                3 => Some(page_table::PageSize::Standard),
+                _ => None,
+            };
+            match size {
+                Some(size) => {
+                    let mapping = Mapping {
+                        vaddr: self.current_vaddr(),
+                        paddr: entry.physical_address(size).unwrap(),
+                        size,
+                        flags: entry.flags(size),
+                    };
+                    self.indices[self.level] += 1;
+                    return Some(mapping);
+                }
+                None => {
+                    self.tables[self.level + 1] = <*const PageTable>::from(entry.addr().unwrap());
+                    self.level += 1;
+                    self.indices[self.level] = 0;
+                }
+            }
+        }
+    }
+}
+
+/// The entry read at one level of a page table walk
+#[derive(Debug, Clone, Copy)]
+pub struct WalkStep {
+    pub level: PageTableLevel,
+    /// The physical address of the table the entry was read from
+    pub table: PhysicalAddress,
+    pub index: usize,
+    pub entry: u64,
+    pub present: bool,
+    /// Whether the entry maps a large or huge page instead of referencing a table
+    pub huge: bool,
+    /// The memory type of the page if the entry is present and maps one
+    pub cache_type: Option<CacheType>,
+}
+
+/// Every entry used to translate a virtual address, see [PageMap::explain]
+#[derive(Debug, Clone, Copy)]
+pub struct WalkTrace {
+    pub vaddr: VirtualAddress,
+    steps: [Option<WalkStep>; 4],
+}
+
+impl WalkTrace {
+    /// Returns the levels of the walk from the PML4 down to the one the walk ended at
+    pub fn steps(&self) -> impl Iterator<Item = &WalkStep> {
+        self.steps.iter().flatten()
+    }
+
+    /// Returns the level whose entry is not present or `None` if the address is mapped
+    pub fn missing_level(&self) -> Option<PageTableLevel> {
+        self.steps()
+            .find(|step| !step.present)
+            .map(|step| step.level)
+    }
+}
+
+impl Display for WalkTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Walk of {}:", self.vaddr)?;
+        for step in self.steps() {
+            write!(
+                f,
+                "\n  {:?}[{}] in table {}: {:#018x}",
+                step.level, step.index, step.table, step.entry
+            )?;
+            if !step.present {
+                write!(f, " (not present)")?;
+            } else if step.huge {
+                write!(f, " (maps a page)")?;
+            }
+            if let Some(cache_type) = step.cache_type {
+                write!(f, " [{}]", cache_type)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// When a page map invalidates the TLB entries of the pages it changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[allow(unused)]
+pub enum TlbPolicy {
+    /// Every unmap or protection change invalidates the affected entries immediately, unless the
+    /// page map is not loaded on any LP in which case nothing is cached for it that could be used
+    /// before it is loaded again and the invalidation is deferred until then
+    #[default]
+    Eager,
+    /// Invalidations of a page map that is not loaded are deferred until it is next loaded, which
+    /// saves the cost of `invpcid` or CR3 reloads for address spaces that are modified in batches.
+    /// This is only sound because a page map that is loaded on the calling LP is always invalidated
+    /// eagerly, so this policy must not be used for page maps that are loaded on other LPs.
+    Lazy,
+}
+
+/// The access the kernel makes to a user buffer on behalf of user space, see
+/// [PageMap::validate_user_ptr]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(unused)]
+pub enum UserAccess {
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+pub struct PageMap {
+    cr3: u64,
+    tlb_policy: TlbPolicy,
+    /// Set when an invalidation was deferred until the page map is next loaded
+    flush_pending: AtomicBool,
+}
+
+impl PageMap {
+    const fn from_raw_cr3(cr3: u64) -> Self {
+        PageMap {
+            cr3,
+            tlb_policy: TlbPolicy::Eager,
+            flush_pending: AtomicBool::new(false),
+        }
+    }
+
+    pub fn try_new() -> Result<Self, Error> {
+        let pml4 = PHYSICAL_FRAME_ALLOCATOR.lock().allocate_zeroed_pinned()?;
+        if let Err(e) = open_account(pml4) {
+            PHYSICAL_FRAME_ALLOCATOR.lock().deallocate(pml4)?;
+            return Err(e);
+        }
+        Ok(PageMap::from_raw_cr3(pml4.bits() as u64))
+    }
+    /// Creates an empty address space that shares the kernel half of the currently loaded page map
+    /// so that the kernel stays mapped when it is loaded.
+    /// The higher half PML4 entries are copied, so the PDPTs they point to are shared with every
+    /// other address space created this way and are not owned by the new page map. They must never
+    /// be freed when it is destroyed.
+    #[allow(unused)]
+    pub fn new_with_kernel_mappings() -> Result<Self, Error> {
+        // SAFETY: reading CR3 has no side effects
+        let current = PageMap::from_cr3(unsafe { asm_get_cr3() })?;
+        let page_map = PageMap::try_new()?;
+        page_map.share_kernel_half(&current);
+        Ok(page_map)
+    }
+
+    /// Copies the higher half PML4 entries of `other` into this page map
+    fn share_kernel_half(&self, other: &PageMap) {
+        let source = <*const PageTable>::from(other.get_pml4_paddr());
+        let destination = <*mut PageTable>::from(self.get_pml4_paddr());
+        for index in layout::HIGHER_HALF_PML4_INDEX..page_table::N_PT_ENTRIES {
+            // SAFETY: both PML4s are valid and reachable through the direct map, the higher half
+            // entries are shared by every page map
+            unsafe { *(*destination).get_mut(index) = (*source).get(index) };
+        }
+    }
+
+    /// Creates a page map for the PML4 that `cr3` refers to, keeping its PCID
+    /// # Returns
+    /// Returns `Error::InvalidAddress` if the PML4 frame lies beyond the physical address width.
+    pub fn from_cr3(cr3: u64) -> Result<Self, Error> {
+        PhysicalAddress::try_new(cr3 & !0xFFF).ok_or(Error::InvalidAddress)?;
+        Ok(PageMap::from_raw_cr3(cr3))
+    }
+    /// Selects when changes to this page map invalidate the TLB, see [TlbPolicy]
+    #[allow(unused)]
+    pub fn set_tlb_policy(&mut self, policy: TlbPolicy) {
+        self.tlb_policy = policy;
+    }
+
+    #[allow(unused)]
+    pub fn tlb_policy(&self) -> TlbPolicy {
+        self.tlb_policy
+    }
+
+    /// Returns the number of standard pages mapped in the user half, where large and huge pages
+    /// count as the standard pages they span.
+    /// Pages reserved with [map_anonymous](Self::map_anonymous) only become resident when they are
+    /// first accessed, until then they are only committed, see
+    /// [committed_pages](Self::committed_pages).
+    /// The pages are counted for the address space rather than this value, so a page map obtained
+    /// with [from_cr3](Self::from_cr3) shares the count of the page map that created the PML4.
+    /// PML4s that were not created with [try_new](Self::try_new), like the bootloader's, have no
+    /// count.
+    #[allow(unused)]
+    pub fn resident_pages(&self) -> usize {
+        self.account()
+            .map_or(0, |account| account.resident_pages.load(Ordering::Acquire))
+    }
+
+    /// Returns the number of standard pages the user half is promised, which are the resident
+    /// pages along with the demand-zero pages that are reserved but have not been accessed yet.
+    /// This is what the memory limit applies to, so that backing a reserved page on its first
+    /// access never fails because of the limit.
+    #[allow(unused)]
+    pub fn committed_pages(&self) -> usize {
+        self.account()
+            .map_or(0, |account| account.committed_pages.load(Ordering::Acquire))
+    }
+
+    /// Limits the number of standard pages that may be committed in the user half, `None` removes
+    /// the limit. Mappings and reservations that exceed the limit fail with
+    /// `Error::MemoryLimitExceeded`, pages that are already committed are kept even if the new
+    /// limit is lower. Like the resident pages the limit
+    /// belongs to the address space, PML4s without a count are never limited.
+    #[allow(unused)]
+    pub fn set_memory_limit(&mut self, pages: Option<usize>) {
+        if let Some(account) = self.account() {
+            account
+                .memory_limit
+                .store(pages.unwrap_or(usize::MAX), Ordering::Release);
+        }
+    }
+
+    #[allow(unused)]
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.account()
+            .map(|account| account.memory_limit.load(Ordering::Acquire))
+            .filter(|&limit| limit != usize::MAX)
+    }
+
+    /// Returns the account of the address space this page map belongs to
+    fn account(&self) -> Option<&'static Account> {
+        find_account(self.get_pml4_paddr())
+    }
+
+    /// Accounts for a page of the given size that `map` maps at `vaddr` as committed and resident,
+    /// kernel pages are not counted since the kernel half is shared by every page map
+    /// # Returns
+    /// Returns `Error::MemoryLimitExceeded` without calling `map` if the page would exceed the
+    /// memory limit, otherwise the result of `map`. Nothing is accounted if `map` fails.
+    fn charged<T>(
+        &mut self,
+        vaddr: VirtualAddress,
+        size: page_table::PageSize,
+        map: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let account = match self.account() {
+            Some(account) if vaddr.is_user() => account,
+            _ => return map(self),
+        };
+        // the pages are counted before they are mapped so that LPs resolving faults in the same
+        // address space at the same time cannot exceed the limit together
+        account.commit(size.n_frames())?;
+        match map(self) {
+            Ok(mapped) => {
+                account.back(size.n_frames());
+                Ok(mapped)
+            }
+            Err(e) => {
+                account.uncommit(size.n_frames());
+                Err(e)
+            }
+        }
+    }
+
+    /// Accounts for the demand-zero page of the given size at `vaddr` that `back` backs as
+    /// resident. The page was committed when it was reserved so this never exceeds the memory
+    /// limit. Nothing is accounted if `back` fails.
+    fn backing_reservation(
+        &mut self,
+        vaddr: VirtualAddress,
+        size: page_table::PageSize,
+        back: impl FnOnce() -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        back()?;
+        if let Some(account) = self.account().filter(|_| vaddr.is_user()) {
+            account.back(size.n_frames());
+        }
+        Ok(())
+    }
+
+    /// Returns true if an invalidation has been deferred until this page map is next loaded
+    #[allow(unused)]
+    pub fn has_pending_flush(&self) -> bool {
+        self.flush_pending.load(Ordering::Acquire)
+    }
+
+    /// Records a deferred invalidation instead of performing it if the page map is not loaded on any
+    /// LP, or not on the calling LP under the lazy policy.
+    /// The pending flag is raised before the other LPs are checked so that an LP loading the page
+    /// map concurrently either is seen here or sees the flag and flushes on load. If the
+    /// invalidation cannot be deferred the flag stays raised, which only costs an extra flush.
+    /// # Returns
+    /// Returns true if the caller must not invalidate anything now.
+    fn defer_flush(&self) -> bool {
+        if self.is_active_on_current_cpu() {
+            return false;
+        }
+        self.flush_pending.store(true, Ordering::SeqCst);
+        self.tlb_policy == TlbPolicy::Lazy || !self.is_active_anywhere()
+    }
+
+    /// Invalidates the TLB entry of the page at `vaddr` according to the TLB policy
+    /// A single `invlpg` or individual-address `invpcid` covers a large or huge page as well.
+    fn invalidate_page(&self, vaddr: VirtualAddress) {
+        if self.defer_flush() {
+            return;
+        }
+        if self.is_active_on_current_cpu() {
+            invalidate_tlb_entry(vaddr);
+        } else {
+            self.flush_range(vaddr, PAGE_SIZE as usize);
+        }
+    }
+
+    /// Returns the address of the PML4. It is validated against the physical address width when
+    /// the page map is created, either by [from_cr3](Self::from_cr3) or by allocating it.
+    pub fn get_pml4_paddr(&self) -> PhysicalAddress {
+        debug_assert!(
+            PhysicalAddress::try_new(self.cr3 & !0xFFF).is_some(),
+            "CR3 {:#x} exceeds MAXPHYADDR",
+            self.cr3
+        );
+        PhysicalAddress::from(self.cr3 & !0xFFF)
+    }
+    pub fn get_pcid(&self) -> u16 {
+        (self.cr3 & 0xFFF) as u16
+    }
+    pub fn set_pcid(&mut self, pcid: u16) -> Result<(), Error> {
+        if self.get_pcid() != 0 {
+            Err(Error::AlredyHasPcid)
+        } else {
+            self.cr3 = (self.cr3 & !0xFFF) | pcid as u64;
+            Ok(())
+        }
+    }
+    /// Maps the physical range starting at `paddr` so that each page's virtual address is equal to
+    /// its physical address.
+    /// # Arguments
+    /// * `paddr` - The page aligned physical base address of the range
+    /// * `n_pages` - The number of pages in the range
+    /// * `flags` - The flags to apply to each page table entry
+    /// # Returns
+    /// Returns `Error::InvalidAddress` without mapping anything if any part of the range is not a
+    /// canonical virtual address.
+    #[allow(unused)]
+    pub fn identity_map(
+        &mut self,
+        paddr: PhysicalAddress,
+        n_pages: PageCount,
+        flags: u64,
+    ) -> Result<(), Error> {
+        if !paddr.is_page_aligned() {
+            return Err(Error::InvalidPAddrAlignment);
+        }
+        let n_pages = n_pages.get() as u64;
+        let last_page = paddr.bits() + (n_pages - 1) * PAGE_SIZE;
+        if !ArchApi::validate_vaddr(paddr.bits()) || !ArchApi::validate_vaddr(last_page) {
+            return Err(Error::InvalidAddress);
+        }
+        for frame in paddr.iter_frames(n_pages) {
+            let vaddr =
+                VirtualAddress::try_from(frame.bits()).map_err(|_| Error::InvalidAddress)?;
+            self.map_page(vaddr, frame, flags)?;
+        }
+        Ok(())
+    }
+
+    /// Loads this page map, runs `f` in its context and then reloads the page map that was active
+    /// beforehand. Writing CR3 in either direction flushes all non-global TLB entries belonging to
+    /// the outgoing address space.
+    /// # Safety
+    /// This page map must map the kernel, including the code and stack in use by the caller, at the
+    /// same addresses as the currently loaded page map.
+    #[allow(unused)]
+    pub unsafe fn with_loaded<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _guard = Cr3Guard {
+            // SAFETY: reading CR3 has no side effects
+            saved_cr3: unsafe { asm_get_cr3() },
+        };
+        announce_load(self.cr3);
+        // SAFETY: the caller guarantees that this page map maps the kernel like the current one
+        unsafe { write_cr3(self.cr3) }
+        record_pcid_owner(self.cr3);
+        count_tlb_flush(TlbFlush::Full);
+        self.flush_pending.store(false, Ordering::Release);
+        f()
+    }
+
+    /// Iterates over every present 4 KiB, 2 MiB and 1 GiB mapping in ascending virtual order
+    /// # Arguments
+    /// * `user_only` - Skip the kernel's higher half mappings
+    #[allow(unused)]
+    pub fn iter_mappings(&self, user_only: bool) -> MappingIter<'_> {
+        MappingIter {
+            tables: [
+                <*const PageTable>::from(self.get_pml4_paddr()),
+                core::ptr::null(),
+                core::ptr::null(),
+                core::ptr::null(),
+            ],
+            indices: [0; 4],
+            level: 0,
+            user_only,
+            _page_map: core::marker::PhantomData,
+        }
+    }
+
+    /// Computes the access rights the hardware grants to `vaddr` by combining the entries at every
+    /// level of the walk that translates it. Write and User are only granted if every level sets
+    /// them while NoExecute applies if any level sets it.
+    /// # Returns
+    /// Returns the kind of memory the effective permissions correspond to, see
+    /// [MemType::with_access], or `Error::EntryNotPresent` if `vaddr` is not mapped.
+    #[allow(unused)]
+    pub fn effective_permissions(&self, vaddr: VirtualAddress) -> Result<MemType, Error> {
+        let (mut writable, mut user, mut no_execute) = (true, true, false);
+        let mut table = <*const PageTable>::from(self.get_pml4_paddr());
+        let indices = [
+            vaddr.pml4_index(),
+            vaddr.pdpt_index(),
+            vaddr.pd_index(),
+            vaddr.pt_index(),
+        ];
+        for (level, index) in indices.into_iter().enumerate() {
+            // SAFETY: `table` is this page map's PML4 or a table one of its present entries points
+            // to
+            let entry = unsafe { (*table).get(index) };
+            if !entry.is_present() {
+                return Err(Error::EntryNotPresent);
+            }
+            writable &= entry.is_writable();
+            user &= entry.is_user();
+            no_execute |= entry.is_no_execute();
+            if level == 3 || (level != 0 && entry.is_huge()) {
+                return Ok(MemType::with_access(user, writable, !no_execute));
+            }
+            table = <*const PageTable>::from(entry.addr()?);
+        }
+        unreachable!()
+    }
+
+    /// Checks that the `len` bytes at the raw address `addr` received from user space lie in the
+    /// user half and may be accessed as `access` by user space, so that the kernel can safely use
+    /// them on its behalf. Pages that are reserved for demand-zero backing are accepted with the
+    /// flags they will be mapped with and copy-on-write pages count as writable, since accessing
+    /// either only faults them in.
+    /// # Returns
+    /// Returns `Error::NonCanonicalAddress` if `addr` is not canonical, `Error::NotUserAddress` if
+    /// the range reaches outside of the user half, `Error::NotMapped` if a page in it is not mapped
+    /// and `Error::InsufficientPermissions` if a page does not grant user space the access.
+    #[allow(unused)]
+    pub fn validate_user_ptr(
+        &self,
+        addr: u64,
+        len: usize,
+        access: UserAccess,
+    ) -> Result<(), Error> {
+        let start = VirtualAddress::try_from(addr).map_err(|_| Error::NonCanonicalAddress)?;
+        if !start.is_user() {
+            return Err(Error::NotUserAddress);
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        let last = addr
+            .checked_add(len as u64 - 1)
+            .filter(|last| *last < layout::USER.end)
+            .ok_or(Error::NotUserAddress)?;
+
+        let required = match access {
+            UserAccess::Read => PteFlags::User as u64,
+            UserAccess::Write => PteFlags::User as u64 | PteFlags::Write as u64,
+        };
+        for page in (start.get_page_base()..=last).step_by(PAGE_SIZE as usize) {
+            // every page up to the last one is in the user half as checked above
+            let page = VirtualAddress::try_from(page).unwrap();
+            let granted = match self.effective_permissions(page) {
+                Ok(mem_type) => match self.find_leaf(page) {
+                    Some((entry, _)) if entry.sw_bit(SwBit::CopyOnWrite) => {
+                        mem_type.flags() | PteFlags::Write as u64
+                    }
+                    _ => mem_type.flags(),
+                },
+                Err(_) => match self.find_leaf(page) {
+                    Some((entry, _)) if entry.is_demand_zero() => entry.demand_zero_flags(),
+                    _ => return Err(Error::NotMapped),
+                },
+            };
+            if granted & required != required {
+                return Err(Error::InsufficientPermissions);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the leaf entry that maps `vaddr` without allocating any tables
+    fn leaf_entry(&self, vaddr: VirtualAddress) -> Option<PageTableEntry> {
+        let mut table = <*const PageTable>::from(self.get_pml4_paddr());
+        let indices = [
+            vaddr.pml4_index(),
+            vaddr.pdpt_index(),
+            vaddr.pd_index(),
+            vaddr.pt_index(),
+        ];
+        for (level, index) in indices.into_iter().enumerate() {
+            // SAFETY: `table` is this page map's PML4 or a table one of its present entries points
+            // to
+            let entry = unsafe { (*table).get(index) };
+            if !entry.is_present() {
+                return None;
+            }
+            if level == 3 || (level != 0 && entry.is_size_bit_set()) {
+                return Some(entry);
+            }
+            table = <*const PageTable>::from(entry.addr().ok()?);
+        }
+        None
+    }
+
+    /// Returns the entries used to translate `vaddr`, from the PML4 entry down to the leaf.
+    /// The walk stops at the first entry that is not present or that maps a page, every level
+    /// below it is `None`. No tables are allocated.
+    pub fn walk_entries(&self, vaddr: VirtualAddress) -> [Option<PageTableEntry>; 4] {
+        let mut entries = [None; 4];
+        let mut table = <*const PageTable>::from(self.get_pml4_paddr());
+        let indices = [
+            vaddr.pml4_index(),
+            vaddr.pdpt_index(),
+            vaddr.pd_index(),
+            vaddr.pt_index(),
+        ];
+        for (level, index) in indices.into_iter().enumerate() {
+            // SAFETY: `table` is this page map's PML4 or a table one of its present entries points
+            // to
+            let entry = unsafe { (*table).get(index) };
+            entries[level] = Some(entry);
+            if !entry.is_present() || level == 3 || (level != 0 && entry.is_size_bit_set()) {
+                break;
+            }
+            match entry.addr() {
+                Ok(addr) => table = <*const PageTable>::from(addr),
+                Err(_) => break,
+            }
+        }
+        entries
+    }
+
+    /// Traces the translation of `vaddr` level by level for debugging. Only 4 level paging is used
+    /// so the walk starts at the PML4. The walk stops at the first entry that is not present or
+    /// that maps a large or huge page. No tables are allocated.
+    pub fn explain(&self, vaddr: VirtualAddress) -> WalkTrace {
+        let pat = PatLayout::current();
+        let mut steps = [None; 4];
+        let mut table = self.get_pml4_paddr();
+        let levels = [
+            PageTableLevel::PML4,
+            PageTableLevel::PDPT,
+            PageTableLevel::PD,
+            PageTableLevel::PT,
+        ];
+        for (step, level) in steps.iter_mut().zip(levels) {
+            let index = level.index_of(vaddr);
+            // SAFETY: `table` is the address of the PML4 or of a table a present entry points to,
+            // read through the direct map
+            let entry = unsafe { (*<*const PageTable>::from(table)).get(index) };
+            // bit 7 of a PML4 entry is reserved and that of a PT entry selects the PAT entry
+            let huge = matches!(level, PageTableLevel::PDPT | PageTableLevel::PD)
+                && entry.is_size_bit_set();
+            let size = match level {
+                PageTableLevel::PDPT if huge => Some(page_table::PageSize::Huge),
+                PageTableLevel::PD if huge => Some(page_table::PageSize::Large),
+                PageTableLevel::PT => Some(page_table::PageSize::Standard),
+                _ => None,
+            };
+            *step = Some(WalkStep {
+                level,
+                table,
+                index,
+                entry: entry.bits(),
+                present: entry.is_present(),
+                huge,
+                cache_type: size
+                    .filter(|_| entry.is_present())
+                    .and_then(|size| entry.cache_type(size, pat)),
+            });
+            if !entry.is_present() || huge {
+                break;
+            }
+            match entry.addr() {
+                Ok(addr) => table = addr,
+                Err(_) => break,
+            }
+        }
+        WalkTrace { vaddr, steps }
+    }
+
+    /// Maps a standard page at `vaddr` only if every intermediate table needed to reach it already
+    /// exists. Unlike [map_page](MemoryMap::map_page) this never allocates, which makes it suitable
+    /// for paths such as fault handling that must not have allocation side effects.
+    /// # Returns
+    /// Returns `Error::NotMapped` without modifying the page map if any table on the walk is missing.
+    #[allow(unused)]
+    pub fn try_map_existing(
+        &mut self,
+        vaddr: VirtualAddress,
+        paddr: PhysicalAddress,
+        flags: u64,
+    ) -> Result<(), Error> {
+        if !vaddr.is_aligned_to(PAGE_SIZE) {
+            return Err(Error::InvalidVAddrAlignment);
+        }
+        check_null_guard(vaddr)?;
+        let mut table = <*mut PageTable>::from(self.get_pml4_paddr());
+        for index in [vaddr.pml4_index(), vaddr.pdpt_index(), vaddr.pd_index()] {
+            // SAFETY: `table` is this page map's PML4 or a table one of its present entries points
+            // to
+            let entry = unsafe { (*table).get(index) };
+            if !entry.is_present() {
+                return Err(Error::NotMapped);
+            }
+            if entry.is_size_bit_set() {
+                return Err(Error::VAddrRangeUnavailable);
+            }
+            table = <*mut PageTable>::from(entry.addr()?);
+        }
+        // SAFETY: the walk above ended at the PT that maps `vaddr`
+        self.charged(vaddr, page_table::PageSize::Standard, |_| unsafe {
+            (*table).map_page(
+                page_table::PageSize::Standard,
+                vaddr.pt_index(),
+                paddr,
+                flags,
+            )
+        })
+    }
+
+    /// Allocates every table that is missing on the walk to the page table covering `vaddr` so
+    /// that standard pages in the surrounding 2 MiB can later be mapped with
+    /// [try_map_existing](PageMap::try_map_existing) without allocating.
+    pub fn prepare_tables(&mut self, vaddr: VirtualAddress) -> Result<(), Error> {
+        check_null_guard(vaddr)?;
+        let mut walker = Walker::new(self);
+        walker.walk_pd(vaddr, PteFlags::Present as u64 | PteFlags::Write as u64)
+    }
+
+    /// Replaces the flags of `n_pages` consecutive standard pages starting at `vaddr` without
+    /// changing the frames they map and invalidates their TLB entries.
+    /// If a page cannot be changed the pages before it keep their new flags and are still flushed.
+    /// # Returns
+    /// Returns `Error::EntryNotPresent` if any page in the range is not mapped and
+    /// `Error::OpNotSupportedAtThisLevel` if part of the range is mapped by a large or huge page.
+    pub fn protect(
+        &mut self,
+        vaddr: VirtualAddress,
+        n_pages: PageCount,
+        flags: u64,
+    ) -> Result<(), Error> {
+        if !vaddr.is_aligned_to(PAGE_SIZE) {
+            return Err(Error::InvalidVAddrAlignment);
+        }
+        let mut result = Ok(());
+        let mut n_changed = 0;
+        for i in 0..n_pages.get() {
+            match self.set_leaf_flags(vaddr + i * PAGE_SIZE as usize, flags) {
+                Ok(()) => n_changed += 1,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.flush_range(vaddr, n_changed * PAGE_SIZE as usize);
+        result
+    }
+
+    /// Creates a copy of this address space that shares the kernel half and every user page with
+    /// it. Writable user pages are write protected in both page maps and marked copy-on-write so
+    /// that whichever side writes first gets a private copy in
+    /// [resolve_cow_fault](Self::resolve_cow_fault). Read-only pages and MMIO are mapped into the
+    /// child as they are. The child takes a reference to every frame this page map owns, see
+    /// [share_frame](crate::memory::pmm::PhysicalFrameAllocator::share_frame), so that a frame is
+    /// only freed once neither side maps it any more.
+    /// This page map is only write protected once the child is complete, if the child cannot be
+    /// built its tables are freed and its references released again, leaving this page map as it
+    /// was.
+    /// # Returns
+    /// Returns `Error::UnsupportedOperation` without modifying this page map if a writable large or
+    /// huge page is mapped in the user half since those cannot be copied on write.
+    #[allow(unused)]
+    pub fn fork(&mut self) -> Result<PageMap, Error> {
+        let is_owned = |mapping: &Mapping| {
+            mapping.flags & (PteFlags::CcMmio as u64 | PteFlags::CcShared as u64) == 0
+        };
+        let is_cow_candidate =
+            |mapping: &Mapping| is_owned(mapping) && mapping.flags & PteFlags::Write as u64 != 0;
+        let cow_flags = |mapping: &Mapping| {
+            (mapping.flags & !(PteFlags::Write as u64)) | PteFlags::CcCopyOnWrite as u64
+        };
+        if self.iter_mappings(true).any(|mapping| {
+            mapping.size != page_table::PageSize::Standard && is_cow_candidate(&mapping)
+        }) {
+            return Err(Error::UnsupportedOperation);
+        }
+
+        let mut child = PageMap::try_new()?;
+        child.set_memory_limit(self.memory_limit());
+        child.share_kernel_half(self);
+        let child_pml4 = <*mut PageTable>::from(child.get_pml4_paddr());
+
+        let parent = PageMap::from_raw_cr3(self.cr3);
+        let mut result = Ok(());
+        for mapping in parent.iter_mappings(true) {
+            debug_assert!(mapping.vaddr.is_user());
+            let flags = if is_cow_candidate(&mapping) {
+                cow_flags(&mapping)
+            } else {
+                mapping.flags
+            };
+            if is_owned(&mapping) {
+                if let Err(e) = PHYSICAL_FRAME_ALLOCATOR.lock().share_frame(mapping.paddr) {
+                    result = Err(e.into());
+                    break;
+                }
+            }
+            // SAFETY: the child was created above and is not visible to anything else yet
+            if let Err(e) = unsafe { map_leaf(child_pml4, &mapping, flags, USER_TABLE_FLAGS) } {
+                if is_owned(&mapping) {
+                    // this page map still holds the frame so this only drops the new reference
+                    let _ = PHYSICAL_FRAME_ALLOCATOR.lock().release_frame(mapping.paddr);
+                }
+                result = Err(e);
+                break;
+            }
+            if let Some(account) = child.account() {
+                account
+                    .committed_pages
+                    .fetch_add(mapping.size.n_frames(), Ordering::AcqRel);
+                account.back(mapping.size.n_frames());
+            }
+        }
+        // write protecting the pages shared with a complete child cannot leave this page map in an
+        // inconsistent state even if it stops part way, a copy-on-write page whose frame is not
+        // shared any more is simply made writable again on its next write
+        if result.is_ok() {
+            for mapping in parent.iter_mappings(true) {
+                if is_cow_candidate(&mapping) {
+                    if let Err(e) = self.set_leaf_flags(mapping.vaddr, cow_flags(&mapping)) {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            // writable pages in this address space have just been write protected
+            self.flush_pcid();
+        }
+        match result {
+            Ok(()) => Ok(child),
+            Err(e) => {
+                // the child has never been loaded
+                let _ = child.destroy();
+                Err(e)
+            }
+        }
+    }
+
+    /// Tears down an address space created with [try_new](Self::try_new) or [fork](Self::fork).
+    /// Every user page is unmapped, releasing its reference to its frame, the user half tables and
+    /// the PML4 are freed and the account of the address space is closed. The kernel half tables
+    /// are shared by every page map and are left alone.
+    /// # Returns
+    /// Returns `Error::MapInUse` without changing anything if the page map is loaded on some LP.
+    #[allow(unused)]
+    pub fn destroy(mut self) -> Result<(), Error> {
+        if self.is_active_anywhere() {
+            return Err(Error::MapInUse);
+        }
+        let view = PageMap::from_raw_cr3(self.cr3);
+        for mapping in view.iter_mappings(true) {
+            let _ = self.clear_leaf(mapping.vaddr, mapping.size);
+        }
+        // SAFETY: the PML4 belongs to this page map, which is not loaded on any LP
+        unsafe {
+            Self::clear_reservations_below(
+                <*mut PageTable>::from(self.get_pml4_paddr()),
+                PageTableLevel::PML4,
+                layout::HIGHER_HALF_PML4_INDEX,
+            )
+        };
+        self.prune_empty_tables()?;
+        close_account(self.get_pml4_paddr());
+        PHYSICAL_FRAME_ALLOCATOR
+            .lock()
+            .deallocate(self.get_pml4_paddr())?;
+        Ok(())
+    }
+
+    /// Rebuilds the currently loaded page map, which is normally the one inherited from the
+    /// bootloader, into a new kernel owned page map and loads it.
+    /// Only the higher half is carried over, the lower half belongs to user space according to the
+    /// kernel layout so the bootloader's identity map is dropped. Every mapping keeps its frame and
+    /// cache type but is made global and supervisor only. Mappings in the kernel image keep their
+    /// write permission and stay executable only if they are not writable, all other mappings are
+    /// never executable. The frames are marked shared since the new page map does not own them.
+    /// The tables of the previous page map are not freed.
+    #[allow(unused)]
+    pub fn rebuild_from_current() -> Result<PageMap, Error> {
+        // SAFETY: reading CR3 has no side effects
+        let current = PageMap::from_cr3(unsafe { asm_get_cr3() })?;
+        let rebuilt = PageMap::try_new()?;
+        let pml4 = <*mut PageTable>::from(rebuilt.get_pml4_paddr());
+
+        let table_flags = PteFlags::Present as u64 | PteFlags::Write as u64;
+        for mapping in current.iter_mappings(false) {
+            if !mapping.vaddr.is_kernel() {
+                continue;
+            }
+            let pat = match mapping.size {
+                page_table::PageSize::Standard => PteFlags::PageSizeOrPat as u64,
+                _ => PteFlags::HugeAndLargePat as u64,
+            };
+            let kept = PteFlags::Write as u64
+                | PteFlags::WriteThrough as u64
+                | PteFlags::CacheDisable as u64
+                | PteFlags::CcMmio as u64
+                | pat;
+            let mut flags = PteFlags::Present as u64
+                | PteFlags::Global as u64
+                | PteFlags::CcShared as u64
+                | (mapping.flags & kept);
+            let executable = layout::KERNEL_IMAGE.contains(mapping.vaddr.bits())
+                && mapping.flags & (PteFlags::NoExecute as u64 | PteFlags::Write as u64) == 0;
+            if !executable {
+                flags |= PteFlags::NoExecute as u64;
+            }
+            // SAFETY: `pml4` belongs to the page map being built, which is not loaded anywhere yet
+            unsafe { map_leaf(pml4, &mapping, flags, table_flags)? };
+        }
+
+        // SAFETY: the rebuilt page map maps the kernel image, stacks and direct map like the
+        // current one
+        unsafe { rebuilt.load()? };
+        Ok(rebuilt)
+    }
+
+    /// Gives the copy-on-write page at `vaddr` a private writable copy of its frame and drops its
+    /// reference to the shared frame. If no other page map references the frame any more the page
+    /// takes it over and is made writable without copying it.
+    /// # Returns
+    /// Returns `Error::InvalidAddress` if `vaddr` is not a user address and
+    /// `Error::InvalidArgument` if the page at `vaddr` is not a copy-on-write page.
+    pub fn resolve_cow_fault(&mut self, vaddr: VirtualAddress) -> Result<(), Error> {
+        // kernel pages are never copy-on-write
+        if !vaddr.is_user() {
+            return Err(Error::InvalidAddress);
+        }
+        let page = VirtualAddress::try_from(vaddr.get_page_base()).unwrap();
+        let mut table = <*mut PageTable>::from(self.get_pml4_paddr());
+        for index in [page.pml4_index(), page.pdpt_index(), page.pd_index()] {
+            // SAFETY: `table` is this page map's PML4 or a table one of its present entries points
+            // to
+            let entry = unsafe { (*table).get(index) };
+            if !entry.is_present() {
+                return Err(Error::NotMapped);
+            }
+            if entry.is_huge() {
+                return Err(Error::InvalidArgument);
+            }
+            table = <*mut PageTable>::from(entry.addr()?);
+        }
+        // SAFETY: the walk above ended at the PT that maps `page`
+        let entry = unsafe { (*table).get_mut(page.pt_index()) };
+        if !entry.is_present() {
+            return Err(Error::NotMapped);
+        }
+        let flags = entry.flags(page_table::PageSize::Standard);
+        if flags & PteFlags::CcCopyOnWrite as u64 == 0 {
+            return Err(Error::InvalidArgument);
+        }
+
+        let shared = entry.addr()?;
+        let private_flags = (flags & !(PteFlags::CcCopyOnWrite as u64)) | PteFlags::Write as u64;
+        let mut pmm = lock_pmm_for_fault()?;
+        if pmm.sharers(shared) == 0 {
+            // the last page map referencing the frame takes it over
+            drop(pmm);
+            entry.set_page_flags(private_flags)?;
+        } else {
+            let frame = pmm.allocate()?;
+            // SAFETY: both frames are reachable through the direct map and the new one is not
+            // mapped anywhere else yet
+            unsafe {
+                <*mut u8>::from(frame)
+                    .copy_from_nonoverlapping(<*const u8>::from(shared), PAGE_SIZE as usize);
+            }
+            let mut private = PageTableEntry::new();
+            if let Err(e) = private.map_page(frame, private_flags, page_table::PageSize::Standard) {
+                pmm.deallocate(frame)?;
+                return Err(e);
+            }
+            // another page map still references the frame so this only drops a reference
+            pmm.release_frame(shared)?;
+            drop(pmm);
+            *entry = private;
+        }
+        self.flush_range(page, PAGE_SIZE as usize);
+        Ok(())
+    }
+
+    /// Reserves `n_pages` standard pages starting at `vaddr` in the user half to be backed by
+    /// zeroed frames on their first access, see [resolve_demand_zero_fault](Self::resolve_demand_zero_fault).
+    /// Every 2 MiB aligned 2 MiB block in the range is reserved as a whole if large pages are
+    /// supported so that it can be backed by a single large page. Anonymous memory always uses one
+    /// of the first four PAT entries, so the PAT bits are ignored in `flags`.
+    /// The reserved pages are committed right away, so the memory limit is enforced here rather
+    /// than when the pages are first accessed. If a page cannot be reserved, the pages reserved by
+    /// this call are released again along with the user half tables in the range that reserve
+    /// nothing afterwards, unless the page map is loaded on some LP, and nothing stays committed.
+    /// # Returns
+    /// Returns `Error::InvalidAddress` if the range is not in the user half,
+    /// `Error::VAddrRangeUnavailable` if any page in it is mapped or reserved already and
+    /// `Error::MemoryLimitExceeded` if the pages would exceed the memory limit.
+    #[allow(unused)]
+    pub fn map_anonymous(
+        &mut self,
+        vaddr: VirtualAddress,
+        n_pages: PageCount,
+        flags: u64,
+    ) -> Result<(), Error> {
+        if !vaddr.is_aligned_to(PAGE_SIZE) {
+            return Err(Error::InvalidVAddrAlignment);
+        }
+        let end = (n_pages.get() as u64)
+            .checked_mul(PAGE_SIZE)
+            .and_then(|size| vaddr.bits().checked_add(size))
+            .ok_or(Error::InvalidAddress)?;
+        if !vaddr.is_user() || end > layout::USER.end {
+            return Err(Error::InvalidAddress);
+        }
+        if !self.is_range_available(vaddr, n_pages) {
+            return Err(Error::VAddrRangeUnavailable);
+        }
+        let account = self.account();
+        if let Some(account) = account {
+            account.commit(n_pages.get())?;
+        }
+
+        let flags = flags
+            & !(PteFlags::PageSizeOrPat as u64
+                | PteFlags::HugeAndLargePat as u64
+                | PteFlags::CcShared as u64);
+        let pml4 = <*mut PageTable>::from(self.get_pml4_paddr());
+        let size_at = |page: VirtualAddress| {
+            if *ARE_LARGE_PAGES_SUPPORTED
+                && page.is_aligned_to(LARGE_PAGE_SIZE)
+                && end - page.bits() >= LARGE_PAGE_SIZE
+            {
+                page_table::PageSize::Large
+            } else {
+                page_table::PageSize::Standard
+            }
+        };
+        let mut page = vaddr;
+        while page.bits() < end {
+            let size = size_at(page);
+            // SAFETY: the PML4 belongs to this page map, which is borrowed mutably
+            let reserved =
+                unsafe { leaf_table(pml4, page, size, USER_TABLE_FLAGS) }.and_then(|table| {
+                    // SAFETY: leaf_table returned a valid table of this page map
+                    let entry = unsafe { (*table).get_mut(leaf_index(page, size)) };
+                    match size {
+                        // the size bit marks the reservation as a large page, the MMU ignores it
+                        // since the entry is not present
+                        page_table::PageSize::Large => {
+                            entry.set_demand_zero(flags | PteFlags::PageSizeOrPat as u64)
+                        }
+                        _ => entry.set_demand_zero(flags),
+                    }
+                });
+            if let Err(e) = reserved {
+                let mut reserved_page = vaddr;
+                while reserved_page.bits() < page.bits() {
+                    let size = size_at(reserved_page);
+                    // SAFETY: the tables on the walk to each page reserved above are still
+                    // installed, so leaf_table only walks them
+                    if let Ok(table) = unsafe { leaf_table(pml4, reserved_page, size, 0) } {
+                        // SAFETY: leaf_table returned a valid table of this page map
+                        unsafe {
+                            *(*table).get_mut(leaf_index(reserved_page, size)) =
+                                PageTableEntry::new()
+                        };
+                    }
+                    reserved_page = reserved_page + size.n_frames() * PAGE_SIZE as usize;
+                }
+                if let Some(account) = account {
+                    account.uncommit(n_pages.get());
+                }
+                if self.prune_range(vaddr, n_pages.get()) {
+                    self.flush_pcid();
+                }
+                return Err(e);
+            }
+            page = page + size.n_frames() * PAGE_SIZE as usize;
+        }
+        Ok(())
+    }
+
+    /// Backs the demand-zero page at `vaddr` with a newly allocated zeroed frame, mapped with the
+    /// flags that were reserved for it. Nothing has to be invalidated since the TLB never caches
+    /// entries that are not present.
+    /// A page reserved as part of a large page is backed by a zeroed 2 MiB block if the PMM has
+    /// one. Otherwise the reservation is split into standard pages and only the page at `vaddr` is
+    /// backed. The pages were committed when they were reserved, so backing them is never refused
+    /// because of the memory limit.
+    /// # Returns
+    /// Returns `Error::InvalidAddress` if `vaddr` is not a user address and
+    /// `Error::InvalidArgument` if the page at `vaddr` is not a demand-zero page.
+    pub fn resolve_demand_zero_fault(&mut self, vaddr: VirtualAddress) -> Result<(), Error> {
+        // kernel pages are never demand-zero
+        if !vaddr.is_user() {
+            return Err(Error::InvalidAddress);
+        }
+        let page = VirtualAddress::try_from(vaddr.get_page_base()).unwrap();
+        let mut table = <*mut PageTable>::from(self.get_pml4_paddr());
+        for index in [page.pml4_index(), page.pdpt_index()] {
+            // SAFETY: `table` is this page map's PML4 or a table one of its present entries points
+            // to
+            let entry = unsafe { (*table).get(index) };
+            if !entry.is_present() {
+                return Err(Error::NotMapped);
+            }
+            if entry.is_huge() {
+                return Err(Error::InvalidArgument);
+            }
+            table = <*mut PageTable>::from(entry.addr()?);
+        }
+        // SAFETY: the walk above ended at the PD that maps `page`
+        let pd_entry = unsafe { (*table).get(page.pd_index()) };
+        if pd_entry.is_demand_zero() && pd_entry.is_size_bit_set() {
+            let large_page =
+                VirtualAddress::try_from(page.bits() & !(LARGE_PAGE_SIZE - 1)).unwrap();
+            match self.back_large_demand_zero(table, large_page, pd_entry) {
+                Ok(()) => return Ok(()),
+                Err(Error::PmmError(_)) => {
+                    self.split_demand_zero(table, page.pd_index(), pd_entry)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        // SAFETY: `table` is still the PD that maps `page`, splitting a reservation only replaces
+        // its entry
+        let pd_entry = unsafe { (*table).get(page.pd_index()) };
+        if !pd_entry.is_present() {
+            return Err(Error::NotMapped);
+        }
+        if pd_entry.is_huge() {
+            return Err(Error::InvalidArgument);
+        }
+        let table = <*mut PageTable>::from(pd_entry.addr()?);
+        // SAFETY: the PD entry points to a PT as checked above
+        let entry = unsafe { (*table).get(page.pt_index()) };
+        if !entry.is_demand_zero() {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.backing_reservation(page, page_table::PageSize::Standard, || {
+            let frame = lock_pmm_for_fault()?.allocate_zeroed()?;
+            let mut backed = PageTableEntry::new();
+            backed.map_page(
+                frame,
+                entry.demand_zero_flags(),
+                page_table::PageSize::Standard,
+            )?;
+            // another LP may have backed the page in the meantime
+            // SAFETY: `table` is the PT that maps `page`, the entry is only replaced atomically
+            if unsafe { (*table).compare_exchange(page.pt_index(), entry.bits(), backed.bits()) }
+                .is_err()
+            {
+                lock_pmm_for_fault()?.deallocate(frame)?;
+                return Err(Error::AddressInUse);
+            }
+            Ok(())
+        })
+    }
+
+    /// Backs the large demand-zero reservation `reserved` in the page directory `pd` with a zeroed
+    /// 2 MiB block
+    fn back_large_demand_zero(
+        &mut self,
+        pd: *mut PageTable,
+        large_page: VirtualAddress,
+        reserved: PageTableEntry,
+    ) -> Result<(), Error> {
+        let n_frames = page_table::PageSize::Large.n_frames() as u64;
+        self.backing_reservation(large_page, page_table::PageSize::Large, || {
+            let block =
+                lock_pmm_for_fault()?.allocate_contiguous_zeroed(n_frames, LARGE_PAGE_SIZE)?;
+            let mut backed = PageTableEntry::new();
+            backed.map_page(
+                block,
+                reserved.demand_zero_flags(),
+                page_table::PageSize::Large,
+            )?;
+            // SAFETY: `pd` is the PD that holds the reservation, the entry is only replaced
+            // atomically
+            if unsafe {
+                (*pd).compare_exchange(large_page.pd_index(), reserved.bits(), backed.bits())
+            }
+            .is_err()
+            {
+                lock_pmm_for_fault()?.deallocate_contiguous(block, n_frames)?;
+                return Err(Error::AddressInUse);
+            }
+            Ok(())
+        })
+    }
+
+    /// Replaces the large demand-zero reservation `reserved` at `index` of the page directory `pd`
+    /// with a page table of standard demand-zero reservations with the same flags. If another LP
+    /// changed the entry first the new table is freed and its change is kept.
+    fn split_demand_zero(
+        &mut self,
+        pd: *mut PageTable,
+        index: usize,
+        reserved: PageTableEntry,
+    ) -> Result<(), Error> {
+        let table_paddr = lock_pmm_for_fault()?.allocate_zeroed_pinned()?;
+        let table = <*mut PageTable>::from(table_paddr);
+        let flags = reserved.demand_zero_flags() & !(PteFlags::PageSizeOrPat as u64);
+        for i in 0..page_table::N_PT_ENTRIES {
+            // SAFETY: the table was just allocated and is not reachable from any page map yet
+            unsafe { (*table).get_mut(i).set_demand_zero(flags)? };
+        }
+        let mut split = PageTableEntry::new();
+        split.map_table(table_paddr, USER_TABLE_FLAGS)?;
+        // SAFETY: `pd` is the PD that holds the reservation, the entry is only replaced atomically
+        if unsafe { (*pd).compare_exchange(index, reserved.bits(), split.bits()) }.is_err() {
+            lock_pmm_for_fault()?.deallocate(table_paddr)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the demand-zero reservation of the standard page at `vaddr`, splitting a large
+    /// reservation that contains it first
+    /// # Returns
+    /// Returns true if a reservation was removed.
+    fn clear_reservation(&mut self, vaddr: VirtualAddress) -> bool {
+        let mut table = <*mut PageTable>::from(self.get_pml4_paddr());
+        for index in [vaddr.pml4_index(), vaddr.pdpt_index()] {
+            // SAFETY: `table` is this page map's PML4 or a table one of its present entries points
+            // to
+            let entry = unsafe { (*table).get(index) };
+            if !entry.is_present() || entry.is_huge() {
+                return false;
+            }
+            let Ok(paddr) = entry.addr() else {
+                return false;
+            };
+            table = <*mut PageTable>::from(paddr);
+        }
+        // SAFETY: the walk above ended at the PD that maps `vaddr`
+        let pd_entry = unsafe { (*table).get(vaddr.pd_index()) };
+        if pd_entry.is_demand_zero()
+            && pd_entry.is_size_bit_set()
+            && self
+                .split_demand_zero(table, vaddr.pd_index(), pd_entry)
+                .is_err()
+        {
+            return false;
+        }
+        // SAFETY: `table` is still the PD that maps `vaddr`, splitting a reservation only replaces
+        // its entry
+        let pd_entry = unsafe { (*table).get(vaddr.pd_index()) };
+        if !pd_entry.is_present() || pd_entry.is_huge() {
+            return false;
+        }
+        let Ok(paddr) = pd_entry.addr() else {
+            return false;
+        };
+        // SAFETY: the PD entry points to a PT as checked above
+        let entry = unsafe { (*<*mut PageTable>::from(paddr)).get_mut(vaddr.pt_index()) };
+        if entry.is_demand_zero() {
+            *entry = PageTableEntry::new();
+            if let Some(account) = self.account() {
+                account.uncommit(1);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns the entry that maps or reserves the page containing `vaddr` and the size of that
+    /// page, whether or not it is present, without allocating any tables
+    /// # Returns
+    /// Returns `None` if a table on the walk to the page is missing.
+    fn find_leaf(&self, vaddr: VirtualAddress) -> Option<(PageTableEntry, page_table::PageSize)> {
+        let mut table = <*const PageTable>::from(self.get_pml4_paddr());
+        let levels = [
+            (vaddr.pml4_index(), None),
+            (vaddr.pdpt_index(), Some(page_table::PageSize::Huge)),
+            (vaddr.pd_index(), Some(page_table::PageSize::Large)),
+        ];
+        for (index, size) in levels {
+            // SAFETY: `table` is this page map's PML4 or a table one of its present entries points
+            // to
+            let entry = unsafe { (*table).get(index) };
+            // a reservation of a whole large page is marked by its size bit like a large page
+            if let Some(size) = size.filter(|_| entry.is_huge()) {
+                if entry.is_present() || entry.is_demand_zero() {
+                    return Some((entry, size));
+                }
+            }
+            if !entry.is_present() {
+                return None;
+            }
+            table = <*const PageTable>::from(entry.addr().ok()?);
+        }
+        Some((
+            // SAFETY: the walk above ended at the PT that maps `vaddr`
+            unsafe { (*table).get(vaddr.pt_index()) },
+            page_table::PageSize::Standard,
+        ))
+    }
+
+    /// Returns the size of the page that maps `vaddr`, found with a walk that only reads the tables
+    /// # Returns
+    /// Returns `Error::NotMapped` if no present page maps `vaddr`, including pages that are only
+    /// reserved as demand-zero.
+    #[allow(unused)]
+    pub fn mapping_size(&self, vaddr: VirtualAddress) -> Result<page_table::PageSize, Error> {
+        match self.find_leaf(vaddr) {
+            Some((entry, size)) if entry.is_present() => Ok(size),
+            _ => Err(Error::NotMapped),
+        }
+    }
+
+    /// Translates `vaddr` to the physical address it is mapped to, found with a walk that only
+    /// reads the tables
+    /// # Returns
+    /// Returns `Error::NotMapped` if no present page maps `vaddr`.
+    pub fn translate(&self, vaddr: VirtualAddress) -> Result<PhysicalAddress, Error> {
+        match self.find_leaf(vaddr) {
+            Some((entry, size)) if entry.is_present() => {
+                let page_mask = size.n_frames() as u64 * PAGE_SIZE - 1;
+                Ok(entry.physical_address(size)? + (vaddr.bits() & page_mask))
+            }
+            _ => Err(Error::NotMapped),
+        }
+    }
+
+    /// Unmaps the page that maps `vaddr` with whichever of [MemoryMap::unmap_page],
+    /// [MemoryMap::unmap_large_page] and [MemoryMap::unmap_huge_page] matches its size as reported
+    /// by [mapping_size](Self::mapping_size), so callers cannot tear down a mapping with the wrong
+    /// variant
+    /// # Returns
+    /// Returns the base of the page's backing along with its size, `Error::NotMapped` if no
+    /// present page maps `vaddr` and `Error::InvalidVAddrAlignment` if `vaddr` is not the base of
+    /// that page.
+    #[allow(unused)]
+    pub fn unmap(
+        &mut self,
+        vaddr: VirtualAddress,
+    ) -> Result<(PhysicalAddress, page_table::PageSize), Error> {
+        let size = self.mapping_size(vaddr)?;
+        if vaddr.bits() & (size.n_frames() as u64 * PAGE_SIZE - 1) != 0 {
+            return Err(Error::InvalidVAddrAlignment);
+        }
+        let (paddr, _) = match size {
+            page_table::PageSize::Standard => self.unmap_page(vaddr)?,
+            page_table::PageSize::Large => self.unmap_large_page(vaddr)?,
+            page_table::PageSize::Huge => self.unmap_huge_page(vaddr)?,
+        };
+        Ok((paddr, size))
+    }
+
+    /// Backs every page in the `size` bytes starting at `vaddr` with a private frame now so that
+    /// accessing the range never faults. Demand-zero pages get a zeroed frame and copy-on-write
+    /// pages are copied, pages that are already backed privately are left alone.
+    /// # Returns
+    /// Returns `Error::NotMapped` if a page in the range is neither mapped nor reserved, the pages
+    /// before it stay populated.
+    #[allow(unused)]
+    pub fn populate(&mut self, vaddr: VirtualAddress, size: usize) -> Result<(), Error> {
+        let end = vaddr.bits() + size as u64;
+        let mut page = vaddr.get_page_base();
+        while page < end {
+            let page_vaddr = VirtualAddress::try_from(page).map_err(|_| Error::InvalidAddress)?;
+            let (entry, page_size) = self.find_leaf(page_vaddr).ok_or(Error::NotMapped)?;
+            if entry.is_demand_zero() {
+                match self.resolve_demand_zero_fault(page_vaddr) {
+                    // an LP that accessed the page in the meantime backed it
+                    Ok(()) | Err(Error::AddressInUse) => {}
+                    Err(e) => return Err(e),
+                }
+                // a large reservation may have been backed by standard pages after all
+                continue;
+            } else if !entry.is_present() {
+                return Err(Error::NotMapped);
+            } else if entry.sw_bit(SwBit::CopyOnWrite) {
+                self.resolve_cow_fault(page_vaddr)?;
+            }
+            let page_bytes = (page_size.n_frames() as u64) * PAGE_SIZE;
+            page = (page & !(page_bytes - 1)) + page_bytes;
+        }
+        Ok(())
+    }
+
+    /// Replaces the flags of the standard page mapped at `page` without invalidating its TLB entry
+    fn set_leaf_flags(&mut self, page: VirtualAddress, flags: u64) -> Result<(), Error> {
+        let mut table = <*mut PageTable>::from(self.get_pml4_paddr());
+        for index in [page.pml4_index(), page.pdpt_index(), page.pd_index()] {
+            // SAFETY: `table` is this page map's PML4 or a table one of its present entries points
+            // to
+            let entry = unsafe { (*table).get(index) };
+            if !entry.is_present() {
+                return Err(Error::EntryNotPresent);
+            }
+            if entry.is_size_bit_set() {
+                return Err(Error::OpNotSupportedAtThisLevel);
+            }
+            table = <*mut PageTable>::from(entry.addr()?);
+        }
+        // SAFETY: the walk above ended at the PT that maps `page`
+        unsafe { (*table).get_mut(page.pt_index()).set_page_flags(flags) }
+    }
+
+    /// Checks that none of the `n_pages` pages starting at `vaddr` is mapped
+    /// Ranges that wrap around the end of the address space or leave the canonical range are
+    /// never available.
+    #[allow(unused)]
+    pub fn is_range_available(&self, vaddr: VirtualAddress, n_pages: PageCount) -> bool {
+        self.first_unavailable(vaddr, n_pages).is_none()
+    }
+
+    /// Returns the address of the first page of the `n_pages` pages starting at `vaddr` that is
+    /// mapped, reserved or not canonical, or `None` if the whole range is available.
+    /// Whenever an entry on the way to a page is not present, the rest of the range it would
+    /// translate is skipped at once, so a range in an empty 512 GiB PML4 slot costs a single read.
+    fn first_unavailable(&self, vaddr: VirtualAddress, n_pages: PageCount) -> Option<u64> {
+        let Some(last) = ((n_pages.get() - 1) as u64)
+            .checked_mul(PAGE_SIZE)
+            .and_then(|offset| vaddr.bits().checked_add(offset))
+        else {
+            return Some(vaddr.bits());
+        };
+        let mut addr = vaddr.bits();
+        while addr <= last {
+            let Ok(page) = VirtualAddress::try_from(addr) else {
+                return Some(addr);
+            };
+            let Some(span) = self.free_span(page) else {
+                return Some(addr);
+            };
+            // the free span reaches the end of the address space if this overflows, so it covers
+            // the rest
+            addr = addr.checked_add(span)?;
+        }
+        None
+    }
+
+    /// Returns the number of bytes from `vaddr` to the end of the region that the first entry not
+    /// present on the walk to `vaddr` would translate, all of which is unmapped, or `None` if the
+    /// page at `vaddr` is mapped or reserved for a demand-zero page.
+    fn free_span(&self, vaddr: VirtualAddress) -> Option<u64> {
+        let mut table = <*const PageTable>::from(self.get_pml4_paddr());
+        let levels = [
+            PageTableLevel::PML4,
+            PageTableLevel::PDPT,
+            PageTableLevel::PD,
+            PageTableLevel::PT,
+        ];
+        for (level, shift) in levels.into_iter().zip([39, 30, 21, 12]) {
+            // SAFETY: `table` is this page map's PML4 or a table one of its present entries points
+            // to
+            let entry = unsafe { (*table).get(level.index_of(vaddr)) };
+            if !entry.is_present() {
+                if entry.is_demand_zero() {
+                    return None;
+                }
+                let slot_size = 1u64 << shift;
+                return Some(slot_size - (vaddr.bits() & (slot_size - 1)));
+            }
+            if level == PageTableLevel::PT
+                || (level != PageTableLevel::PML4 && entry.is_size_bit_set())
+            {
+                return None;
+            }
+            table = <*const PageTable>::from(entry.addr().ok()?);
+        }
+        None
+    }
+
+    /// Finds the lowest `alignment` aligned region of `n_pages` pages within `[start, end)` that has no
+    /// pages mapped in it. `start` is itself the first candidate if it is already aligned.
+    /// Every candidate is checked to end at or below `end` before it is probed and the search
+    /// stops as soon as advancing to the next candidate would overflow. A candidate that overlaps a
+    /// page in use is followed by the first aligned candidate past that page rather than the next
+    /// aligned one, so occupied stretches are skipped without probing each candidate in them.
+    /// Candidates that overlap one of the [layout::GUARDS] between the regions of the higher half
+    /// are skipped as well even though nothing is mapped there.
+    /// # Returns
+    /// Returns `Error::InvalidArgument` if `alignment` is not a power of two multiple of the page size
+    /// and `Error::VAddrRangeUnavailable` if no such region exists.
+    #[allow(unused)]
+    pub fn find_available_region(
+        &self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+        n_pages: PageCount,
+        alignment: u64,
+    ) -> Result<VirtualAddress, Error> {
+        if !alignment.is_power_of_two() || alignment < PAGE_SIZE {
+            return Err(Error::InvalidArgument);
+        }
+        let mut candidate = start.aligned_after(alignment).map(|vaddr| vaddr.bits());
+        while let Some(base) = candidate {
+            let region_end = match base.checked_add(n_pages.bytes() as u64) {
+                Some(region_end) if region_end <= end.bits() => region_end,
+                _ => break,
+            };
+            if let Some(guard) = layout::GUARDS
+                .iter()
+                .find(|guard| guard.overlaps(&layout::Region::new(base, region_end)))
+            {
+                candidate = guard
+                    .end
+                    .checked_add(alignment - 1)
+                    .map(|next| next & !(alignment - 1));
+                continue;
+            }
+            candidate = match VirtualAddress::try_from(base) {
+                Ok(vaddr) => match self.first_unavailable(vaddr, n_pages) {
+                    None => return Ok(vaddr),
+                    Some(taken) => taken
+                        .checked_add(PAGE_SIZE + alignment - 1)
+                        .map(|next| next & !(alignment - 1)),
+                },
+                Err(_) => base.checked_add(alignment),
+            };
+        }
+        Err(Error::VAddrRangeUnavailable)
+    }
+
+    /// Maps `n_pages` newly allocated, zeroed frames at exactly `vaddr` rather than at an address
+    /// chosen by [find_available_region](PageMap::find_available_region). This is intended for
+    /// loaders that have to place memory at a fixed address, such as an ELF segment's `p_vaddr`.
+    /// Pages that are already mapped in the range are replaced or cause the request to fail
+    /// depending on `mode`. `hint` selects how the frames are allocated, see [RegionHint].
+    /// If a page cannot be mapped, the pages mapped by this call are unmapped and their frames freed
+    /// again along with the user half tables in the range that map nothing afterwards, unless the
+    /// page map is loaded on some LP. Pages unmapped by [FixedMapping::Replace] are not restored.
+    /// # Returns
+    /// Returns `Error::AddressInUse` without modifying the page map if `mode` is
+    /// [FixedMapping::NoReplace] and any page in the range is mapped, and `Error::InvalidAddress`
+    /// if the range overlaps the null guard or is not entirely canonical.
+    #[allow(unused)]
+    pub fn map_region_fixed(
+        &mut self,
+        vaddr: VirtualAddress,
+        n_pages: PageCount,
+        flags: u64,
+        mode: FixedMapping,
+        hint: RegionHint,
+    ) -> Result<(), Error> {
+        if !vaddr.is_aligned_to(PAGE_SIZE) {
+            return Err(Error::InvalidVAddrAlignment);
+        }
+        check_null_guard(vaddr)?;
+        let last_page = ((n_pages.get() - 1) as u64)
+            .checked_mul(PAGE_SIZE)
+            .and_then(|offset| vaddr.bits().checked_add(offset))
+            .ok_or(Error::InvalidAddress)?;
+        if !ArchApi::validate_vaddr(last_page) {
+            return Err(Error::InvalidAddress);
+        }
+
+        let pages = || (0..n_pages.get()).map(|i| vaddr + i * PAGE_SIZE as usize);
+        match mode {
+            FixedMapping::NoReplace => {
+                if !self.is_range_available(vaddr, n_pages) {
+                    return Err(Error::AddressInUse);
+                }
+            }
+            FixedMapping::Replace => {
+                for page in pages() {
+                    if self.leaf_entry(page).is_some() {
+                        self.clear_leaf(page, page_table::PageSize::Standard)?;
+                    }
+                }
+                self.flush_range(vaddr, n_pages.bytes());
+            }
+        }
+
+        let (dma_base, flags) = match hint {
+            RegionHint::DmaCoherent => {
+                let base = PHYSICAL_FRAME_ALLOCATOR
+                    .lock()
+                    .allocate_contiguous_zeroed(n_pages.get() as u64, PAGE_SIZE)?;
+                // PAT entry 3 is uncacheable, bit 7 of a standard page's entry is its PAT bit
+                let flags =
+                    (flags & !(PteFlags::PageSizeOrPat as u64)) | CacheType::Uncacheable.flags();
+                (Some(base), flags)
+            }
+            _ => (None, flags),
+        };
+
+        let mut n_mapped = 0;
+        let mut result = Ok(());
+        for (i, page) in pages().enumerate() {
+            let frame = match dma_base {
+                Some(base) => Ok(base + i as u64 * PAGE_SIZE),
+                None => Self::allocate_backing_frame(page, hint),
+            };
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            if let Err(e) = self.map_page(page, frame, flags) {
+                PHYSICAL_FRAME_ALLOCATOR.lock().deallocate(frame)?;
+                result = Err(e);
+                break;
+            }
+            n_mapped += 1;
+        }
+        if result.is_err() {
+            for page in pages().take(n_mapped) {
+                self.clear_leaf(page, page_table::PageSize::Standard)?;
+            }
+            if self.prune_range(vaddr, n_pages.get()) {
+                self.flush_pcid();
+            } else {
+                self.flush_range(vaddr, n_mapped * PAGE_SIZE as usize);
+            }
+            // the frames of a contiguous block after the one that failed to map were never mapped
+            let n_unused = n_pages.get().saturating_sub(n_mapped + 1);
+            if let (Some(base), true) = (dma_base, n_unused > 0) {
+                let first_unused = base + (n_mapped + 1) as u64 * PAGE_SIZE;
+                PHYSICAL_FRAME_ALLOCATOR
+                    .lock()
+                    .deallocate_contiguous(first_unused, n_unused as u64)?;
+            }
+        }
+        result
+    }
+
+    /// Maps the frames of `buf` into the lowest free range of the user half, uncacheable and with
+    /// the access `mem_type` grants, and returns the address of the first page.
+    /// The pages are marked as device memory like MMIO, so unmapping them never frees the frames,
+    /// which belong to `buf`. The caller has to unmap them before `buf` is dropped.
+    /// # Returns
+    /// Returns `Error::VAddrRangeUnavailable` if the user half has no free range large enough.
+    #[allow(unused)]
+    pub fn map_dma_buffer(
+        &mut self,
+        buf: &DmaBuffer,
+        mem_type: MemType,
+    ) -> Result<VirtualAddress, Error> {
+        let n_pages = buf.n_pages();
+        let vaddr = self.find_available_region(
+            VirtualAddress::try_from(layout::USER.start).map_err(|_| Error::InvalidAddress)?,
+            VirtualAddress::try_from(layout::USER.end - 1).map_err(|_| Error::InvalidAddress)?,
+            n_pages,
+            PAGE_SIZE,
+        )?;
+        // user pages are never global and the uncacheable PAT entry replaces the default one
+        let flags = (mem_type.flags_with_cache(CacheType::Uncacheable)
+            & !(PteFlags::Global as u64))
+            | PteFlags::User as u64
+            | PteFlags::CcMmio as u64;
+
+        for i in 0..n_pages.get() {
+            let page = vaddr + i * PAGE_SIZE as usize;
+            if let Err(e) = self.map_page(page, buf.paddr() + i as u64 * PAGE_SIZE, flags) {
+                for mapped in (0..i).map(|j| vaddr + j * PAGE_SIZE as usize) {
+                    self.clear_leaf(mapped, page_table::PageSize::Standard)?;
+                }
+                self.flush_range(vaddr, i * PAGE_SIZE as usize);
+                return Err(e);
+            }
+        }
+        Ok(vaddr)
+    }
+
+    /// Allocates a zeroed frame to back `page` in the way `hint` asks for
+    fn allocate_backing_frame(
+        page: VirtualAddress,
+        hint: RegionHint,
+    ) -> Result<PhysicalAddress, Error> {
+        let mut pmm = PHYSICAL_FRAME_ALLOCATOR.lock();
+        let frame = match hint {
+            RegionHint::ReadMostly => {
+                let color = page.page_number() % pmm.n_colors();
+                pmm.allocate_colored(color).or_else(|_| pmm.allocate())?
+            }
+            _ => pmm.allocate()?,
+        };
+        drop(pmm);
+        // SAFETY: the frame was just allocated and is reachable through the direct map
+        unsafe { <*mut u8>::from(frame).write_bytes(0, PAGE_SIZE as usize) };
+        Ok(frame)
+    }
+
+    /// Maps every `PT_LOAD` segment of `elf` at the address it was linked at and returns the
+    /// entry point. Each page is backed by a newly allocated frame that is filled with the
+    /// segment's file bytes and zeroed beyond them, so the BSS needs no further initialization.
+    /// Executable segments are mapped as [MemType::KernelCode], writable ones as
+    /// [MemType::KernelReadWrite] and all others as [MemType::KernelReadOnly].
+    /// Once every segment is loaded the instruction stream of the calling LP is synchronized with
+    /// the new code.
+    /// Segments may not share pages with each other or with existing mappings. If any segment
+    /// cannot be loaded, every page mapped by this call is unmapped and freed again.
+    /// # Returns
+    /// Returns `Error::ElfError` if a segment is malformed or both writable and executable,
+    /// `Error::AddressInUse` if a segment overlaps a page that is already mapped and
+    /// `Error::UnsupportedOperation` if the image has more than 16 non-empty loadable segments.
+    #[allow(unused)]
+    pub fn load_elf(&mut self, elf: &Elf64) -> Result<VirtualAddress, Error> {
+        self.load_image(elf, 0, None)
+    }
+
+    /// Loads a position independent image like [PageMap::load_elf] but adds `bias` to every link
+    /// time address, so an image linked at 0 ends up at `bias`, and applies the entries of its
+    /// `DT_RELA` and `DT_JMPREL` relocation tables to the loaded pages. Relative relocations store
+    /// `bias` plus their addend, GOT and PLT relocations store the address of their symbol, which
+    /// is looked up by name with `resolve` if the image does not define it.
+    /// Relocations are written through the direct map, so they may target read only segments, but
+    /// only the pages loaded by this call.
+    /// # Returns
+    /// Returns `Error::InvalidArgument` if `bias` is not page aligned, `Error::ElfError` if a
+    /// relocation is of an unsupported type or refers to a symbol that cannot be resolved and
+    /// `Error::InvalidAddress` if a relocation targets memory outside the loaded segments, along
+    /// with the errors of [PageMap::load_elf]. Nothing stays mapped if this fails.
+    #[allow(unused)]
+    pub fn load_relocatable_elf(
+        &mut self,
+        elf: &Elf64,
+        bias: VirtualAddress,
+        resolve: &mut dyn FnMut(&str) -> Option<u64>,
+    ) -> Result<VirtualAddress, Error> {
+        if bias.bits() & (PAGE_SIZE - 1) != 0 {
+            return Err(Error::InvalidArgument);
+        }
+        self.load_image(elf, bias.bits(), Some(resolve))
+    }
+
+    /// Loads every segment of `elf` `bias` bytes above its link time address and relocates the
+    /// image if a resolver is given
+    fn load_image(
+        &mut self,
+        elf: &Elf64,
+        bias: u64,
+        resolve: SymbolResolver,
+    ) -> Result<VirtualAddress, Error> {
+        let entry = elf
+            .entry()
+            .checked_add(bias)
+            .and_then(|entry| VirtualAddress::try_from(entry).ok())
+            .ok_or(Error::InvalidAddress)?;
+        let mut loaded: [Option<(VirtualAddress, usize)>; 16] = [None; 16];
+        let mut result = Ok(entry);
+        for (i, segment) in elf.load_segments().filter(|s| s.memsz != 0).enumerate() {
+            if i == loaded.len() {
+                result = Err(Error::UnsupportedOperation);
+                break;
+            }
+            match self.load_segment(elf, &segment, bias) {
+                Ok(range) => loaded[i] = Some(range),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if let (Ok(_), Some(resolve)) = (&result, resolve) {
+            if let Err(e) = self.apply_relocations(elf, bias, &loaded, resolve) {
+                result = Err(e);
+            }
         }
+        if result.is_err() {
+            for (base, n_pages) in loaded.into_iter().flatten() {
+                for page in (0..n_pages).map(|i| base + i * PAGE_SIZE as usize) {
+                    self.clear_leaf(page, page_table::PageSize::Standard)?;
+                }
+                self.flush_range(base, n_pages * PAGE_SIZE as usize);
+            }
+        } else {
+            for (base, n_pages) in loaded.into_iter().flatten() {
+                ArchApi::sync_instruction_cache(base, n_pages * PAGE_SIZE as usize);
+            }
+        }
+        result
     }
-    fn walk_cr3(&mut self) -> Result<(), Error> {
-        unsafe {
-            self.pml4 = Some(&mut *(<*mut PageTable>::from(self.page_map.get_pml4_paddr())));
+
+    /// Applies the dynamic relocations of `elf` to the segments in `loaded`, which were loaded
+    /// `bias` bytes above their link time addresses
+    fn apply_relocations(
+        &self,
+        elf: &Elf64,
+        bias: u64,
+        loaded: &[Option<(VirtualAddress, usize)>],
+        resolve: &mut dyn FnMut(&str) -> Option<u64>,
+    ) -> Result<(), Error> {
+        let relocations = elf.relocations()?;
+        for rela in relocations.entries() {
+            let Some(value) = relocations.value(&rela, bias, resolve)? else {
+                continue;
+            };
+            let target = rela.offset.checked_add(bias).ok_or(Error::InvalidAddress)?;
+            // the target may straddle a page boundary, so every byte is translated on its own
+            for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+                let vaddr = target
+                    .checked_add(i as u64)
+                    .and_then(|vaddr| VirtualAddress::try_from(vaddr).ok())
+                    .ok_or(Error::InvalidAddress)?;
+                let in_image = loaded.iter().flatten().any(|(base, n_pages)| {
+                    (base.bits()..base.bits() + (*n_pages as u64) * PAGE_SIZE)
+                        .contains(&vaddr.bits())
+                });
+                if !in_image {
+                    return Err(Error::InvalidAddress);
+                }
+                let paddr = self.translate(vaddr)?;
+                // SAFETY: the page is mapped to `paddr` as part of the image being loaded, which is
+                // reachable through the direct map
+                unsafe { <*mut u8>::from(paddr).write(byte) };
+            }
         }
         Ok(())
     }
-    fn walk_pml4(&mut self, vaddr: VirtualAddress, flags: u64) -> Result<(), Error> {
-        match &mut self.pml4 {
-            Some(pml4) => {
+
+    /// Maps a single loadable segment `bias` bytes above its link time address, returning the base
+    /// and number of pages it occupies
+    /// Nothing stays mapped if this fails.
+    fn load_segment(
+        &mut self,
+        elf: &Elf64,
+        segment: &ProgramHeader,
+        bias: u64,
+    ) -> Result<(VirtualAddress, usize), Error> {
+        let data = elf.segment_data(segment)?;
+        let mem_type = match (segment.is_writable(), segment.is_executable()) {
+            (true, true) => return Err(ElfError::WritableAndExecutable.into()),
+            (false, true) => MemType::KernelCode,
+            (true, false) => MemType::KernelReadWrite,
+            (false, false) => MemType::KernelReadOnly,
+        };
+        let seg_start = segment
+            .vaddr
+            .checked_add(bias)
+            .ok_or(Error::InvalidAddress)?;
+        let seg_end = seg_start
+            .checked_add(segment.memsz)
+            .ok_or(Error::InvalidAddress)?;
+        let first_page = seg_start & !(PAGE_SIZE - 1);
+        let n_pages = (seg_end - first_page).div_ceil(PAGE_SIZE) as usize;
+        let base = VirtualAddress::try_from(first_page).map_err(|_| Error::InvalidAddress)?;
+        let page_count = PageCount::new(n_pages).ok_or(Error::InvalidArgument)?;
+        if !self.is_range_available(base, page_count) {
+            return Err(Error::AddressInUse);
+        }
+
+        let file_end = seg_start + data.len() as u64;
+        let mut n_mapped = 0;
+        let mut result = Ok((base, n_pages));
+        for page in (0..n_pages).map(|i| base + i * PAGE_SIZE as usize) {
+            let frame = match PHYSICAL_FRAME_ALLOCATOR.lock().allocate_zeroed() {
+                Ok(frame) => frame,
+                Err(e) => {
+                    result = Err(e.into());
+                    break;
+                }
+            };
+            // copy the part of the file bytes that falls within this page
+            let copy_start = page.bits().max(seg_start);
+            let copy_end = (page.bits() + PAGE_SIZE).min(file_end);
+            if copy_start < copy_end {
+                let src = &data[(copy_start - seg_start) as usize..(copy_end - seg_start) as usize];
+                // SAFETY: the frame backs `page` and the copy stays within it, `src` is part of the
+                // image which does not overlap it
                 unsafe {
-                    let pml4_ptr = addr_of_mut!(*pml4);
-                    self.pdpt = Some(
-                        &mut *((*pml4_ptr).get_or_map_table(
-                            vaddr,
-                            page_table::PageTableLevel::PML4,
-                            flags,
-                        )?),
-                    );
+                    let dst = <*mut u8>::from(frame).add((copy_start - page.bits()) as usize);
+                    dst.copy_from_nonoverlapping(src.as_ptr(), src.len());
                 }
-                Ok(())
             }
-            None => {
-                self.walk_cr3()?;
-                self.walk_pml4(vaddr, flags)
+            if let Err(e) = self.map_page(page, frame, mem_type.flags()) {
+                PHYSICAL_FRAME_ALLOCATOR.lock().deallocate(frame)?;
+                result = Err(e);
+                break;
+            }
+            n_mapped += 1;
+        }
+        if result.is_err() {
+            for page in (0..n_mapped).map(|i| base + i * PAGE_SIZE as usize) {
+                self.clear_leaf(page, page_table::PageSize::Standard)?;
             }
+            self.flush_range(base, n_mapped * PAGE_SIZE as usize);
         }
+        result
     }
 
-    fn walk_pdpt(&mut self, vaddr: VirtualAddress, flags: u64) -> Result<(), Error> {
-        match &mut self.pdpt {
-            Some(pdpt) => {
-                unsafe {
-                    let pdpt_ptr = addr_of_mut!(*pdpt);
-                    self.pd = Some(
-                        &mut *((*pdpt_ptr).get_or_map_table(
-                            vaddr,
-                            page_table::PageTableLevel::PDPT,
-                            flags,
-                        )?),
-                    );
+    /// Builds the descriptor used by `invpcid` to invalidate this page map's PCID
+    /// The linear address field is only used by the individual-address invalidation type.
+    fn invpcid_descriptor(&self, vaddr: VirtualAddress) -> [u64; 2] {
+        [self.get_pcid() as u64, vaddr.bits()]
+    }
+
+    /// Checks whether this page map is the one currently loaded in CR3 on the calling LP. Only the
+    /// PML4 address is compared, the PCID and the cache control bits in CR3 are ignored.
+    pub fn is_active_on_current_cpu(&self) -> bool {
+        // SAFETY: reading CR3 has no side effects
+        PhysicalAddress::from(unsafe { asm_get_cr3() } & !0xFFF) == self.get_pml4_paddr()
+    }
+
+    /// Checks whether this page map is loaded, or about to be loaded, on any LP as far as the LPs
+    /// have announced it, see [announce_load]
+    pub fn is_active_anywhere(&self) -> bool {
+        let pml4 = self.get_pml4_paddr().bits();
+        self.is_active_on_current_cpu()
+            || LOADED_PML4S
+                .iter()
+                .any(|slot| slot.load(Ordering::SeqCst) == pml4)
+    }
+
+    /// Flushes every TLB entry belonging to this address space.
+    /// If the page map has a PCID a single-context `invpcid` is used where it is supported. Without
+    /// `invpcid` the page map is loaded into CR3 with the no-flush bit clear, which invalidates all
+    /// entries tagged with its PCID, and the previous page map is restored without flushing its own
+    /// entries. Without a PCID, CR3 is reloaded if this page map is the one currently loaded. When
+    /// PCIDs are not in use the TLB cannot hold any entries for an address space that is not loaded
+    /// so there is nothing to flush in that case. Under [TlbPolicy::Lazy] the flush of a page map
+    /// that is not loaded is deferred until it is loaded, the same applies to [flush_range](Self::flush_range).
+    pub fn flush_pcid(&self) {
+        if self.defer_flush() {
+            return;
+        }
+        if self.get_pcid() != 0 && *IS_INVPCID_SUPPORTED {
+            let descriptor = self.invpcid_descriptor(VirtualAddress::new());
+            // SAFETY: invpcid is supported and the descriptor is valid, it only drops TLB entries
+            unsafe {
+                asm! {
+                    "invpcid {kind}, [{descriptor}]",
+                    kind = in(reg) 1u64,
+                    descriptor = in(reg) descriptor.as_ptr(),
                 }
-                Ok(())
             }
-            None => {
-                logln!("Walking PML4");
-                self.walk_pml4(vaddr, flags)?;
-                self.walk_pdpt(vaddr, flags)
+            count_tlb_flush(TlbFlush::Pcid);
+        } else if self.get_pcid() != 0 {
+            // SAFETY: reading CR3 has no side effects
+            let saved_cr3 = unsafe { asm_get_cr3() };
+            announce_load(self.cr3);
+            // SAFETY: this page map maps the kernel like every page map, it is only loaded to drop
+            // the entries of its PCID
+            unsafe { write_cr3(self.cr3) }
+            record_pcid_owner(self.cr3);
+            count_tlb_flush(TlbFlush::Pcid);
+            if PhysicalAddress::from(saved_cr3 & !0xFFF) != self.get_pml4_paddr() {
+                announce_load(saved_cr3);
+                // SAFETY: the previous page map was loaded when this was called
+                unsafe { write_cr3(saved_cr3 | CR3_NO_FLUSH) }
             }
+        } else if self.is_active_on_current_cpu() {
+            // SAFETY: reloading the current CR3 only flushes the TLB
+            unsafe { write_cr3(asm_get_cr3()) }
+            count_tlb_flush(TlbFlush::Full);
         }
     }
 
-    fn walk_pd(&mut self, vaddr: VirtualAddress, flags: u64) -> Result<(), Error> {
-        match &mut self.pd {
-            Some(pd) => {
+    /// Invalidates the TLB entries for every page overlapping the `size` bytes starting at `start`.
+    /// Above [tlb_flush_threshold] pages the whole address space is flushed instead. Pages of the
+    /// loaded page map are invalidated with `invlpg` while pages of a page map with a PCID that is
+    /// not loaded are invalidated with an individual-address `invpcid`.
+    pub fn flush_range(&self, start: VirtualAddress, size: usize) {
+        if self.defer_flush() {
+            return;
+        }
+        let base = VirtualAddress::try_from(start.get_page_base()).unwrap();
+        let n_pages = ((start.get_page_offset() + size) as u64).div_ceil(PAGE_SIZE) as usize;
+        if n_pages > tlb_flush_threshold() {
+            self.flush_pcid();
+        } else if self.is_active_on_current_cpu() {
+            for i in 0..n_pages {
+                invalidate_tlb_entry(base + i * PAGE_SIZE as usize);
+            }
+        } else if self.get_pcid() != 0 && !*IS_INVPCID_SUPPORTED {
+            self.flush_pcid();
+        } else if self.get_pcid() != 0 {
+            for i in 0..n_pages {
+                let descriptor = self.invpcid_descriptor(base + i * PAGE_SIZE as usize);
+                // SAFETY: invpcid is supported and the descriptor is valid, it only drops TLB
+                // entries
                 unsafe {
-                    let pd_ptr = addr_of_mut!(*pd);
-                    logln!("Obtained PD pointer: {:p}", pd_ptr);
-                    self.pd = Some(
-                        &mut *((*pd_ptr).get_or_map_table(
-                            vaddr,
-                            page_table::PageTableLevel::PD,
-                            flags,
-                        )?),
-                    );
-                    logln!("Obtained or Mapped PD table.");
+                    asm! {
+                        "invpcid {kind}, [{descriptor}]",
+                        kind = in(reg) 0u64,
+                        descriptor = in(reg) descriptor.as_ptr(),
+                    }
                 }
-                Ok(())
-            }
-            None => {
-                logln!("Walking PDPT");
-                self.walk_pdpt(vaddr, flags)?;
-                self.walk_pd(vaddr, flags)
+                count_tlb_flush(TlbFlush::SinglePage);
             }
         }
     }
-}
 
-#[repr(transparent)]
-#[derive(Debug)]
-pub struct PageMap {
-    cr3: u64,
-}
+    /// Unmaps `n_pages` consecutive standard pages starting at `vaddr` and invalidates their TLB
+    /// entries. Above [tlb_flush_threshold] pages the whole address space is flushed instead of
+    /// invalidating each page individually.
+    /// Unless `keep_tables` is set the user half tables that no longer map anything afterwards are
+    /// freed. Keeping them makes mapping the same range again cheap when it is mapped and unmapped
+    /// repeatedly, [prune_empty_tables](Self::prune_empty_tables) frees them later on.
+    /// Tables are only freed if the page map is not loaded on any LP, since the paging-structure
+    /// caches of such an LP could still reference them, otherwise they are kept as if
+    /// `keep_tables` was set. If any were freed the whole address space is flushed after they
+    /// were, so that no cached reference to them survives.
+    /// If a page cannot be unmapped the pages before it stay unmapped and are still flushed.
+    #[allow(unused)]
+    pub fn unmap_range(
+        &mut self,
+        vaddr: VirtualAddress,
+        n_pages: PageCount,
+        keep_tables: bool,
+    ) -> Result<(), Error> {
+        let mut result = Ok(());
+        let mut n_cleared = 0;
+        let mut n_removed = 0;
+        for i in 0..n_pages.get() {
+            let page = vaddr + i * PAGE_SIZE as usize;
+            match self.clear_leaf(page, page_table::PageSize::Standard) {
+                Ok(_) => n_cleared += 1,
+                // pages that were reserved but never backed are not in the TLB
+                Err(Error::NotMapped) if self.clear_reservation(page) => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+            n_removed = i + 1;
+        }
+        let pruned = !keep_tables && n_removed > 0 && self.prune_range(vaddr, n_removed);
+        if pruned {
+            self.flush_pcid();
+        } else {
+            self.flush_range(vaddr, n_cleared * PAGE_SIZE as usize);
+        }
+        result
+    }
 
-impl PageMap {
-    pub fn try_new() -> Result<Self, Error> {
-        Ok(PageMap {
-            cr3: PHYSICAL_FRAME_ALLOCATOR.lock().allocate()?.bits() as u64,
-        })
+    /// Frees the user half tables on the walks to the `n_pages` pages starting at `vaddr` that map
+    /// nothing, unless the page map is loaded on some LP. The caller has to flush the address space
+    /// afterwards if any were freed.
+    /// # Returns
+    /// Returns true if any table was freed.
+    fn prune_range(&mut self, vaddr: VirtualAddress, n_pages: usize) -> bool {
+        let pml4_paddr = self.get_pml4_paddr();
+        while PRUNING_PML4
+            .compare_exchange(0, pml4_paddr.bits(), Ordering::SeqCst, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        let mut pruned = false;
+        if !self.is_active_anywhere() {
+            // every page table covers 2 MiB so it is enough to prune one walk per 2 MiB
+            let start = vaddr.bits() & !(LARGE_PAGE_SIZE - 1);
+            let end = vaddr.bits() + n_pages as u64 * PAGE_SIZE;
+            for base in (start..end).step_by(LARGE_PAGE_SIZE as usize) {
+                if let Ok(base) = VirtualAddress::try_from(base) {
+                    pruned |= self.prune_walk(base);
+                }
+            }
+        }
+        PRUNING_PML4.store(0, Ordering::SeqCst);
+        pruned
     }
-    pub fn from_cr3(cr3: u64) -> Result<Self, Error> {
-        // clear the PCID bits
-        //cr3 &= !0xFFF;
 
-        if ArchApi::validate_paddr(cr3 as usize) == false {
-            Err(Error::InvalidAddress)
+    /// Frees every table in the user half that maps nothing, such as those left in place by
+    /// [unmap_range](Self::unmap_range) with `keep_tables` set, and flushes the address space.
+    /// Tables of the kernel half are shared by every page map and are never freed.
+    /// The page map must not be loaded on any LP, or be about to be loaded, while its tables are
+    /// freed since the paging-structure caches of that LP could still reference them. Loads wait
+    /// for a prune of the same page map to finish, see [announce_load].
+    /// # Returns
+    /// Returns the number of tables that were freed or `Error::MapInUse` without freeing any if the
+    /// page map is loaded on some LP.
+    #[allow(unused)]
+    pub fn prune_empty_tables(&mut self) -> Result<usize, Error> {
+        let pml4_paddr = self.get_pml4_paddr();
+        while PRUNING_PML4
+            .compare_exchange(0, pml4_paddr.bits(), Ordering::SeqCst, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        let in_use = self.is_active_anywhere();
+        let freed = if in_use {
+            None
         } else {
-            Ok(PageMap { cr3: cr3 })
+            let pml4 = <*mut PageTable>::from(pml4_paddr);
+            // SAFETY: no LP has the page map loaded and the claim on it keeps any LP from loading
+            // it
+            Some(unsafe {
+                Self::prune_below(pml4, PageTableLevel::PML4, layout::HIGHER_HALF_PML4_INDEX)
+            })
+        };
+        PRUNING_PML4.store(0, Ordering::SeqCst);
+
+        let freed = freed.ok_or(Error::MapInUse)?;
+        if freed > 0 {
+            self.flush_pcid();
         }
+        Ok(freed)
     }
-    pub fn get_pml4_paddr(&self) -> PhysicalAddress {
-        PhysicalAddress::from(self.cr3 & !0xFFF)
+
+    /// Frees every empty table referenced by the first `n_entries` entries of `table`, which is at
+    /// `level`, after pruning the tables below each of them.
+    /// # Returns
+    /// Returns the number of tables that were freed.
+    unsafe fn prune_below(table: *mut PageTable, level: PageTableLevel, n_entries: usize) -> usize {
+        let child_level = match level {
+            PageTableLevel::PML4 => PageTableLevel::PDPT,
+            PageTableLevel::PDPT => PageTableLevel::PD,
+            PageTableLevel::PD => PageTableLevel::PT,
+            PageTableLevel::PT => return 0,
+        };
+        let mut freed = 0;
+        for index in 0..n_entries {
+            let entry = (*table).get(index);
+            if !entry.is_present() || (level != PageTableLevel::PML4 && entry.is_size_bit_set()) {
+                continue;
+            }
+            let Ok(child_paddr) = entry.addr() else {
+                continue;
+            };
+            let child = <*mut PageTable>::from(child_paddr);
+            freed += Self::prune_below(child, child_level, page_table::N_PT_ENTRIES);
+            if (*child).is_empty() && (*table).unmap_table(index).is_ok() {
+                freed += 1;
+            }
+        }
+        freed
     }
-    pub fn get_pcid(&self) -> u16 {
-        (self.cr3 & 0xFFF) as u16
+
+    /// Removes every demand-zero reservation in the first `n_entries` entries of `table`, which is
+    /// at `level`, and in the tables below them. Nothing is uncommitted.
+    unsafe fn clear_reservations_below(
+        table: *mut PageTable,
+        level: PageTableLevel,
+        n_entries: usize,
+    ) {
+        let child_level = match level {
+            PageTableLevel::PML4 => Some(PageTableLevel::PDPT),
+            PageTableLevel::PDPT => Some(PageTableLevel::PD),
+            PageTableLevel::PD => Some(PageTableLevel::PT),
+            PageTableLevel::PT => None,
+        };
+        for index in 0..n_entries {
+            let entry = (*table).get(index);
+            if entry.is_demand_zero() {
+                *(*table).get_mut(index) = PageTableEntry::new();
+                continue;
+            }
+            if !entry.is_present() || (level != PageTableLevel::PML4 && entry.is_size_bit_set()) {
+                continue;
+            }
+            if let (Some(child_level), Ok(child)) = (child_level, entry.addr()) {
+                Self::clear_reservations_below(
+                    <*mut PageTable>::from(child),
+                    child_level,
+                    page_table::N_PT_ENTRIES,
+                );
+            }
+        }
     }
-    pub fn set_pcid(&mut self, pcid: u16) -> Result<(), Error> {
-        if self.get_pcid() != 0 {
-            Err(Error::AlredyHasPcid)
-        } else {
-            self.cr3 = (self.cr3 & !0xFFF) | pcid as u64;
-            Ok(())
+
+    /// Frees the tables on the walk to `vaddr` that map nothing, deepest first, stopping at the
+    /// first table that is still in use. The PML4 and the tables of the kernel half are never freed.
+    /// # Returns
+    /// Returns true if any table was freed.
+    fn prune_walk(&mut self, vaddr: VirtualAddress) -> bool {
+        if vaddr.pml4_index() >= layout::HIGHER_HALF_PML4_INDEX {
+            return false;
+        }
+        let levels = [
+            PageTableLevel::PML4,
+            PageTableLevel::PDPT,
+            PageTableLevel::PD,
+        ];
+        // the parent table of each table on the walk
+        let mut parents = [core::ptr::null_mut::<PageTable>(); 3];
+        let mut depth = 0;
+        let mut table = <*mut PageTable>::from(self.get_pml4_paddr());
+        for (parent, level) in parents.iter_mut().zip(levels) {
+            // SAFETY: `table` is this page map's PML4 or a table one of its present entries points
+            // to
+            let entry = unsafe { (*table).get(level.index_of(vaddr)) };
+            if !entry.is_present() || (level != PageTableLevel::PML4 && entry.is_size_bit_set()) {
+                break;
+            }
+            let Ok(child) = entry.addr() else {
+                break;
+            };
+            *parent = table;
+            table = <*mut PageTable>::from(child);
+            depth += 1;
+        }
+        let mut pruned = false;
+        for (parent, level) in parents.into_iter().zip(levels).take(depth).rev() {
+            let index = level.index_of(vaddr);
+            // SAFETY: each parent is a table on the walk above and the entries below it were only
+            // freed by this loop
+            unsafe {
+                let Ok(child) = (*parent).get(index).addr() else {
+                    break;
+                };
+                if !(*<*const PageTable>::from(child)).is_empty()
+                    || (*parent).unmap_table(index).is_err()
+                {
+                    break;
+                }
+            }
+            pruned = true;
         }
+        pruned
     }
-    fn invalidate_pcid(&self) {
-        let mut pcid = [0u64; 2];
-        pcid[0] = self.get_pcid() as u64;
-        unsafe {
-            asm! {
-                "invpcid 1, [{pcid}]",
-                pcid = in(reg) pcid.as_ptr(),
+
+    /// Clears the entry that maps the page of the given size at `vaddr` without invalidating its
+    /// TLB entry. The walk only reads the intermediate tables so no tables are allocated.
+    /// # Returns
+    /// Returns `Error::NotMapped` if the page or any table leading to it is not present.
+    fn clear_leaf(
+        &mut self,
+        vaddr: VirtualAddress,
+        size: page_table::PageSize,
+    ) -> Result<(PhysicalAddress, u64), Error> {
+        let indices = [
+            vaddr.pml4_index(),
+            vaddr.pdpt_index(),
+            vaddr.pd_index(),
+            vaddr.pt_index(),
+        ];
+        let leaf_level = match size {
+            page_table::PageSize::Standard => 3,
+            page_table::PageSize::Large => 2,
+            page_table::PageSize::Huge => 1,
+        };
+        let mut table = <*mut PageTable>::from(self.get_pml4_paddr());
+        for (level, index) in indices.into_iter().enumerate().take(leaf_level) {
+            // SAFETY: `table` is this page map's PML4 or a table one of its present entries points
+            // to
+            let entry = unsafe { (*table).get(index) };
+            if !entry.is_present() {
+                return Err(Error::NotMapped);
             }
+            if level != 0 && entry.is_huge() {
+                return Err(Error::EntryNotTable);
+            }
+            table = <*mut PageTable>::from(entry.addr()?);
+        }
+        // SAFETY: the walk above ended at the table that holds the leaf
+        let leaf = unsafe { (*table).get(indices[leaf_level]) };
+        if !leaf.is_present() {
+            return Err(Error::NotMapped);
         }
+        if size != page_table::PageSize::Standard && !leaf.is_huge() {
+            return Err(Error::NoSizeBit);
+        }
+        // SAFETY: the walk above ended at the table that holds the leaf
+        let unmapped = unsafe { (*table).unmap_page(size, indices[leaf_level]) }?;
+        if let Some(account) = self.account().filter(|_| vaddr.is_user()) {
+            account.unmap(size.n_frames());
+        }
+        Ok(unmapped)
     }
 }
 
@@ -162,18 +2759,40 @@ impl MemoryMap for PageMap {
     type Flags = u64;
 
     /// Loads the page map into the logical processor.
+    /// A page map without a PCID is loaded the legacy way, which flushes every non-global TLB
+    /// entry and works whether or not PCIDs are enabled.
+    /// The TLB entries tagged with the page map's PCID are kept if this LP last used the PCID for
+    /// this same page map and no flush has been deferred, otherwise they may belong to a different
+    /// PML4 that used the PCID before and are flushed. Keeping them relies on every invalidation of
+    /// the page map while it was not loaded having been performed on this LP.
+    /// # Returns
+    /// Returns `Error::InvalidPcid` if the page map has a PCID but PCIDs are not enabled on this
+    /// LP, since the PCID's bits would be read as cache control bits instead.
     unsafe fn load(&self) -> Result<(), Self::Error> {
-        if self.get_pcid() != 0 {
-            unsafe {
-                asm! {
-                    "mov cr3, {0}",
-                    in(reg) self.cr3,
-                }
-            }
-            Ok(())
+        if self.get_pcid() == 0 {
+            announce_load(self.cr3);
+            // SAFETY: the caller guarantees that this page map maps the kernel like the current one
+            unsafe { write_cr3(self.cr3) }
+            self.flush_pending.store(false, Ordering::Release);
+            count_tlb_flush(TlbFlush::Full);
+            return Ok(());
+        }
+        if !is_cr4_feature_enabled(Cr4Feature::Pcide) {
+            return Err(Error::InvalidPcid);
+        }
+        announce_load(self.cr3);
+        let owned = record_pcid_owner(self.cr3);
+        let pending = self.flush_pending.swap(false, Ordering::AcqRel);
+        if owned && !pending {
+            // SAFETY: the caller guarantees that this page map maps the kernel like the current
+            // one, the TLB entries of its PCID already belong to it
+            unsafe { write_cr3(self.cr3 | CR3_NO_FLUSH) }
         } else {
-            Err(Error::InvalidPcid)
+            // SAFETY: the caller guarantees that this page map maps the kernel like the current one
+            unsafe { write_cr3(self.cr3) }
+            count_tlb_flush(TlbFlush::Pcid);
         }
+        Ok(())
     }
 
     /// Maps a page at the given virtual address.
@@ -189,21 +2808,20 @@ impl MemoryMap for PageMap {
     ) -> Result<(), Self::Error> {
         if vaddr.is_aligned_to(crate::arch::ISA_PARAMS.paging.page_size) == false {
             Err(Error::InvalidVAddrAlignment)
-        } else if vaddr.is_null() {
-            Err(Error::InvalidAddress)
         } else {
-            let mut walker = Walker::new(self);
-            logln!("Walker created.");
-            walker.walk_pd(vaddr, flags)?;
-            logln!("Walker walked to PD.");
-            walker.pt.unwrap().map_page(
-                page_table::PageSize::Standard,
-                vaddr.pt_index(),
-                paddr,
-                flags,
-            )?;
-
-            Ok(())
+            check_null_guard(vaddr)?;
+            self.charged(vaddr, page_table::PageSize::Standard, |page_map| {
+                let mut walker = Walker::new(page_map);
+                debugln!("Walker created.");
+                walker.walk_pd(vaddr, flags)?;
+                debugln!("Walker walked to PD.");
+                walker.pt.ok_or(Error::WalkIncomplete)?.map_page(
+                    page_table::PageSize::Standard,
+                    vaddr.pt_index(),
+                    paddr,
+                    flags,
+                )
+            })
         }
     }
 
@@ -212,22 +2830,23 @@ impl MemoryMap for PageMap {
     /// * `vaddr` - The virtual address to unmap.
     /// # Returns
     /// Returns an error of type `Self::Error` if unmapping fails or the physical address that was
-    /// previously mapped to the given virtual address if successful.
-    fn unmap_page(&mut self, vaddr: VirtualAddress) -> Result<PhysicalAddress, Self::Error> {
-        let mut walker = Walker::new(self);
-        walker.walk_pd(vaddr, 0)?;
-        unsafe {
-            walker
-                .pt
-                .unwrap()
-                .unmap_page(page_table::PageSize::Standard, vaddr.pt_index())
-        }
+    /// previously mapped to the given virtual address along with the flags it was mapped with if
+    /// successful. Unmapping a page that is not mapped, for instance because it was already
+    /// unmapped, returns `Error::NotMapped`.
+    fn unmap_page(
+        &mut self,
+        vaddr: VirtualAddress,
+    ) -> Result<(PhysicalAddress, Self::Flags), Self::Error> {
+        let unmapped = self.clear_leaf(vaddr, page_table::PageSize::Standard)?;
+        self.invalidate_page(vaddr);
+        Ok(unmapped)
     }
 
     /// Maps a large page (2 MiB) at the given virtual address.
     /// # Arguments
     /// * `vaddr` - The virtual address to map.
-    /// * `paddr` - The physical address to map.
+    /// * `paddr` - The base of the block to map, a contiguous allocation aligned to the page size
+    ///   that the mapping takes ownership of unless it is MMIO or shared.
     /// * `flags` - The flags to apply to the page table entry.
     /// # Returns
     /// Returns an error of type `Self::Error` if mapping fails.
@@ -237,35 +2856,50 @@ impl MemoryMap for PageMap {
         paddr: PhysicalAddress,
         flags: Self::Flags,
     ) -> Result<(), Self::Error> {
-        let mut walker = Walker::new(self);
-        walker.walk_pdpt(vaddr, flags)?;
-        walker
-            .pd
-            .unwrap()
-            .map_page(page_table::PageSize::Large, vaddr.pd_index(), paddr, flags)
+        if !*ARE_LARGE_PAGES_SUPPORTED {
+            Err(Error::UnsupportedOperation)
+        } else {
+            check_null_guard(vaddr)?;
+            self.charged(vaddr, page_table::PageSize::Large, |page_map| {
+                let mut walker = Walker::new(page_map);
+                walker.walk_pdpt(vaddr, flags)?;
+                walker.pd.ok_or(Error::WalkIncomplete)?.map_page(
+                    page_table::PageSize::Large,
+                    vaddr.pd_index(),
+                    paddr,
+                    flags,
+                )
+            })
+        }
     }
 
     /// Unmaps a large page from the given page map at the given virtual address.
+    /// Unless the page is MMIO or shared, the mapping owns the contiguous 2 MiB block backing it
+    /// and every frame of the block is returned to the PMM, the caller must not free it again.
     /// # Arguments
     /// * `vaddr` - The virtual address to unmap.
     /// # Returns
-    /// Returns an error of type `Self::Error` if unmapping fails or the physical address that was
-    /// previously mapped to the given virtual address if successful.
-    fn unmap_large_page(&mut self, vaddr: VirtualAddress) -> Result<PhysicalAddress, Self::Error> {
-        let mut walker = Walker::new(self);
-        walker.walk_pdpt(vaddr, 0)?;
-        unsafe {
-            walker
-                .pd
-                .unwrap()
-                .unmap_page(page_table::PageSize::Large, vaddr.pd_index())
+    /// Returns an error of type `Self::Error` if unmapping fails or the base of the block that was
+    /// previously mapped to the given virtual address along with the flags it was mapped with if
+    /// successful.
+    fn unmap_large_page(
+        &mut self,
+        vaddr: VirtualAddress,
+    ) -> Result<(PhysicalAddress, Self::Flags), Self::Error> {
+        if !*ARE_LARGE_PAGES_SUPPORTED {
+            Err(Error::UnsupportedOperation)
+        } else {
+            let unmapped = self.clear_leaf(vaddr, page_table::PageSize::Large)?;
+            self.invalidate_page(vaddr);
+            Ok(unmapped)
         }
     }
 
     /// Maps a huge page (1 GiB) at the given virtual address.
     /// # Arguments
     /// * `vaddr` - The virtual address to map.
-    /// * `paddr` - The physical address to map.
+    /// * `paddr` - The base of the block to map, a contiguous allocation aligned to the page size
+    ///   that the mapping takes ownership of unless it is MMIO or shared.
     /// * `flags` - The flags to apply to the page table entry.
     /// # Returns
     /// Returns an error of type `Self::Error` if mapping fails.
@@ -278,41 +2912,540 @@ impl MemoryMap for PageMap {
         if *ARE_HUGE_PAGES_SUPPORTED == false {
             Err(Error::UnsupportedOperation)
         } else {
-            let mut walker = Walker::new(self);
-            walker.walk_pml4(vaddr, flags)?;
-            walker.pdpt.unwrap().map_page(
-                page_table::PageSize::Huge,
-                vaddr.pdpt_index(),
-                paddr,
-                flags,
-            )
+            check_null_guard(vaddr)?;
+            self.charged(vaddr, page_table::PageSize::Huge, |page_map| {
+                let mut walker = Walker::new(page_map);
+                walker.walk_pml4(vaddr, flags)?;
+                walker.pdpt.ok_or(Error::WalkIncomplete)?.map_page(
+                    page_table::PageSize::Huge,
+                    vaddr.pdpt_index(),
+                    paddr,
+                    flags,
+                )
+            })
         }
     }
 
     /// Unmaps a huge page from the given page map at the given virtual address.
+    /// Unless the page is MMIO or shared, the mapping owns the contiguous 1 GiB block backing it
+    /// and every frame of the block is returned to the PMM, the caller must not free it again.
     /// # Arguments
     /// * `vaddr` - The virtual address to unmap.
     /// # Returns
-    /// Returns an error of type `Self::Error` if unmapping fails or the physical address that was
-    /// previously mapped to the given virtual address if successful.
-    fn unmap_huge_page(&mut self, vaddr: VirtualAddress) -> Result<PhysicalAddress, Self::Error> {
+    /// Returns an error of type `Self::Error` if unmapping fails or the base of the block that was
+    /// previously mapped to the given virtual address along with the flags it was mapped with if
+    /// successful.
+    fn unmap_huge_page(
+        &mut self,
+        vaddr: VirtualAddress,
+    ) -> Result<(PhysicalAddress, Self::Flags), Self::Error> {
         if *ARE_HUGE_PAGES_SUPPORTED == false {
             Err(Error::UnsupportedOperation)
         } else {
-            let mut walker = Walker::new(self);
-            walker.walk_pml4(vaddr, 0)?;
-            unsafe {
-                walker
-                    .pdpt
-                    .unwrap()
-                    .unmap_page(page_table::PageSize::Huge, vaddr.pdpt_index())
-            }
+            let unmapped = self.clear_leaf(vaddr, page_table::PageSize::Huge)?;
+            self.invalidate_page(vaddr);
+            Ok(unmapped)
         }
     }
 }
 
 global_asm!(include_str!("mod.asm"));
 
+#[cfg(not(test))]
 extern "C" {
     pub fn asm_get_cr3() -> u64;
 }
+
+/// Writes `cr3` to CR3, which switches to the page map it refers to and flushes the TLB unless
+/// [CR3_NO_FLUSH] is set
+/// # Safety
+/// The page map must map the kernel, including the code and stack in use by the caller, at the
+/// same addresses as the currently loaded page map.
+#[cfg(not(test))]
+unsafe fn write_cr3(cr3: u64) {
+    // SAFETY: the caller guarantees that the page map maps the kernel like the current one
+    unsafe { asm!("mov cr3, {0}", in(reg) cr3) }
+}
+
+// Unit tests run as a user space process that may not access CR3, so each test thread has a
+// simulated one instead. It is 0 until a page map is loaded.
+#[cfg(test)]
+std::thread_local! {
+    static TEST_CR3: core::cell::Cell<u64> = const { core::cell::Cell::new(0) };
+}
+
+#[cfg(test)]
+pub unsafe fn asm_get_cr3() -> u64 {
+    TEST_CR3.get()
+}
+
+#[cfg(test)]
+unsafe fn write_cr3(cr3: u64) {
+    // the no-flush bit only affects the write and is never read back
+    TEST_CR3.set(cr3 & !CR3_NO_FLUSH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::x86_64::cpu::{tlb_stats, TlbStats};
+    use crate::memory::address::UAddr;
+    use crate::memory::pmm::{test_memory, Error as PmmError};
+
+    const USER_READ_WRITE: u64 = PteFlags::Present as u64
+        | PteFlags::Write as u64
+        | PteFlags::User as u64
+        | PteFlags::NoExecute as u64;
+
+    /// Returns the `i`th page of a 512 GiB region of the user half that only tests map in, so every
+    /// table on its walk below the PML4 is allocated by the test
+    fn user_page(i: usize) -> VirtualAddress {
+        VirtualAddress::try_from(0x80_0000_0000 + i as u64 * PAGE_SIZE).unwrap()
+    }
+
+    fn free_frames() -> UAddr {
+        PHYSICAL_FRAME_ALLOCATOR.lock().free_frames()
+    }
+
+    fn is_pml4_entry_present(page_map: &PageMap, vaddr: VirtualAddress) -> bool {
+        page_map.walk_entries(vaddr)[0].unwrap().is_present()
+    }
+
+    /// Creates a page map with `n_pages` pages mapped from `user_page(0)` on and loads it into the
+    /// simulated CR3 of the calling thread
+    fn loaded_page_map(n_pages: usize) -> PageMap {
+        let mut page_map = PageMap::try_new().unwrap();
+        page_map
+            .map_region_fixed(
+                user_page(0),
+                PageCount::new(n_pages).unwrap(),
+                USER_READ_WRITE,
+                FixedMapping::NoReplace,
+                RegionHint::WriteHeavy,
+            )
+            .unwrap();
+        // SAFETY: the simulated CR3 is only read by the page maps of this thread
+        unsafe { page_map.load() }.unwrap();
+        page_map
+    }
+
+    fn unload_and_destroy(page_map: PageMap) {
+        // SAFETY: the simulated CR3 is only read by the page maps of this thread
+        unsafe { write_cr3(0) }
+        page_map.destroy().unwrap();
+    }
+
+    /// Publishes `page_map` as loaded on another LP, as that LP does before writing it to CR3
+    fn load_on_other_cpu(page_map: &PageMap) {
+        LOADED_PML4S[MAX_CPUS - 1].store(page_map.get_pml4_paddr().bits(), Ordering::SeqCst);
+    }
+
+    fn unload_from_other_cpu() {
+        LOADED_PML4S[MAX_CPUS - 1].store(0, Ordering::SeqCst);
+    }
+
+    /// Returns how many TLB invalidations of each kind were counted since `before` was taken
+    fn tlb_flushes_since(before: TlbStats) -> TlbStats {
+        let now = tlb_stats();
+        TlbStats {
+            single_page: now.single_page - before.single_page,
+            full: now.full - before.full,
+            pcid: now.pcid - before.pcid,
+        }
+    }
+
+    #[test]
+    fn running_out_of_memory_mid_walk_frees_the_tables_the_walk_installed() {
+        let _memory = test_memory::lock();
+        let free_before = free_frames();
+        let mut page_map = PageMap::try_new().unwrap();
+        let frame = PHYSICAL_FRAME_ALLOCATOR.lock().allocate().unwrap();
+        let free_after_setup = free_frames();
+
+        // the PDPT can be allocated but the PD cannot
+        PHYSICAL_FRAME_ALLOCATOR.lock().inject_failure_after(1);
+        assert_eq!(
+            page_map.map_page(user_page(0), frame, USER_READ_WRITE),
+            Err(Error::PmmError(PmmError::OutOfMemory))
+        );
+        PHYSICAL_FRAME_ALLOCATOR.lock().clear_injected_failure();
+        assert_eq!(free_frames(), free_after_setup);
+        assert!(!is_pml4_entry_present(&page_map, user_page(0)));
+        assert_eq!(page_map.committed_pages(), 0);
+        assert_eq!(page_map.resident_pages(), 0);
+
+        page_map
+            .map_page(user_page(0), frame, USER_READ_WRITE)
+            .unwrap();
+        assert_eq!(page_map.translate(user_page(0)), Ok(frame));
+        page_map.destroy().unwrap();
+        assert_eq!(free_frames(), free_before);
+    }
+
+    #[test]
+    fn failed_fixed_mapping_frees_the_frames_and_tables_it_allocated() {
+        let _memory = test_memory::lock();
+        let free_before = free_frames();
+        let mut page_map = PageMap::try_new().unwrap();
+        let free_after_setup = free_frames();
+        let n_pages = PageCount::new(8).unwrap();
+
+        // the first page's frame, PDPT, PD and PT and the next two pages' frames can be allocated
+        PHYSICAL_FRAME_ALLOCATOR.lock().inject_failure_after(6);
+        assert_eq!(
+            page_map.map_region_fixed(
+                user_page(0),
+                n_pages,
+                USER_READ_WRITE,
+                FixedMapping::NoReplace,
+                RegionHint::WriteHeavy,
+            ),
+            Err(Error::PmmError(PmmError::OutOfMemory))
+        );
+        PHYSICAL_FRAME_ALLOCATOR.lock().clear_injected_failure();
+        assert_eq!(free_frames(), free_after_setup);
+        assert!(page_map.iter_mappings(true).next().is_none());
+        assert!(!is_pml4_entry_present(&page_map, user_page(0)));
+        assert_eq!(page_map.committed_pages(), 0);
+        assert_eq!(page_map.resident_pages(), 0);
+
+        page_map.destroy().unwrap();
+        assert_eq!(free_frames(), free_before);
+    }
+
+    #[test]
+    fn failed_reservation_releases_the_pages_and_tables_it_reserved() {
+        let _memory = test_memory::lock();
+        let mut page_map = PageMap::try_new().unwrap();
+        let free_after_setup = free_frames();
+        // the last 4 pages of the first GiB and the first 4 of the second, which have their own PD
+        let vaddr = user_page((1 << 18) - 4);
+        let n_pages = PageCount::new(8).unwrap();
+
+        // the PDPT and the first PD and PT can be allocated but the second PD cannot
+        PHYSICAL_FRAME_ALLOCATOR.lock().inject_failure_after(3);
+        assert_eq!(
+            page_map.map_anonymous(vaddr, n_pages, USER_READ_WRITE),
+            Err(Error::PmmError(PmmError::OutOfMemory))
+        );
+        PHYSICAL_FRAME_ALLOCATOR.lock().clear_injected_failure();
+        assert_eq!(page_map.committed_pages(), 0);
+        assert!(page_map.is_range_available(vaddr, n_pages));
+        assert!(!is_pml4_entry_present(&page_map, vaddr));
+        assert_eq!(free_frames(), free_after_setup);
+
+        page_map
+            .map_anonymous(vaddr, n_pages, USER_READ_WRITE)
+            .unwrap();
+        assert_eq!(page_map.committed_pages(), 8);
+        page_map.destroy().unwrap();
+    }
+
+    #[test]
+    fn memory_limit_allows_pages_up_to_the_limit() {
+        let _memory = test_memory::lock();
+        let free_before = free_frames();
+        let mut page_map = PageMap::try_new().unwrap();
+        page_map.set_memory_limit(Some(4));
+        assert_eq!(page_map.memory_limit(), Some(4));
+
+        page_map
+            .map_region_fixed(
+                user_page(0),
+                PageCount::new(4).unwrap(),
+                USER_READ_WRITE,
+                FixedMapping::NoReplace,
+                RegionHint::WriteHeavy,
+            )
+            .unwrap();
+        assert_eq!(page_map.committed_pages(), 4);
+        assert_eq!(page_map.resident_pages(), 4);
+
+        let frame = PHYSICAL_FRAME_ALLOCATOR.lock().allocate().unwrap();
+        assert_eq!(
+            page_map.map_page(user_page(4), frame, USER_READ_WRITE),
+            Err(Error::MemoryLimitExceeded)
+        );
+        assert_eq!(
+            page_map.map_anonymous(user_page(4), PageCount::new(1).unwrap(), USER_READ_WRITE),
+            Err(Error::MemoryLimitExceeded)
+        );
+        assert_eq!(page_map.translate(user_page(4)), Err(Error::NotMapped));
+        assert_eq!(page_map.committed_pages(), 4);
+
+        page_map
+            .unmap_range(user_page(0), PageCount::new(1).unwrap(), false)
+            .unwrap();
+        assert_eq!(page_map.committed_pages(), 3);
+        assert_eq!(page_map.resident_pages(), 3);
+        page_map
+            .map_page(user_page(4), frame, USER_READ_WRITE)
+            .unwrap();
+        assert_eq!(page_map.committed_pages(), 4);
+
+        page_map.destroy().unwrap();
+        assert_eq!(free_frames(), free_before);
+    }
+
+    #[test]
+    fn effective_permissions_combine_every_level_of_the_walk() {
+        let _memory = test_memory::lock();
+        let mut page_map = PageMap::try_new().unwrap();
+        let frame = PHYSICAL_FRAME_ALLOCATOR.lock().allocate().unwrap();
+        page_map
+            .map_page(user_page(0), frame, USER_READ_WRITE)
+            .unwrap();
+        assert_eq!(
+            page_map.effective_permissions(user_page(0)),
+            Ok(MemType::UserReadWrite)
+        );
+        assert_eq!(
+            page_map.effective_permissions(user_page(1 << 9)),
+            Err(Error::EntryNotPresent)
+        );
+
+        // clear Write in the PDPT entry that points to the PD, the leaf stays writable
+        let pml4 = <*mut PageTable>::from(page_map.get_pml4_paddr());
+        // SAFETY: the tables were installed by map_page above and nothing else uses them
+        unsafe {
+            let pdpt =
+                <*mut PageTable>::from((*pml4).get(user_page(0).pml4_index()).addr().unwrap());
+            let entry = (*pdpt).get_mut(user_page(0).pdpt_index());
+            *entry = PageTableEntry::from_bits(entry.bits() & !(PteFlags::Write as u64));
+        }
+        assert!(page_map.walk_entries(user_page(0))[3]
+            .unwrap()
+            .is_writable());
+        assert_eq!(
+            page_map.effective_permissions(user_page(0)),
+            Ok(MemType::UserReadOnly)
+        );
+        assert_eq!(
+            page_map.validate_user_ptr(user_page(0).bits(), 8, UserAccess::Write),
+            Err(Error::InsufficientPermissions)
+        );
+        assert_eq!(
+            page_map.validate_user_ptr(user_page(0).bits(), 8, UserAccess::Read),
+            Ok(())
+        );
+
+        page_map.destroy().unwrap();
+    }
+
+    #[test]
+    fn unmapping_pages_counts_one_single_page_invalidation_each() {
+        let _memory = test_memory::lock();
+        let mut page_map = loaded_page_map(4);
+        let before = tlb_stats();
+        for i in 0..4 {
+            page_map
+                .unmap_range(user_page(i), PageCount::new(1).unwrap(), true)
+                .unwrap();
+        }
+        assert_eq!(
+            tlb_flushes_since(before),
+            TlbStats {
+                single_page: 4,
+                full: 0,
+                pcid: 0,
+            }
+        );
+        unload_and_destroy(page_map);
+    }
+
+    #[test]
+    fn batched_range_flush_counts_one_full_flush() {
+        let _memory = test_memory::lock();
+        let n_pages = tlb_flush_threshold() + 1;
+        let mut page_map = loaded_page_map(n_pages);
+        let before = tlb_stats();
+        page_map
+            .unmap_range(user_page(0), PageCount::new(n_pages).unwrap(), true)
+            .unwrap();
+        assert_eq!(
+            tlb_flushes_since(before),
+            TlbStats {
+                single_page: 0,
+                full: 1,
+                pcid: 0,
+            }
+        );
+        unload_and_destroy(page_map);
+    }
+
+    #[test]
+    fn flush_range_invalidates_each_page_it_overlaps_up_to_the_threshold() {
+        let _memory = test_memory::lock();
+        let page_map = loaded_page_map(1);
+        let threshold = tlb_flush_threshold();
+
+        // a range starting mid-page overlaps one page more than its size covers
+        let before = tlb_stats();
+        page_map.flush_range(
+            user_page(0) + 0x800usize,
+            (threshold - 1) * PAGE_SIZE as usize,
+        );
+        assert_eq!(
+            tlb_flushes_since(before),
+            TlbStats {
+                single_page: threshold as u64,
+                full: 0,
+                pcid: 0,
+            }
+        );
+
+        let before = tlb_stats();
+        page_map.flush_range(user_page(0) + 0x800usize, threshold * PAGE_SIZE as usize);
+        assert_eq!(
+            tlb_flushes_since(before),
+            TlbStats {
+                single_page: 0,
+                full: 1,
+                pcid: 0,
+            }
+        );
+        unload_and_destroy(page_map);
+    }
+
+    #[test]
+    fn unmap_range_switches_to_a_full_flush_just_above_the_configured_threshold() {
+        let _memory = test_memory::lock();
+        let mut page_map = loaded_page_map(17);
+        set_tlb_flush_threshold(8);
+
+        let before = tlb_stats();
+        page_map
+            .unmap_range(user_page(0), PageCount::new(8).unwrap(), true)
+            .unwrap();
+        let at_threshold = tlb_flushes_since(before);
+
+        let before = tlb_stats();
+        page_map
+            .unmap_range(user_page(8), PageCount::new(9).unwrap(), true)
+            .unwrap();
+        let above_threshold = tlb_flushes_since(before);
+
+        set_tlb_flush_threshold(DEFAULT_TLB_FLUSH_THRESHOLD);
+        assert_eq!(
+            at_threshold,
+            TlbStats {
+                single_page: 8,
+                full: 0,
+                pcid: 0,
+            }
+        );
+        assert_eq!(
+            above_threshold,
+            TlbStats {
+                single_page: 0,
+                full: 1,
+                pcid: 0,
+            }
+        );
+        unload_and_destroy(page_map);
+    }
+
+    /// Unmaps a page of a page map with a PCID that another LP has loaded under `policy`
+    /// # Returns
+    /// Returns the page map and the TLB invalidations counted by the unmap
+    fn unmap_page_loaded_elsewhere(policy: TlbPolicy) -> (PageMap, TlbStats) {
+        let mut page_map = PageMap::try_new().unwrap();
+        page_map.set_pcid(1).unwrap();
+        page_map.set_tlb_policy(policy);
+        let frame = PHYSICAL_FRAME_ALLOCATOR.lock().allocate().unwrap();
+        page_map
+            .map_page(user_page(0), frame, USER_READ_WRITE)
+            .unwrap();
+        load_on_other_cpu(&page_map);
+        let before = tlb_stats();
+        page_map.unmap_page(user_page(0)).unwrap();
+        let flushes = tlb_flushes_since(before);
+        unload_from_other_cpu();
+        (page_map, flushes)
+    }
+
+    #[test]
+    fn eager_policy_invalidates_on_unmap() {
+        let _memory = test_memory::lock();
+        // the page map this LP runs on, flushing another one restores it afterwards
+        let current = loaded_page_map(1);
+        let (page_map, flushes) = unmap_page_loaded_elsewhere(TlbPolicy::Eager);
+        assert_eq!(
+            flushes,
+            TlbStats {
+                single_page: 0,
+                full: 0,
+                pcid: 1,
+            }
+        );
+        page_map.destroy().unwrap();
+        unload_and_destroy(current);
+    }
+
+    #[test]
+    fn lazy_policy_defers_the_invalidation_until_the_page_map_is_loaded() {
+        let _memory = test_memory::lock();
+        // the page map this LP runs on, flushing another one restores it afterwards
+        let current = loaded_page_map(1);
+        let (page_map, flushes) = unmap_page_loaded_elsewhere(TlbPolicy::Lazy);
+        assert_eq!(
+            flushes,
+            TlbStats {
+                single_page: 0,
+                full: 0,
+                pcid: 0,
+            }
+        );
+        assert!(page_map.has_pending_flush());
+
+        let before = tlb_stats();
+        // SAFETY: the simulated CR3 is only read by the page maps of this thread
+        unsafe { page_map.with_loaded(|| ()) };
+        // loading the page map drops its stale entries and switching back flushes once more
+        assert_eq!(tlb_flushes_since(before).full, 2);
+        assert!(!page_map.has_pending_flush());
+        page_map.destroy().unwrap();
+        unload_and_destroy(current);
+    }
+
+    #[test]
+    fn unmapping_from_the_loaded_page_map_invalidates_the_page() {
+        let _memory = test_memory::lock();
+        let mut page_map = loaded_page_map(1);
+        assert!(page_map.is_active_on_current_cpu());
+        let before = tlb_stats();
+        page_map.unmap_page(user_page(0)).unwrap();
+        assert_eq!(
+            tlb_flushes_since(before),
+            TlbStats {
+                single_page: 1,
+                full: 0,
+                pcid: 0,
+            }
+        );
+        unload_and_destroy(page_map);
+    }
+
+    #[test]
+    fn unmapping_from_a_page_map_loaded_nowhere_skips_the_invalidation() {
+        let _memory = test_memory::lock();
+        let mut page_map = PageMap::try_new().unwrap();
+        let frame = PHYSICAL_FRAME_ALLOCATOR.lock().allocate().unwrap();
+        page_map
+            .map_page(user_page(0), frame, USER_READ_WRITE)
+            .unwrap();
+        assert!(!page_map.is_active_on_current_cpu());
+        assert!(!page_map.is_active_anywhere());
+        let before = tlb_stats();
+        page_map.unmap_page(user_page(0)).unwrap();
+        assert_eq!(
+            tlb_flushes_since(before),
+            TlbStats {
+                single_page: 0,
+                full: 0,
+                pcid: 0,
+            }
+        );
+        page_map.destroy().unwrap();
+    }
+}
@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use page_table_entry::*;
 
 use crate::arch::x86_64::memory::*;
@@ -7,14 +9,25 @@ use crate::memory::pmm::PHYSICAL_FRAME_ALLOCATOR;
 
 pub mod page_table_entry;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PageSize {
     Standard = 0,
     Large = 1,
     Huge = 2,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl PageSize {
+    /// Returns the number of standard page frames a page of this size spans
+    pub const fn n_frames(self) -> usize {
+        match self {
+            PageSize::Standard => 1,
+            PageSize::Large => LARGE_PAGE_NFRAMES as usize,
+            PageSize::Huge => HUGE_PAGE_NFRAMES as usize,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PageTableLevel {
     PML4 = 4,
     PDPT = 3,
@@ -22,33 +35,122 @@ pub enum PageTableLevel {
     PT = 1,
 }
 
-const N_PT_ENTRIES: usize = 512;
+impl PageTableLevel {
+    /// Returns the index of the entry in a table at this level that translates `vaddr`
+    pub fn index_of(&self, vaddr: VirtualAddress) -> usize {
+        match self {
+            PageTableLevel::PML4 => vaddr.pml4_index(),
+            PageTableLevel::PDPT => vaddr.pdpt_index(),
+            PageTableLevel::PD => vaddr.pd_index(),
+            PageTableLevel::PT => vaddr.pt_index(),
+        }
+    }
+}
+
+pub const N_PT_ENTRIES: usize = 512;
 const LARGE_PAGE_NFRAMES: u64 = 512;
 const HUGE_PAGE_NFRAMES: u64 = 512 * 512;
 
 #[repr(align(4096))]
 #[derive(Debug)]
 pub struct PageTable {
-    table: [PageTableEntry; N_PT_ENTRIES],
+    /// LPs walking the same page map may install tables in the same entry at the same time, so
+    /// every access that is not made through `&mut self` is atomic
+    table: [AtomicU64; N_PT_ENTRIES],
 }
 
+// The hardware expects a page table to be a single page aligned frame of 512 8-byte entries
+const _: () = assert!(core::mem::size_of::<PageTableEntry>() == 8);
+const _: () = assert!(core::mem::size_of::<AtomicU64>() == 8);
+const _: () = assert!(core::mem::size_of::<PageTable>() == 4096);
+const _: () = assert!(core::mem::align_of::<PageTable>() == 4096);
+const _: () = assert!(N_PT_ENTRIES * core::mem::size_of::<PageTableEntry>() == 4096);
+
 impl PageTable {
+    #[allow(unused)]
     pub fn new() -> Self {
         Self {
-            table: [PageTableEntry::new(); N_PT_ENTRIES],
+            table: [const { AtomicU64::new(0) }; N_PT_ENTRIES],
         }
     }
 
-    pub fn map_table(&mut self, index: usize, flags: u64) -> Result<PhysicalAddress, Error> {
-        let table_paddr = PHYSICAL_FRAME_ALLOCATOR.lock().allocate()?;
-        self.table[index].map_table(table_paddr, flags)?;
-        Ok(PHYSICAL_FRAME_ALLOCATOR.lock().allocate().unwrap())
+    /// Returns a copy of the entry at `index`
+    #[inline]
+    pub fn get(&self, index: usize) -> PageTableEntry {
+        PageTableEntry::from_bits(self.table[index].load(Ordering::Acquire))
+    }
+
+    /// Returns true if none of the entries in the table are present or reserved for demand-zero
+    /// pages
+    pub fn is_empty(&self) -> bool {
+        (0..N_PT_ENTRIES).all(|index| {
+            let entry = self.get(index);
+            !entry.is_present() && !entry.is_demand_zero()
+        })
+    }
+
+    /// Returns a mutable reference to the entry at `index`
+    #[inline]
+    pub fn get_mut(&mut self, index: usize) -> &mut PageTableEntry {
+        // SAFETY: an entry is a transparent u64 and the table is borrowed exclusively
+        unsafe { &mut *(self.table[index].get_mut() as *mut u64).cast::<PageTableEntry>() }
+    }
+
+    /// Atomically replaces the entry at `index` with `new` if it is still equal to `expected`.
+    /// This allows entries to be installed without holding a lock over the whole table.
+    /// # Returns
+    /// Returns the previous value of the entry in `Ok` if it was replaced or in `Err` if it was not.
+    #[inline]
+    pub fn compare_exchange(&self, index: usize, expected: u64, new: u64) -> Result<u64, u64> {
+        self.table[index].compare_exchange(expected, new, Ordering::AcqRel, Ordering::Acquire)
+    }
+
+    /// Allocates a new empty page table and installs it at `index`.
+    /// If another LP installs a table at the same index first, the newly allocated table is freed
+    /// and the one that was installed by the other LP is returned instead.
+    pub fn map_table(&self, index: usize, flags: u64) -> Result<PhysicalAddress, Error> {
+        let current = self.get(index);
+        if current.is_present() {
+            return Err(Error::VAddrRangeUnavailable);
+        }
+        let table_paddr = PHYSICAL_FRAME_ALLOCATOR.lock().allocate_zeroed_pinned()?;
+        self.install_table(index, current, table_paddr, flags)
+    }
+
+    /// Installs the newly allocated table at `table_paddr` at `index` if the entry is still
+    /// `current`, which [map_table](Self::map_table) read before allocating the table. Otherwise
+    /// the table is freed and the table another LP installed in the meantime is returned.
+    fn install_table(
+        &self,
+        index: usize,
+        current: PageTableEntry,
+        table_paddr: PhysicalAddress,
+        flags: u64,
+    ) -> Result<PhysicalAddress, Error> {
+        let mut new = PageTableEntry::new();
+        if let Err(e) = new.map_table(table_paddr, flags) {
+            PHYSICAL_FRAME_ALLOCATOR.lock().deallocate(table_paddr)?;
+            return Err(e);
+        }
+
+        match self.compare_exchange(index, current.bits(), new.bits()) {
+            Ok(_) => Ok(table_paddr),
+            Err(winner) => {
+                PHYSICAL_FRAME_ALLOCATOR.lock().deallocate(table_paddr)?;
+                let winner = PageTableEntry::from_bits(winner);
+                if winner.is_present() && !winner.is_size_bit_set() {
+                    winner.addr()
+                } else {
+                    Err(Error::VAddrRangeUnavailable)
+                }
+            }
+        }
     }
 
     pub unsafe fn unmap_table(&mut self, index: usize) -> Result<(), Error> {
-        if self.table[index].is_present() {
-            if !self.table[index].is_size_bit_set() {
-                let table_paddr = self.table[index].unmap()?;
+        if self.get(index).is_present() {
+            if !self.get(index).is_size_bit_set() {
+                let table_paddr = self.get_mut(index).unmap()?;
                 PHYSICAL_FRAME_ALLOCATOR.lock().deallocate(table_paddr)?;
                 Ok(())
             } else {
@@ -83,52 +185,135 @@ impl PageTable {
                 }
             }
         }
-        self.table[index].map_page(paddr, flags, size)?;
+        self.get_mut(index).map_page(paddr, flags, size)?;
         Ok(())
     }
 
+    /// Clears the entry at `index` and releases the page it mapped if the mapping owned it, which
+    /// frees it unless other mappings still share it, see
+    /// [release_frame](crate::memory::pmm::PhysicalFrameAllocator::release_frame).
+    /// MMIO and shared pages are left allocated since their frames belong to a device or to
+    /// another address space. Large and huge pages are always backed by a single block of
+    /// physically contiguous frames aligned to the page size, so an owned one is released as a
+    /// whole block rather than frame by frame.
     pub unsafe fn unmap_page(
         &mut self,
         size: PageSize,
         index: usize,
-    ) -> Result<PhysicalAddress, Error> {
-        let page_paddr = self.table[index].unmap()?;
-        match size {
-            PageSize::Standard => PHYSICAL_FRAME_ALLOCATOR.lock().deallocate(page_paddr)?,
-            PageSize::Large => PHYSICAL_FRAME_ALLOCATOR
-                .lock()
-                .deallocate_contiguous(page_paddr, LARGE_PAGE_NFRAMES)?,
-            PageSize::Huge => PHYSICAL_FRAME_ALLOCATOR
-                .lock()
-                .deallocate_contiguous(page_paddr, HUGE_PAGE_NFRAMES)?,
+    ) -> Result<(PhysicalAddress, u64), Error> {
+        let flags = self.get(index).flags(size);
+        let owns_frame = self.get(index).owns_frame();
+        let page_paddr = self.get_mut(index).unmap()?;
+        if !owns_frame {
+            return Ok((page_paddr, flags));
         }
-        Ok(page_paddr)
+        PHYSICAL_FRAME_ALLOCATOR
+            .lock()
+            .release_contiguous(page_paddr, size.n_frames() as u64)?;
+        Ok((page_paddr, flags))
     }
 
     pub fn get_or_map_table(
-        &mut self,
+        &self,
         vaddr: VirtualAddress,
         level: PageTableLevel,
         flags: u64,
     ) -> Result<*mut PageTable, Error> {
-        let index = match level {
-            PageTableLevel::PML4 => vaddr.pml4_index(),
-            PageTableLevel::PDPT => vaddr.pdpt_index(),
-            PageTableLevel::PD => vaddr.pd_index(),
-            PageTableLevel::PT => vaddr.pt_index(),
-        };
-        if self.table[index].is_present() {
+        let index = level.index_of(vaddr);
+        let entry = self.get(index);
+        if entry.is_present() {
             match level {
                 PageTableLevel::PDPT | PageTableLevel::PD => {
-                    if self.table[index].is_size_bit_set() {
+                    if entry.is_size_bit_set() {
                         return Err(Error::VAddrRangeUnavailable);
                     }
                 }
                 _ => {}
             }
-            Ok(<*mut PageTable>::from(self.table[index].addr().unwrap()))
+            Ok(<*mut PageTable>::from(entry.addr().unwrap()))
         } else {
             Ok(<*mut PageTable>::from(self.map_table(index, flags)?))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::memory::pmm::test_memory;
+
+    const TABLE_FLAGS: u64 = PteFlags::Present as u64 | PteFlags::Write as u64;
+
+    fn free_frames() -> UAddr {
+        PHYSICAL_FRAME_ALLOCATOR.lock().free_frames()
+    }
+
+    #[test]
+    fn walker_losing_the_race_frees_its_table_and_uses_the_winners() {
+        let _memory = test_memory::lock();
+        let pml4 = PageTable::new();
+        let free_before = free_frames();
+
+        // walker A finds the entry empty and allocates a table for it
+        let seen_by_a = pml4.get(7);
+        let table_a = PHYSICAL_FRAME_ALLOCATOR
+            .lock()
+            .allocate_zeroed_pinned()
+            .unwrap();
+        // walker B installs its own table before A gets to
+        let table_b = pml4.map_table(7, TABLE_FLAGS).unwrap();
+        assert_eq!(
+            pml4.install_table(7, seen_by_a, table_a, TABLE_FLAGS),
+            Ok(table_b)
+        );
+
+        assert_eq!(pml4.get(7).addr(), Ok(table_b));
+        assert_eq!(free_frames(), free_before - 1);
+        let pmm = PHYSICAL_FRAME_ALLOCATOR.lock();
+        assert!(!pmm.is_pinned(table_a));
+        assert!(pmm.is_pinned(table_b));
+        drop(pmm);
+
+        let mut pml4 = pml4;
+        // SAFETY: the table below entry 7 is empty and only referenced by this PML4
+        unsafe { pml4.unmap_table(7) }.unwrap();
+        assert_eq!(free_frames(), free_before);
+    }
+
+    #[test]
+    fn concurrent_walkers_all_use_the_single_table_installed() {
+        let _memory = test_memory::lock();
+        let pml4 = PageTable::new();
+        let vaddr = VirtualAddress::try_from(0x80_0000_0000u64).unwrap();
+        let free_before = free_frames();
+
+        let tables: Vec<usize> = thread::scope(|scope| {
+            let walkers: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        pml4.get_or_map_table(vaddr, PageTableLevel::PML4, TABLE_FLAGS)
+                            .unwrap() as usize
+                    })
+                })
+                .collect();
+            walkers
+                .into_iter()
+                .map(|walker| walker.join().unwrap())
+                .collect()
+        });
+
+        let installed = pml4.get(vaddr.pml4_index()).addr().unwrap();
+        assert!(tables
+            .iter()
+            .all(|&table| table == <*mut PageTable>::from(installed) as usize));
+        assert_eq!(free_frames(), free_before - 1);
+
+        let mut pml4 = pml4;
+        // SAFETY: the table is empty and the walkers that used it are done
+        unsafe { pml4.unmap_table(vaddr.pml4_index()) }.unwrap();
+        assert_eq!(free_frames(), free_before);
+    }
+}
@@ -1,5 +1,6 @@
-use super::PageSize;
+use super::{PageSize, PageTableLevel};
 
+use crate::arch::x86_64::cpu::read_msr_u64;
 use crate::arch::x86_64::memory::*;
 use crate::memory::address::*;
 
@@ -23,11 +24,266 @@ pub enum PteFlags {
     PageSizeOrPat = 1 << 7, // PageSize for entires in the PDPT, and PD for 1GiB and 2MiB pages and PAT for 4KiB pages
     Global = 1 << 8,
     HugeAndLargePat = 1 << 12, // Only for entries in the PDPT, and PD for 1GiB and 2MiB pages
-    CcCopyOnWrite = 1 << 52, // Only for entries that point to pages. This bit indicates that the page should be copied on write
-    CcShared = 1 << 53, // Only for entries that point to pages. This bit indicates that the page is shared between multiple address spaces
+    CcCopyOnWrite = SwBit::CopyOnWrite.mask() as isize, // Only for entries that point to pages. This bit indicates that the page should be copied on write
+    CcShared = SwBit::Shared.mask() as isize, // Only for entries that point to pages. This bit indicates that the page is shared between multiple address spaces
+    CcMmio = SwBit::Mmio.mask() as isize, // Only for entries that point to pages. This bit indicates that the page is device memory that is not owned by the PMM
     NoExecute = 1 << 63,
 }
 
+/// The bits of an entry that the MMU ignores and software may use, bits 9-11 and 52-58.
+/// Bits 59-62 are left alone since they hold the protection key when protection keys are enabled.
+const SW_AVAILABLE_BITS: u64 = 0x7 << 9 | 0x7F << 52;
+
+/// The software available bits of an entry and the kernel feature each of them is allocated to.
+/// Every feature that stores state in an entry must claim its bit here so that no two collide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(unused)]
+pub enum SwBit {
+    /// The page is copied on the first write to it
+    CopyOnWrite,
+    /// The page is shared between multiple address spaces
+    Shared,
+    /// The page is device memory that is not owned by the PMM
+    Mmio,
+    /// The entry is not present and a zeroed frame is mapped on the first access
+    DemandZero,
+    /// The entry is not present and the page belongs to a reserved range that must not be mapped
+    ReservedRange,
+}
+
+impl SwBit {
+    pub const ALL: [SwBit; 5] = [
+        SwBit::CopyOnWrite,
+        SwBit::Shared,
+        SwBit::Mmio,
+        SwBit::DemandZero,
+        SwBit::ReservedRange,
+    ];
+
+    /// Returns the index of the bit in the entry
+    pub const fn bit(self) -> u32 {
+        match self {
+            SwBit::CopyOnWrite => 52,
+            SwBit::Shared => 53,
+            SwBit::Mmio => 54,
+            SwBit::DemandZero => 9,
+            SwBit::ReservedRange => 10,
+        }
+    }
+
+    pub const fn mask(self) -> u64 {
+        1 << self.bit()
+    }
+
+    /// Returns the bits claimed by any feature
+    const fn allocated() -> u64 {
+        let mut mask = 0;
+        let mut i = 0;
+        while i < SwBit::ALL.len() {
+            mask |= SwBit::ALL[i].mask();
+            i += 1;
+        }
+        mask
+    }
+}
+
+// every bit must be one the MMU ignores and no two features may claim the same bit
+const _: () = {
+    let mut claimed = 0u64;
+    let mut i = 0;
+    while i < SwBit::ALL.len() {
+        let mask = SwBit::ALL[i].mask();
+        assert!(
+            mask & SW_AVAILABLE_BITS != 0,
+            "software bit is used by the MMU"
+        );
+        assert!(claimed & mask == 0, "software bit is claimed twice");
+        claimed |= mask;
+        i += 1;
+    }
+};
+
+/// The memory type of a mapping as selected by its PAT, PCD and PWT bits
+/// The encodings assume the power-on default contents of the IA32_PAT MSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[allow(unused)]
+pub enum CacheType {
+    /// Reads and writes are cached and writes only reach memory on eviction. This is by far the
+    /// fastest type for ordinary RAM so it is the default for all kernel memory.
+    #[default]
+    WriteBack,
+    /// Reads are cached but every write also goes to memory, useful for memory shared with
+    /// devices that do not snoop the cache.
+    WriteThrough,
+    /// Uncached unless overridden to write-combining by the MTRRs
+    UncachedMinus,
+    /// Never cached, required for most MMIO
+    Uncacheable,
+    /// Writes are buffered and combined before they reach memory, useful for framebuffers
+    WriteCombining,
+    /// Reads are cached and writes go to memory and invalidate the cached line on every LP
+    WriteProtected,
+}
+
+impl CacheType {
+    /// Returns the PCD and PWT bits that select this type
+    /// Only PAT entries 0-3 are used so the PAT bit is always clear. The power-on layout has no
+    /// entry for write-combining or write-protected memory so those fall back to uncached-minus.
+    pub const fn flags(self) -> u64 {
+        match self {
+            CacheType::WriteBack => 0,
+            CacheType::WriteThrough => PteFlags::WriteThrough as u64,
+            CacheType::UncachedMinus | CacheType::WriteCombining | CacheType::WriteProtected => {
+                PteFlags::CacheDisable as u64
+            }
+            CacheType::Uncacheable => PteFlags::WriteThrough as u64 | PteFlags::CacheDisable as u64,
+        }
+    }
+
+    /// Decodes a memory type encoding as it is stored in an entry of the IA32_PAT MSR
+    pub const fn from_pat_encoding(encoding: u8) -> Option<Self> {
+        match encoding {
+            0 => Some(CacheType::Uncacheable),
+            1 => Some(CacheType::WriteCombining),
+            4 => Some(CacheType::WriteThrough),
+            5 => Some(CacheType::WriteProtected),
+            6 => Some(CacheType::WriteBack),
+            7 => Some(CacheType::UncachedMinus),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for CacheType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let abbreviation = match self {
+            CacheType::WriteBack => "WB",
+            CacheType::WriteThrough => "WT",
+            CacheType::UncachedMinus => "UC-",
+            CacheType::Uncacheable => "UC",
+            CacheType::WriteCombining => "WC",
+            CacheType::WriteProtected => "WP",
+        };
+        f.write_str(abbreviation)
+    }
+}
+
+/// The IA32_PAT MSR
+const IA32_PAT_MSR: u32 = 0x277;
+
+/// The memory types held by the 8 entries of the page attribute table, which the PAT, PCD and PWT
+/// bits of a page's entry index into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatLayout(u64);
+
+impl PatLayout {
+    /// The layout the PAT has at power-on: WB, WT, UC-, UC, repeated for entries 4-7
+    #[allow(unused)]
+    pub const POWER_ON: PatLayout = PatLayout(0x0007_0406_0007_0406);
+
+    /// Reads the layout that is currently programmed on the calling LP
+    pub fn current() -> Self {
+        PatLayout(read_msr_u64(IA32_PAT_MSR))
+    }
+
+    /// Returns the memory type held by entry `index` or `None` if it holds a reserved encoding
+    pub const fn entry(self, index: usize) -> Option<CacheType> {
+        CacheType::from_pat_encoding((self.0 >> (index * 8)) as u8 & 0x7)
+    }
+
+    /// Returns the memory type that the PAT, PCD and PWT bits in the entry flags `flags` of a page
+    /// of `size` select
+    pub fn cache_type_of(self, flags: u64, size: PageSize) -> Option<CacheType> {
+        let pat_bit = match size {
+            PageSize::Standard => PteFlags::PageSizeOrPat as u64,
+            _ => PteFlags::HugeAndLargePat as u64,
+        };
+        let index = (flags & PteFlags::WriteThrough as u64 != 0) as usize
+            | ((flags & PteFlags::CacheDisable as u64 != 0) as usize) << 1
+            | ((flags & pat_bit != 0) as usize) << 2;
+        self.entry(index)
+    }
+}
+
+/// The kinds of memory the kernel maps and the entry flags each of them is mapped with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(unused)]
+pub enum MemType {
+    KernelCode,
+    KernelReadOnly,
+    KernelReadWrite,
+    /// Kernel memory that is both writable and executable, which the kernel never maps itself but
+    /// which may be left over from the bootloader
+    KernelWritableCode,
+    Mmio,
+    UserCode,
+    UserReadOnly,
+    UserReadWrite,
+    UserWritableCode,
+}
+
+impl MemType {
+    /// Returns the kind of memory that grants the given access, MMIO is never returned since it
+    /// differs from other memory only in its cache type
+    pub const fn with_access(user: bool, writable: bool, executable: bool) -> Self {
+        match (user, writable, executable) {
+            (false, false, true) => MemType::KernelCode,
+            (false, false, false) => MemType::KernelReadOnly,
+            (false, true, false) => MemType::KernelReadWrite,
+            (false, true, true) => MemType::KernelWritableCode,
+            (true, false, true) => MemType::UserCode,
+            (true, false, false) => MemType::UserReadOnly,
+            (true, true, false) => MemType::UserReadWrite,
+            (true, true, true) => MemType::UserWritableCode,
+        }
+    }
+
+    /// Returns true if user space may access this kind of memory
+    pub const fn is_user(self) -> bool {
+        matches!(
+            self,
+            MemType::UserCode
+                | MemType::UserReadOnly
+                | MemType::UserReadWrite
+                | MemType::UserWritableCode
+        )
+    }
+
+    /// Returns the entry flags for this kind of memory with its default cache type
+    pub const fn flags(self) -> u64 {
+        self.flags_with_cache(self.default_cache_type())
+    }
+
+    /// Returns the entry flags for this kind of memory with an explicitly chosen cache type
+    pub const fn flags_with_cache(self, cache_type: CacheType) -> u64 {
+        let access = match self {
+            MemType::KernelCode | MemType::UserCode => 0,
+            MemType::KernelReadOnly | MemType::UserReadOnly => PteFlags::NoExecute as u64,
+            MemType::KernelReadWrite | MemType::UserReadWrite => {
+                PteFlags::Write as u64 | PteFlags::NoExecute as u64
+            }
+            MemType::KernelWritableCode | MemType::UserWritableCode => PteFlags::Write as u64,
+            MemType::Mmio => {
+                PteFlags::Write as u64 | PteFlags::NoExecute as u64 | PteFlags::CcMmio as u64
+            }
+        };
+        // user pages are never global since every address space maps its own
+        let scope = if self.is_user() {
+            PteFlags::User as u64
+        } else {
+            PteFlags::Global as u64
+        };
+        PteFlags::Present as u64 | scope | access | cache_type.flags()
+    }
+
+    pub const fn default_cache_type(self) -> CacheType {
+        match self {
+            MemType::Mmio => CacheType::Uncacheable,
+            _ => CacheType::WriteBack,
+        }
+    }
+}
+
 static FLAG_MASK: u64 = PteFlags::Present as u64
     | PteFlags::Write as u64
     | PteFlags::User as u64
@@ -37,8 +293,7 @@ static FLAG_MASK: u64 = PteFlags::Present as u64
     | PteFlags::Dirty as u64
     | PteFlags::PageSizeOrPat as u64
     | PteFlags::Global as u64
-    | PteFlags::CcCopyOnWrite as u64
-    | PteFlags::CcShared as u64
+    | SwBit::allocated()
     | PteFlags::NoExecute as u64;
 
 static HUGE_AND_LARGE_PAGE_FLAG_MASK: u64 = FLAG_MASK | PteFlags::HugeAndLargePat as u64;
@@ -55,6 +310,18 @@ impl PageTableEntry {
         Self { entry: 0 }
     }
 
+    /// Creates an entry from its raw value
+    #[inline]
+    pub const fn from_bits(bits: u64) -> Self {
+        Self { entry: bits }
+    }
+
+    /// Returns the raw value of the entry
+    #[inline]
+    pub fn bits(&self) -> u64 {
+        self.entry
+    }
+
     #[inline]
     pub fn addr(&self) -> Result<PhysicalAddress, Error> {
         if self.is_present() == false {
@@ -64,6 +331,19 @@ impl PageTableEntry {
         }
     }
 
+    /// Returns the base address of the page mapped by this entry
+    /// For large and huge pages bit 12 is the PAT bit and the address bits below the page size are
+    /// reserved so they are masked off as well.
+    #[inline]
+    pub fn physical_address(&self, size: PageSize) -> Result<PhysicalAddress, Error> {
+        let page_mask = match size {
+            PageSize::Standard => !0xFFF,
+            PageSize::Large => !0x1F_FFFF,
+            PageSize::Huge => !0x3FFF_FFFF,
+        };
+        Ok(PhysicalAddress::from(self.addr()?.bits() & page_mask))
+    }
+
     pub fn map_table(&mut self, paddr: PhysicalAddress, flags: u64) -> Result<(), Error> {
         if self.is_present() {
             Err(Error::VAddrRangeUnavailable)
@@ -96,6 +376,34 @@ impl PageTableEntry {
         }
     }
 
+    /// Replaces the flags of a present entry that maps a standard page, keeping its address
+    pub fn set_page_flags(&mut self, flags: u64) -> Result<(), Error> {
+        if !self.is_present() {
+            Err(Error::EntryNotPresent)
+        } else {
+            self.entry = (self.entry & *ADDR_MASK) | (flags & FLAG_MASK);
+            Ok(())
+        }
+    }
+
+    /// Returns the memory type of the page mapped by this entry under the PAT layout `pat`, or
+    /// `None` if the PAT entry it selects holds a reserved encoding.
+    /// `size` determines whether bit 7 or bit 12 is the PAT bit.
+    pub fn cache_type(&self, size: PageSize, pat: PatLayout) -> Option<CacheType> {
+        pat.cache_type_of(self.entry, size)
+    }
+
+    /// Returns the flags set in this entry
+    /// `size` determines whether bit 12 is treated as the PAT bit or as part of the address
+    #[inline]
+    pub fn flags(&self, size: PageSize) -> u64 {
+        if size == PageSize::Standard {
+            self.entry & FLAG_MASK
+        } else {
+            self.entry & HUGE_AND_LARGE_PAGE_FLAG_MASK
+        }
+    }
+
     pub fn unmap(&mut self) -> Result<PhysicalAddress, Error> {
         let paddr = self.addr()?;
         self.entry = 0;
@@ -107,8 +415,253 @@ impl PageTableEntry {
         self.entry & PteFlags::Present as u64 != 0
     }
 
+    /// Returns true if the frame this entry maps was allocated from the PMM for this mapping alone
+    /// and should therefore be freed when the mapping is removed. Device memory and frames that
+    /// are shared with other address spaces are not owned by the mapping.
+    #[inline]
+    pub fn owns_frame(&self) -> bool {
+        self.entry & (PteFlags::CcMmio as u64 | PteFlags::CcShared as u64) == 0
+    }
+
+    /// Returns whether the software bit of `feature` is set
+    #[inline]
+    #[allow(unused)]
+    pub fn sw_bit(&self, feature: SwBit) -> bool {
+        self.entry & feature.mask() != 0
+    }
+
+    #[inline]
+    #[allow(unused)]
+    pub fn set_sw_bit(&mut self, feature: SwBit) {
+        self.entry |= feature.mask();
+    }
+
+    #[inline]
+    #[allow(unused)]
+    pub fn clear_sw_bit(&mut self, feature: SwBit) {
+        self.entry &= !feature.mask();
+    }
+
+    /// Returns true if the entry is not present but reserves its page to be backed by a zeroed
+    /// frame on the first access
+    #[inline]
+    pub fn is_demand_zero(&self) -> bool {
+        !self.is_present() && self.sw_bit(SwBit::DemandZero)
+    }
+
+    /// Reserves the page of this non-present entry to be backed by a zeroed frame on the first
+    /// access. The flags the page will be mapped with are kept in the entry until then, the MMU
+    /// ignores every bit of an entry that is not present.
+    #[allow(unused)]
+    pub fn set_demand_zero(&mut self, flags: u64) -> Result<(), Error> {
+        if self.is_present() {
+            Err(Error::VAddrRangeUnavailable)
+        } else {
+            self.entry =
+                (flags & FLAG_MASK & !(PteFlags::Present as u64)) | SwBit::DemandZero.mask();
+            Ok(())
+        }
+    }
+
+    /// Returns the flags a demand-zero entry's page is to be mapped with once it is backed
+    #[inline]
+    pub fn demand_zero_flags(&self) -> u64 {
+        (self.entry & FLAG_MASK & !SwBit::DemandZero.mask()) | PteFlags::Present as u64
+    }
+
     #[inline]
     pub fn is_size_bit_set(&self) -> bool {
         self.entry & PteFlags::PageSizeOrPat as u64 != 0
     }
+
+    /// Returns the bits of this entry that are reserved for an entry at `level` but set anyway.
+    /// Any of them being set in a present entry causes a page fault with the reserved bit flag set
+    /// when the entry is used for a translation.
+    pub fn reserved_bits(&self, level: PageTableLevel) -> u64 {
+        if !self.is_present() {
+            return 0;
+        }
+        // address bits at or above the physical address width
+        let mut reserved = ((1u64 << 52) - 1) & !((1u64 << *PADDR_SIGBITS) - 1);
+        match level {
+            PageTableLevel::PML4 => reserved |= PteFlags::PageSizeOrPat as u64,
+            // the address bits below the page size of huge and large pages, above the PAT bit
+            PageTableLevel::PDPT if self.is_size_bit_set() => reserved |= 0x3FFF_E000,
+            PageTableLevel::PD if self.is_size_bit_set() => reserved |= 0x1F_E000,
+            _ => {}
+        }
+        self.entry & reserved
+    }
+
+    /// Returns true if this entry is a PDPT or PD entry that maps a huge or large page directly
+    /// rather than pointing to a lower level table. Only meaningful for entries at those levels.
+    #[inline]
+    pub fn is_huge(&self) -> bool {
+        self.is_size_bit_set()
+    }
+
+    #[inline]
+    pub fn is_writable(&self) -> bool {
+        self.entry & PteFlags::Write as u64 != 0
+    }
+
+    #[inline]
+    pub fn is_user(&self) -> bool {
+        self.entry & PteFlags::User as u64 != 0
+    }
+
+    #[inline]
+    pub fn is_no_execute(&self) -> bool {
+        self.entry & PteFlags::NoExecute as u64 != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRESENT: u64 = PteFlags::Present as u64;
+    const WRITE: u64 = PteFlags::Write as u64;
+    const USER: u64 = PteFlags::User as u64;
+    const NO_EXECUTE: u64 = PteFlags::NoExecute as u64;
+    const PAGE_SIZE_OR_PAT: u64 = PteFlags::PageSizeOrPat as u64;
+    const LARGE_PAT: u64 = PteFlags::HugeAndLargePat as u64;
+
+    fn entry(bits: u64) -> PageTableEntry {
+        PageTableEntry { entry: bits }
+    }
+
+    /// The lowest address bit that is at or above the physical address width
+    fn first_reserved_addr_bit() -> u64 {
+        1 << *PADDR_SIGBITS
+    }
+
+    #[test]
+    fn accessors_decode_known_bit_patterns() {
+        let pte = entry(0x1234_5000 | PRESENT | WRITE | USER | NO_EXECUTE);
+        assert!(pte.is_present() && pte.is_writable() && pte.is_user() && pte.is_no_execute());
+        assert!(!pte.is_huge() && !pte.is_demand_zero());
+        assert!(pte.owns_frame());
+
+        let pte = entry(0x1234_5000 | PRESENT);
+        assert!(pte.is_present());
+        assert!(!pte.is_writable() && !pte.is_user() && !pte.is_no_execute());
+
+        let pte = entry(0x4000_0000 | PRESENT | PAGE_SIZE_OR_PAT | SwBit::Shared.mask());
+        assert!(pte.is_huge());
+        assert!(pte.sw_bit(SwBit::Shared) && !pte.owns_frame());
+
+        let pte = entry(WRITE | SwBit::DemandZero.mask());
+        assert!(!pte.is_present() && pte.is_demand_zero());
+        assert_eq!(pte.demand_zero_flags(), PRESENT | WRITE);
+        assert_eq!(pte.addr(), Err(Error::EntryNotPresent));
+    }
+
+    #[test]
+    fn addr_masks_flags_and_bits_above_the_address_width() {
+        let bits = 0x1234_5000 | PRESENT | WRITE | NO_EXECUTE | SwBit::CopyOnWrite.mask() | 0xFFF;
+        assert_eq!(entry(bits).addr(), Ok(PhysicalAddress::new(0x1234_5000)));
+        let bits = 0x1234_5000 | PRESENT | first_reserved_addr_bit();
+        assert_eq!(entry(bits).addr(), Ok(PhysicalAddress::new(0x1234_5000)));
+    }
+
+    #[test]
+    fn physical_address_masks_below_the_page_size() {
+        // bit 12 is the PAT bit of large and huge pages, not an address bit
+        let bits = 0x4060_0000 | LARGE_PAT | PRESENT | PAGE_SIZE_OR_PAT;
+        let pte = entry(bits);
+        assert_eq!(
+            pte.physical_address(PageSize::Standard),
+            Ok(PhysicalAddress::new(0x4060_1000))
+        );
+        assert_eq!(
+            pte.physical_address(PageSize::Large),
+            Ok(PhysicalAddress::new(0x4060_0000))
+        );
+        assert_eq!(
+            pte.physical_address(PageSize::Huge),
+            Ok(PhysicalAddress::new(0x4000_0000))
+        );
+        assert_eq!(
+            entry(bits & !PRESENT).physical_address(PageSize::Large),
+            Err(Error::EntryNotPresent)
+        );
+    }
+
+    #[test]
+    fn flags_only_keep_the_pat_bit_of_large_and_huge_pages() {
+        let pte = entry(0x4060_0000 | LARGE_PAT | PRESENT | WRITE | PAGE_SIZE_OR_PAT);
+        assert_eq!(
+            pte.flags(PageSize::Standard),
+            PRESENT | WRITE | PAGE_SIZE_OR_PAT
+        );
+        assert_eq!(
+            pte.flags(PageSize::Large),
+            LARGE_PAT | PRESENT | WRITE | PAGE_SIZE_OR_PAT
+        );
+        assert_eq!(pte.flags(PageSize::Huge), pte.flags(PageSize::Large));
+    }
+
+    #[test]
+    fn map_page_masks_the_address_and_the_flags() {
+        let mut pte = PageTableEntry::new();
+        assert_eq!(
+            pte.map_page(PhysicalAddress::new(0x1001), PRESENT, PageSize::Standard),
+            Err(Error::InvalidPAddrAlignment)
+        );
+        // bit 12 is dropped from the flags of a standard page
+        pte.map_page(
+            PhysicalAddress::new(0x20_0000),
+            PRESENT | WRITE | LARGE_PAT,
+            PageSize::Standard,
+        )
+        .unwrap();
+        assert_eq!(pte.bits(), 0x20_0000 | PRESENT | WRITE);
+        assert_eq!(
+            pte.map_page(PhysicalAddress::new(0x40_0000), PRESENT, PageSize::Standard),
+            Err(Error::VAddrRangeUnavailable)
+        );
+        assert_eq!(pte.unmap(), Ok(PhysicalAddress::new(0x20_0000)));
+        assert_eq!(pte.bits(), 0);
+
+        pte.map_page(
+            PhysicalAddress::new(0x20_0000),
+            PRESENT | PAGE_SIZE_OR_PAT | LARGE_PAT,
+            PageSize::Large,
+        )
+        .unwrap();
+        assert_eq!(
+            pte.bits(),
+            0x20_0000 | PRESENT | PAGE_SIZE_OR_PAT | LARGE_PAT
+        );
+    }
+
+    #[test]
+    fn reserved_bits_at_each_level() {
+        let high = first_reserved_addr_bit();
+        let table = entry(0x1000 | PRESENT | WRITE);
+        for level in [
+            PageTableLevel::PML4,
+            PageTableLevel::PDPT,
+            PageTableLevel::PD,
+            PageTableLevel::PT,
+        ] {
+            assert_eq!(table.reserved_bits(level), 0);
+            assert_eq!(entry(table.bits() | high).reserved_bits(level), high);
+            // nothing is reserved in an entry that is not present
+            assert_eq!(entry(high | PAGE_SIZE_OR_PAT).reserved_bits(level), 0);
+        }
+
+        let sized = entry(0x4000_0000 | PRESENT | PAGE_SIZE_OR_PAT);
+        assert_eq!(sized.reserved_bits(PageTableLevel::PML4), PAGE_SIZE_OR_PAT);
+        // bit 7 is the PAT bit of a standard page
+        assert_eq!(sized.reserved_bits(PageTableLevel::PT), 0);
+        assert_eq!(sized.reserved_bits(PageTableLevel::PDPT), 0);
+        assert_eq!(sized.reserved_bits(PageTableLevel::PD), 0);
+
+        // the PAT bit is never reserved but the address bits below the page size are
+        let sized = entry(sized.bits() | LARGE_PAT | 1 << 13 | 1 << 21);
+        assert_eq!(sized.reserved_bits(PageTableLevel::PDPT), 1 << 13 | 1 << 21);
+        assert_eq!(sized.reserved_bits(PageTableLevel::PD), 1 << 13);
+    }
 }
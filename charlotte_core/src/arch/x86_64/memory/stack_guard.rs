@@ -0,0 +1,85 @@
+//! # Stack Guards
+//! A registry of the unmapped guard pages below each kernel stack. A page fault inside one of them
+//! means that the stack above it overflowed, which the page fault handler reports as such instead
+//! of as a generic page fault. Note that the handler can only report an overflow of the stack it
+//! is not itself running on, until #PF is given its own IST stack an overflow of the current
+//! stack escalates to a double fault.
+
+use spin::mutex::spin::SpinMutex;
+
+use super::Error;
+use crate::memory::address::{PageCount, VirtualAddress};
+
+/// The maximum number of guard ranges that can be registered at the same time
+pub const MAX_STACK_GUARDS: usize = 64;
+
+/// A registered guard range `[start, end)` and the name of the stack's owner
+#[derive(Debug, Clone, Copy)]
+struct StackGuard {
+    start: u64,
+    end: u64,
+    owner: &'static str,
+}
+
+static STACK_GUARDS: SpinMutex<[Option<StackGuard>; MAX_STACK_GUARDS]> =
+    SpinMutex::new([None; MAX_STACK_GUARDS]);
+
+/// Registers the `n_pages` guard pages starting at `start` as belonging to the stack of `owner`
+/// # Returns
+/// Returns `Error::AddressInUse` if the range overlaps a registered guard and
+/// `Error::OutOfMemory` if the registry is full.
+#[allow(unused)]
+pub fn register(
+    start: VirtualAddress,
+    n_pages: PageCount,
+    owner: &'static str,
+) -> Result<(), Error> {
+    let end = start
+        .bits()
+        .checked_add(n_pages.bytes() as u64)
+        .ok_or(Error::InvalidAddress)?;
+    let mut guards = STACK_GUARDS.lock();
+    if guards
+        .iter()
+        .flatten()
+        .any(|guard| guard.start < end && start.bits() < guard.end)
+    {
+        return Err(Error::AddressInUse);
+    }
+    let slot = guards
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(Error::OutOfMemory)?;
+    *slot = Some(StackGuard {
+        start: start.bits(),
+        end,
+        owner,
+    });
+    Ok(())
+}
+
+/// Removes the guard range starting at `start`, e.g. when its stack is freed
+/// # Returns
+/// Returns `Error::NotMapped` if no guard range starts at `start`.
+#[allow(unused)]
+pub fn unregister(start: VirtualAddress) -> Result<(), Error> {
+    let mut guards = STACK_GUARDS.lock();
+    let slot = guards
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|guard| guard.start == start.bits()))
+        .ok_or(Error::NotMapped)?;
+    *slot = None;
+    Ok(())
+}
+
+/// Returns the owner of the stack whose guard range contains `vaddr`, if any.
+/// This is called from the page fault handler so it never waits for the registry lock, if the
+/// lock is held the address is not classified.
+pub fn find_owner(vaddr: VirtualAddress) -> Option<&'static str> {
+    let guards = STACK_GUARDS.try_lock()?;
+    guards
+        .iter()
+        .flatten()
+        .find(|guard| guard.start <= vaddr.bits() && vaddr.bits() < guard.end)
+        .map(|guard| guard.owner)
+}
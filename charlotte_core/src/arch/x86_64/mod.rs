@@ -3,32 +3,41 @@
 
 use core::convert::From;
 use core::fmt::Write;
-use core::str;
 use core::{
     borrow::{Borrow, BorrowMut},
     ptr::addr_of,
 };
 
-use memory::page_map::{asm_get_cr3, PageMap};
+use memory::page_map::{asm_get_cr3, FixedMapping, PageMap, RegionHint};
 use spin::lazy::Lazy;
 use spin::mutex::spin::SpinMutex;
 
 use cpu::*;
 use gdt::{tss::Tss, Gdt};
 use idt::*;
-use serial::{ComPort, SerialPort};
+use serial::{com1_rx_handler, ComPort, SerialPort};
 
+use crate::acpi::madt::MadtEntry;
 use crate::acpi::{parse, AcpiInfo};
 use crate::arch::x86_64::interrupts::apic::Apic;
-use crate::arch::x86_64::interrupts::isa_handler::register_iv_handler;
-use crate::arch::x86_64::memory::page_map::page_table::page_table_entry::PteFlags;
-use crate::arch::{HwTimerMode, IsaParams, MemoryMap, PagingParams};
+use crate::arch::x86_64::interrupts::isa_handler::{register_iv_handler, IntIdx};
+use crate::arch::x86_64::memory::page_map::page_table::page_table_entry::{
+    MemType, PatLayout, PteFlags,
+};
+use crate::arch::x86_64::memory::page_map::page_table::PageSize;
+use crate::arch::{ClockSource, HwTimerMode, IsaParams, MemoryMap, PagingParams, LOGGER};
+use crate::elf::Elf64;
 use crate::framebuffer::colors::Color;
 use crate::framebuffer::framebuffer::FRAMEBUFFER;
 use crate::logln;
-use crate::memory::address::{/*PhysicalAddress,*/ VirtualAddress};
-use crate::memory::pmm::PHYSICAL_FRAME_ALLOCATOR;
-
+use crate::memory::address::{/*PhysicalAddress,*/ PageCount, VirtualAddress};
+use crate::memory::dma::DmaBuffer;
+use crate::memory::layout;
+use crate::memory::pmm::{AllocationStrategy, PHYSICAL_FRAME_ALLOCATOR};
+use crate::{bootinfo, cmdline};
+
+mod backtrace;
+mod clock;
 mod cpu;
 mod exceptions;
 mod gdt;
@@ -37,6 +46,10 @@ mod idt;
 mod interrupts;
 mod memory;
 mod serial;
+mod watchdog;
+
+#[cfg(test)]
+pub use cpu::simulate_cpu;
 
 /// The Api struct is used to provide an implementation of the ArchApi trait for the x86_64 architecture.
 pub struct Api {
@@ -70,8 +83,34 @@ impl crate::arch::Api for Api {
         logln!("Initializing the bootstrap processor");
         Api::init_bsp();
         logln!("============================================================\n");
+        logln!("Protecting kernel image sections");
+        match memory::protect_kernel_sections() {
+            Ok(()) => {
+                logln!("Kernel image sections protected");
+            }
+            Err(e) => {
+                logln!("Failed to protect kernel image sections: {:?}", e);
+            }
+        }
+        if let Err(e) = memory::fixmap::init() {
+            logln!("Failed to allocate the fixmap page tables: {:?}", e);
+        }
+        logln!("============================================================\n");
         logln!("Parsing ACPI information");
         let tbls = parse();
+        let bsp_apic_id = current_apic_id();
+        logln!("BSP APIC ID: {}", bsp_apic_id);
+        let bsp_in_madt = tbls.madt().entries().any(|entry| match entry {
+            MadtEntry::ProcessorLocalApic(lapic) => lapic.apic_id as u32 == bsp_apic_id,
+            MadtEntry::ProcessorLocalX2Apic(x2apic) => x2apic.x2apic_id == bsp_apic_id,
+            _ => false,
+        });
+        if !bsp_in_madt {
+            logln!("Warning: the BSP's APIC ID is not listed in the MADT");
+        }
+        logln!("============================================================\n");
+        logln!("Initializing clock sources");
+        clock::init(tbls.hpet());
         logln!("============================================================\n");
         let mut api = Api {
             acpi_info: tbls,
@@ -131,8 +170,8 @@ impl crate::arch::Api for Api {
                 masked == 0 || masked == 0xFFFF800000000000
             }
             57 => {
-                let masked = raw & 0xFFFE000000000000;
-                masked == 0 || masked == 0xFFFE000000000000
+                let masked = raw & 0xFF00000000000000;
+                masked == 0 || masked == 0xFF00000000000000
             }
             _ => false,
         }
@@ -143,6 +182,11 @@ impl crate::arch::Api for Api {
         unsafe { asm_halt() }
     }
 
+    /// Wait for an interrupt unless the calling LP has work waiting
+    fn idle() {
+        cpu::idle()
+    }
+
     /// Kernel Panic
     fn panic() -> ! {
         unsafe { asm_halt() }
@@ -163,6 +207,10 @@ impl crate::arch::Api for Api {
     ///  Initialize the application processors (APs)
     fn init_ap(&mut self) {
         //! This routine is run by each application processor to initialize itself prior to being handed off to the scheduler.
+        logln!("Initializing AP with APIC ID {}", current_apic_id());
+        if let Some(index) = register_cpu() {
+            logln!("AP assigned logical CPU index {}", index);
+        }
     }
 
     fn setup_isa_timer(&mut self, tps: u32, mode: HwTimerMode, _: u16) {
@@ -204,8 +252,28 @@ impl crate::arch::Api for Api {
         irq_restore();
     }
 
+    fn irq_save() -> bool {
+        let enabled = asm_are_interrupts_enabled();
+        irq_disable();
+        enabled
+    }
+
+    fn irq_restore(enabled: bool) {
+        if enabled {
+            cpu::irq_restore();
+        }
+    }
+
+    fn current_cpu_index() -> Option<usize> {
+        cpu::current_cpu_index()
+    }
+
     fn init_interrupts(&mut self) {
         self.bsp_apic.enable(BSP_IDT.lock().borrow_mut());
+        register_iv_handler(com1_rx_handler, IntIdx::Com1 as u8);
+        SerialPort::try_new(ComPort::COM1)
+            .unwrap()
+            .enable_rx_interrupt();
     }
 
     fn set_interrupt_handler(&mut self, h: fn(vector: u64), vector: u32) {
@@ -219,6 +287,118 @@ impl crate::arch::Api for Api {
     fn end_of_interrupt() {
         Apic::signal_eoi();
     }
+
+    fn sync_instruction_cache(start: VirtualAddress, len: usize) {
+        cpu::sync_instruction_cache(start, len)
+    }
+
+    fn log_translation(vaddr: VirtualAddress) {
+        // SAFETY: reading CR3 has no side effects
+        let page_map = match PageMap::from_cr3(unsafe { asm_get_cr3() }) {
+            Ok(page_map) => page_map,
+            Err(e) => {
+                logln!("Unable to access the current page map: {:?}", e);
+                return;
+            }
+        };
+        let mapping = page_map.iter_mappings(false).find(|mapping| {
+            let page_size = match mapping.size {
+                PageSize::Standard => 0x1000,
+                PageSize::Large => 0x20_0000,
+                PageSize::Huge => 0x4000_0000,
+            };
+            (mapping.vaddr.bits()..mapping.vaddr.bits() + page_size).contains(&vaddr.bits())
+        });
+        match mapping {
+            Some(mapping) => {
+                let offset = vaddr.bits() - mapping.vaddr.bits();
+                logln!(
+                    "{} -> {} ({:?} page, flags: {:#x})",
+                    vaddr,
+                    mapping.paddr + offset,
+                    mapping.size,
+                    mapping.flags
+                );
+                match PatLayout::current().cache_type_of(mapping.flags, mapping.size) {
+                    Some(cache_type) => logln!("Memory type: {}", cache_type),
+                    None => logln!("Memory type: reserved PAT encoding"),
+                }
+                if let Ok(mem_type) = page_map.effective_permissions(vaddr) {
+                    logln!("Effective permissions: {:?}", mem_type);
+                }
+            }
+            None => {
+                logln!("{} is not mapped", vaddr);
+            }
+        }
+        let trace = page_map.explain(vaddr);
+        if let Some(level) = trace.missing_level() {
+            logln!("The {:?} entry is not present", level);
+        }
+        logln!("{}", trace);
+    }
+
+    fn clock_sources(&self) -> impl Iterator<Item = &'static dyn ClockSource> {
+        clock::sources()
+    }
+
+    fn log_boot_summary(&self) {
+        const MIB: u64 = 1024 * 1024;
+
+        let features = CpuFeatures::detect();
+        let memory = PHYSICAL_FRAME_ALLOCATOR.lock().memory_report();
+        // an LP is usable if it is enabled or can be brought online later
+        let n_lps = self
+            .acpi_info
+            .madt()
+            .entries()
+            .filter(|entry| match entry {
+                MadtEntry::ProcessorLocalApic(lapic) => lapic.flags & 0b11 != 0,
+                MadtEntry::ProcessorLocalX2Apic(x2apic) => x2apic.flags & 0b11 != 0,
+                _ => false,
+            })
+            .count();
+        let paging_levels = if is_cr4_feature_enabled(Cr4Feature::La57) {
+            5
+        } else {
+            4
+        };
+
+        let mut logger = LOGGER.lock();
+        let _ = writeln!(
+            logger,
+            "==================== Boot summary ===================="
+        );
+        let _ = writeln!(
+            logger,
+            "CPU:      {} family {:#X} model {:#X} stepping {:#X}",
+            features.vendor_str(),
+            features.family,
+            features.model,
+            features.stepping
+        );
+        let _ = writeln!(logger, "LPs:      {}", n_lps);
+        let _ = writeln!(
+            logger,
+            "Memory:   {} MiB usable, {} MiB free",
+            memory.usable / MIB,
+            memory.free / MIB
+        );
+        let _ = writeln!(logger, "Paging:   {}-level", paging_levels);
+        let _ = write!(logger, "Enabled:  ");
+        let enabled = features
+            .feature_states()
+            .into_iter()
+            .filter(|(_, _, enabled)| *enabled);
+        for (i, (name, _, _)) in enabled.enumerate() {
+            let _ = write!(logger, "{}{}", if i > 0 { ", " } else { "" }, name);
+        }
+        let _ = writeln!(logger);
+        let _ = writeln!(
+            logger,
+            "======================================================"
+        );
+    }
 }
 
 impl Api {
@@ -234,6 +414,9 @@ impl Api {
     fn init_bsp() {
         //! This routine is run by the bootstrap processor to initialize itself prior to bringing up the kernel.
         logln!("Processor information:");
+        if let Some(index) = register_cpu() {
+            logln!("BSP assigned logical CPU index {}", index);
+        }
         BSP_GDT.load();
         logln!("Loaded GDT");
         Gdt::reload_segment_regs();
@@ -249,9 +432,35 @@ impl Api {
         BSP_IDT.lock().borrow().load();
         logln!("Loaded IDT");
 
-        let mut vendor_string = [0u8; 12];
-        unsafe { asm_get_vendor_string(&mut vendor_string) }
-        logln!("CPU Vendor ID: {}", str::from_utf8(&vendor_string).unwrap());
+        // the GS base points at the BSP's per-CPU block since it was registered, keep it
+        init_gs_base(read_gs_base());
+        if !gs_base_paths_agree() {
+            panic!("rdgsbase and the IA32_GS_BASE MSR report different GS bases");
+        }
+        CpuFeatures::detect().log_summary();
+
+        logln!("Initializing the physical memory manager");
+        PHYSICAL_FRAME_ALLOCATOR.lock().init_from_memory_map();
+        PHYSICAL_FRAME_ALLOCATOR.lock().set_n_colors(cache_colors());
+        if let Some(strategy) = bootinfo::cmdline()
+            .and_then(|raw| cmdline::parse(raw).get("pmm_strategy"))
+            .and_then(AllocationStrategy::from_name)
+        {
+            PHYSICAL_FRAME_ALLOCATOR.lock().set_strategy(strategy);
+        }
+        logln!(
+            "Frame allocation strategy: {:?}",
+            PHYSICAL_FRAME_ALLOCATOR.lock().strategy()
+        );
+        logln!("Physical memory manager initialized");
+        let report = PHYSICAL_FRAME_ALLOCATOR.lock().memory_report();
+        logln!("Physical memory:\n{}", report);
+        if let Some(n_pages) = bootinfo::cmdline()
+            .and_then(|raw| cmdline::parse(raw).get("tlb_flush_threshold"))
+            .and_then(|value| value.parse().ok())
+        {
+            memory::page_map::set_tlb_flush_threshold(n_pages);
+        }
     }
 
     fn pmm_self_test() {
@@ -287,6 +496,29 @@ impl Api {
             logln!("Failed to deallocate frame: {:?}", e);
         }
         logln!("Single frame allocation and deallocation test complete.");
+        logln!("Performing frame pinning test.");
+        {
+            let mut pmm = PHYSICAL_FRAME_ALLOCATOR.lock();
+            let frame = pmm.allocate().expect("Failed to allocate a frame to pin");
+            pmm.pin_frame(frame)
+                .expect("Failed to pin an allocated frame");
+            if pmm.reclaimable_frames().any(|candidate| candidate == frame) {
+                panic!("The pinned frame {} is reclaimable", frame);
+            }
+            pmm.unpin_frame(frame)
+                .expect("Failed to unpin a pinned frame");
+            if !pmm.reclaimable_frames().any(|candidate| candidate == frame) {
+                panic!("The unpinned frame {} is not reclaimable", frame);
+            }
+            pmm.pin_frame(frame)
+                .expect("Failed to pin an allocated frame");
+            pmm.deallocate(frame)
+                .expect("Failed to free a pinned frame");
+            if pmm.is_pinned(frame) {
+                panic!("The frame {} is still pinned after being freed", frame);
+            }
+        }
+        logln!("Frame pinning test complete.");
         logln!("Performing contiguous frame allocation and deallocation test.");
         let contiguous_alloc = PHYSICAL_FRAME_ALLOCATOR.lock().allocate_contiguous(256, 64);
         match contiguous_alloc {
@@ -306,6 +538,26 @@ impl Api {
             }
         }
         logln!("Contiguous frame allocation and deallocation test complete.");
+        if bootinfo::cmdline().is_some_and(|raw| cmdline::parse(raw).flag("pmm_self_test")) {
+            logln!("Performing exhaustive frame allocation test.");
+            match PHYSICAL_FRAME_ALLOCATOR.lock().self_test() {
+                Ok(()) => {
+                    logln!("Exhaustive frame allocation test passed.");
+                }
+                Err(e) => panic!("Exhaustive frame allocation test failed: {:?}", e),
+            }
+        }
+        if bootinfo::cmdline().is_some_and(|raw| cmdline::parse(raw).flag("pmm_benchmark")) {
+            logln!("Benchmarking the frame allocator.");
+            match PHYSICAL_FRAME_ALLOCATOR.lock().benchmark(read_tsc) {
+                Ok(report) => {
+                    logln!("{}", report);
+                }
+                Err(e) => {
+                    logln!("Frame allocator benchmark failed: {:?}", e);
+                }
+            }
+        }
         logln!("Physical Memory Manager test suite finished.");
     }
 
@@ -317,6 +569,10 @@ impl Api {
             Err(e) => panic!("Failed to create PageMap from CR3: {:?}", e),
         };
         logln!("PageMap created from current CR3 value.");
+        // no processor has a physical address width above 52 bits
+        if PageMap::from_cr3(cr3 | 1 << 62).is_ok() {
+            panic!("A CR3 value with a PML4 beyond MAXPHYADDR was accepted");
+        }
 
         logln!("Starting page mapping test...");
         let frame = match PHYSICAL_FRAME_ALLOCATOR.lock().allocate() {
@@ -324,18 +580,25 @@ impl Api {
             Err(e) => panic!("Failed to allocate frame: {:?}", e),
         };
 
-        //map to the beginning of the higher half of the virtual address space i.e. the beginning of kernelspace
-        let vaddr = match VirtualAddress::try_from(0xFFFF800000000000) {
+        // the heap region is not in use yet, and every page size gets its own 1 GiB of it so that
+        // the tables one test leaves behind do not get in the way of the next one
+        let vaddr = match VirtualAddress::try_from(layout::KERNEL_HEAP.start) {
             Ok(vaddr) => vaddr,
             Err(e) => {
                 panic!("Failed to create VirtualAddress: {:?}", e);
             }
         };
-        pm.map_page(
-            vaddr,
-            frame,
-            PteFlags::Write as u64 | PteFlags::Global as u64 | PteFlags::NoExecute as u64,
-        );
+        let flags = PteFlags::Present as u64
+            | PteFlags::Write as u64
+            | PteFlags::Global as u64
+            | PteFlags::NoExecute as u64;
+        if let Err(e) = pm.map_page(vaddr, frame, flags) {
+            panic!("Failed to map page: {:?}", e);
+        }
+        match pm.translate(vaddr + 0x123usize) {
+            Ok(paddr) if paddr == frame + 0x123 => {}
+            other => panic!("The mapped page translated to {:?}", other),
+        }
         logln!(
             "Mapped page at virtual address: {:?} to physical frame: {:?}",
             vaddr,
@@ -348,10 +611,19 @@ impl Api {
             let val = ptr.read();
             logln!("Read value: 0x{:x} from virtual address: {:?}", val, vaddr);
         }
-        let _ = pm.unmap_page(vaddr);
+        let free_before = PHYSICAL_FRAME_ALLOCATOR.lock().free_frames();
+        // the size of the page is detected from the tables rather than named by the caller
+        match pm.unmap(vaddr) {
+            Ok((_, PageSize::Standard)) => {}
+            other => panic!("Unmapping the page returned {:?}", other),
+        }
         logln!("Unmapped page at virtual address: {:?}", vaddr);
-        let _ = PHYSICAL_FRAME_ALLOCATOR.lock().deallocate(frame);
-        logln!("Deallocated frame: {:?}", frame);
+        // the mapping owns its backing, so unmapping it returns every frame of it to the PMM
+        let freed = PHYSICAL_FRAME_ALLOCATOR.lock().free_frames() - free_before;
+        if freed != 1 {
+            panic!("Unmapping the page freed {} frames instead of {}", freed, 1);
+        }
+        logln!("Unmapping freed the frame: {:?}", frame);
         logln!("Page mapping test successful.");
 
         logln!("Starting large page mapping test...");
@@ -362,11 +634,10 @@ impl Api {
             Ok(frame) => frame,
             Err(e) => panic!("Failed to allocate frame: {:?}", e),
         };
-        pm.map_large_page(
-            vaddr,
-            large_frame,
-            PteFlags::Write as u64 | PteFlags::Global as u64 | PteFlags::NoExecute as u64,
-        );
+        let vaddr = vaddr + 0x4000_0000usize;
+        if let Err(e) = pm.map_large_page(vaddr, large_frame, flags) {
+            panic!("Failed to map large page: {:?}", e);
+        }
         logln!(
             "Mapped large page at virtual address: {:?} to physical frame: {:?}",
             vaddr,
@@ -379,12 +650,22 @@ impl Api {
             let val = ptr.read();
             logln!("Read value: 0x{:x} from virtual address: {:?}", val, vaddr);
         }
-        let _ = pm.unmap_large_page(vaddr);
+        let free_before = PHYSICAL_FRAME_ALLOCATOR.lock().free_frames();
+        // the size of the page is detected from the tables rather than named by the caller
+        match pm.unmap(vaddr) {
+            Ok((_, PageSize::Large)) => {}
+            other => panic!("Unmapping the large page returned {:?}", other),
+        }
         logln!("Unmapped large page at virtual address: {:?}", vaddr);
-        let _ = PHYSICAL_FRAME_ALLOCATOR
-            .lock()
-            .deallocate_contiguous(large_frame, 512);
-        logln!("Deallocated large frame: {:?}", large_frame);
+        // the mapping owns its backing, so unmapping it returns every frame of it to the PMM
+        let freed = PHYSICAL_FRAME_ALLOCATOR.lock().free_frames() - free_before;
+        if freed != 512 {
+            panic!(
+                "Unmapping the large page freed {} frames instead of {}",
+                freed, 512
+            );
+        }
+        logln!("Unmapping freed the large frame: {:?}", large_frame);
         logln!("Large page mapping test successful.");
 
         logln!("Starting huge page mapping test...");
@@ -395,11 +676,10 @@ impl Api {
             Ok(frame) => frame,
             Err(e) => panic!("Failed to allocate frame: {:?}", e),
         };
-        pm.map_huge_page(
-            vaddr,
-            huge_frame,
-            PteFlags::Write as u64 | PteFlags::Global as u64 | PteFlags::NoExecute as u64,
-        );
+        let vaddr = vaddr + 0x4000_0000usize;
+        if let Err(e) = pm.map_huge_page(vaddr, huge_frame, flags) {
+            panic!("Failed to map huge page: {:?}", e);
+        }
         logln!(
             "Mapped huge page at virtual address: {:?} to physical frame: {:?}",
             vaddr,
@@ -412,14 +692,240 @@ impl Api {
             let val = ptr.read();
             logln!("Read value: 0x{:x} from virtual address: {:?}", val, vaddr);
         }
-        let _ = pm.unmap_huge_page(vaddr);
+        let free_before = PHYSICAL_FRAME_ALLOCATOR.lock().free_frames();
+        // the size of the page is detected from the tables rather than named by the caller
+        match pm.unmap(vaddr) {
+            Ok((_, PageSize::Huge)) => {}
+            other => panic!("Unmapping the huge page returned {:?}", other),
+        }
         logln!("Unmapped huge page at virtual address: {:?}", vaddr);
-        let _ = PHYSICAL_FRAME_ALLOCATOR
-            .lock()
-            .deallocate_contiguous(huge_frame, 512 * 512);
-        logln!("Deallocated huge frame: {:?}", huge_frame);
+        // the mapping owns its backing, so unmapping it returns every frame of it to the PMM
+        let freed = PHYSICAL_FRAME_ALLOCATOR.lock().free_frames() - free_before;
+        if freed != 512 * 512 {
+            panic!(
+                "Unmapping the huge page freed {} frames instead of {}",
+                freed,
+                512 * 512
+            );
+        }
+        logln!("Unmapping freed the huge frame: {:?}", huge_frame);
         logln!("Huge page mapping test successful.");
 
+        logln!("Checking the guards between the higher half regions...");
+        for guard in layout::GUARDS {
+            let vaddr = VirtualAddress::try_from(guard.start).unwrap();
+            if pm.mapping_size(vaddr).is_ok() {
+                panic!("The guard page at {} is mapped", vaddr);
+            }
+        }
+        // two pages starting at the last page of the direct map would run into the guard after it
+        let start = VirtualAddress::try_from(layout::HHDM.end - 0x1000).unwrap();
+        let end = VirtualAddress::try_from(layout::KERNEL_HEAP.start + 0x4000).unwrap();
+        match pm.find_available_region(start, end, PageCount::new(2).unwrap(), 0x1000) {
+            Ok(found) if found.bits() == layout::KERNEL_HEAP.start => {}
+            other => panic!("A region across a guard page was found at {:?}", other),
+        }
+        logln!("Guard pages are unmapped and skipped by region searches.");
+
+        Self::address_space_self_test();
+
         logln!("VMM Self Test Complete.");
     }
+
+    /// Tests the ways of populating an address space on a new page map that is never loaded, so
+    /// that its empty lower half can be mapped freely
+    fn address_space_self_test() {
+        logln!("Testing mappings in a new address space...");
+        let mut space = match PageMap::new_with_kernel_mappings() {
+            Ok(space) => space,
+            Err(e) => panic!("Failed to create an address space: {:?}", e),
+        };
+        let flags = PteFlags::Present as u64 | PteFlags::Write as u64 | PteFlags::NoExecute as u64;
+        let one_page = PageCount::new(1).unwrap();
+
+        let frame = match PHYSICAL_FRAME_ALLOCATOR.lock().allocate() {
+            Ok(frame) => frame,
+            Err(e) => panic!("Failed to allocate frame: {:?}", e),
+        };
+        if let Err(e) = space.identity_map(frame, one_page, flags) {
+            panic!("Failed to identity map {}: {:?}", frame, e);
+        }
+        let vaddr = VirtualAddress::try_from(frame.bits()).unwrap();
+        match space.translate(vaddr) {
+            Ok(paddr) if paddr == frame => {}
+            other => panic!("The identity mapped page translated to {:?}", other),
+        }
+        if let Err(e) = space.unmap(vaddr) {
+            panic!("Failed to unmap the identity mapped page: {:?}", e);
+        }
+        logln!("Identity mapping test successful.");
+
+        let base = VirtualAddress::try_from(0x40_0000u64).unwrap();
+        let four_pages = PageCount::new(4).unwrap();
+        if let Err(e) = space.map_region_fixed(
+            base,
+            four_pages,
+            flags,
+            FixedMapping::NoReplace,
+            RegionHint::WriteHeavy,
+        ) {
+            panic!("Failed to map a fixed region at {:?}: {:?}", base, e);
+        }
+        for i in 0..four_pages.get() {
+            if let Err(e) = space.translate(base + i * 0x1000) {
+                panic!("Page {} of the fixed region is not mapped: {:?}", i, e);
+            }
+        }
+        match space.map_region_fixed(
+            base,
+            one_page,
+            flags,
+            FixedMapping::NoReplace,
+            RegionHint::WriteHeavy,
+        ) {
+            Err(memory::Error::AddressInUse) => {}
+            other => panic!("Mapping over a fixed region returned {:?}", other),
+        }
+        if let Err(e) = space.unmap_range(base, four_pages, false) {
+            panic!("Failed to unmap the fixed region: {:?}", e);
+        }
+        logln!("Fixed region mapping test successful.");
+
+        // a minimal executable with one writable segment of 8 file bytes followed by its BSS
+        let mut image = [0u8; 128];
+        image[..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        image[16..18].copy_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        image[18..20].copy_from_slice(&62u16.to_le_bytes()); // EM_X86_64
+        image[20..24].copy_from_slice(&1u32.to_le_bytes());
+        image[24..32].copy_from_slice(&0x40_0000u64.to_le_bytes());
+        image[32..40].copy_from_slice(&64u64.to_le_bytes());
+        image[52..54].copy_from_slice(&64u16.to_le_bytes());
+        image[54..56].copy_from_slice(&56u16.to_le_bytes());
+        image[56..58].copy_from_slice(&1u16.to_le_bytes());
+        image[64..68].copy_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+        image[68..72].copy_from_slice(&6u32.to_le_bytes()); // PF_R | PF_W
+        image[72..80].copy_from_slice(&120u64.to_le_bytes());
+        image[80..88].copy_from_slice(&0x40_0000u64.to_le_bytes());
+        image[96..104].copy_from_slice(&8u64.to_le_bytes());
+        image[104..112].copy_from_slice(&0x1800u64.to_le_bytes());
+        image[112..120].copy_from_slice(&0x1000u64.to_le_bytes());
+        image[120..].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let elf = match Elf64::parse(&image) {
+            Ok(elf) => elf,
+            Err(e) => panic!("Failed to parse the test image: {:?}", e),
+        };
+        match space.load_elf(&elf) {
+            Ok(entry) if entry == base => {}
+            other => panic!("Loading the test image returned {:?}", other),
+        }
+        for page in 0..2usize {
+            let paddr = match space.translate(base + page * 0x1000) {
+                Ok(paddr) => paddr,
+                Err(e) => panic!("Page {} of the test image is not mapped: {:?}", page, e),
+            };
+            // SAFETY: the frame was allocated by load_elf and is reachable through the direct map
+            let bytes = unsafe { core::slice::from_raw_parts(<*mut u8>::from(paddr), 0x1000) };
+            let (data, bss) = bytes.split_at(if page == 0 { 8 } else { 0 });
+            if data != &image[120..120 + data.len()] || bss.iter().any(|&b| b != 0) {
+                panic!("Page {} of the test image has the wrong contents", page);
+            }
+        }
+        if let Err(e) = space.unmap_range(base, PageCount::new(2).unwrap(), false) {
+            panic!("Failed to unmap the test image: {:?}", e);
+        }
+        logln!("ELF loading test successful.");
+
+        let buf = match DmaBuffer::allocate(PageCount::new(2).unwrap()) {
+            Ok(buf) => buf,
+            Err(e) => panic!("Failed to allocate a DMA buffer: {:?}", e),
+        };
+        let dma_vaddr = match space.map_dma_buffer(&buf, MemType::KernelReadWrite) {
+            Ok(vaddr) => vaddr,
+            Err(e) => panic!("Failed to map the DMA buffer: {:?}", e),
+        };
+        for page in 0..2usize {
+            match space.translate(dma_vaddr + page * 0x1000) {
+                Ok(paddr) if paddr == buf.paddr() + page as u64 * 0x1000 => {}
+                other => panic!("Page {} of the DMA buffer translated to {:?}", page, other),
+            }
+        }
+        if let Err(e) = space.unmap_range(dma_vaddr, buf.n_pages(), false) {
+            panic!("Failed to unmap the DMA buffer: {:?}", e);
+        }
+        drop(buf);
+        logln!("DMA buffer mapping test successful.");
+
+        if let Err(e) = space.map_region_fixed(
+            base,
+            one_page,
+            flags | PteFlags::User as u64,
+            FixedMapping::NoReplace,
+            RegionHint::WriteHeavy,
+        ) {
+            panic!("Failed to map a page to fork: {:?}", e);
+        }
+        let shared = space.translate(base).unwrap();
+        let mut child = match space.fork() {
+            Ok(child) => child,
+            Err(e) => panic!("Failed to fork the address space: {:?}", e),
+        };
+        if PHYSICAL_FRAME_ALLOCATOR.lock().sharers(shared) != 1 {
+            panic!("The forked page is not shared with the child");
+        }
+        if let Err(e) = child.resolve_cow_fault(base) {
+            panic!(
+                "Failed to resolve a copy-on-write fault in the child: {:?}",
+                e
+            );
+        }
+        if child.translate(base).is_ok_and(|paddr| paddr == shared)
+            || PHYSICAL_FRAME_ALLOCATOR.lock().sharers(shared) != 0
+        {
+            panic!("The child did not get a private copy of the forked page");
+        }
+        // the parent is the last page map referencing the frame so it takes it over
+        if let Err(e) = space.resolve_cow_fault(base) {
+            panic!(
+                "Failed to resolve a copy-on-write fault in the parent: {:?}",
+                e
+            );
+        }
+        if space.translate(base).ok() != Some(shared) {
+            panic!("The parent copied a frame it was the last sharer of");
+        }
+        for page_map in [&mut child, &mut space] {
+            if let Err(e) = page_map.unmap_range(base, one_page, false) {
+                panic!("Failed to unmap the forked page: {:?}", e);
+            }
+        }
+        if let Err(e) = child.destroy() {
+            panic!("Failed to destroy the child: {:?}", e);
+        }
+        logln!("Fork test successful.");
+
+        if let Err(e) = space.map_anonymous(base, one_page, flags | PteFlags::User as u64) {
+            panic!("Failed to reserve an anonymous page: {:?}", e);
+        }
+        if space.committed_pages() != 1 || space.resident_pages() != 0 {
+            panic!("A reserved anonymous page was not committed without being resident");
+        }
+        if let Err(e) = space.resolve_demand_zero_fault(base) {
+            panic!("Failed to back the anonymous page: {:?}", e);
+        }
+        if space.committed_pages() != 1 || space.resident_pages() != 1 {
+            panic!("Backing an anonymous page did not make it resident");
+        }
+        if let Err(e) = space.unmap_range(base, one_page, false) {
+            panic!("Failed to unmap the anonymous page: {:?}", e);
+        }
+        if space.committed_pages() != 0 || space.resident_pages() != 0 {
+            panic!("Unmapping an anonymous page did not release it");
+        }
+        logln!("Anonymous memory accounting test successful.");
+
+        if let Err(e) = space.destroy() {
+            panic!("Failed to destroy the address space: {:?}", e);
+        }
+        logln!("Address space tests successful.");
+    }
 }
@@ -1,6 +1,60 @@
 use core::fmt::{self, Write};
 
+use crate::arch::x86_64::interrupts::apic::Apic;
 use crate::arch::{Api, ArchApi, Serial};
+use crate::sync::IrqSafeMutex;
+
+const RX_BUFFER_SIZE: usize = 256;
+
+/// Bytes received by the serial RX interrupt handler that have not been read yet
+/// Readers take this lock with interrupts disabled so that the RX interrupt handler can never spin
+/// on it while it is held by the code it interrupted.
+static RX_BUFFER: IrqSafeMutex<RxRingBuffer> = IrqSafeMutex::new(RxRingBuffer::new());
+
+struct RxRingBuffer {
+    buf: [u8; RX_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl RxRingBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [0u8; RX_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Appends a byte to the buffer, bytes received while the buffer is full are dropped
+    fn push(&mut self, byte: u8) {
+        if self.len < RX_BUFFER_SIZE {
+            self.buf[(self.head + self.len) % RX_BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            None
+        } else {
+            let byte = self.buf[self.head];
+            self.head = (self.head + 1) % RX_BUFFER_SIZE;
+            self.len -= 1;
+            Some(byte)
+        }
+    }
+}
+
+/// Interrupt handler for the COM1 receive interrupt
+/// Drains the receive FIFO into the RX buffer so that no input is lost between reads.
+pub fn com1_rx_handler(_vector: u64) {
+    let port = SerialPort {
+        io_port: ComPort::COM1 as u16,
+    };
+    port.drain_rx_fifo();
+    Apic::signal_eoi();
+}
 
 #[allow(unused)]
 pub enum ComPort {
@@ -46,6 +100,54 @@ impl SerialPort {
     fn received(&self) -> bool {
         (ArchApi::inb(self.io_port + 5) & 1) != 0
     }
+
+    /// Reads a byte directly from the receive register if the line status register reports that
+    /// one is available.
+    pub fn read_byte(&self) -> Option<u8> {
+        if self.received() {
+            Some(ArchApi::inb(self.io_port))
+        } else {
+            None
+        }
+    }
+
+    /// Enables the received data available interrupt so that incoming bytes are buffered by
+    /// [com1_rx_handler] instead of having to be polled for.
+    pub fn enable_rx_interrupt(&self) {
+        ArchApi::outb(self.io_port + 1, 0x01);
+    }
+
+    /// Moves every byte waiting in the receive FIFO into the RX buffer
+    fn drain_rx_fifo(&self) {
+        while let Some(byte) = self.read_byte() {
+            RX_BUFFER.lock().push(byte);
+        }
+    }
+
+    /// Returns the next byte received, preferring bytes already buffered by the RX interrupt handler
+    fn next_byte(&self) -> Option<u8> {
+        let buffered = RX_BUFFER.lock().pop();
+        buffered.or_else(|| self.read_byte())
+    }
+
+    /// Reads bytes into `buf` until a line terminator is received or `buf` is full.
+    /// The line terminator is not stored in `buf`.
+    /// # Returns
+    /// The number of bytes stored in `buf`
+    #[allow(unused)]
+    pub fn read_line(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        while len < buf.len() {
+            if let Some(byte) = self.next_byte() {
+                if byte == b'\r' || byte == b'\n' {
+                    break;
+                }
+                buf[len] = byte;
+                len += 1;
+            }
+        }
+        len
+    }
 }
 
 impl Write for SerialPort {
@@ -73,10 +175,134 @@ impl Write for SerialPort {
 
 impl Serial for SerialPort {
     fn read_char(&mut self) -> char {
-        while !self.received() {}
-        ArchApi::inb(self.io_port) as char
+        loop {
+            if let Some(byte) = self.next_byte() {
+                return byte as char;
+            }
+        }
     }
     fn put_char(&mut self, c: char) {
         self.write_char(c).unwrap()
     }
 }
+
+/// A 16550 UART at COM1 that port I/O goes to in unit tests, which run as a user space process that
+/// may not access I/O ports. Each test thread has its own UART.
+#[cfg(test)]
+pub mod simulated {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    use super::ComPort;
+
+    /// LCR bit 7, the data register and IER hold the baud rate divisor while it is set
+    const LCR_DLAB: u8 = 0x80;
+    /// MCR bit 4, transmitted bytes are received again instead of being sent
+    const MCR_LOOPBACK: u8 = 0x10;
+    /// LSR bit 0, a received byte is waiting in the data register
+    const LSR_DATA_READY: u8 = 0x01;
+    /// LSR bits 5 and 6, the transmit holding register and the transmitter are empty
+    const LSR_TRANSMIT_EMPTY: u8 = 0x60;
+
+    #[derive(Default)]
+    struct Uart {
+        ier: u8,
+        lcr: u8,
+        mcr: u8,
+        received: VecDeque<u8>,
+        transmitted: Vec<u8>,
+    }
+
+    std::thread_local! {
+        static COM1: RefCell<Uart> = RefCell::new(Uart::default());
+    }
+
+    /// Returns the register of COM1 that `port` selects, `None` for every other port
+    fn register(port: u16) -> Option<u16> {
+        let base = ComPort::COM1 as u16;
+        (base..base + 8).contains(&port).then(|| port - base)
+    }
+
+    pub fn outb(port: u16, val: u8) {
+        COM1.with_borrow_mut(|uart| match register(port) {
+            Some(0) if uart.lcr & LCR_DLAB != 0 => {}
+            Some(0) if uart.mcr & MCR_LOOPBACK != 0 => uart.received.push_back(val),
+            Some(0) => uart.transmitted.push(val),
+            Some(1) if uart.lcr & LCR_DLAB == 0 => uart.ier = val,
+            Some(3) => uart.lcr = val,
+            Some(4) => uart.mcr = val,
+            _ => {}
+        })
+    }
+
+    pub fn inb(port: u16) -> u8 {
+        COM1.with_borrow_mut(|uart| match register(port) {
+            Some(0) => uart.received.pop_front().unwrap_or(0),
+            Some(1) => uart.ier,
+            Some(3) => uart.lcr,
+            Some(4) => uart.mcr,
+            Some(5) if uart.received.is_empty() => LSR_TRANSMIT_EMPTY,
+            Some(5) => LSR_TRANSMIT_EMPTY | LSR_DATA_READY,
+            _ => 0xFF,
+        })
+    }
+
+    /// Returns the interrupts enabled in the IER of the calling thread's COM1
+    pub fn enabled_interrupts() -> u8 {
+        COM1.with_borrow(|uart| uart.ier)
+    }
+
+    /// Takes the bytes that the calling thread sent on COM1 outside of loopback mode
+    pub fn take_transmitted() -> Vec<u8> {
+        COM1.with_borrow_mut(|uart| core::mem::take(&mut uart.transmitted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MCR with DTR, RTS, OUT2 and loopback set, as used by [SerialPort::try_new] to test the chip
+    const MCR_LOOPBACK: u8 = 0x1E;
+
+    #[test]
+    fn looped_back_line_is_buffered_by_the_rx_handler_and_read_back() {
+        let mut port = SerialPort::try_new(ComPort::COM1).unwrap();
+        port.enable_rx_interrupt();
+        assert_eq!(simulated::enabled_interrupts(), 0x01);
+
+        ArchApi::outb(port.io_port + 4, MCR_LOOPBACK);
+        port.write_str("kmon\n").unwrap();
+        // what com1_rx_handler does when the received data available interrupt fires
+        port.drain_rx_fifo();
+        assert_eq!(port.read_byte(), None);
+
+        let mut buf = [0u8; 16];
+        let len = port.read_line(&mut buf);
+        assert_eq!(&buf[..len], b"kmon");
+        // the line was terminated by the \r, so the \n is still buffered
+        assert_eq!(port.next_byte(), Some(b'\n'));
+        assert_eq!(port.next_byte(), None);
+    }
+
+    #[test]
+    fn written_bytes_are_transmitted_with_crlf_line_endings() {
+        let mut port = SerialPort::try_new(ComPort::COM1).unwrap();
+        simulated::take_transmitted();
+        port.write_str("a\nb").unwrap();
+        assert_eq!(simulated::take_transmitted(), b"a\r\nb");
+    }
+
+    #[test]
+    fn rx_buffer_drops_bytes_received_while_full() {
+        let mut buffer = RxRingBuffer::new();
+        for byte in 0..=RX_BUFFER_SIZE {
+            buffer.push(byte as u8);
+        }
+        for byte in 0..RX_BUFFER_SIZE {
+            assert_eq!(buffer.pop(), Some(byte as u8));
+        }
+        assert_eq!(buffer.pop(), None);
+    }
+}
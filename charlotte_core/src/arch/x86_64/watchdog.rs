@@ -0,0 +1,182 @@
+//! # Watchdog
+//! A watchdog timer built on the local APIC timer. Once armed it must be petted more often than
+//! its timeout or it will expire, log a backtrace of the code it interrupted and invoke its
+//! expiry callback.
+//!
+//! The timer raises an NMI rather than a fixed interrupt so that code hanging with interrupts
+//! disabled is caught as well. Each LP has its own watchdog, which takes over the APIC timer of
+//! that LP so it cannot be used at the same time as the ISA timer tick on it.
+
+use core::fmt::Write;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+use core::time::Duration;
+
+use ignore_result::Ignore;
+
+use crate::arch::x86_64::backtrace::log_backtrace;
+use crate::arch::x86_64::cpu::{current_cpu_index, MAX_CPUS};
+#[cfg(not(test))]
+use crate::arch::x86_64::interrupts::apic::Apic as Timer;
+use crate::arch::x86_64::interrupts::apic::{Apic, TimerDivisor};
+use crate::arch::x86_64::serial::{ComPort::COM1, SerialPort};
+#[cfg(test)]
+use simulated::Timer;
+
+/// The initial count each LP's timer is reloaded with when petted, 0 if its watchdog is not armed
+static WATCHDOG_COUNTS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+/// The `fn()` each LP's watchdog calls when it expires, null if there is none. The NMI handler
+/// cannot wait for a lock that the code it interrupted may hold, so the callback is kept in an
+/// atomic instead.
+static WATCHDOG_CALLBACKS: [AtomicPtr<()>; MAX_CPUS] =
+    [const { AtomicPtr::new(null_mut()) }; MAX_CPUS];
+
+/// Arms the calling LP's watchdog so that `on_expiry` is called if it is not petted within
+/// `timeout`. Nothing is armed if the LP has not been assigned a logical index.
+#[allow(unused)]
+pub fn arm(apic: &Apic, timeout: Duration, on_expiry: fn()) {
+    let (count, divisor) = apic.timer_params_for(timeout);
+    arm_ticks(count.max(1), divisor, on_expiry);
+}
+
+/// Arms the calling LP's watchdog to expire once `count` timer ticks at `divisor` have elapsed
+fn arm_ticks(count: u32, divisor: TimerDivisor, on_expiry: fn()) {
+    let Some(index) = current_cpu_index() else {
+        return;
+    };
+    // the callback is published before the timer is started so the NMI always finds it
+    WATCHDOG_CALLBACKS[index].store(on_expiry as *mut (), Ordering::Release);
+    WATCHDOG_COUNTS[index].store(count, Ordering::Release);
+    Timer::start_oneshot_nmi_timer(count, divisor);
+}
+
+/// Resets the countdown of the calling LP's watchdog, this has no effect if it is not armed
+#[allow(unused)]
+pub fn pet() {
+    let Some(index) = current_cpu_index() else {
+        return;
+    };
+    let count = WATCHDOG_COUNTS[index].load(Ordering::Acquire);
+    if count != 0 {
+        Timer::reset_timer_count(count);
+    }
+}
+
+/// Stops the calling LP's watchdog without invoking its expiry callback
+#[allow(unused)]
+pub fn disarm() {
+    let Some(index) = current_cpu_index() else {
+        return;
+    };
+    WATCHDOG_COUNTS[index].store(0, Ordering::Release);
+    Timer::mask_timer();
+    WATCHDOG_CALLBACKS[index].store(null_mut(), Ordering::Release);
+}
+
+/// Handles an NMI if the calling LP's watchdog raised it, which is the case if the watchdog is
+/// armed and its countdown has run out. The watchdog is disarmed before its callback is invoked.
+/// # Returns
+/// Returns false if the NMI has another source.
+pub fn handle_nmi() -> bool {
+    let Some(index) = current_cpu_index() else {
+        return false;
+    };
+    if WATCHDOG_COUNTS[index].load(Ordering::Acquire) == 0 || Timer::timer_count() != 0 {
+        return false;
+    }
+    WATCHDOG_COUNTS[index].store(0, Ordering::Release);
+    // The logger may be held by the code that hung so write to the serial port directly
+    let mut logger = SerialPort::try_new(COM1).unwrap();
+    writeln!(&mut logger, "Watchdog expired! Backtrace:").ignore();
+    log_backtrace(&mut logger);
+
+    let callback = WATCHDOG_CALLBACKS[index].swap(null_mut(), Ordering::AcqRel);
+    if !callback.is_null() {
+        // SAFETY: only `fn()` pointers are stored in WATCHDOG_CALLBACKS
+        let on_expiry = unsafe { core::mem::transmute::<*mut (), fn()>(callback) };
+        on_expiry();
+    }
+    true
+}
+
+/// A one-shot APIC timer for each test thread that raises the watchdog's NMI when it runs out
+#[cfg(test)]
+mod simulated {
+    use core::cell::Cell;
+
+    use crate::arch::x86_64::interrupts::apic::TimerDivisor;
+
+    std::thread_local! {
+        /// The current count of the timer, `None` while it is masked
+        static CURRENT_COUNT: Cell<Option<u32>> = const { Cell::new(None) };
+    }
+
+    pub struct Timer;
+
+    impl Timer {
+        pub fn start_oneshot_nmi_timer(count: u32, _divisor: TimerDivisor) {
+            CURRENT_COUNT.set(Some(count));
+        }
+
+        pub fn reset_timer_count(count: u32) {
+            if CURRENT_COUNT.get().is_some() {
+                CURRENT_COUNT.set(Some(count));
+            }
+        }
+
+        pub fn mask_timer() {
+            CURRENT_COUNT.set(None);
+        }
+
+        pub fn timer_count() -> u32 {
+            CURRENT_COUNT.get().unwrap_or(0)
+        }
+    }
+
+    /// Lets `ticks` ticks of the timer elapse and delivers its NMI if the countdown runs out
+    pub fn elapse(ticks: u32) {
+        if let Some(count @ 1..) = CURRENT_COUNT.get() {
+            let remaining = count.saturating_sub(ticks);
+            CURRENT_COUNT.set(Some(remaining));
+            if remaining == 0 {
+                super::handle_nmi();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::x86_64::cpu::register_cpu;
+    use core::sync::atomic::AtomicUsize;
+
+    static EXPIRIES: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_expiry() {
+        EXPIRIES.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn missed_pet_invokes_the_expiry_callback() {
+        register_cpu().unwrap();
+        arm_ticks(100, TimerDivisor::Div1, count_expiry);
+        simulated::elapse(60);
+        pet();
+        simulated::elapse(60);
+        assert_eq!(EXPIRIES.load(Ordering::SeqCst), 0);
+
+        simulated::elapse(40);
+        assert_eq!(EXPIRIES.load(Ordering::SeqCst), 1);
+        // an expired watchdog is disarmed and petting it does not restart it
+        pet();
+        simulated::elapse(200);
+        assert!(!handle_nmi());
+        assert_eq!(EXPIRIES.load(Ordering::SeqCst), 1);
+
+        arm_ticks(100, TimerDivisor::Div1, count_expiry);
+        disarm();
+        simulated::elapse(200);
+        assert_eq!(EXPIRIES.load(Ordering::SeqCst), 1);
+    }
+}
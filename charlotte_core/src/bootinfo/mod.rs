@@ -1,10 +1,12 @@
 //! # Boot Information
 //! This module contains requests for information from the Limine boot protocol.
 
+use core::fmt;
 pub use limine::memory_map;
 pub use limine::request::*;
 #[allow(unused)]
 pub use limine::response::*;
+
 use limine::BaseRevision;
 
 #[allow(unused)]
@@ -22,3 +24,57 @@ pub static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
 
 /// This request is used to obtain RSDP data
 pub static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
+
+/// This request is used to obtain the physical and virtual base addresses of the kernel image
+pub static KERNEL_ADDRESS_REQUEST: KernelAddressRequest = KernelAddressRequest::new();
+
+/// This request is used to obtain the kernel file and the command line it was booted with
+pub static KERNEL_FILE_REQUEST: KernelFileRequest = KernelFileRequest::new();
+
+/// Returns the raw kernel command line passed by the bootloader, if there is one
+pub fn cmdline() -> Option<&'static str> {
+    let response = KERNEL_FILE_REQUEST.get_response()?;
+    core::str::from_utf8(response.file().cmdline()).ok()
+}
+
+/// A request the kernel cannot run without that the bootloader did not answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingResponse {
+    MemoryMap,
+    Hhdm,
+    KernelAddress,
+    Rsdp,
+}
+
+impl fmt::Display for MissingResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MissingResponse::MemoryMap => "memory map",
+            MissingResponse::Hhdm => "higher half direct map",
+            MissingResponse::KernelAddress => "kernel address",
+            MissingResponse::Rsdp => "RSDP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Checks that the bootloader answered every request the kernel depends on.
+/// This is meant to be called before anything else so that a missing response is reported by
+/// name instead of surfacing as a panic deep inside whichever code happens to use it first.
+/// # Returns
+/// Returns the first request that was not answered.
+pub fn validate_responses() -> Result<(), MissingResponse> {
+    if MEMORY_MAP_REQUEST.get_response().is_none() {
+        return Err(MissingResponse::MemoryMap);
+    }
+    if HHDM_REQUEST.get_response().is_none() {
+        return Err(MissingResponse::Hhdm);
+    }
+    if KERNEL_ADDRESS_REQUEST.get_response().is_none() {
+        return Err(MissingResponse::KernelAddress);
+    }
+    if RSDP_REQUEST.get_response().is_none() {
+        return Err(MissingResponse::Rsdp);
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+//! # Kernel Command Line
+//! Parses the kernel command line into `key=value` options and boolean flags.
+//! Tokens are separated by whitespace, a token without an `=` is a boolean flag.
+
+#[derive(Debug, Clone, Copy)]
+pub struct CmdLine<'a> {
+    raw: &'a str,
+}
+
+/// Parses the kernel command line
+pub fn parse(raw: &str) -> CmdLine<'_> {
+    CmdLine { raw }
+}
+
+impl<'a> CmdLine<'a> {
+    /// Iterates over each option on the command line in order as `(key, value)` pairs where
+    /// `value` is `None` for boolean flags
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, Option<&'a str>)> {
+        self.raw
+            .split_whitespace()
+            .map(|token| match token.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (token, None),
+            })
+    }
+
+    /// Returns the value of the option `key`, if the option is repeated the last value wins
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.iter()
+            .filter(|(k, _)| *k == key)
+            .filter_map(|(_, value)| value)
+            .last()
+    }
+
+    /// Checks whether the boolean flag `key` is present
+    pub fn flag(&self, key: &str) -> bool {
+        self.iter().any(|(k, value)| k == key && value.is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_and_flags_are_split_on_whitespace() {
+        let args = parse("  log_level=debug\tserial_log_only  kmon_debug\n");
+        let options: Vec<_> = args.iter().collect();
+        assert_eq!(
+            options,
+            [
+                ("log_level", Some("debug")),
+                ("serial_log_only", None),
+                ("kmon_debug", None)
+            ]
+        );
+    }
+
+    #[test]
+    fn last_value_wins() {
+        let args = parse("pmm=linear pmm pmm=recycling");
+        assert_eq!(args.get("pmm"), Some("recycling"));
+        assert_eq!(args.get("missing"), None);
+    }
+
+    #[test]
+    fn flags_are_only_tokens_without_a_value() {
+        let args = parse("quiet=1 debug a=b=c empty=");
+        assert!(!args.flag("quiet"));
+        assert!(args.flag("debug"));
+        assert_eq!(args.get("quiet"), Some("1"));
+        // only the first `=` separates the key from the value
+        assert_eq!(args.get("a"), Some("b=c"));
+        assert_eq!(args.get("empty"), Some(""));
+        assert_eq!(parse("").iter().count(), 0);
+    }
+}
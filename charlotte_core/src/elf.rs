@@ -0,0 +1,653 @@
+//! # ELF64
+//! Parsing of ELF64 images such as separately compiled kernel modules or the first user program.
+//! Only images for the ISA the kernel was built for are accepted. Executables are placed at the
+//! virtual addresses they were linked at, position independent images can be loaded at any base
+//! and relocated with the entries of their dynamic relocation tables.
+
+/// The reasons an image can be rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(unused)]
+pub enum Error {
+    /// The image ends before a structure it describes
+    Truncated,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    UnsupportedVersion,
+    /// The image is for a different ISA than the one the kernel was built for
+    WrongMachine,
+    /// The image is neither an executable nor a position independent executable
+    NotExecutable,
+    /// The program header table entries are smaller than an ELF64 program header
+    BadProgramHeaderSize,
+    /// A segment occupies more bytes of the file than it does in memory
+    BadSegmentSize,
+    /// A segment requests to be both writable and executable
+    WritableAndExecutable,
+    /// The dynamic section lacks an entry needed to find a table it refers to, or a table it
+    /// refers to is not part of any loadable segment
+    BadDynamicSection,
+    /// A relocation of a type the loader does not know how to apply
+    UnsupportedRelocation(u32),
+    /// A relocation refers to a symbol that is neither defined in the image nor resolved by the
+    /// caller
+    UnresolvedSymbol,
+}
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+#[cfg(target_arch = "x86_64")]
+const EM_HOST: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+const EM_HOST: u16 = 183;
+#[cfg(target_arch = "riscv64")]
+const EM_HOST: u16 = 243;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+pub const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+const DT_NULL: u64 = 0;
+const DT_PLTRELSZ: u64 = 2;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_PLTREL: u64 = 20;
+const DT_JMPREL: u64 = 23;
+
+const DYN_SIZE: usize = 16;
+const RELA_SIZE: usize = 24;
+const SYM_SIZE: usize = 24;
+/// The section index of symbols that are not defined in the image
+const SHN_UNDEF: u16 = 0;
+
+#[cfg(target_arch = "x86_64")]
+const R_NONE: u32 = 0;
+#[cfg(target_arch = "x86_64")]
+const R_GLOB_DAT: u32 = 6;
+#[cfg(target_arch = "x86_64")]
+const R_JUMP_SLOT: u32 = 7;
+#[cfg(target_arch = "x86_64")]
+const R_RELATIVE: u32 = 8;
+#[cfg(target_arch = "aarch64")]
+const R_NONE: u32 = 0;
+#[cfg(target_arch = "aarch64")]
+const R_GLOB_DAT: u32 = 1025;
+#[cfg(target_arch = "aarch64")]
+const R_JUMP_SLOT: u32 = 1026;
+#[cfg(target_arch = "aarch64")]
+const R_RELATIVE: u32 = 1027;
+// RISC-V has no GOT specific type, GOT entries use the generic absolute type R_RISCV_64 instead
+#[cfg(target_arch = "riscv64")]
+const R_NONE: u32 = 0;
+#[cfg(target_arch = "riscv64")]
+const R_GLOB_DAT: u32 = u32::MAX;
+#[cfg(target_arch = "riscv64")]
+const R_JUMP_SLOT: u32 = 5;
+#[cfg(target_arch = "riscv64")]
+const R_RELATIVE: u32 = 3;
+
+fn read_u16(image: &[u8], offset: usize) -> Result<u16, Error> {
+    let bytes = image.get(offset..offset + 2).ok_or(Error::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Result<u32, Error> {
+    let bytes = image.get(offset..offset + 4).ok_or(Error::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(image: &[u8], offset: usize) -> Result<u64, Error> {
+    let bytes = image.get(offset..offset + 8).ok_or(Error::Truncated)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// A validated ELF64 image
+#[derive(Debug, Clone, Copy)]
+#[allow(unused)]
+pub struct Elf64<'a> {
+    image: &'a [u8],
+    entry: u64,
+    phoff: usize,
+    phnum: usize,
+}
+
+#[allow(unused)]
+impl<'a> Elf64<'a> {
+    /// Validates the ELF header of `image` and the bounds of its program header table
+    pub fn parse(image: &'a [u8]) -> Result<Self, Error> {
+        let ident = image.get(..16).ok_or(Error::Truncated)?;
+        if ident[..4] != ELF_MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if ident[4] != ELFCLASS64 {
+            return Err(Error::NotElf64);
+        }
+        if ident[5] != ELFDATA2LSB {
+            return Err(Error::NotLittleEndian);
+        }
+        if ident[6] != EV_CURRENT {
+            return Err(Error::UnsupportedVersion);
+        }
+        if image.len() < EHDR_SIZE {
+            return Err(Error::Truncated);
+        }
+        let e_type = read_u16(image, 16)?;
+        if e_type != ET_EXEC && e_type != ET_DYN {
+            return Err(Error::NotExecutable);
+        }
+        if read_u16(image, 18)? != EM_HOST {
+            return Err(Error::WrongMachine);
+        }
+        let entry = read_u64(image, 24)?;
+        let phoff = usize::try_from(read_u64(image, 32)?).map_err(|_| Error::Truncated)?;
+        let phentsize = read_u16(image, 54)? as usize;
+        let phnum = read_u16(image, 56)? as usize;
+        if phnum != 0 && phentsize != PHDR_SIZE {
+            return Err(Error::BadProgramHeaderSize);
+        }
+        let table_end = phnum
+            .checked_mul(PHDR_SIZE)
+            .and_then(|size| phoff.checked_add(size))
+            .ok_or(Error::Truncated)?;
+        if table_end > image.len() {
+            return Err(Error::Truncated);
+        }
+
+        Ok(Elf64 {
+            image,
+            entry,
+            phoff,
+            phnum,
+        })
+    }
+
+    /// Returns the virtual address execution of the image starts at
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Returns every program header in the image
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let image = self.image;
+        let phoff = self.phoff;
+        // the whole table was bounds checked by parse
+        (0..self.phnum).map(move |i| ProgramHeader::read(image, phoff + i * PHDR_SIZE).unwrap())
+    }
+
+    /// Returns the program headers of the segments that have to be loaded into memory
+    pub fn load_segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers()
+            .filter(|header| header.p_type == PT_LOAD)
+    }
+
+    /// Returns the dynamic relocations of the image, `DT_RELA` followed by the PLT relocations in
+    /// `DT_JMPREL`, along with the dynamic symbol table they refer to. An image without a dynamic
+    /// section has no relocations.
+    /// # Returns
+    /// Returns `Error::BadDynamicSection` if a table is not part of a loadable segment or the PLT
+    /// relocations are not of the RELA kind, and `Error::Truncated` if a table runs past the image.
+    pub fn relocations(&self) -> Result<Relocations<'a>, Error> {
+        let mut relocations = Relocations {
+            elf: *self,
+            tables: [(0, 0); 2],
+            symbols: None,
+        };
+        let Some(dynamic) = self.dynamic_entries()? else {
+            return Ok(relocations);
+        };
+        let (mut rela, mut relasz, mut jmprel, mut pltrelsz) = (None, 0, None, 0);
+        let (mut symtab, mut strtab) = (None, None);
+        for (tag, value) in dynamic {
+            match tag {
+                DT_RELA => rela = Some(value),
+                DT_RELASZ => relasz = value,
+                DT_RELAENT if value != RELA_SIZE as u64 => return Err(Error::BadDynamicSection),
+                DT_JMPREL => jmprel = Some(value),
+                DT_PLTRELSZ => pltrelsz = value,
+                DT_PLTREL if value != DT_RELA => return Err(Error::BadDynamicSection),
+                DT_SYMTAB => symtab = Some(value),
+                DT_STRTAB => strtab = Some(value),
+                _ => {}
+            }
+        }
+        for (table, (addr, size)) in relocations
+            .tables
+            .iter_mut()
+            .zip([(rela, relasz), (jmprel, pltrelsz)])
+        {
+            if let Some(addr) = addr.filter(|_| size != 0) {
+                let size = usize::try_from(size).map_err(|_| Error::Truncated)?;
+                *table = (self.vaddr_to_offset(addr, size)?, size / RELA_SIZE);
+            }
+        }
+        relocations.symbols = symtab.zip(strtab);
+        Ok(relocations)
+    }
+
+    /// Returns the tag and value of every entry of the dynamic section up to `DT_NULL`, or `None`
+    /// if the image has no dynamic section
+    fn dynamic_entries(&self) -> Result<Option<impl Iterator<Item = (u64, u64)> + 'a>, Error> {
+        let Some(dynamic) = self
+            .program_headers()
+            .find(|header| header.p_type == PT_DYNAMIC)
+        else {
+            return Ok(None);
+        };
+        let data = self.segment_data(&dynamic)?;
+        let entries = data.as_chunks::<DYN_SIZE>().0.iter().map(|entry| {
+            (
+                u64::from_le_bytes(entry[..8].try_into().unwrap()),
+                u64::from_le_bytes(entry[8..].try_into().unwrap()),
+            )
+        });
+        Ok(Some(entries.take_while(|(tag, _)| *tag != DT_NULL)))
+    }
+
+    /// Converts the `len` bytes at the link time address `vaddr` to the offset of their bytes in
+    /// the file, they have to lie within the file bytes of a single loadable segment
+    fn vaddr_to_offset(&self, vaddr: u64, len: usize) -> Result<usize, Error> {
+        let end = vaddr.checked_add(len as u64).ok_or(Error::Truncated)?;
+        let segment = self
+            .load_segments()
+            .find(|segment| {
+                segment
+                    .vaddr
+                    .checked_add(segment.filesz)
+                    .is_some_and(|file_end| segment.vaddr <= vaddr && end <= file_end)
+            })
+            .ok_or(Error::BadDynamicSection)?;
+        let offset = segment
+            .offset
+            .checked_add(vaddr - segment.vaddr)
+            .and_then(|offset| usize::try_from(offset).ok())
+            .ok_or(Error::Truncated)?;
+        match offset.checked_add(len) {
+            Some(end) if end <= self.image.len() => Ok(offset),
+            _ => Err(Error::Truncated),
+        }
+    }
+
+    /// Returns the bytes of the image that initialize the start of `segment`
+    /// # Returns
+    /// Returns `Error::BadSegmentSize` if the segment has more file bytes than memory bytes and
+    /// `Error::Truncated` if the bytes lie outside the image.
+    pub fn segment_data(&self, segment: &ProgramHeader) -> Result<&'a [u8], Error> {
+        if segment.filesz > segment.memsz {
+            return Err(Error::BadSegmentSize);
+        }
+        let start = usize::try_from(segment.offset).map_err(|_| Error::Truncated)?;
+        let len = usize::try_from(segment.filesz).map_err(|_| Error::Truncated)?;
+        let end = start.checked_add(len).ok_or(Error::Truncated)?;
+        self.image.get(start..end).ok_or(Error::Truncated)
+    }
+}
+
+/// An ELF64 program header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(unused)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+#[allow(unused)]
+impl ProgramHeader {
+    fn read(image: &[u8], offset: usize) -> Result<Self, Error> {
+        Ok(ProgramHeader {
+            p_type: read_u32(image, offset)?,
+            flags: read_u32(image, offset + 4)?,
+            offset: read_u64(image, offset + 8)?,
+            vaddr: read_u64(image, offset + 16)?,
+            filesz: read_u64(image, offset + 32)?,
+            memsz: read_u64(image, offset + 40)?,
+            align: read_u64(image, offset + 48)?,
+        })
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.flags & PF_R != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+/// The dynamic relocations of an image, see [Elf64::relocations]
+#[derive(Debug, Clone, Copy)]
+pub struct Relocations<'a> {
+    elf: Elf64<'a>,
+    /// The file offset and number of entries of the `DT_RELA` and `DT_JMPREL` tables, which were
+    /// bounds checked when they were found
+    tables: [(usize, usize); 2],
+    /// The link time addresses of the dynamic symbol and string tables, if the image has both
+    symbols: Option<(u64, u64)>,
+}
+
+#[allow(unused)]
+impl<'a> Relocations<'a> {
+    /// Returns every relocation, those of `DT_RELA` first
+    pub fn entries(&self) -> impl Iterator<Item = Rela> + 'a {
+        let image = self.elf.image;
+        self.tables
+            .into_iter()
+            .flat_map(move |(offset, n_entries)| {
+                (0..n_entries).map(move |i| Rela::read(image, offset + i * RELA_SIZE).unwrap())
+            })
+    }
+
+    /// Computes the value that `rela` stores at its target when the image is loaded `bias` bytes
+    /// above the addresses it was linked at. Symbols that are not defined in the image are looked up
+    /// by name with `resolve`.
+    /// # Returns
+    /// Returns `None` for relocations that store nothing, `Error::UnsupportedRelocation` for types
+    /// other than the relative, GOT and PLT ones and `Error::UnresolvedSymbol` if `resolve` does not
+    /// know a symbol.
+    pub fn value(
+        &self,
+        rela: &Rela,
+        bias: u64,
+        resolve: &mut dyn FnMut(&str) -> Option<u64>,
+    ) -> Result<Option<u64>, Error> {
+        match rela.r_type {
+            R_NONE => Ok(None),
+            R_RELATIVE => Ok(Some(bias.wrapping_add_signed(rela.addend))),
+            R_GLOB_DAT | R_JUMP_SLOT => {
+                let (name, shndx, value) = self.symbol(rela.sym)?;
+                if shndx != SHN_UNDEF {
+                    Ok(Some(bias.wrapping_add(value)))
+                } else {
+                    resolve(name).map(Some).ok_or(Error::UnresolvedSymbol)
+                }
+            }
+            r_type => Err(Error::UnsupportedRelocation(r_type)),
+        }
+    }
+
+    /// Returns the name, section index and value of the symbol at `index` of the dynamic symbol table
+    fn symbol(&self, index: u32) -> Result<(&'a str, u16, u64), Error> {
+        let (symtab, strtab) = self.symbols.ok_or(Error::BadDynamicSection)?;
+        let image = self.elf.image;
+        let entry = (index as u64)
+            .checked_mul(SYM_SIZE as u64)
+            .and_then(|offset| symtab.checked_add(offset))
+            .ok_or(Error::Truncated)?;
+        let sym = self.elf.vaddr_to_offset(entry, SYM_SIZE)?;
+        let name_offset = read_u32(image, sym)? as u64;
+        let shndx = read_u16(image, sym + 6)?;
+        let value = read_u64(image, sym + 8)?;
+
+        let name_start = self
+            .elf
+            .vaddr_to_offset(strtab.checked_add(name_offset).ok_or(Error::Truncated)?, 1)?;
+        let name_bytes = &image[name_start..];
+        let len = name_bytes
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or(Error::Truncated)?;
+        let name =
+            core::str::from_utf8(&name_bytes[..len]).map_err(|_| Error::BadDynamicSection)?;
+        Ok((name, shndx, value))
+    }
+}
+
+/// An entry of a RELA relocation table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(unused)]
+pub struct Rela {
+    /// The link time address of the bytes the relocation is applied to
+    pub offset: u64,
+    pub r_type: u32,
+    /// The index of the symbol in the dynamic symbol table
+    pub sym: u32,
+    pub addend: i64,
+}
+
+impl Rela {
+    fn read(image: &[u8], offset: usize) -> Result<Self, Error> {
+        let info = read_u64(image, offset + 8)?;
+        Ok(Rela {
+            offset: read_u64(image, offset)?,
+            r_type: info as u32,
+            sym: (info >> 32) as u32,
+            addend: read_u64(image, offset + 16)? as i64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(image: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+        if image.len() < offset + bytes.len() {
+            image.resize(offset + bytes.len(), 0);
+        }
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Builds an ELF header for the host ISA followed by a table of `phnum` empty program headers
+    fn header(e_type: u16, phnum: u16) -> Vec<u8> {
+        let mut image = vec![0; EHDR_SIZE + phnum as usize * PHDR_SIZE];
+        put(&mut image, 0, &ELF_MAGIC);
+        put(&mut image, 4, &[ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+        put(&mut image, 16, &e_type.to_le_bytes());
+        put(&mut image, 18, &EM_HOST.to_le_bytes());
+        put(&mut image, 24, &0x1000u64.to_le_bytes());
+        put(&mut image, 32, &(EHDR_SIZE as u64).to_le_bytes());
+        put(&mut image, 54, &(PHDR_SIZE as u16).to_le_bytes());
+        put(&mut image, 56, &phnum.to_le_bytes());
+        image
+    }
+
+    /// Fills in program header `index` with a segment whose bytes are at `offset` in the file
+    fn phdr(image: &mut Vec<u8>, index: usize, p_type: u32, offset: u64, vaddr: u64, size: u64) {
+        let at = EHDR_SIZE + index * PHDR_SIZE;
+        put(image, at, &p_type.to_le_bytes());
+        put(image, at + 4, &PF_R.to_le_bytes());
+        put(image, at + 8, &offset.to_le_bytes());
+        put(image, at + 16, &vaddr.to_le_bytes());
+        put(image, at + 32, &size.to_le_bytes());
+        put(image, at + 40, &size.to_le_bytes());
+    }
+
+    #[test]
+    fn parse_accepts_a_valid_image() {
+        let mut image = header(ET_EXEC, 1);
+        phdr(&mut image, 0, PT_LOAD, 0, 0x40_0000, 0x100);
+        image.resize(0x100, 0);
+        let elf = Elf64::parse(&image).unwrap();
+        assert_eq!(elf.entry(), 0x1000);
+        let segments: Vec<_> = elf.load_segments().collect();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].vaddr, 0x40_0000);
+        assert!(segments[0].is_readable() && !segments[0].is_writable());
+        assert_eq!(elf.segment_data(&segments[0]).unwrap().len(), 0x100);
+        assert_eq!(elf.relocations().unwrap().entries().count(), 0);
+    }
+
+    #[test]
+    fn parse_rejects_malformed_headers() {
+        let image = header(ET_EXEC, 1);
+        let cases: [(usize, &[u8], Error); 7] = [
+            (0, b"\x7FELG", Error::BadMagic),
+            (4, &[1], Error::NotElf64),
+            (5, &[2], Error::NotLittleEndian),
+            (6, &[0], Error::UnsupportedVersion),
+            (16, &1u16.to_le_bytes(), Error::NotExecutable),
+            (18, &0xFFFFu16.to_le_bytes(), Error::WrongMachine),
+            (54, &32u16.to_le_bytes(), Error::BadProgramHeaderSize),
+        ];
+        for (offset, bytes, error) in cases {
+            let mut image = image.clone();
+            put(&mut image, offset, bytes);
+            assert_eq!(Elf64::parse(&image).unwrap_err(), error);
+        }
+
+        assert_eq!(Elf64::parse(&image[..15]).unwrap_err(), Error::Truncated);
+        assert_eq!(Elf64::parse(&image[..63]).unwrap_err(), Error::Truncated);
+        // the program header table runs past the end of the image
+        assert_eq!(
+            Elf64::parse(&image[..image.len() - 1]).unwrap_err(),
+            Error::Truncated
+        );
+        let mut image = image.clone();
+        put(&mut image, 32, &u64::MAX.to_le_bytes());
+        assert_eq!(Elf64::parse(&image).unwrap_err(), Error::Truncated);
+    }
+
+    /// Builds a position independent image that is loaded as a single segment at address 0 and
+    /// whose dynamic section is made of `dynamic`, followed by `tail` at offset [TAIL]
+    fn dynamic_image(dynamic: &[(u64, u64)], tail: &[u8]) -> Vec<u8> {
+        let mut image = header(ET_DYN, 2);
+        let dynamic_offset = image.len();
+        for (i, (tag, value)) in dynamic.iter().chain([&(DT_NULL, 0)]).enumerate() {
+            put(
+                &mut image,
+                dynamic_offset + i * DYN_SIZE,
+                &tag.to_le_bytes(),
+            );
+            put(
+                &mut image,
+                dynamic_offset + i * DYN_SIZE + 8,
+                &value.to_le_bytes(),
+            );
+        }
+        let dynamic_size = (image.len() - dynamic_offset) as u64;
+        put(&mut image, TAIL, tail);
+        let size = image.len() as u64;
+        phdr(&mut image, 0, PT_LOAD, 0, 0, size);
+        phdr(
+            &mut image,
+            1,
+            PT_DYNAMIC,
+            dynamic_offset as u64,
+            dynamic_offset as u64,
+            dynamic_size,
+        );
+        image
+    }
+
+    /// Where [dynamic_image] places the tables the dynamic section refers to
+    const TAIL: usize = 0x200;
+
+    fn rela(offset: u64, r_type: u32, sym: u32, addend: i64) -> Vec<u8> {
+        let info = (sym as u64) << 32 | r_type as u64;
+        [
+            offset.to_le_bytes(),
+            info.to_le_bytes(),
+            addend.to_le_bytes(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn relocations_are_computed() {
+        let symtab = TAIL + 3 * RELA_SIZE;
+        let strtab = symtab + 2 * SYM_SIZE;
+        let mut tail = [
+            rela(0x100, R_RELATIVE, 0, 0x40),
+            rela(0x108, R_GLOB_DAT, 1, 0),
+            rela(0x110, 0xFF, 0, 0),
+        ]
+        .concat();
+        // symbol 0 is the null symbol, symbol 1 is the undefined symbol `ext`
+        tail.resize(3 * RELA_SIZE + 2 * SYM_SIZE, 0);
+        put(&mut tail, 3 * RELA_SIZE + SYM_SIZE, &1u32.to_le_bytes());
+        tail.extend_from_slice(b"\0ext\0");
+        let image = dynamic_image(
+            &[
+                (DT_RELA, TAIL as u64),
+                (DT_RELASZ, 3 * RELA_SIZE as u64),
+                (DT_RELAENT, RELA_SIZE as u64),
+                (DT_SYMTAB, symtab as u64),
+                (DT_STRTAB, strtab as u64),
+            ],
+            &tail,
+        );
+        let elf = Elf64::parse(&image).unwrap();
+        let relocations = elf.relocations().unwrap();
+        let entries: Vec<_> = relocations.entries().collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].offset, 0x108);
+        assert_eq!(entries[1].sym, 1);
+
+        let mut resolve = |name: &str| (name == "ext").then_some(0xDEAD_0000);
+        let bias = 0x1000_0000;
+        assert_eq!(
+            relocations.value(&entries[0], bias, &mut resolve),
+            Ok(Some(0x1000_0040))
+        );
+        assert_eq!(
+            relocations.value(&entries[1], bias, &mut resolve),
+            Ok(Some(0xDEAD_0000))
+        );
+        assert_eq!(
+            relocations.value(&entries[1], bias, &mut |_| None),
+            Err(Error::UnresolvedSymbol)
+        );
+        assert_eq!(
+            relocations.value(&entries[2], bias, &mut resolve),
+            Err(Error::UnsupportedRelocation(0xFF))
+        );
+    }
+
+    #[test]
+    fn malformed_dynamic_sections_are_rejected() {
+        let relocations = |dynamic: &[(u64, u64)]| {
+            let image = dynamic_image(dynamic, &rela(0, R_RELATIVE, 0, 0));
+            Elf64::parse(&image).unwrap().relocations().map(|_| ())
+        };
+        assert_eq!(
+            relocations(&[(DT_RELA, TAIL as u64), (DT_RELASZ, RELA_SIZE as u64)]),
+            Ok(())
+        );
+        assert_eq!(
+            relocations(&[(DT_RELAENT, 16)]),
+            Err(Error::BadDynamicSection)
+        );
+        assert_eq!(
+            relocations(&[(DT_PLTREL, 17)]),
+            Err(Error::BadDynamicSection)
+        );
+        // the table lies past the end of the only loadable segment
+        assert_eq!(
+            relocations(&[(DT_RELA, 0x1000), (DT_RELASZ, RELA_SIZE as u64)]),
+            Err(Error::BadDynamicSection)
+        );
+        assert_eq!(
+            relocations(&[(DT_RELA, u64::MAX - 8), (DT_RELASZ, RELA_SIZE as u64)]),
+            Err(Error::Truncated)
+        );
+    }
+
+    #[test]
+    fn segments_ending_past_the_address_space_are_skipped() {
+        let mut image = dynamic_image(&[(DT_RELA, u64::MAX - 0x10), (DT_RELASZ, 8)], &[0; 8]);
+        let size = image.len() as u64;
+        phdr(&mut image, 0, PT_LOAD, 0, u64::MAX - 0x20, size);
+        let elf = Elf64::parse(&image).unwrap();
+        assert_eq!(elf.relocations().unwrap_err(), Error::BadDynamicSection);
+    }
+}
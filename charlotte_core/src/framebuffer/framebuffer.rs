@@ -20,11 +20,34 @@ pub struct FrameBufferInfo {
     address: AtomicPtr<u32>,
     width: usize,
     height: usize,
+    /// The number of bytes between the starts of two rows, which may include padding
     pitch: usize,
     bpp: usize,
+    red: Channel,
+    green: Channel,
+    blue: Channel,
     scale: usize,
 }
 
+/// The position and width of one color channel within a pixel
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    shift: u8,
+    size: u8,
+}
+
+impl Channel {
+    /// Scales an 8 bit channel value to the width of the channel and moves it into position
+    fn encode(self, value: u32) -> u32 {
+        let scaled = if self.size >= 8 {
+            value << (self.size - 8)
+        } else {
+            value >> (8 - self.size)
+        };
+        scaled << self.shift
+    }
+}
+
 /// including its memory address, dimensions, pixel format, etc.
 #[derive(Copy, Clone)]
 pub struct Point {
@@ -46,6 +69,18 @@ impl FrameBufferInfo {
             height: framebuffer.height() as usize,
             pitch: framebuffer.pitch() as usize,
             bpp: framebuffer.bpp() as usize,
+            red: Channel {
+                shift: framebuffer.red_mask_shift(),
+                size: framebuffer.red_mask_size(),
+            },
+            green: Channel {
+                shift: framebuffer.green_mask_shift(),
+                size: framebuffer.green_mask_size(),
+            },
+            blue: Channel {
+                shift: framebuffer.blue_mask_shift(),
+                size: framebuffer.blue_mask_size(),
+            },
             scale: 1,
         };
 
@@ -74,7 +109,7 @@ impl FrameBufferInfo {
         let mut err = dx + dy; // error value e_xy
 
         loop {
-            self.draw_pixel(x0 as usize, y0 as usize, color);
+            self.write_pixel(x0 as usize, y0 as usize, color);
             // Draw the current pixel
             if x0 == x1 && y0 == y1 {
                 break;
@@ -91,6 +126,7 @@ impl FrameBufferInfo {
                 y0 += sy;
             }
         }
+        self.flush();
     }
 
     /// Clears the entire screen to a single color.
@@ -101,15 +137,28 @@ impl FrameBufferInfo {
     pub fn clear_screen(&self, color: u32) {
         for y in 0..self.height {
             for x in 0..self.width {
-                let pixel_offset = y * self.pitch / (self.bpp / 8) + x;
-                unsafe {
-                    *self.address.load(Ordering::Relaxed).add(pixel_offset) = color;
-                }
+                self.write_pixel(x, y, color);
             }
         }
+        self.flush();
+    }
+
+    /// Drains the write-combining buffers so that every pixel written so far reaches the device.
+    /// The framebuffer is usually mapped write-combining, so writes may linger in the buffers of
+    /// the LP until they are flushed. All drawing methods except [draw_pixel](Self::draw_pixel)
+    /// flush once when they are done.
+    pub fn flush(&self) {
+        #[cfg(target_arch = "x86_64")]
+        // SAFETY: sfence only orders stores
+        unsafe {
+            core::arch::x86_64::_mm_sfence()
+        };
+        #[cfg(not(target_arch = "x86_64"))]
+        core::sync::atomic::fence(Ordering::Release);
     }
 
     /// Draws a single pixel at the specified location.
+    /// The pixel is not flushed, call [flush](Self::flush) after drawing a batch of pixels.
     ///
     /// # Arguments
     ///
@@ -118,14 +167,43 @@ impl FrameBufferInfo {
     /// * `color` - The color of the pixel in ARGB format.
 
     pub fn draw_pixel(&self, x: usize, y: usize, color: u32) {
+        self.write_pixel(x, y, color);
+    }
+
+    fn write_pixel(&self, x: usize, y: usize, color: u32) {
         if x < self.width && y < self.height {
-            let pixel_offset = y * self.pitch / (self.bpp / 8) + x;
+            let bytes_per_pixel = self.bpp.div_ceil(8);
+            let offset = y * self.pitch + x * bytes_per_pixel;
+            let pixel = self.encode(color);
             unsafe {
-                *self.address.load(Ordering::Relaxed).add(pixel_offset) = color;
+                let address = self
+                    .address
+                    .load(Ordering::Relaxed)
+                    .cast::<u8>()
+                    .add(offset);
+                if bytes_per_pixel == 4 {
+                    address.cast::<u32>().write_volatile(pixel);
+                } else {
+                    for (i, byte) in pixel
+                        .to_le_bytes()
+                        .into_iter()
+                        .take(bytes_per_pixel)
+                        .enumerate()
+                    {
+                        address.add(i).write_volatile(byte);
+                    }
+                }
             }
         }
     }
 
+    /// Converts an ARGB color to the pixel format of the framebuffer
+    fn encode(&self, color: u32) -> u32 {
+        self.red.encode((color >> 16) & 0xFF)
+            | self.green.encode((color >> 8) & 0xFF)
+            | self.blue.encode(color & 0xFF)
+    }
+
     /// Draws text starting from a specified location.
     ///
     /// # Arguments
@@ -151,11 +229,12 @@ impl FrameBufferInfo {
                     x = start_x;
                 }
                 _ => {
-                    self.draw_char(x, y, c, color, background_color);
+                    self.write_char(x, y, c, color, background_color);
                     x += FONT_WIDTH;
                 }
             }
         }
+        self.flush();
     }
 
     /// Helper method to draw a single character from its bitmap.
@@ -167,7 +246,17 @@ impl FrameBufferInfo {
     /// * `bitmap` - A reference to the bitmap array representing the character.
     /// * `color` - The color of the character in ARGB format.
     pub fn draw_char(&self, x: usize, y: usize, chracter: char, color: u32, background_color: u32) {
-        let char_int: usize = chracter as usize;
+        self.write_char(x, y, chracter, color, background_color);
+        self.flush();
+    }
+
+    fn write_char(&self, x: usize, y: usize, chracter: char, color: u32, background_color: u32) {
+        // the font only has glyphs for the first 256 code points
+        let char_int: usize = if (chracter as usize) < 256 {
+            chracter as usize
+        } else {
+            '?' as usize
+        };
         let first_byte_index = char_int * 16;
         let mut do_draw: bool;
         let mut colour_buffer: u32;
@@ -179,7 +268,7 @@ impl FrameBufferInfo {
                 } else {
                     colour_buffer = background_color;
                 }
-                self.draw_pixel(x + bi, y + by, colour_buffer);
+                self.write_pixel(x + bi, y + by, colour_buffer);
             }
         }
     }
@@ -196,9 +285,10 @@ impl FrameBufferInfo {
     pub fn draw_rect(&self, x: usize, y: usize, width: usize, height: usize, color: u32) {
         for row in y..y + height {
             for col in x..x + width {
-                self.draw_pixel(col, row, color);
+                self.write_pixel(col, row, color);
             }
         }
+        self.flush();
     }
 
     /// Draws a filled triangle between three points.
@@ -231,7 +321,7 @@ impl FrameBufferInfo {
                 let x_start = interpolate_x(p_left, p_right_start, y);
                 let x_end = interpolate_x(p_left, p_right_end, y);
                 for x in x_start.min(x_end)..=x_start.max(x_end) {
-                    self_ref.draw_pixel(x as usize, y as usize, color);
+                    self_ref.write_pixel(x as usize, y as usize, color);
                 }
             }
         };
@@ -255,6 +345,7 @@ impl FrameBufferInfo {
             vertices[0],
             vertices[1],
         );
+        self.flush();
     }
 
     /// Return the framebuffer scaling multiplier
@@ -288,3 +379,85 @@ pub fn init_framebuffer() -> Option<FrameBufferInfo> {
         panic!("No framebuffer returned from bootlaoder!");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_encode_scales_to_the_channel_width() {
+        let red = Channel { shift: 16, size: 8 };
+        assert_eq!(red.encode(0xAB), 0xAB_0000);
+
+        // RGB565 green and red
+        let green = Channel { shift: 5, size: 6 };
+        assert_eq!(green.encode(0xFF), 0x3F << 5);
+        assert_eq!(green.encode(0x80), 0x20 << 5);
+        let red = Channel { shift: 11, size: 5 };
+        assert_eq!(red.encode(0xFF), 0x1F << 11);
+
+        // 10 bit channels keep the 8 bit value in their most significant bits
+        let blue = Channel { shift: 0, size: 10 };
+        assert_eq!(blue.encode(0xFF), 0x3FC);
+        assert_eq!(blue.encode(0), 0);
+    }
+
+    /// Builds a 24 bpp framebuffer over `memory` with `pitch` bytes per row
+    fn mock_framebuffer(
+        memory: &mut [u8],
+        width: usize,
+        height: usize,
+        pitch: usize,
+    ) -> FrameBufferInfo {
+        assert!(memory.len() >= height * pitch);
+        FrameBufferInfo {
+            address: AtomicPtr::new(memory.as_mut_ptr().cast()),
+            width,
+            height,
+            pitch,
+            bpp: 24,
+            red: Channel { shift: 16, size: 8 },
+            green: Channel { shift: 8, size: 8 },
+            blue: Channel { shift: 0, size: 8 },
+            scale: 1,
+        }
+    }
+
+    #[test]
+    fn draw_text_renders_the_glyph_of_the_first_character() {
+        const WIDTH: usize = 3 * FONT_WIDTH;
+        const HEIGHT: usize = FONT_HEIGHT;
+        // pad every row so that a renderer ignoring the pitch lands on the wrong bytes
+        const PITCH: usize = WIDTH * 3 + 13;
+        const FOREGROUND: u32 = 0x00AA_BBCC;
+        const BACKGROUND: u32 = 0x0011_2233;
+        let mut memory = vec![0u8; HEIGHT * PITCH];
+        let framebuffer = mock_framebuffer(&mut memory, WIDTH, HEIGHT, PITCH);
+
+        framebuffer.draw_text(0, 0, "Hi", FOREGROUND, BACKGROUND);
+
+        let glyph = &FONT['H' as usize * FONT_HEIGHT..('H' as usize + 1) * FONT_HEIGHT];
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..FONT_WIDTH {
+                let expected = if (bits >> (7 - col)) & 1 != 0 {
+                    FOREGROUND
+                } else {
+                    BACKGROUND
+                };
+                let offset = row * PITCH + col * 3;
+                assert_eq!(
+                    memory[offset..offset + 3],
+                    expected.to_le_bytes()[..3],
+                    "pixel ({}, {})",
+                    col,
+                    row
+                );
+            }
+            // the padding at the end of the row is never written
+            assert!(memory[row * PITCH + WIDTH * 3..(row + 1) * PITCH]
+                .iter()
+                .all(|&byte| byte == 0));
+        }
+        assert!(glyph.iter().any(|&bits| bits != 0));
+    }
+}
@@ -1,11 +1,124 @@
-use crate::arch::Serial;
-use crate::log;
+use crate::arch::{Api, ArchApi, Serial};
+use crate::memory::address::VirtualAddress;
+use crate::memory::pmm::PHYSICAL_FRAME_ALLOCATOR;
+use crate::{log, logln};
 use core::fmt::Write;
+use core::str::{self, SplitWhitespace};
+
+/// The reasons a debug command can fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    UnknownCommand,
+    MissingArgument,
+    InvalidArgument,
+}
+
+/// A command understood by the prompt when debug commands are enabled
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    handler: fn(&mut SplitWhitespace) -> Result<(), CommandError>,
+}
+
+const COMMANDS: [Command; 5] = [
+    Command {
+        name: "help",
+        usage: "help - list the available commands",
+        handler: cmd_help,
+    },
+    Command {
+        name: "mem",
+        usage: "mem <vaddr> - show the translation of a hexadecimal virtual address",
+        handler: cmd_mem,
+    },
+    Command {
+        name: "frames",
+        usage: "frames - show physical memory manager statistics",
+        handler: cmd_frames,
+    },
+    Command {
+        name: "leaks",
+        usage: "leaks - list frames that are still allocated and where they were allocated",
+        handler: cmd_leaks,
+    },
+    Command {
+        name: "tasks",
+        usage: "tasks - show the scheduler state",
+        handler: cmd_tasks,
+    },
+];
+
+/// Runs the command named by the first word of `line` with the remaining words as its arguments
+/// Blank lines are ignored.
+pub fn dispatch(line: &str) -> Result<(), CommandError> {
+    dispatch_from(&COMMANDS, line)
+}
+
+/// Looks up the command named by the first word of `line` in `commands`, see [dispatch]
+fn dispatch_from(commands: &[Command], line: &str) -> Result<(), CommandError> {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return Ok(());
+    };
+    let command = commands
+        .iter()
+        .find(|command| command.name == name)
+        .ok_or(CommandError::UnknownCommand)?;
+    (command.handler)(&mut words)
+}
+
+fn cmd_help(_: &mut SplitWhitespace) -> Result<(), CommandError> {
+    for command in COMMANDS.iter() {
+        logln!("{}", command.usage);
+    }
+    Ok(())
+}
+
+fn cmd_mem(args: &mut SplitWhitespace) -> Result<(), CommandError> {
+    let arg = args.next().ok_or(CommandError::MissingArgument)?;
+    let digits = arg.strip_prefix("0x").unwrap_or(arg);
+    let raw = u64::from_str_radix(digits, 16).map_err(|_| CommandError::InvalidArgument)?;
+    let vaddr = VirtualAddress::try_from(raw).map_err(|_| CommandError::InvalidArgument)?;
+    ArchApi::log_translation(vaddr);
+    Ok(())
+}
+
+fn cmd_frames(_: &mut SplitWhitespace) -> Result<(), CommandError> {
+    let pmm = PHYSICAL_FRAME_ALLOCATOR.lock();
+    let free_frames = pmm.free_frames();
+    let report = pmm.memory_report();
+    drop(pmm);
+    logln!("Free frames: {} ({} KiB)", free_frames, free_frames * 4);
+    logln!("{}", report);
+    Ok(())
+}
+
+fn cmd_leaks(_: &mut SplitWhitespace) -> Result<(), CommandError> {
+    if !cfg!(debug_assertions) {
+        logln!("Allocation call sites are only recorded in debug builds");
+        return Ok(());
+    }
+    let pmm = PHYSICAL_FRAME_ALLOCATOR.lock();
+    let mut n_live = 0;
+    for (frame, location) in pmm.live_allocations() {
+        logln!("{} allocated at {}", frame, location);
+        n_live += 1;
+    }
+    drop(pmm);
+    logln!("{} frames allocated with a recorded call site", n_live);
+    Ok(())
+}
+
+fn cmd_tasks(_: &mut SplitWhitespace) -> Result<(), CommandError> {
+    logln!("No scheduler is running");
+    Ok(())
+}
 
 pub struct Kmon<T: Serial> {
     pub port: T,
     recv_buf_pos: usize,
     pub recv_buf: [char; 256],
+    debug_commands: bool,
 }
 
 impl<T: Serial> Kmon<T> {
@@ -18,21 +131,43 @@ impl<T: Serial> Kmon<T> {
             port,
             recv_buf_pos: 0,
             recv_buf: ['\0'; 256],
+            debug_commands: false,
         }
     }
 
+    /// Makes the prompt execute each line as a debug command instead of echoing it
+    pub fn enable_debug_commands(&mut self) {
+        self.debug_commands = true;
+    }
+
     fn handle_line(&mut self) {
         self.recv_buf[self.recv_buf_pos] = '\0';
-        for i in 0..self.recv_buf_pos {
-            log!("{}", self.recv_buf[i]);
+        if self.debug_commands {
+            // only printable ASCII characters are ever stored in the buffer
+            let mut line = [0u8; 256];
+            for (byte, c) in line.iter_mut().zip(&self.recv_buf[..self.recv_buf_pos]) {
+                *byte = *c as u8;
+            }
+            let line = str::from_utf8(&line[..self.recv_buf_pos]).unwrap();
+            if let Err(e) = dispatch(line) {
+                logln!("{:?}, type help for a list of commands", e);
+            }
+        } else {
+            for i in 0..self.recv_buf_pos {
+                log!("{}", self.recv_buf[i]);
+            }
+            log!("\n");
         }
-        log!("\n");
         self.recv_buf_pos = 0;
         self.print_term_begin();
     }
 
     fn handle_char(&mut self, c: char) {
         if Self::is_ascii_printable(c as u8) {
+            // keep room for the terminating null
+            if self.recv_buf_pos == self.recv_buf.len() - 1 {
+                return;
+            }
             log!("{}", c);
             log!("_\x08");
             self.recv_buf[self.recv_buf_pos] = c;
@@ -75,3 +210,90 @@ impl<T: Serial> Kmon<T> {
         log!(">>> _\x08");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// The number of the test handler that ran last and the number of arguments it was given
+    static CALLED: AtomicUsize = AtomicUsize::new(0);
+    static N_ARGS: AtomicUsize = AtomicUsize::new(0);
+
+    fn record(handler: usize, args: &mut SplitWhitespace) {
+        CALLED.store(handler, Ordering::SeqCst);
+        N_ARGS.store(args.count(), Ordering::SeqCst);
+    }
+
+    fn first(args: &mut SplitWhitespace) -> Result<(), CommandError> {
+        record(1, args);
+        Ok(())
+    }
+
+    fn second(args: &mut SplitWhitespace) -> Result<(), CommandError> {
+        record(2, args);
+        Err(CommandError::InvalidArgument)
+    }
+
+    const TEST_COMMANDS: [Command; 2] = [
+        Command {
+            name: "first",
+            usage: "first",
+            handler: first,
+        },
+        Command {
+            name: "second",
+            usage: "second",
+            handler: second,
+        },
+    ];
+
+    #[test]
+    fn dispatch_runs_the_named_handler() {
+        assert_eq!(dispatch_from(&TEST_COMMANDS, "first"), Ok(()));
+        assert_eq!(CALLED.load(Ordering::SeqCst), 1);
+        assert_eq!(N_ARGS.load(Ordering::SeqCst), 0);
+        // the handler's error is passed on
+        assert_eq!(
+            dispatch_from(&TEST_COMMANDS, "  second a  b "),
+            Err(CommandError::InvalidArgument)
+        );
+        assert_eq!(CALLED.load(Ordering::SeqCst), 2);
+        assert_eq!(N_ARGS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn dispatch_rejects_unknown_commands() {
+        assert_eq!(dispatch("bogus"), Err(CommandError::UnknownCommand));
+        assert_eq!(dispatch("MEM 0x1000"), Err(CommandError::UnknownCommand));
+        assert_eq!(
+            dispatch_from(&TEST_COMMANDS, "firs"),
+            Err(CommandError::UnknownCommand)
+        );
+        assert_eq!(dispatch(""), Ok(()));
+        assert_eq!(dispatch(" \t "), Ok(()));
+    }
+
+    #[test]
+    fn mem_validates_its_argument() {
+        assert_eq!(dispatch("mem"), Err(CommandError::MissingArgument));
+        assert_eq!(dispatch("mem 0xzz"), Err(CommandError::InvalidArgument));
+        assert_eq!(dispatch("mem -1"), Err(CommandError::InvalidArgument));
+        // not canonical with either 4 or 5 level paging
+        assert_eq!(
+            dispatch("mem 0x0100000000000000"),
+            Err(CommandError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn command_names_are_unique() {
+        for (i, command) in COMMANDS.iter().enumerate() {
+            assert!(COMMANDS[i + 1..]
+                .iter()
+                .all(|other| other.name != command.name));
+            assert!(command.usage.starts_with(command.name));
+        }
+    }
+}
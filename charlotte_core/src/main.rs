@@ -1,28 +1,66 @@
-#![no_std]
-#![no_main]
+// unit tests are built for the host, where the standard library and its entry point are available
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused))]
 #![warn(missing_copy_implementations)]
 
 use core::fmt::Write;
 use core::panic::PanicInfo;
 
-use arch::{Api, ArchApi, HwTimerMode};
+use arch::{Api, ArchApi, HwTimerMode, LogLevel, LOGGER};
 
 use crate::kmon::Kmon;
 
 mod acpi;
 mod arch;
 mod bootinfo;
+mod cmdline;
+mod elf;
 mod framebuffer;
 mod kmon;
 mod memory;
+mod sync;
+mod time;
 
 /// This is the kernel entrypoint function,
 /// the first thing it does is call: [isa_init](ArchApi::isa_init)
 /// you should check the documentation on that function for details,
 /// since that contains all the ISA specific initialization code.
+#[cfg(not(test))]
 #[no_mangle]
 unsafe extern "C" fn main() -> ! {
-    let mut arch_api = ArchApi::isa_init();
+    if let Err(missing) = bootinfo::validate_responses() {
+        logln!(
+            "The bootloader did not provide a {} response, which the kernel requires. Halting!",
+            missing
+        );
+        ArchApi::halt();
+    }
+    let args = bootinfo::cmdline().map(cmdline::parse);
+    if let Some(level) = args
+        .and_then(|args| args.get("log_level"))
+        .and_then(LogLevel::from_name)
+    {
+        LOGGER.lock().set_level(level);
+    }
+    if args.is_some_and(|args| args.flag("serial_log_only")) {
+        LOGGER.lock().set_console_mirroring(false);
+    }
+    let arch_api = ArchApi::isa_init();
+    match time::init(&arch_api) {
+        Some(source) => {
+            LOGGER.lock().enable_timestamps(time::monotonic);
+            logln!(
+                "Using the {} clock source with a resolution of {}ns",
+                source.name(),
+                source.resolution_ns()
+            );
+        }
+        None => {
+            logln!("No clock source is available, log lines are not timestamped");
+        }
+    }
+    arch_api.log_boot_summary();
     logln!("Bring up finished, starting kernel interactive prompt");
 
 //This code currently causes a triple fault if allowed to run. A fix is needed!
@@ -34,10 +72,14 @@ unsafe extern "C" fn main() -> ! {
     arch_api.start_isa_timers(); */
     let port = arch_api.get_serial();
     let mut mon = Kmon::new(port);
+    if args.is_some_and(|args| args.flag("kmon_debug")) {
+        mon.enable_debug_commands();
+    }
     mon.repl_loop();
 
-    #[allow(clippy::empty_loop)]
-    loop {}
+    loop {
+        ArchApi::idle();
+    }
 }
 
 #[no_mangle]
@@ -53,9 +95,30 @@ fn on_tick(_: u64) {
     ArchApi::end_of_interrupt();
 }
 
+/// How long a panicking LP waits for another LP to finish its log line before it reports the
+/// panic on the serial port directly
+#[cfg(not(test))]
+const PANIC_LOG_SPINS: usize = 1 << 20;
+
+#[cfg(not(test))]
 #[panic_handler]
-fn rust_panic(_info: &PanicInfo) -> ! {
-    logln!("A kernel panic has occurred due to a Rust runtime panic.");
-    logln!("PanicInfo: {:?}", _info);
+fn rust_panic(info: &PanicInfo) -> ! {
+    // the panic may have happened while this LP was writing a log line
+    // SAFETY: this LP never returns to the code that held the lock
+    unsafe { LOGGER.force_unlock_if_held_here() };
+    match LOGGER.try_lock_for(PANIC_LOG_SPINS) {
+        Some(mut logger) => report_panic(&mut *logger, info),
+        // the LP holding the logger may be stuck as well, so it is bypassed
+        None => report_panic(&mut ArchApi::get_logger(), info),
+    }
     ArchApi::panic()
 }
+
+#[cfg(not(test))]
+fn report_panic(logger: &mut impl Write, info: &PanicInfo) {
+    let _ = writeln!(
+        logger,
+        "A kernel panic has occurred due to a Rust runtime panic."
+    );
+    let _ = writeln!(logger, "PanicInfo: {:?}", info);
+}
@@ -1,6 +1,9 @@
+use core::fmt;
+use core::num::NonZeroUsize;
 use core::ops::Add;
 
 use crate::arch::{Api, ArchApi, ISA_PARAMS};
+use crate::memory::layout;
 use crate::memory::pmm::DIRECT_MAP;
 
 pub const PAGE_SIZE: UAddr = ISA_PARAMS.paging.page_size;
@@ -20,7 +23,7 @@ pub trait MemoryAddress {
     fn is_vaddress() -> bool;
 }
 
-#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct PhysicalAddress(UAddr);
 
@@ -30,6 +33,15 @@ impl PhysicalAddress {
         Self(addr)
     }
 
+    /// Like [new](Self::new) but only accepts addresses that fit in the physical address width
+    /// of the processor
+    /// # Returns
+    /// Returns `None` if any bit above the physical address width is set.
+    #[inline]
+    pub fn try_new(addr: UAddr) -> Option<Self> {
+        ArchApi::validate_paddr(addr as usize).then_some(Self(addr))
+    }
+
     pub const fn as_usize(&self) -> usize {
         self.0 as usize
     }
@@ -49,6 +61,23 @@ impl PhysicalAddress {
         Self::new(pfn << PAGE_SHIFT)
     }
 
+    /// Returns the number of the frame that this address is the base of
+    /// The address is expected to be page aligned.
+    #[inline]
+    pub fn frame_number(&self) -> usize {
+        debug_assert!(
+            self.is_page_aligned(),
+            "frame_number called on an unaligned address"
+        );
+        self.pfn() as usize
+    }
+
+    /// Returns the base address of the frame with the given frame number
+    #[inline]
+    pub const fn from_frame_number(n: usize) -> Self {
+        Self::from_pfn(n as UAddr)
+    }
+
     #[inline]
     /// # Safety
     /// This function will panic in case align == 0 or align - 1 == 0
@@ -136,8 +165,75 @@ impl Add<UAddr> for PhysicalAddress {
     }
 }
 
+/// Addresses are formatted as `0x` followed by all 16 hex digits so that they line up in dumps
+impl fmt::Display for PhysicalAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)
+    }
+}
+
+impl fmt::LowerHex for PhysicalAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Debug for PhysicalAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PhysicalAddress({})", self)
+    }
+}
+
+/// A non-zero number of whole pages whose size in bytes fits in a `usize`
+/// Sizes taken by the mapping APIs use this type so that they never have to check for zero or
+/// partial pages themselves.
 #[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq)]
 #[repr(transparent)]
+pub struct PageCount(NonZeroUsize);
+
+impl PageCount {
+    /// Returns `None` if `n_pages` is zero or too many pages to express in bytes
+    #[inline]
+    pub const fn new(n_pages: usize) -> Option<Self> {
+        if n_pages.checked_mul(PAGE_SIZE as usize).is_none() {
+            return None;
+        }
+        match NonZeroUsize::new(n_pages) {
+            Some(n_pages) => Some(Self(n_pages)),
+            None => None,
+        }
+    }
+
+    /// Returns `None` if `bytes` is zero or not a whole number of pages
+    #[inline]
+    #[allow(unused)]
+    pub const fn from_bytes(bytes: usize) -> Option<Self> {
+        if bytes & (PAGE_SIZE as usize - 1) != 0 {
+            None
+        } else {
+            Self::new(bytes >> PAGE_SHIFT)
+        }
+    }
+
+    /// Returns the number of pages needed to hold `bytes` or `None` if `bytes` is zero
+    #[inline]
+    pub const fn from_bytes_round_up(bytes: usize) -> Option<Self> {
+        Self::new(bytes.div_ceil(PAGE_SIZE as usize))
+    }
+
+    #[inline]
+    pub const fn get(&self) -> usize {
+        self.0.get()
+    }
+
+    #[inline]
+    pub const fn bytes(&self) -> usize {
+        self.0.get() * PAGE_SIZE as usize
+    }
+}
+
+#[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
+#[repr(transparent)]
 pub struct VirtualAddress(UAddr);
 
 #[derive(Debug, Clone, Copy)]
@@ -163,10 +259,36 @@ impl VirtualAddress {
     pub fn is_null(&self) -> bool {
         self.0 == 0
     }
+    /// Check if the virtual address is in the higher half, which belongs to the kernel.
+    /// Canonical addresses are sign extended from the highest implemented bit (47 or 56 depending
+    /// on the paging mode) so the top bit identifies the half regardless of the paging mode.
+    #[inline]
+    pub fn is_kernel(&self) -> bool {
+        self.0 & (1 << (UAddr::BITS - 1)) != 0
+    }
+    /// Check if the virtual address is in the lower half and above the null guard, i.e. an address
+    /// user space may map
+    #[inline]
+    pub fn is_user(&self) -> bool {
+        !self.is_kernel() && self.0 >= layout::USER.start
+    }
     /// Check if the virtual address is aligned to the specified alignment
+    /// `align` must be a power of two. Zero is aligned to every alignment.
     #[inline]
     pub fn is_aligned_to(&self, align: UAddr) -> bool {
-        self.0 % align == 0
+        debug_assert!(align.is_power_of_two(), "alignment must be a power of two");
+        self.0 & (align - 1) == 0
+    }
+    /// Returns the lowest address that is aligned to `align` and not below this address
+    /// An address that is already aligned is returned unchanged. `align` must be a power of two.
+    /// # Returns
+    /// Returns `None` if rounding up would overflow or leave the canonical address range.
+    #[inline]
+    pub fn aligned_after(&self, align: UAddr) -> Option<VirtualAddress> {
+        debug_assert!(align.is_power_of_two(), "alignment must be a power of two");
+        self.0
+            .checked_next_multiple_of(align)
+            .and_then(|addr| VirtualAddress::try_from(addr).ok())
     }
     /// Get the base address of the page that the virtual address is in
     #[inline]
@@ -178,6 +300,11 @@ impl VirtualAddress {
     pub fn get_page_offset(&self) -> usize {
         (self.0 & 0xfff) as usize
     }
+    /// Get the number of the page that the virtual address is in
+    #[inline]
+    pub fn page_number(&self) -> usize {
+        (self.0 >> PAGE_SHIFT) as usize
+    }
     #[inline]
     pub fn pml4_index(&self) -> usize {
         ((self.0 >> 39) & 0x1ff) as usize
@@ -192,7 +319,7 @@ impl VirtualAddress {
     }
     #[inline]
     pub fn pt_index(&self) -> usize {
-        ((self.0 >> 12) & 0x1ff) as usize
+        self.page_number() & 0x1ff
     }
 }
 
@@ -204,7 +331,7 @@ impl MemoryAddress for VirtualAddress {
     }
 
     fn is_page_aligned(&self) -> bool {
-        self.is_aligned_to(self.get_page_base())
+        self.is_aligned_to(PAGE_SIZE)
     }
 
     fn is_vaddress() -> bool {
@@ -272,3 +399,127 @@ impl Add<UAddr> for VirtualAddress {
         Self(self.0 + val)
     }
 }
+
+/// Addresses are formatted as `0x` followed by all 16 hex digits so that they line up in dumps
+impl fmt::Display for VirtualAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)
+    }
+}
+
+impl fmt::LowerHex for VirtualAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Debug for VirtualAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VirtualAddress({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_count_rejects_zero_and_overflow() {
+        assert_eq!(PageCount::new(0), None);
+        assert_eq!(PageCount::new(3).map(|count| count.get()), Some(3));
+        let max_pages = usize::MAX / PAGE_SIZE as usize;
+        assert!(PageCount::new(max_pages).is_some());
+        assert_eq!(PageCount::new(max_pages + 1), None);
+    }
+
+    #[test]
+    fn page_count_from_bytes() {
+        let page = PAGE_SIZE as usize;
+        assert_eq!(PageCount::from_bytes(0), None);
+        assert_eq!(PageCount::from_bytes(page + 1), None);
+        assert_eq!(PageCount::from_bytes(2 * page), PageCount::new(2));
+        assert_eq!(PageCount::from_bytes_round_up(0), None);
+        assert_eq!(PageCount::from_bytes_round_up(1), PageCount::new(1));
+        assert_eq!(PageCount::from_bytes_round_up(page + 1), PageCount::new(2));
+        assert_eq!(PageCount::new(5).unwrap().bytes(), 5 * page);
+    }
+
+    const ALIGNMENTS: [UAddr; 3] = [PAGE_SIZE, 2 << 20, 1 << 30];
+
+    fn vaddr(addr: u64) -> VirtualAddress {
+        VirtualAddress::try_from(addr).unwrap()
+    }
+
+    #[test]
+    fn aligned_after_keeps_aligned_addresses() {
+        for align in ALIGNMENTS {
+            assert_eq!(
+                VirtualAddress::NULL.aligned_after(align),
+                Some(VirtualAddress::NULL)
+            );
+            assert_eq!(
+                vaddr(3 * align).aligned_after(align),
+                Some(vaddr(3 * align))
+            );
+            let high = vaddr(0u64.wrapping_sub(2 * align));
+            assert_eq!(high.aligned_after(align), Some(high));
+        }
+    }
+
+    #[test]
+    fn aligned_after_rounds_up() {
+        for align in ALIGNMENTS {
+            for offset in [1, align / 2, align - 1] {
+                let aligned = vaddr(3 * align + offset).aligned_after(align).unwrap();
+                assert_eq!(aligned, vaddr(4 * align));
+                assert!(aligned.is_aligned_to(align));
+            }
+        }
+    }
+
+    #[test]
+    fn aligned_after_rejects_overflow() {
+        for align in ALIGNMENTS {
+            let last = vaddr(0u64.wrapping_sub(align));
+            assert_eq!(last.aligned_after(align), Some(last));
+            assert_eq!(vaddr(last.bits() + 1).aligned_after(align), None);
+            assert_eq!(vaddr(u64::MAX).aligned_after(align), None);
+        }
+    }
+
+    #[test]
+    fn aligned_after_rejects_the_canonical_hole() {
+        // the first address above the lower half is the first non-canonical one
+        let hole = 1u64 << (ArchApi::get_vaddr_width() - 1);
+        for align in ALIGNMENTS {
+            let below = vaddr(hole - align);
+            assert_eq!(below.aligned_after(align), Some(below));
+            assert_eq!(vaddr(below.bits() + 1).aligned_after(align), None);
+            assert_eq!(vaddr(hole - 1).aligned_after(align), None);
+        }
+    }
+
+    #[test]
+    fn addresses_format_as_padded_hex() {
+        let paddr = PhysicalAddress::new(0x1000);
+        assert_eq!(format!("{}", paddr), "0x0000000000001000");
+        assert_eq!(
+            format!("{:?}", paddr),
+            "PhysicalAddress(0x0000000000001000)"
+        );
+        let vaddr = VirtualAddress::try_from(0xffff_8000_0000_1000).unwrap();
+        assert_eq!(format!("{}", vaddr), "0xffff800000001000");
+        assert_eq!(format!("{:?}", vaddr), "VirtualAddress(0xffff800000001000)");
+    }
+
+    #[test]
+    fn lower_hex_honors_the_format_flags() {
+        let paddr = PhysicalAddress::new(0xabc000);
+        assert_eq!(format!("{:x}", paddr), "abc000");
+        assert_eq!(format!("{:#x}", paddr), "0xabc000");
+        assert_eq!(format!("{:#010x}", paddr), "0x00abc000");
+        let vaddr = VirtualAddress::try_from(0x7000).unwrap();
+        assert_eq!(format!("{:x}", vaddr), "7000");
+        assert_eq!(format!("{:>8x}", vaddr), "    7000");
+    }
+}
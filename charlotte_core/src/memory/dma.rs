@@ -0,0 +1,44 @@
+//! # DMA Buffers
+//! Physically contiguous, zeroed blocks of frames for devices to read from and write to.
+
+use crate::memory::address::{PageCount, PhysicalAddress, PAGE_SIZE};
+use crate::memory::pmm::{Error, PHYSICAL_FRAME_ALLOCATOR};
+
+/// A physically contiguous block of zeroed frames that is freed again when the buffer is dropped.
+/// Mappings of the buffer do not own its frames, so the buffer has to outlive all of them.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    base: PhysicalAddress,
+    n_pages: PageCount,
+}
+
+impl DmaBuffer {
+    /// Allocates `n_pages` contiguous frames, zeroes them and pins them since a device may access
+    /// them at any time
+    #[allow(unused)]
+    pub fn allocate(n_pages: PageCount) -> Result<Self, Error> {
+        let mut pmm = PHYSICAL_FRAME_ALLOCATOR.lock();
+        let base = pmm.allocate_contiguous_zeroed(n_pages.get() as u64, PAGE_SIZE)?;
+        for frame in base.iter_frames(n_pages.get() as u64) {
+            pmm.pin_frame(frame)?;
+        }
+        Ok(Self { base, n_pages })
+    }
+
+    /// Returns the physical address of the first frame, which is what a device is programmed with
+    pub fn paddr(&self) -> PhysicalAddress {
+        self.base
+    }
+
+    pub fn n_pages(&self) -> PageCount {
+        self.n_pages
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let _ = PHYSICAL_FRAME_ALLOCATOR
+            .lock()
+            .deallocate_contiguous(self.base, self.n_pages.get() as u64);
+    }
+}
@@ -0,0 +1,102 @@
+//! # Virtual Memory Layout
+//! The fixed regions of the virtual address space and where each of them lives.
+//! The values assume 4-level paging which gives a 48 bit canonical address space split into a
+//! lower (user) half and a higher (kernel) half by a non-canonical hole.
+
+use crate::memory::address::UAddr;
+
+/// A half open range of virtual addresses `[start, end)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: UAddr,
+    pub end: UAddr,
+}
+
+impl Region {
+    pub const fn new(start: UAddr, end: UAddr) -> Self {
+        Region { start, end }
+    }
+
+    pub const fn contains(&self, addr: UAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub const fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    pub const fn size(&self) -> UAddr {
+        self.end - self.start
+    }
+}
+
+/// User space, everything in the lower half above the null guard page
+pub const USER: Region = Region::new(0x1000, 0x0000_8000_0000_0000);
+/// The addresses between the two canonical halves, these can never be mapped
+pub const CANONICAL_HOLE: Region = Region::new(0x0000_8000_0000_0000, 0xFFFF_8000_0000_0000);
+/// The size of the unmapped guards that separate the regions of the higher half
+pub const GUARD_SIZE: UAddr = 0x1000;
+/// The direct map of all physical memory. Limine places it at the start of the higher half unless
+/// KASLR is in use, the actual base is read from the bootloader at runtime.
+pub const HHDM: Region = Region::new(0xFFFF_8000_0000_0000, 0xFFFF_9000_0000_0000 - GUARD_SIZE);
+/// The kernel heap
+pub const KERNEL_HEAP: Region =
+    Region::new(0xFFFF_9000_0000_0000, 0xFFFF_A000_0000_0000 - GUARD_SIZE);
+/// Per-CPU windows for temporarily mapping single frames, see the fixmap module of each ISA
+pub const FIXMAP: Region = Region::new(0xFFFF_A000_0000_0000, 0xFFFF_A000_0020_0000);
+/// The top 2 GiB where the linker script places the kernel image, minus the last page which is
+/// never mapped
+pub const KERNEL_IMAGE: Region = Region::new(0xFFFF_FFFF_8000_0000, 0xFFFF_FFFF_FFFF_F000);
+
+/// The gaps between adjacent regions of the higher half. Nothing is ever mapped in them, so that a
+/// pointer running off the end of one region faults instead of reaching into the next.
+pub const GUARDS: [Region; 4] = [
+    Region::new(HHDM.end, KERNEL_HEAP.start),
+    Region::new(KERNEL_HEAP.end, FIXMAP.start),
+    Region::new(FIXMAP.end, FIXMAP.end + GUARD_SIZE),
+    Region::new(KERNEL_IMAGE.start - GUARD_SIZE, KERNEL_IMAGE.start),
+];
+
+/// The index of the first PML4 entry that belongs to the higher half
+pub const HIGHER_HALF_PML4_INDEX: usize = (CANONICAL_HOLE.end >> 39) as usize & 0x1FF;
+
+const REGIONS: [Region; 6] = [
+    USER,
+    CANONICAL_HOLE,
+    HHDM,
+    KERNEL_HEAP,
+    FIXMAP,
+    KERNEL_IMAGE,
+];
+
+// every region must be non-empty, the regions must be listed in ascending order and none of them may
+// overlap any other
+const _: () = {
+    let mut i = 0;
+    while i < REGIONS.len() {
+        assert!(REGIONS[i].start < REGIONS[i].end);
+        if i > 0 {
+            assert!(REGIONS[i - 1].end <= REGIONS[i].start);
+        }
+        let mut j = 0;
+        while j < REGIONS.len() {
+            assert!(i == j || !REGIONS[i].overlaps(&REGIONS[j]));
+            j += 1;
+        }
+        i += 1;
+    }
+};
+
+// every guard must be at least a page in size and must not overlap any region
+const _: () = {
+    let mut i = 0;
+    while i < GUARDS.len() {
+        assert!(GUARDS[i].size() >= GUARD_SIZE);
+        let mut j = 0;
+        while j < REGIONS.len() {
+            assert!(!GUARDS[i].overlaps(&REGIONS[j]));
+            j += 1;
+        }
+        i += 1;
+    }
+};
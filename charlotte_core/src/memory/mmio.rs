@@ -0,0 +1,68 @@
+//! # Memory Mapped I/O
+//! Accessors for memory mapped device registers. Every access is volatile so that the compiler
+//! can neither elide nor merge register reads and writes.
+
+use crate::memory::address::VirtualAddress;
+
+/// A single memory mapped register of type `T`
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct Mmio<T: Copy> {
+    ptr: *mut T,
+}
+
+impl<T: Copy> Mmio<T> {
+    /// # Safety
+    /// `vaddr` must be mapped to a device register of type `T` for as long as the accessor is used.
+    pub unsafe fn new(vaddr: VirtualAddress) -> Self {
+        debug_assert!(vaddr.is_aligned_to(core::mem::align_of::<T>() as u64));
+        Self {
+            ptr: <*mut T>::from(vaddr),
+        }
+    }
+
+    #[inline]
+    pub fn read(&self) -> T {
+        // SAFETY: `new` requires `ptr` to be mapped to a register of type `T`
+        unsafe { self.ptr.read_volatile() }
+    }
+
+    #[inline]
+    pub fn write(&self, value: T) {
+        // SAFETY: `new` requires `ptr` to be mapped to a register of type `T`
+        unsafe { self.ptr.write_volatile(value) }
+    }
+}
+
+/// A block of memory mapped registers addressed by their byte offset from the base of the block
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterBlock {
+    base: VirtualAddress,
+}
+
+impl RegisterBlock {
+    /// # Safety
+    /// `base` must be mapped to the device's register block for as long as the accessor is used
+    /// and every offset used with it must be that of a register of the type it is accessed as.
+    pub unsafe fn new(base: VirtualAddress) -> Self {
+        Self { base }
+    }
+
+    /// Returns an accessor for the register of type `T` at `offset` bytes from the block's base
+    #[inline]
+    pub fn reg<T: Copy>(&self, offset: usize) -> Mmio<T> {
+        // SAFETY: `new` requires every offset used with the block to be that of a register of type
+        // `T`
+        unsafe { Mmio::new(self.base + offset) }
+    }
+
+    #[inline]
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        self.reg(offset).read()
+    }
+
+    #[inline]
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        self.reg(offset).write(value)
+    }
+}
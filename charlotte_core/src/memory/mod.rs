@@ -4,5 +4,21 @@
 //! all virtual address spaces.
 
 pub mod address;
+pub mod dma;
+pub mod layout;
+pub mod mmio;
 pub mod pmm;
 pub mod span_printer;
+
+/// Zeroes up to `budget` recently freed frames so that the data they held does not linger in free
+/// memory and later zeroed allocations can skip zeroing them.
+/// This is meant to be called by an idle LP, it only holds the frame allocator lock for as long as
+/// it takes to zero `budget` frames.
+/// # Returns
+/// Returns the number of frames that were scrubbed.
+#[allow(unused)]
+pub fn scrub_freed_frames(budget: usize) -> usize {
+    pmm::PHYSICAL_FRAME_ALLOCATOR
+        .lock()
+        .scrub_freed_frames(budget)
+}
@@ -1,10 +1,13 @@
 use crate::bootinfo;
 use crate::memory::address::{PhysicalAddress, UAddr, VirtualAddress};
 
-use core::slice::from_raw_parts_mut;
+use core::fmt;
+use core::panic::Location;
+use core::slice::{from_raw_parts, from_raw_parts_mut};
 
 use spin::{lazy::Lazy, mutex::Mutex};
 
+#[cfg(not(test))]
 pub static DIRECT_MAP: Lazy<VirtualAddress> = Lazy::new(|| {
     VirtualAddress::try_from(
         bootinfo::HHDM_REQUEST
@@ -15,8 +18,18 @@ pub static DIRECT_MAP: Lazy<VirtualAddress> = Lazy::new(|| {
     .expect("Direct map address does not fit in a VirtualAddress")
 });
 
-pub static PHYSICAL_FRAME_ALLOCATOR: Lazy<Mutex<PhysicalFrameAllocator>> =
-    Lazy::new(|| Mutex::new(PhysicalFrameAllocator::new()));
+/// Unit tests have no direct map, physical addresses are offsets into [test_memory] instead
+#[cfg(test)]
+pub static DIRECT_MAP: Lazy<VirtualAddress> = Lazy::new(|| {
+    VirtualAddress::try_from(test_memory::base())
+        .expect("Test memory address does not fit in a VirtualAddress")
+});
+
+/// The global physical frame allocator
+/// It hands out no frames until [init_from_memory_map](PhysicalFrameAllocator::init_from_memory_map)
+/// has been called during ISA initialization.
+pub static PHYSICAL_FRAME_ALLOCATOR: Mutex<PhysicalFrameAllocator> =
+    Mutex::new(PhysicalFrameAllocator::new());
 
 pub struct MemoryMap {
     entries: &'static [&'static bootinfo::memory_map::Entry],
@@ -67,6 +80,114 @@ impl MemoryMap {
     }
 }
 
+/// A summary of physical memory by the type the bootloader reported for it
+/// All values are in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryReport {
+    pub usable: UAddr,
+    /// Usable memory that is currently not allocated
+    pub free: UAddr,
+    pub reserved: UAddr,
+    pub acpi_reclaimable: UAddr,
+    pub acpi_nvs: UAddr,
+    pub bad: UAddr,
+    pub bootloader_reclaimable: UAddr,
+    pub kernel_and_modules: UAddr,
+    pub framebuffer: UAddr,
+    /// Memory of a type this kernel does not know about
+    pub unknown: UAddr,
+}
+
+impl MemoryReport {
+    /// Sums the lengths of the memory map entries of each type. `free` is left at 0.
+    pub fn from_entries<'a>(
+        entries: impl Iterator<Item = &'a &'a bootinfo::memory_map::Entry>,
+    ) -> Self {
+        use bootinfo::memory_map::EntryType;
+
+        let mut report = MemoryReport::default();
+        for entry in entries {
+            let total = match entry.entry_type {
+                EntryType::USABLE => &mut report.usable,
+                EntryType::RESERVED => &mut report.reserved,
+                EntryType::ACPI_RECLAIMABLE => &mut report.acpi_reclaimable,
+                EntryType::ACPI_NVS => &mut report.acpi_nvs,
+                EntryType::BAD_MEMORY => &mut report.bad,
+                EntryType::BOOTLOADER_RECLAIMABLE => &mut report.bootloader_reclaimable,
+                EntryType::KERNEL_AND_MODULES => &mut report.kernel_and_modules,
+                EntryType::FRAMEBUFFER => &mut report.framebuffer,
+                _ => &mut report.unknown,
+            };
+            *total += entry.length;
+        }
+        report
+    }
+}
+
+impl fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows = [
+            ("Usable", self.usable),
+            ("Free", self.free),
+            ("Reserved", self.reserved),
+            ("ACPI reclaimable", self.acpi_reclaimable),
+            ("ACPI NVS", self.acpi_nvs),
+            ("Bad", self.bad),
+            ("Bootloader reclaimable", self.bootloader_reclaimable),
+            ("Kernel and modules", self.kernel_and_modules),
+            ("Framebuffer", self.framebuffer),
+            ("Unknown", self.unknown),
+        ];
+        for (i, (name, bytes)) in rows.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:<24}{} KiB", name, bytes / 1024)?;
+        }
+        Ok(())
+    }
+}
+
+/// The number of operations performed by [PhysicalFrameAllocator::benchmark]
+const BENCHMARK_OPS: usize = 4096;
+/// The number of allocations the benchmark keeps alive at once
+const BENCHMARK_SLOTS: usize = 64;
+/// The largest allocation the benchmark makes in frames
+const BENCHMARK_MAX_FRAMES: UAddr = 8;
+/// The number of operations between two measurements of the fragmentation
+const BENCHMARK_SAMPLE_INTERVAL: usize = 64;
+
+/// The results of [PhysicalFrameAllocator::benchmark], timings are in counter ticks
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BenchmarkReport {
+    pub allocations: usize,
+    pub allocation_ticks: u64,
+    pub frees: usize,
+    pub free_ticks: u64,
+    pub reallocations: usize,
+    pub reallocation_ticks: u64,
+    /// The number of allocations and reallocations that failed for lack of memory
+    pub failures: usize,
+    /// The largest share of free frames that lay outside the longest run of free frames, in percent
+    pub peak_fragmentation: UAddr,
+}
+
+impl fmt::Display for BenchmarkReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows = [
+            ("Allocate", self.allocations, self.allocation_ticks),
+            ("Free", self.frees, self.free_ticks),
+            ("Reallocate", self.reallocations, self.reallocation_ticks),
+        ];
+        for (name, ops, ticks) in rows {
+            let per_op = ticks.checked_div(ops as u64).unwrap_or(0);
+            writeln!(f, "{:<12}{:>6} ops {:>10} cycles/op", name, ops, per_op)?;
+        }
+        writeln!(f, "Failed allocations: {}", self.failures)?;
+        write!(f, "Peak fragmentation: {}%", self.peak_fragmentation)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(unused)]
 pub enum Error {
@@ -78,6 +199,16 @@ pub enum Error {
     AddressOutOfRange,
     InvalidSize,
     InvalidAlignment,
+    AlreadyAllocated,
+    AllocationTooLarge,
+    SelfTestFailed(PhysicalAddress),
+    NotInitialized,
+    InvalidColor,
+    AlignmentUnavailable,
+    /// A frame that is free was asked to be pinned or shared
+    FrameNotAllocated,
+    /// A frame is already referenced by as many mappings as its share count can track
+    TooManySharers,
 }
 
 enum RegionAvailability {
@@ -86,19 +217,129 @@ enum RegionAvailability {
 }
 
 const FRAME_SIZE: UAddr = 4096;
+/// The number of recently freed frames remembered for reuse by single frame allocations
+const FREE_LIST_CAPACITY: usize = 64;
+const _: () = assert!(FREE_LIST_CAPACITY <= u64::BITS as usize);
+/// The number of live single frame allocations whose call sites are recorded in debug builds
+#[cfg(debug_assertions)]
+const CALL_SITE_CAPACITY: usize = 256;
+
+/// How [PhysicalFrameAllocator::allocate] picks a frame when cache coloring is disabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocationStrategy {
+    /// The most recently freed frame is reused if there is one, which is likely still cached,
+    /// otherwise the lowest free frame is taken
+    #[default]
+    Recycling,
+    /// The lowest free frame is always taken, which keeps allocations packed at the bottom of
+    /// physical memory and the addresses handed out reproducible from boot to boot
+    FirstFit,
+}
+
+impl AllocationStrategy {
+    /// Parses a strategy from its name as given on the kernel command line e.g.
+    /// `pmm_strategy=first_fit`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "recycling" => Some(AllocationStrategy::Recycling),
+            "first_fit" => Some(AllocationStrategy::FirstFit),
+            _ => None,
+        }
+    }
+}
 
 /// A bitmap based physical frame allocator
 pub struct PhysicalFrameAllocator {
     bitmap: &'static mut [u8],
+    /// A bit per frame like `bitmap`, set for allocated frames that must stay where they are and
+    /// may never be reclaimed or migrated, such as page tables and DMA buffers. Freeing a frame
+    /// unpins it.
+    pinned: &'static mut [u8],
+    /// The number of references to each allocated frame besides the one it was allocated with,
+    /// such as the mappings of an address space and its forks sharing the frame. Releasing a
+    /// shared frame only drops a reference, the frame is freed along with the last one. A block
+    /// of contiguous frames is counted on its first frame.
+    sharers: &'static mut [u16],
+    /// The largest number of frames a single contiguous allocation may request
+    max_alloc_frames: UAddr,
+    /// The number of cache colors frames are distributed over, 1 disables coloring
+    n_colors: usize,
+    /// The color the next uncolored allocation is taken from
+    next_color: usize,
+    strategy: AllocationStrategy,
+    /// Every byte of the bitmap before this index is known to be full, so single frame
+    /// allocations start searching here instead of at the start of the bitmap
+    first_free_byte: usize,
+    /// Frames freed by [deallocate](Self::deallocate) that single frame allocations reuse before
+    /// searching the bitmap. The bitmap stays authoritative, a frame on the list may have been
+    /// handed out by another kind of allocation since, so every frame is checked when it is taken.
+    free_list: [PhysicalAddress; FREE_LIST_CAPACITY],
+    free_list_len: usize,
+    /// Bit `i` is set if the frame in `free_list[i]` has been zeroed by
+    /// [scrub_freed_frames](Self::scrub_freed_frames) and not been written to since
+    free_list_scrubbed: u64,
+    /// The number of times [allocate_zeroed](Self::allocate_zeroed) skipped zeroing a frame because
+    /// it had already been scrubbed
+    zeroes_skipped: usize,
+    /// The number of single frame allocations that may still succeed before `allocate` reports
+    /// `Error::OutOfMemory`, `None` unless a unit test has injected a failure
+    #[cfg(test)]
+    fail_after: Option<usize>,
+    /// A frame whose pattern [self_test](Self::self_test) reads and writes at another frame
+    /// instead, `None` unless a unit test has injected an aliasing fault
+    #[cfg(test)]
+    alias: Option<(PhysicalAddress, PhysicalAddress)>,
+    /// The frames handed out by `allocate` and `allocate_zeroed` that have not been freed yet along
+    /// with the location they were allocated from. Allocations made while the table is full are
+    /// not recorded.
+    #[cfg(debug_assertions)]
+    call_sites: [Option<(PhysicalAddress, &'static Location<'static>)>; CALL_SITE_CAPACITY],
+    #[cfg(debug_assertions)]
+    n_call_sites: usize,
+    initialized: bool,
 }
 
 impl PhysicalFrameAllocator {
-    fn new() -> PhysicalFrameAllocator {
+    const fn new() -> PhysicalFrameAllocator {
+        PhysicalFrameAllocator {
+            bitmap: &mut [],
+            pinned: &mut [],
+            sharers: &mut [],
+            max_alloc_frames: 0,
+            n_colors: 1,
+            next_color: 0,
+            strategy: AllocationStrategy::Recycling,
+            first_free_byte: 0,
+            free_list: [PhysicalAddress::new(0); FREE_LIST_CAPACITY],
+            free_list_len: 0,
+            free_list_scrubbed: 0,
+            zeroes_skipped: 0,
+            #[cfg(test)]
+            fail_after: None,
+            #[cfg(test)]
+            alias: None,
+            #[cfg(debug_assertions)]
+            call_sites: [None; CALL_SITE_CAPACITY],
+            #[cfg(debug_assertions)]
+            n_call_sites: 0,
+            initialized: false,
+        }
+    }
+
+    /// Builds the frame bitmap from the bootloader provided memory map.
+    /// Calling this more than once has no effect.
+    pub fn init_from_memory_map(&mut self) {
+        if self.initialized {
+            return;
+        }
         let memory_map = MemoryMap::get();
         let total_memory = memory_map.highest_address();
         let bitmap_len = (total_memory / FRAME_SIZE).div_ceil(u8::BITS as u64);
-        // find a region that is large enough to hold the bitmap
-        let region = memory_map.find_best_fit(bitmap_len)
+        let n_frames = bitmap_len * u8::BITS as u64;
+        let sharers_len = n_frames * core::mem::size_of::<u16>() as u64;
+        // find a region that is large enough to hold the bitmap followed by the pin bitmap and the
+        // share counts
+        let region = memory_map.find_best_fit(2 * bitmap_len + sharers_len)
             .expect("Failed to find a physical memory region large enough to hold the physical frame allocator bitmap");
 
         // Initialize bitmap and create PFA
@@ -109,7 +350,25 @@ impl PhysicalFrameAllocator {
             from_raw_parts_mut(bitmap_addr, bitmap_len as usize)
         };
 
-        let mut pfa = PhysicalFrameAllocator { bitmap };
+        // SAFETY: the pinned bitmap follows the frame bitmap in the region reserved for both above
+        let pinned = unsafe {
+            let pinned_addr = bitmap_addr.add(bitmap_len as usize);
+            pinned_addr.write_bytes(0, bitmap_len as usize);
+            from_raw_parts_mut(pinned_addr, bitmap_len as usize)
+        };
+
+        // SAFETY: the share counts follow the two bitmaps in the same region, which is page
+        // aligned, so they are aligned for u16 and the region is large enough to hold them
+        let sharers = unsafe {
+            let sharers_addr = bitmap_addr.add(2 * bitmap_len as usize).cast::<u16>();
+            sharers_addr.write_bytes(0, n_frames as usize);
+            from_raw_parts_mut(sharers_addr, n_frames as usize)
+        };
+
+        self.max_alloc_frames = (bitmap.len() * 8) as UAddr;
+        self.bitmap = bitmap;
+        self.pinned = pinned;
+        self.sharers = sharers;
 
         // clear the bits corresponding to available frames
         for entry in MemoryMap::get().iter() {
@@ -118,7 +377,7 @@ impl PhysicalFrameAllocator {
                     let start = PhysicalAddress::new(entry.base);
                     let n_frames = entry.length / FRAME_SIZE;
                     for addr in start.iter_frames(n_frames) {
-                        pfa.clear_by_address(addr);
+                        self.clear_by_address(addr);
                     }
                 }
                 _ => {
@@ -126,20 +385,29 @@ impl PhysicalFrameAllocator {
                     let start = PhysicalAddress::new(entry.base);
                     let n_frames = entry.length / FRAME_SIZE;
                     for addr in start.iter_frames(n_frames) {
-                        pfa.set_by_address(addr);
+                        self.set_by_address(addr);
                     }
                 }
             }
         }
 
-        // set the bits corresponding to the bitmap as unavailable
+        // set the bits corresponding to the bitmaps as unavailable
         let bitmap_start = PhysicalAddress::new(region.base);
         let bitmap_frames = (region.length).div_ceil(FRAME_SIZE);
         for addr in bitmap_start.iter_frames(bitmap_frames) {
-            pfa.set_by_address(addr);
+            self.set_by_address(addr);
         }
 
-        pfa
+        self.initialized = true;
+    }
+
+    #[inline]
+    fn ensure_initialized(&self) -> Result<(), Error> {
+        if self.initialized {
+            Ok(())
+        } else {
+            Err(Error::NotInitialized)
+        }
     }
 
     #[inline]
@@ -147,18 +415,608 @@ impl PhysicalFrameAllocator {
         (self.bitmap.len() * 8) as UAddr
     }
 
+    /// Limits the number of frames a single contiguous allocation may request
+    /// Requests for more frames fail with `Error::AllocationTooLarge`.
+    #[allow(unused)]
+    pub fn set_max_alloc_frames(&mut self, max_alloc_frames: UAddr) {
+        self.max_alloc_frames = max_alloc_frames;
+    }
+
+    /// Sets the number of cache colors to spread allocations over.
+    /// Frames whose frame numbers are congruent modulo `n_colors` compete for the same cache sets,
+    /// so `n_colors` should be the size of one way of the cache divided by the frame size.
+    /// A value of 0 or 1 disables coloring.
+    pub fn set_n_colors(&mut self, n_colors: usize) {
+        self.n_colors = n_colors.max(1);
+        self.next_color = 0;
+    }
+
+    /// Selects how single frames are allocated when cache coloring is disabled.
+    /// Frames remembered for reuse are forgotten when switching away from
+    /// [AllocationStrategy::Recycling].
+    pub fn set_strategy(&mut self, strategy: AllocationStrategy) {
+        self.strategy = strategy;
+        if strategy != AllocationStrategy::Recycling {
+            self.free_list_len = 0;
+            self.free_list_scrubbed = 0;
+        }
+    }
+
+    pub fn strategy(&self) -> AllocationStrategy {
+        self.strategy
+    }
+
+    /// Returns the number of cache colors frames are distributed over
+    pub fn n_colors(&self) -> usize {
+        self.n_colors
+    }
+
+    /// Returns the cache color of `frame`
+    #[allow(unused)]
+    pub fn color_of(&self, frame: PhysicalAddress) -> usize {
+        frame.frame_number() % self.n_colors
+    }
+
+    /// Makes `allocate` fail with `Error::OutOfMemory` once `n` more frames have been allocated
+    /// This allows error paths such as running out of memory in the middle of a page table walk
+    /// to be exercised deterministically by unit tests. The failure persists until it is cleared.
+    #[cfg(test)]
+    pub fn inject_failure_after(&mut self, n: usize) {
+        self.fail_after = Some(n);
+    }
+
+    /// Removes an injected failure, allocations succeed normally again afterwards
+    #[cfg(test)]
+    pub fn clear_injected_failure(&mut self) {
+        self.fail_after = None;
+    }
+
+    /// Makes [self_test](Self::self_test) access `alias` whenever it accesses `frame`, as if both
+    /// were backed by the same memory
+    #[cfg(test)]
+    pub fn inject_alias(&mut self, frame: PhysicalAddress, alias: PhysicalAddress) {
+        self.alias = Some((frame, alias));
+    }
+
+    #[cfg(test)]
+    fn check_injected_failure(&mut self) -> Result<(), Error> {
+        match self.fail_after {
+            Some(0) => Err(Error::OutOfMemory),
+            Some(ref mut n) => {
+                *n -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Summarizes the bootloader memory map by region type along with the memory that is free now
+    pub fn memory_report(&self) -> MemoryReport {
+        MemoryReport {
+            free: self.free_frames() * FRAME_SIZE,
+            ..MemoryReport::from_entries(MemoryMap::get().iter())
+        }
+    }
+
+    /// Returns the number of frames that are currently available for allocation
+    pub fn free_frames(&self) -> UAddr {
+        self.bitmap
+            .iter()
+            .map(|byte| byte.count_zeros() as UAddr)
+            .sum()
+    }
+
+    /// Exhaustively tests the allocator by allocating every free frame, writing a pattern unique to
+    /// each frame through the direct map and then reading every pattern back to confirm that no two
+    /// frames alias each other. All frames are freed afterwards and the allocator is left in exactly
+    /// the state it was in before the test, including the color rotation, the search hint and the
+    /// free list. Scrubbed frames on the free list are zeroed again after the test wrote to them.
+    /// # Returns
+    /// Returns `Error::SelfTestFailed` with the address of the first frame whose pattern did not
+    /// survive or whose state was not restored.
+    pub fn self_test(&mut self) -> Result<(), Error> {
+        self.ensure_initialized()?;
+        const PATTERN: u64 = 0x5A5A_5A5A_5A5A_5A5A;
+        let baseline = self.free_frames();
+        let (next_color, first_free_byte) = (self.next_color, self.first_free_byte);
+        let (free_list, free_list_len) = (self.free_list, self.free_list_len);
+        let free_list_scrubbed = self.free_list_scrubbed;
+
+        // keep a copy of the bitmap so that the frames allocated by the test can be identified
+        let snapshot_len = self.bitmap.len();
+        let snapshot_frames = (snapshot_len as UAddr).div_ceil(FRAME_SIZE);
+        let snapshot_base = self.allocate_contiguous(snapshot_frames, FRAME_SIZE)?;
+        // SAFETY: the snapshot frames were just allocated and are reachable through the direct map
+        let snapshot = unsafe {
+            let snapshot_addr = <*mut u8>::from(snapshot_base);
+            snapshot_addr.copy_from_nonoverlapping(self.bitmap.as_ptr(), snapshot_len);
+            from_raw_parts(snapshot_addr, snapshot_len)
+        };
+        let was_free = |frame: PhysicalAddress| {
+            let (byte, bit) = (frame.frame_number() / 8, frame.frame_number() % 8);
+            snapshot[byte] & (1 << bit) == 0
+        };
+
+        let mut result = Ok(());
+        let capacity = self.frame_capacity();
+        let frames = || (0..capacity).map(PhysicalAddress::from_pfn);
+        for frame in frames().filter(|frame| was_free(*frame)) {
+            self.set_by_address(frame);
+            // SAFETY: the frame is free and was just claimed by the test, it is reachable through
+            // the direct map
+            unsafe {
+                self.pattern_word(frame)
+                    .write_volatile(PATTERN ^ frame.pfn())
+            };
+        }
+        for frame in frames().filter(|frame| was_free(*frame)) {
+            // SAFETY: the frame was written above and is still claimed by the test
+            let val = unsafe { self.pattern_word(frame).read_volatile() };
+            if val != PATTERN ^ frame.pfn() && result.is_ok() {
+                result = Err(Error::SelfTestFailed(frame));
+            }
+            self.clear_by_address(frame);
+        }
+
+        self.deallocate_contiguous(snapshot_base, snapshot_frames)?;
+        if result.is_ok() && self.free_frames() != baseline {
+            result = Err(Error::SelfTestFailed(snapshot_base));
+        }
+
+        for (slot, &frame) in free_list[..free_list_len].iter().enumerate() {
+            if free_list_scrubbed & (1 << slot) != 0 && !self.get_by_address(frame) {
+                // SAFETY: the frame is free and nothing else may access it, it is reachable
+                // through the direct map
+                unsafe { <*mut u8>::from(frame).write_bytes(0, FRAME_SIZE as usize) };
+            }
+        }
+        self.next_color = next_color;
+        self.first_free_byte = first_free_byte;
+        self.free_list = free_list;
+        self.free_list_len = free_list_len;
+        self.free_list_scrubbed = free_list_scrubbed;
+        result
+    }
+
+    /// Returns the word of `frame` that [self_test](Self::self_test) writes its pattern to
+    fn pattern_word(&self, frame: PhysicalAddress) -> *mut u64 {
+        #[cfg(test)]
+        let frame = match self.alias {
+            Some((aliased, alias)) if aliased == frame => alias,
+            _ => frame,
+        };
+        <*mut u64>::from(frame)
+    }
+
+    /// Times a fixed pseudo random mix of allocations, frees and reallocations of up to
+    /// [BENCHMARK_MAX_FRAMES] frames. `read_counter` should be a cycle counter such as the TSC.
+    /// Every frame allocated by the benchmark is freed again, leaving the allocator in the state it
+    /// was in before.
+    pub fn benchmark(&mut self, read_counter: fn() -> u64) -> Result<BenchmarkReport, Error> {
+        self.ensure_initialized()?;
+        let baseline = self.free_frames();
+        let (next_color, first_free_byte) = (self.next_color, self.first_free_byte);
+
+        let mut live: [Option<(PhysicalAddress, UAddr)>; BENCHMARK_SLOTS] = [None; BENCHMARK_SLOTS];
+        let mut report = BenchmarkReport::default();
+        let mut rng = 0x9E37_79B9_7F4A_7C15u64;
+        for op in 0..BENCHMARK_OPS {
+            // xorshift64, the sequence is the same on every run so results are comparable
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            let slot = rng as usize % BENCHMARK_SLOTS;
+            let n_frames = (rng >> 32) % BENCHMARK_MAX_FRAMES + 1;
+
+            let start = read_counter();
+            match live[slot] {
+                None => {
+                    live[slot] = self
+                        .benchmark_allocate(n_frames)
+                        .ok()
+                        .map(|base| (base, n_frames));
+                    report.allocation_ticks += read_counter().wrapping_sub(start);
+                    report.allocations += 1;
+                    report.failures += live[slot].is_none() as usize;
+                }
+                Some((base, old_frames)) if rng & (1 << 63) != 0 => {
+                    self.benchmark_free(base, old_frames)?;
+                    live[slot] = None;
+                    report.free_ticks += read_counter().wrapping_sub(start);
+                    report.frees += 1;
+                }
+                Some((base, old_frames)) => {
+                    // like a heap reallocation the contents move to the new frames
+                    if let Ok(new_base) = self.benchmark_allocate(n_frames) {
+                        let len = (old_frames.min(n_frames) * FRAME_SIZE) as usize;
+                        // SAFETY: both blocks are allocated, at least `len` bytes long and distinct
+                        // since the old block is still allocated
+                        unsafe {
+                            <*mut u8>::from(new_base)
+                                .copy_from_nonoverlapping(<*const u8>::from(base), len)
+                        };
+                        self.benchmark_free(base, old_frames)?;
+                        live[slot] = Some((new_base, n_frames));
+                    } else {
+                        report.failures += 1;
+                    }
+                    report.reallocation_ticks += read_counter().wrapping_sub(start);
+                    report.reallocations += 1;
+                }
+            }
+
+            if op % BENCHMARK_SAMPLE_INTERVAL == 0 {
+                let free = self.free_frames();
+                let outside = free - self.longest_free_run();
+                let fragmentation = (outside * 100).checked_div(free).unwrap_or(0);
+                report.peak_fragmentation = report.peak_fragmentation.max(fragmentation);
+            }
+        }
+
+        for (base, n_frames) in live.into_iter().flatten() {
+            self.benchmark_free(base, n_frames)?;
+        }
+        self.next_color = next_color;
+        self.first_free_byte = first_free_byte;
+        debug_assert_eq!(self.free_frames(), baseline);
+        Ok(report)
+    }
+
+    fn benchmark_allocate(&mut self, n_frames: UAddr) -> Result<PhysicalAddress, Error> {
+        if n_frames == 1 {
+            self.allocate()
+        } else {
+            self.allocate_contiguous(n_frames, FRAME_SIZE)
+        }
+    }
+
+    fn benchmark_free(&mut self, base: PhysicalAddress, n_frames: UAddr) -> Result<(), Error> {
+        if n_frames == 1 {
+            self.deallocate(base)
+        } else {
+            self.deallocate_contiguous(base, n_frames)
+        }
+    }
+
+    /// Returns the length of the longest run of free frames
+    fn longest_free_run(&self) -> UAddr {
+        let (mut longest, mut current) = (0, 0);
+        for byte in self.bitmap.iter() {
+            match *byte {
+                0x00 => current += 8,
+                0xFF => {
+                    longest = longest.max(current);
+                    current = 0;
+                }
+                byte => {
+                    for bit in 0..8 {
+                        if byte & (1 << bit) == 0 {
+                            current += 1;
+                        } else {
+                            longest = longest.max(current);
+                            current = 0;
+                        }
+                    }
+                }
+            }
+        }
+        longest.max(current)
+    }
+
+    /// Allocates a single frame. When cache coloring is enabled successive allocations rotate
+    /// through the colors, falling back to any free frame if the next color is exhausted.
+    /// Otherwise the frame is picked according to the [AllocationStrategy]. Under
+    /// [AllocationStrategy::Recycling] the most recently freed frame is reused if there is one,
+    /// which takes constant time, and the bitmap is only searched from the first byte with a free
+    /// frame if not.
+    #[cfg_attr(debug_assertions, track_caller)]
     pub fn allocate(&mut self) -> Result<PhysicalAddress, Error> {
-        for (byte_index, byte) in self.bitmap.iter_mut().enumerate() {
+        let (frame, _) = self.allocate_tracking_scrubbed()?;
+        #[cfg(debug_assertions)]
+        self.record_call_site(frame, Location::caller());
+        Ok(frame)
+    }
+
+    /// Allocates a single frame as [allocate](Self::allocate) does and reports whether the frame
+    /// is known to be zeroed because it was scrubbed while it sat on the free list
+    fn allocate_tracking_scrubbed(&mut self) -> Result<(PhysicalAddress, bool), Error> {
+        self.ensure_initialized()?;
+        #[cfg(test)]
+        self.check_injected_failure()?;
+        if self.n_colors > 1 {
+            let color = self.next_color;
+            self.next_color = (color + 1) % self.n_colors;
+            if let Ok(frame) = self.allocate_colored(color) {
+                return Ok((frame, false));
+            }
+        } else if self.strategy == AllocationStrategy::Recycling {
+            if let Some(popped) = self.pop_free_list() {
+                return Ok(popped);
+            }
+        }
+        let start = self.first_free_byte;
+        for (byte_index, byte) in self.bitmap.iter_mut().enumerate().skip(start) {
             let bit_index = byte.trailing_ones() as usize;
             if bit_index < 8 {
                 *byte |= 1 << bit_index;
-                return Ok(self.index_to_address(byte_index, bit_index));
+                self.first_free_byte = byte_index;
+                return Ok((self.index_to_address(byte_index, bit_index), false));
+            }
+        }
+        self.first_free_byte = self.bitmap.len();
+        Err(Error::OutOfMemory)
+    }
+
+    /// Allocates a single frame and zeroes it through the direct map before returning it.
+    /// Frames that were scrubbed while they were free are not zeroed a second time.
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn allocate_zeroed(&mut self) -> Result<PhysicalAddress, Error> {
+        let (frame, scrubbed) = self.allocate_tracking_scrubbed()?;
+        #[cfg(debug_assertions)]
+        self.record_call_site(frame, Location::caller());
+        if scrubbed {
+            self.zeroes_skipped += 1;
+        } else {
+            // SAFETY: the frame was just allocated and is reachable through the direct map
+            unsafe { <*mut u8>::from(frame).write_bytes(0, FRAME_SIZE as usize) };
+        }
+        Ok(frame)
+    }
+
+    /// Allocates a single zeroed frame like [allocate_zeroed](Self::allocate_zeroed) and pins it,
+    /// see [pin_frame](Self::pin_frame)
+    #[cfg_attr(debug_assertions, track_caller)]
+    pub fn allocate_zeroed_pinned(&mut self) -> Result<PhysicalAddress, Error> {
+        let frame = self.allocate_zeroed()?;
+        self.pin_frame(frame)?;
+        Ok(frame)
+    }
+
+    /// Pins an allocated frame so that it is never reclaimed or migrated, which is required for
+    /// frames whose physical address is held by hardware, like page tables and DMA buffers.
+    /// Pinning a pinned frame has no effect. The frame stays pinned until it is unpinned or freed.
+    /// # Returns
+    /// Returns `Error::FrameNotAllocated` if the frame is free.
+    pub fn pin_frame(&mut self, frame: PhysicalAddress) -> Result<(), Error> {
+        let (byte, bit) = self.checked_index(frame)?;
+        if self.bitmap[byte] & (1 << bit) == 0 {
+            return Err(Error::FrameNotAllocated);
+        }
+        self.pinned[byte] |= 1 << bit;
+        Ok(())
+    }
+
+    /// Makes a pinned frame eligible for reclamation and migration again. Unpinning a frame that
+    /// is not pinned has no effect.
+    #[allow(unused)]
+    pub fn unpin_frame(&mut self, frame: PhysicalAddress) -> Result<(), Error> {
+        let (byte, bit) = self.checked_index(frame)?;
+        self.pinned[byte] &= !(1 << bit);
+        Ok(())
+    }
+
+    #[allow(unused)]
+    pub fn is_pinned(&self, frame: PhysicalAddress) -> bool {
+        self.checked_index(frame)
+            .is_ok_and(|(byte, bit)| self.pinned[byte] & (1 << bit) != 0)
+    }
+
+    /// Records another reference to an allocated frame, such as a mapping of it in a forked
+    /// address space, so that [release_frame](Self::release_frame) only frees it once every
+    /// reference has been released. A block of contiguous frames is shared through its first frame.
+    /// # Returns
+    /// Returns `Error::FrameNotAllocated` if the frame is free and `Error::TooManySharers` if the
+    /// frame already has the most references that can be tracked.
+    pub fn share_frame(&mut self, frame: PhysicalAddress) -> Result<(), Error> {
+        let (byte, bit) = self.checked_index(frame)?;
+        if self.bitmap[byte] & (1 << bit) == 0 {
+            return Err(Error::FrameNotAllocated);
+        }
+        let count = &mut self.sharers[frame.frame_number()];
+        *count = count.checked_add(1).ok_or(Error::TooManySharers)?;
+        Ok(())
+    }
+
+    /// Returns the number of references to `frame` besides the one it was allocated with
+    pub fn sharers(&self, frame: PhysicalAddress) -> usize {
+        match self.checked_index(frame) {
+            Ok(_) => self.sharers[frame.frame_number()] as usize,
+            Err(_) => 0,
+        }
+    }
+
+    /// Drops a reference to `frame` and frees it if that was the last one, see
+    /// [share_frame](Self::share_frame)
+    /// # Returns
+    /// Returns whether the frame was freed.
+    pub fn release_frame(&mut self, frame: PhysicalAddress) -> Result<bool, Error> {
+        self.release_contiguous(frame, 1)
+    }
+
+    /// Drops a reference to the block of `n_frames` contiguous frames starting at `base` and frees
+    /// the whole block if that was the last one
+    /// # Returns
+    /// Returns whether the block was freed.
+    pub fn release_contiguous(
+        &mut self,
+        base: PhysicalAddress,
+        n_frames: UAddr,
+    ) -> Result<bool, Error> {
+        self.checked_index(base)?;
+        let count = &mut self.sharers[base.frame_number()];
+        if *count > 0 {
+            *count -= 1;
+            return Ok(false);
+        }
+        if n_frames == 1 {
+            self.deallocate(base)?;
+        } else {
+            self.deallocate_contiguous(base, n_frames)?;
+        }
+        Ok(true)
+    }
+
+    /// Returns every frame that is in use and not pinned, in ascending order. A reclamation or
+    /// migration pass must only ever consider the frames returned here. Frames that were never
+    /// usable, like firmware memory, are in use as far as the bitmap is concerned and are included.
+    #[allow(unused)]
+    pub fn reclaimable_frames(&self) -> impl Iterator<Item = PhysicalAddress> + '_ {
+        self.bitmap
+            .iter()
+            .zip(self.pinned.iter())
+            .enumerate()
+            .filter(|(_, (allocated, pinned))| **allocated & !**pinned != 0)
+            .flat_map(move |(byte_index, (allocated, pinned))| {
+                let candidates = *allocated & !*pinned;
+                (0..8)
+                    .filter(move |bit| candidates & (1 << bit) != 0)
+                    .map(move |bit| self.index_to_address(byte_index, bit))
+            })
+    }
+
+    /// Validates `frame` and returns the byte and bit that track it in the bitmaps
+    fn checked_index(&self, frame: PhysicalAddress) -> Result<(usize, usize), Error> {
+        self.ensure_initialized()?;
+        if !frame.is_page_aligned() {
+            return Err(Error::AddressMisaligned);
+        }
+        if frame.pfn() >= self.frame_capacity() {
+            return Err(Error::AddressOutOfRange);
+        }
+        Ok(self.address_to_index(frame))
+    }
+
+    /// Zeroes up to `budget` frames on the free list that have not been scrubbed yet, most
+    /// recently freed first, so that the data they held does not linger in free memory and a
+    /// later [allocate_zeroed](Self::allocate_zeroed) does not have to zero them again.
+    /// Frames that are no longer free are skipped without counting against the budget.
+    /// # Returns
+    /// Returns the number of frames that were scrubbed.
+    pub fn scrub_freed_frames(&mut self, budget: usize) -> usize {
+        let mut scrubbed = 0;
+        for slot in (0..self.free_list_len).rev() {
+            if scrubbed == budget {
+                break;
+            }
+            let frame = self.free_list[slot];
+            if self.free_list_scrubbed & (1 << slot) != 0 || self.get_by_address(frame) {
+                continue;
+            }
+            // SAFETY: the frame is free and nothing else may access it, it is reachable through the
+            // direct map
+            unsafe { <*mut u8>::from(frame).write_bytes(0, FRAME_SIZE as usize) };
+            self.free_list_scrubbed |= 1 << slot;
+            scrubbed += 1;
+        }
+        scrubbed
+    }
+
+    /// Records that `frame` was allocated from `location` if there is room left in the table
+    #[cfg(debug_assertions)]
+    fn record_call_site(&mut self, frame: PhysicalAddress, location: &'static Location<'static>) {
+        if let Some(slot) = self.call_sites.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some((frame, location));
+            self.n_call_sites += 1;
+        }
+    }
+
+    /// Forgets the call site of `frame` once it has been freed
+    #[cfg(debug_assertions)]
+    fn forget_call_site(&mut self, frame: PhysicalAddress) {
+        if self.n_call_sites == 0 {
+            return;
+        }
+        let recorded = self
+            .call_sites
+            .iter_mut()
+            .find(|slot| matches!(slot, Some((recorded, _)) if *recorded == frame));
+        if let Some(slot) = recorded {
+            *slot = None;
+            self.n_call_sites -= 1;
+        }
+    }
+
+    /// Returns every frame allocated by [allocate](Self::allocate) or
+    /// [allocate_zeroed](Self::allocate_zeroed) that has not been freed yet, along with the
+    /// location of the call that allocated it. Call sites are only recorded in debug builds, in
+    /// release builds nothing is returned.
+    pub fn live_allocations(
+        &self,
+    ) -> impl Iterator<Item = (PhysicalAddress, &'static Location<'static>)> + '_ {
+        #[cfg(debug_assertions)]
+        return self.call_sites.iter().flatten().copied();
+        #[cfg(not(debug_assertions))]
+        return core::iter::empty();
+    }
+
+    /// Returns the number of times [allocate_zeroed](Self::allocate_zeroed) handed out a scrubbed
+    /// frame without zeroing it again
+    #[allow(unused)]
+    pub fn zeroes_skipped(&self) -> usize {
+        self.zeroes_skipped
+    }
+
+    /// Allocates the lowest available frame of the given cache color
+    /// # Returns
+    /// Returns `Error::InvalidColor` if `color` is not below the configured number of colors.
+    pub fn allocate_colored(&mut self, color: usize) -> Result<PhysicalAddress, Error> {
+        self.ensure_initialized()?;
+        if color >= self.n_colors {
+            return Err(Error::InvalidColor);
+        }
+        for pfn in (color as UAddr..self.frame_capacity()).step_by(self.n_colors) {
+            let frame = PhysicalAddress::from_pfn(pfn);
+            if !self.get_by_address(frame) {
+                self.set_by_address(frame);
+                return Ok(frame);
+            }
+        }
+        Err(Error::OutOfMemory)
+    }
+
+    /// Allocates the lowest available frame whose base address is below `limit`.
+    /// This is intended for structures that must reside in low memory e.g. the AP trampoline.
+    #[allow(unused)]
+    pub fn allocate_below(&mut self, limit: PhysicalAddress) -> Result<PhysicalAddress, Error> {
+        self.ensure_initialized()?;
+        let limit_pfn = limit.pfn().min(self.frame_capacity());
+        for pfn in 0..limit_pfn {
+            let frame = PhysicalAddress::from_pfn(pfn);
+            if !self.get_by_address(frame) {
+                self.set_by_address(frame);
+                return Ok(frame);
             }
         }
         Err(Error::OutOfMemory)
     }
 
+    /// Marks the frame at exactly `frame` as allocated.
+    /// # Returns
+    /// Returns `Error::AlreadyAllocated` if the frame is already in use or otherwise unavailable.
+    #[allow(unused)]
+    pub fn reserve_specific(&mut self, frame: PhysicalAddress) -> Result<(), Error> {
+        self.ensure_initialized()?;
+        if !frame.is_page_aligned() {
+            return Err(Error::AddressMisaligned);
+        }
+        if frame.pfn() >= self.frame_capacity() {
+            return Err(Error::AddressOutOfRange);
+        }
+        if self.get_by_address(frame) {
+            return Err(Error::AlreadyAllocated);
+        }
+        self.set_by_address(frame);
+        Ok(())
+    }
+
+    /// Releases a frame previously claimed with [reserve_specific](Self::reserve_specific).
+    #[allow(unused)]
+    pub fn release_specific(&mut self, frame: PhysicalAddress) -> Result<(), Error> {
+        self.deallocate(frame)
+    }
+
     pub fn deallocate(&mut self, frame: PhysicalAddress) -> Result<(), Error> {
+        self.ensure_initialized()?;
         if !frame.is_page_aligned() {
             return Err(Error::AddressMisaligned);
         }
@@ -166,20 +1024,56 @@ impl PhysicalFrameAllocator {
             return Err(Error::AddressOutOfRange);
         }
         self.clear_by_address(frame);
+        if self.strategy == AllocationStrategy::Recycling && self.free_list_len < FREE_LIST_CAPACITY
+        {
+            self.free_list[self.free_list_len] = frame;
+            self.free_list_scrubbed &= !(1 << self.free_list_len);
+            self.free_list_len += 1;
+        }
         Ok(())
     }
 
+    /// Takes the most recently freed frame that is still free off the free list, skipping frames
+    /// that were allocated by other means since they were freed, along with whether it was scrubbed
+    fn pop_free_list(&mut self) -> Option<(PhysicalAddress, bool)> {
+        while self.free_list_len > 0 {
+            self.free_list_len -= 1;
+            let frame = self.free_list[self.free_list_len];
+            let scrubbed = self.free_list_scrubbed & (1 << self.free_list_len) != 0;
+            self.free_list_scrubbed &= !(1 << self.free_list_len);
+            if !self.get_by_address(frame) {
+                self.set_by_address(frame);
+                return Some((frame, scrubbed));
+            }
+        }
+        None
+    }
+
+    /// Allocates `n_frames` physically contiguous frames whose base is aligned to `alignment`.
+    /// Alignments below the frame size are always satisfied since every frame is frame aligned.
+    /// # Returns
+    /// Returns `Error::InvalidAlignment` if `alignment` is not a power of two and
+    /// `Error::AlignmentUnavailable` only if `alignment` exceeds the size of physical memory, in which
+    /// case no frame other than frame 0 could ever satisfy it. Any other alignment that cannot be
+    /// satisfied right now fails with `Error::InsufficientContiguousMemoryAvailable`.
     pub fn allocate_contiguous(
         &mut self,
         n_frames: UAddr,
         alignment: UAddr,
     ) -> Result<PhysicalAddress, Error> {
+        self.ensure_initialized()?;
         //validate inputs
         if n_frames == 0 {
             return Err(Error::InvalidSize);
         }
+        if n_frames > self.max_alloc_frames || n_frames.checked_mul(FRAME_SIZE).is_none() {
+            return Err(Error::AllocationTooLarge);
+        }
         if !alignment.is_power_of_two() {
-            return Err(Error::AddressMisaligned);
+            return Err(Error::InvalidAlignment);
+        }
+        if alignment / FRAME_SIZE >= self.frame_capacity() {
+            return Err(Error::AlignmentUnavailable);
         }
 
         // if the requested alignment is less than the frame size, then the alignment is the frame size
@@ -196,20 +1090,38 @@ impl PhysicalFrameAllocator {
                 }
                 RegionAvailability::Unavailable(last_frame) => {
                     // skip to the next properly aligned address after the last frame in the gap
-                    base = PhysicalAddress::new(
-                        (last_frame.bits() + FRAME_SIZE).next_multiple_of(corrected_alignment),
-                    );
+                    match (last_frame.bits() + FRAME_SIZE)
+                        .checked_next_multiple_of(corrected_alignment)
+                    {
+                        Some(next) => base = PhysicalAddress::new(next),
+                        None => break,
+                    }
                 }
             }
         }
         Err(Error::InsufficientContiguousMemoryAvailable)
     }
 
+    /// Like [allocate_contiguous](Self::allocate_contiguous) but zeroes all of the allocated
+    /// frames through the direct map before returning them
+    #[allow(unused)]
+    pub fn allocate_contiguous_zeroed(
+        &mut self,
+        n_frames: UAddr,
+        alignment: UAddr,
+    ) -> Result<PhysicalAddress, Error> {
+        let base = self.allocate_contiguous(n_frames, alignment)?;
+        // SAFETY: the frames were just allocated and are reachable through the direct map
+        unsafe { <*mut u8>::from(base).write_bytes(0, (n_frames * FRAME_SIZE) as usize) };
+        Ok(base)
+    }
+
     pub fn deallocate_contiguous(
         &mut self,
         base: PhysicalAddress,
         n_frames: UAddr,
     ) -> Result<(), Error> {
+        self.ensure_initialized()?;
         // validate inputs
         if n_frames == 0 {
             return Err(Error::InvalidSize);
@@ -232,34 +1144,296 @@ impl PhysicalFrameAllocator {
         // the address of the last frame in the gap is returned
         // this is useful for the allocate_contiguous method
         // if a gap is found, the method can continue searching from after the gap
-        for address in (base.bits()..(base + n_frames).bits()).rev() {
-            if self.get_by_address(PhysicalAddress::from(address)) {
-                return RegionAvailability::Unavailable(PhysicalAddress::from(address));
+        for frame in (0..n_frames).rev().map(|i| base + i * FRAME_SIZE) {
+            if self.get_by_address(frame) {
+                return RegionAvailability::Unavailable(frame);
             }
         }
         RegionAvailability::Available
     }
 
     fn index_to_address(&self, byte: usize, bit: usize) -> PhysicalAddress {
-        PhysicalAddress::from_pfn((byte * 8 + bit) as UAddr)
+        PhysicalAddress::from_frame_number(byte * 8 + bit)
     }
 
-    fn address_to_index(&self, address: PhysicalAddress) -> (UAddr, UAddr) {
-        ((address.pfn() / 8) as UAddr, (address.pfn() % 8) as UAddr)
+    fn address_to_index(&self, address: PhysicalAddress) -> (usize, usize) {
+        (address.frame_number() / 8, address.frame_number() % 8)
     }
 
     fn get_by_address(&self, address: PhysicalAddress) -> bool {
         let (byte, bit) = self.address_to_index(address);
-        self.bitmap[byte as usize] & (1 << bit) != 0
+        self.bitmap[byte] & (1 << bit) != 0
     }
 
     fn set_by_address(&mut self, address: PhysicalAddress) {
         let (byte, bit) = self.address_to_index(address);
-        self.bitmap[byte as usize] |= 1 << bit;
+        self.bitmap[byte] |= 1 << bit;
     }
 
     fn clear_by_address(&mut self, address: PhysicalAddress) {
         let (byte, bit) = self.address_to_index(address);
-        self.bitmap[byte as usize] &= !(1 << bit);
+        self.bitmap[byte] &= !(1 << bit);
+        self.pinned[byte] &= !(1 << bit);
+        self.sharers[address.frame_number()] = 0;
+        self.first_free_byte = self.first_free_byte.min(byte);
+        #[cfg(debug_assertions)]
+        self.forget_call_site(address);
+        // a frame that is freed again may have been written to since it was scrubbed under an
+        // older free list entry
+        if self.free_list_scrubbed != 0 {
+            for slot in 0..self.free_list_len {
+                if self.free_list[slot] == address {
+                    self.free_list_scrubbed &= !(1 << slot);
+                }
+            }
+        }
+    }
+}
+
+/// Physical memory for unit tests, which run as a user space process without a direct map. A leaked
+/// allocation stands in for physical memory starting at address 0 and the global allocator hands
+/// out its frames, so that code which reaches frames through the direct map can be tested.
+#[cfg(test)]
+pub mod test_memory {
+    use std::alloc::{alloc_zeroed, Layout};
+    use std::sync::{Mutex as StdMutex, MutexGuard as StdMutexGuard};
+
+    use super::*;
+
+    /// The number of frames of test memory
+    pub const N_FRAMES: usize = 8192;
+
+    /// Returns the address that test memory starts at
+    pub fn base() -> UAddr {
+        static BASE: Lazy<UAddr> = Lazy::new(|| {
+            let size = N_FRAMES * FRAME_SIZE as usize;
+            let layout = Layout::from_size_align(size, FRAME_SIZE as usize).unwrap();
+            // SAFETY: the layout has a non-zero size
+            let base = unsafe { alloc_zeroed(layout) };
+            assert!(!base.is_null(), "Unable to allocate test memory");
+            base as UAddr
+        });
+        *BASE
+    }
+
+    /// Serializes the tests that use the global allocator, handing it test memory the first time.
+    /// Frame 0 stays allocated so that no frame is at physical address 0 and injected failures
+    /// are cleared.
+    pub fn lock() -> StdMutexGuard<'static, ()> {
+        static LOCK: StdMutex<()> = StdMutex::new(());
+        let guard = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut pmm = PHYSICAL_FRAME_ALLOCATOR.lock();
+        if !pmm.initialized {
+            *pmm = super::tests::allocator(N_FRAMES);
+            pmm.reserve_specific(PhysicalAddress::new(0)).unwrap();
+        }
+        pmm.clear_injected_failure();
+        guard
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an initialized allocator managing `n_frames` free frames starting at address 0.
+    /// Its bitmaps are leaked, as the ones of the kernel's allocator live for the whole run.
+    pub(super) fn allocator(n_frames: usize) -> PhysicalFrameAllocator {
+        let mut pmm = PhysicalFrameAllocator::new();
+        pmm.bitmap = Vec::leak(vec![0; n_frames / 8]);
+        pmm.pinned = Vec::leak(vec![0; n_frames / 8]);
+        pmm.sharers = Vec::leak(vec![0; n_frames]);
+        pmm.max_alloc_frames = n_frames as UAddr;
+        pmm.initialized = true;
+        pmm
+    }
+
+    fn frame(pfn: UAddr) -> PhysicalAddress {
+        PhysicalAddress::from_pfn(pfn)
+    }
+
+    #[test]
+    fn specific_frames_are_validated() {
+        let mut pmm = allocator(64);
+        assert_eq!(
+            pmm.reserve_specific(PhysicalAddress::new(0x1001)),
+            Err(Error::AddressMisaligned)
+        );
+        assert_eq!(
+            pmm.reserve_specific(frame(64)),
+            Err(Error::AddressOutOfRange)
+        );
+        assert_eq!(pmm.reserve_specific(frame(7)), Ok(()));
+        assert_eq!(pmm.reserve_specific(frame(7)), Err(Error::AlreadyAllocated));
+        assert_eq!(pmm.release_specific(frame(7)), Ok(()));
+        assert_eq!(pmm.reserve_specific(frame(7)), Ok(()));
+    }
+
+    #[test]
+    fn bitmap_index_round_trips() {
+        let mut pmm = allocator(64);
+        assert_eq!(pmm.index_to_address(3, 5), frame(29));
+        assert_eq!(pmm.address_to_index(frame(29)), (3, 5));
+        assert!(!pmm.get_by_address(frame(29)));
+        pmm.set_by_address(frame(29));
+        assert!(pmm.get_by_address(frame(29)));
+        assert_eq!(pmm.bitmap[3], 1 << 5);
+        pmm.clear_by_address(frame(29));
+        assert!(!pmm.get_by_address(frame(29)));
+    }
+
+    #[test]
+    fn contiguous_allocations_validate_their_size() {
+        let mut pmm = allocator(64);
+        assert_eq!(pmm.allocate_contiguous(0, 1), Err(Error::InvalidSize));
+        assert_eq!(
+            pmm.allocate_contiguous(65, 1),
+            Err(Error::AllocationTooLarge)
+        );
+        pmm.set_max_alloc_frames(UAddr::MAX);
+        assert_eq!(
+            pmm.allocate_contiguous(UAddr::MAX / FRAME_SIZE + 1, 1),
+            Err(Error::AllocationTooLarge)
+        );
+    }
+
+    #[test]
+    fn uninitialized_allocator_refuses_requests() {
+        let mut pmm = PhysicalFrameAllocator::new();
+        assert_eq!(pmm.allocate(), Err(Error::NotInitialized));
+        assert_eq!(pmm.deallocate(frame(0)), Err(Error::NotInitialized));
+    }
+
+    #[test]
+    fn allocate_reports_exhaustion() {
+        let mut pmm = allocator(8);
+        for pfn in 0..8 {
+            assert_eq!(pmm.allocate(), Ok(frame(pfn)));
+        }
+        assert_eq!(pmm.allocate(), Err(Error::OutOfMemory));
+        assert_eq!(pmm.free_frames(), 0);
+    }
+
+    #[test]
+    fn contiguous_allocations_skip_gaps_and_honor_alignment() {
+        let mut pmm = allocator(64);
+        pmm.reserve_specific(frame(1)).unwrap();
+        assert_eq!(pmm.allocate_contiguous(2, 4 * FRAME_SIZE), Ok(frame(4)));
+        assert_eq!(pmm.allocate_contiguous(2, 1), Ok(frame(2)));
+        assert_eq!(
+            pmm.allocate_contiguous(1, 3 * FRAME_SIZE),
+            Err(Error::InvalidAlignment)
+        );
+        assert_eq!(
+            pmm.allocate_contiguous(1, 64 * FRAME_SIZE),
+            Err(Error::AlignmentUnavailable)
+        );
+        pmm.deallocate_contiguous(frame(4), 2).unwrap();
+        assert_eq!(pmm.allocate_contiguous(2, 1), Ok(frame(4)));
+    }
+
+    #[test]
+    fn allocate_recycles_the_most_recently_freed_frame() {
+        let mut pmm = allocator(64);
+        assert_eq!(pmm.allocate(), Ok(frame(0)));
+        assert_eq!(pmm.allocate(), Ok(frame(1)));
+        assert_eq!(pmm.allocate(), Ok(frame(2)));
+        pmm.deallocate(frame(0)).unwrap();
+        pmm.deallocate(frame(2)).unwrap();
+        assert_eq!(pmm.allocate(), Ok(frame(2)));
+        assert_eq!(pmm.allocate(), Ok(frame(0)));
+    }
+
+    #[test]
+    fn first_fit_ignores_recently_freed_frames() {
+        let mut pmm = allocator(64);
+        pmm.set_strategy(AllocationStrategy::FirstFit);
+        for pfn in 0..3 {
+            assert_eq!(pmm.allocate(), Ok(frame(pfn)));
+        }
+        pmm.deallocate(frame(2)).unwrap();
+        pmm.deallocate(frame(0)).unwrap();
+        assert_eq!(pmm.allocate(), Ok(frame(0)));
+        assert_eq!(pmm.allocate(), Ok(frame(2)));
+    }
+
+    #[test]
+    fn freeing_a_frame_unpins_it() {
+        let mut pmm = allocator(16);
+        let pinned = pmm.allocate().unwrap();
+        let unpinned = pmm.allocate().unwrap();
+        pmm.pin_frame(pinned).unwrap();
+        assert!(pmm.is_pinned(pinned));
+        assert_eq!(pmm.reclaimable_frames().collect::<Vec<_>>(), [unpinned]);
+        pmm.deallocate(pinned).unwrap();
+        assert!(!pmm.is_pinned(pinned));
+        assert_eq!(pmm.pin_frame(pinned), Err(Error::FrameNotAllocated));
+    }
+
+    #[test]
+    fn shared_frames_are_freed_with_the_last_reference() {
+        let mut pmm = allocator(64);
+        let shared = pmm.allocate().unwrap();
+        pmm.share_frame(shared).unwrap();
+        pmm.share_frame(shared).unwrap();
+        assert_eq!(pmm.sharers(shared), 2);
+        assert_eq!(pmm.release_frame(shared), Ok(false));
+        assert_eq!(pmm.release_frame(shared), Ok(false));
+        assert!(pmm.get_by_address(shared));
+        assert_eq!(pmm.release_frame(shared), Ok(true));
+        assert!(!pmm.get_by_address(shared));
+        assert_eq!(pmm.share_frame(shared), Err(Error::FrameNotAllocated));
+
+        let full = pmm.allocate().unwrap();
+        pmm.sharers[full.frame_number()] = u16::MAX;
+        assert_eq!(pmm.share_frame(full), Err(Error::TooManySharers));
+        assert_eq!(pmm.sharers(full), u16::MAX as usize);
+    }
+
+    #[test]
+    fn self_test_detects_aliased_frames_and_restores_the_allocator() {
+        let _memory = test_memory::lock();
+        // the frames below the ones taken from the kernel's allocator may be in use by it
+        let base = PHYSICAL_FRAME_ALLOCATOR
+            .lock()
+            .allocate_contiguous(64, 8 * FRAME_SIZE)
+            .unwrap();
+        let mut pmm = allocator(base.pfn() as usize + 64);
+        for pfn in 0..base.pfn() {
+            pmm.set_by_address(frame(pfn));
+        }
+        pmm.set_strategy(AllocationStrategy::Recycling);
+        pmm.set_n_colors(4);
+        let freed = [pmm.allocate().unwrap(), pmm.allocate().unwrap()];
+        for frame in freed {
+            pmm.deallocate(frame).unwrap();
+        }
+        assert_eq!(pmm.scrub_freed_frames(1), 1);
+        let state = |pmm: &PhysicalFrameAllocator| {
+            (
+                pmm.next_color,
+                pmm.first_free_byte,
+                pmm.free_list,
+                pmm.free_list_len,
+                pmm.free_list_scrubbed,
+                pmm.free_frames(),
+            )
+        };
+        let before = state(&pmm);
+
+        assert_eq!(pmm.self_test(), Ok(()));
+        assert_eq!(state(&pmm), before);
+        // SAFETY: the scrubbed frame is free and lies in the test memory
+        assert_eq!(unsafe { <*const u64>::from(freed[1]).read() }, 0);
+
+        let aliased = base + 40 * FRAME_SIZE;
+        pmm.inject_alias(aliased, base + 50 * FRAME_SIZE);
+        assert_eq!(pmm.self_test(), Err(Error::SelfTestFailed(aliased)));
+        assert_eq!(state(&pmm), before);
+
+        PHYSICAL_FRAME_ALLOCATOR
+            .lock()
+            .deallocate_contiguous(base, 64)
+            .unwrap();
     }
 }
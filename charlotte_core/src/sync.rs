@@ -0,0 +1,265 @@
+//! # Synchronization
+//! Locks for data that is also used from interrupt handlers. Taking an ordinary spinlock that an
+//! interrupted context on the same LP already holds never succeeds, so these keep interrupts
+//! disabled on the calling LP for as long as the lock is held.
+
+use core::hint::spin_loop;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::mutex::{SpinMutex, SpinMutexGuard};
+
+use crate::arch::{Api, ArchApi};
+
+/// The most iterations of `pause` a waiter spins for between two attempts to take a lock
+const MAX_BACKOFF: usize = 1 << 10;
+
+/// A spinlock that disables interrupts on the calling LP while it is held
+/// Waiters retry with exponential backoff, pausing between attempts, so that contended locks do not
+/// keep the lock's cache line bouncing between LPs and waste less power.
+pub struct IrqSafeMutex<T> {
+    inner: SpinMutex<T>,
+    /// One more than the logical index of the LP holding the lock, 0 if it is free or held by an
+    /// LP without an index
+    owner: AtomicUsize,
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSafeMutex {
+            inner: SpinMutex::new(value),
+            owner: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wraps a guard of the inner lock and records the calling LP as its holder
+    fn guard<'a>(
+        &'a self,
+        guard: SpinMutexGuard<'a, T>,
+        irq_enabled: bool,
+    ) -> IrqSafeMutexGuard<'a, T> {
+        let owner = ArchApi::current_cpu_index().map_or(0, |index| index + 1);
+        self.owner.store(owner, Ordering::Relaxed);
+        IrqSafeMutexGuard {
+            guard: ManuallyDrop::new(guard),
+            owner: &self.owner,
+            irq_enabled,
+        }
+    }
+
+    /// Disables interrupts and spins until the lock is acquired, interrupts are restored to their
+    /// previous state when the returned guard is dropped
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let irq_enabled = ArchApi::irq_save();
+        // without a budget acquire only returns once it holds the lock
+        let guard = self.acquire(None).unwrap();
+        self.guard(guard, irq_enabled)
+    }
+
+    /// Like [lock](Self::lock) but gives up after spinning for about `spins` iterations of the
+    /// spin loop hint. This lets contexts such as interrupt handlers, which must not wait on a lock
+    /// that the code they interrupted may hold, make bounded progress.
+    #[allow(unused)]
+    pub fn try_lock_for(&self, spins: usize) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let irq_enabled = ArchApi::irq_save();
+        match self.acquire(Some(spins)) {
+            Some(guard) => Some(self.guard(guard, irq_enabled)),
+            None => {
+                ArchApi::irq_restore(irq_enabled);
+                None
+            }
+        }
+    }
+
+    /// Tries to take the lock, waiting twice as long after each failed attempt up to
+    /// [MAX_BACKOFF], until it is taken or `max_spins` iterations have been spent waiting
+    fn acquire(&self, max_spins: Option<usize>) -> Option<SpinMutexGuard<'_, T>> {
+        let mut backoff = 1;
+        let mut spins = 0;
+        loop {
+            if let Some(guard) = self.inner.try_lock() {
+                return Some(guard);
+            }
+            let wait = match max_spins {
+                Some(max_spins) if spins >= max_spins => return None,
+                Some(max_spins) => backoff.min(max_spins - spins),
+                None => backoff,
+            };
+            for _ in 0..wait {
+                spin_loop();
+            }
+            spins += wait;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Acquires the lock only if it is free
+    #[allow(unused)]
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let irq_enabled = ArchApi::irq_save();
+        match self.inner.try_lock() {
+            Some(guard) => Some(self.guard(guard, irq_enabled)),
+            None => {
+                ArchApi::irq_restore(irq_enabled);
+                None
+            }
+        }
+    }
+
+    /// Releases the lock if the calling LP holds it, a lock that is free or held by another LP is
+    /// left alone. A lock taken by an LP without a logical index is never released.
+    /// # Safety
+    /// The calling LP must never return to the code holding the lock, as on a panic, since that
+    /// code keeps using the data as if it were still locked.
+    /// # Returns
+    /// Returns true if the lock was released.
+    pub unsafe fn force_unlock_if_held_here(&self) -> bool {
+        let Some(index) = ArchApi::current_cpu_index() else {
+            return false;
+        };
+        if self.owner.load(Ordering::Relaxed) != index + 1 {
+            return false;
+        }
+        self.owner.store(0, Ordering::Relaxed);
+        // SAFETY: the lock is held by the calling LP, which the caller guarantees never returns to
+        // the holder
+        unsafe { self.inner.force_unlock() };
+        true
+    }
+}
+
+/// Grants access to the data of an [IrqSafeMutex], see [IrqSafeMutex::lock]
+pub struct IrqSafeMutexGuard<'a, T> {
+    guard: ManuallyDrop<SpinMutexGuard<'a, T>>,
+    owner: &'a AtomicUsize,
+    irq_enabled: bool,
+}
+
+impl<T> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.owner.store(0, Ordering::Relaxed);
+        // the lock has to be released before interrupts are enabled again
+        // SAFETY: the guard is never used again after it is dropped here
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        ArchApi::irq_restore(self.irq_enabled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::arch::x86_64::simulate_cpu;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Returns whether interrupts are enabled on the calling LP without changing that
+    fn irq_enabled() -> bool {
+        let enabled = ArchApi::irq_save();
+        ArchApi::irq_restore(enabled);
+        enabled
+    }
+
+    #[test]
+    fn try_lock_for_fails_while_held() {
+        let mutex = IrqSafeMutex::new(0);
+        let guard = mutex.lock();
+        assert!(!irq_enabled());
+        assert!(mutex.try_lock_for(0).is_none());
+        assert!(mutex.try_lock_for(4 * MAX_BACKOFF).is_none());
+        assert!(mutex.try_lock().is_none());
+        // failed attempts leave the interrupt state of the holder alone
+        assert!(!irq_enabled());
+        drop(guard);
+        assert!(irq_enabled());
+    }
+
+    #[test]
+    fn try_lock_for_succeeds_when_free() {
+        let mutex = IrqSafeMutex::new(0);
+        for spins in [0, 1, MAX_BACKOFF] {
+            let mut guard = mutex.try_lock_for(spins).unwrap();
+            *guard += 1;
+            assert!(!irq_enabled());
+            drop(guard);
+            assert!(irq_enabled());
+        }
+        assert_eq!(*mutex.lock(), 3);
+    }
+
+    #[test]
+    fn try_lock_for_restores_disabled_interrupts() {
+        let mutex = IrqSafeMutex::new(());
+        let were_enabled = ArchApi::irq_save();
+        drop(mutex.try_lock_for(1).unwrap());
+        assert!(!irq_enabled());
+        let guard = mutex.lock();
+        assert!(mutex.try_lock_for(1).is_none());
+        drop(guard);
+        assert!(!irq_enabled());
+        ArchApi::irq_restore(were_enabled);
+    }
+
+    #[test]
+    fn try_lock_for_backs_off_until_the_holder_releases() {
+        static MUTEX: IrqSafeMutex<usize> = IrqSafeMutex::new(0);
+        let (locked, wait) = mpsc::channel();
+        let holder = thread::spawn(move || {
+            let mut guard = MUTEX.lock();
+            locked.send(()).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            *guard = 1;
+        });
+        wait.recv().unwrap();
+        // the holder is asleep so the first attempts fail and the waiter backs off
+        assert!(MUTEX.try_lock_for(MAX_BACKOFF).is_none());
+        let guard = MUTEX.try_lock_for(usize::MAX).unwrap();
+        assert_eq!(*guard, 1);
+        drop(guard);
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn force_unlock_only_releases_a_lock_held_by_the_calling_lp() {
+        static MUTEX: IrqSafeMutex<()> = IrqSafeMutex::new(());
+        let (locked, wait) = mpsc::channel();
+        let (release, released) = mpsc::channel();
+        let writer = thread::spawn(move || {
+            simulate_cpu(1);
+            let guard = MUTEX.lock();
+            locked.send(()).unwrap();
+            released.recv().unwrap();
+            drop(guard);
+        });
+        wait.recv().unwrap();
+        simulate_cpu(0);
+        // SAFETY: the lock is not held by this thread so it is left alone
+        assert!(!unsafe { MUTEX.force_unlock_if_held_here() });
+        assert!(MUTEX.try_lock().is_none());
+        release.send(()).unwrap();
+        writer.join().unwrap();
+
+        // a writer that panics mid-line never drops its guard
+        core::mem::forget(MUTEX.lock());
+        // SAFETY: the guard was forgotten so nothing uses the lock anymore
+        assert!(unsafe { MUTEX.force_unlock_if_held_here() });
+        assert!(MUTEX.try_lock().is_some());
+        ArchApi::irq_restore(true);
+    }
+}
@@ -0,0 +1,40 @@
+//! # Time
+//! The kernel's monotonic clock. Of the clock sources the ISA provides, the one with the finest
+//! resolution is selected once at boot and every reading is relative to the moment it was selected.
+
+use core::time::Duration;
+
+use spin::Once;
+
+use crate::arch::{Api, ArchApi, ClockSource};
+
+struct Monotonic {
+    source: &'static dyn ClockSource,
+    start_ns: u64,
+}
+
+static MONOTONIC: Once<Monotonic> = Once::new();
+
+/// Selects the clock source with the finest resolution among those found by the ISA
+/// # Returns
+/// Returns the selected source or `None` if the ISA did not find any
+pub fn init(api: &ArchApi) -> Option<&'static dyn ClockSource> {
+    let source = api
+        .clock_sources()
+        .min_by_key(|source| source.resolution_ns())?;
+    let monotonic = MONOTONIC.call_once(|| Monotonic {
+        source,
+        start_ns: source.now_ns(),
+    });
+    Some(monotonic.source)
+}
+
+/// Returns the time elapsed since the clock source was selected, zero before [init]
+pub fn monotonic() -> Duration {
+    match MONOTONIC.get() {
+        Some(monotonic) => {
+            Duration::from_nanos(monotonic.source.now_ns().saturating_sub(monotonic.start_ns))
+        }
+        None => Duration::ZERO,
+    }
+}